@@ -0,0 +1,116 @@
+// Generates src/romdb.txt from the NES 2.0 XML database (nes20db.xml, kept
+// by the NESdev community), whose <rom> checksum is the CRC32 of the PRG and
+// CHR data the way romdb.rs looks it up.
+//
+//   cargo run --example romdb -- nes20db.xml > src/romdb.txt
+//
+// The file is a flat list of <game> elements with one attribute-only tag
+// per property, so it is scanned by hand rather than parsed:
+//   <game>
+//     <!-- path\to\Game (World).nes -->
+//     <prgrom size="32768" crc32="..."/>
+//     <rom size="40960" crc32="3337EC46" .../>
+//     <pcb mapper="0" submapper="0" mirroring="V" battery="0"/>
+//     <prgram size="8192"/>  (also prgnvram, chrram and chrnvram)
+//     <console type="0" region="0"/>
+//   </game>
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+const HEADER: &str = "\
+# Header corrections for known games, looked up by the CRC32 of the PRG and
+# CHR data (no header or trainer). See romdb.rs for the format.
+#
+# Generated by examples/romdb.rs from the NES 2.0 XML database.
+#
+# crc32    mapper  mirroring  battery  prg-ram  chr-ram  region  name";
+
+// The value of `name` in the first <tag ...> of `game`.
+fn attribute<'a>(game: &'a str, tag: &str, name: &str) -> Option<&'a str> {
+    let start = game.find(&format!("<{} ", tag))?;
+    let element = &game[start..start + game[start..].find('>')?];
+    let key = format!(" {}=\"", name);
+    let value = &element[element.find(&key)? + key.len()..];
+    Some(&value[..value.find('"')?])
+}
+
+fn size(game: &str, tags: &[&str]) -> u32 {
+    tags.iter()
+        .filter_map(|t| attribute(game, t, "size"))
+        .map(|s| s.parse::<u32>().unwrap_or(0))
+        .sum()
+}
+
+fn ram(n: u32) -> String {
+    if n != 0 && n.is_multiple_of(1024) { format!("{}K", n / 1024) } else { n.to_string() }
+}
+
+// The game's file name is only in the comment before its other elements.
+fn name(game: &str) -> String {
+    let comment = game.find("<!--").and_then(|start| {
+        let text = &game[start + 4..];
+        text.find("-->").map(|end| text[..end].trim())
+    });
+    let path = comment.unwrap_or("").replace('\\', "/");
+    match Path::new(&path).file_stem() {
+        Some(stem) => stem.to_string_lossy().into_owned(),
+        None => "?".to_string(),
+    }
+}
+
+fn entry(game: &str) -> Option<(String, String)> {
+    let crc = attribute(game, "rom", "crc32")?.to_uppercase();
+    attribute(game, "pcb", "mapper")?;
+    let pcb = |name, default| attribute(game, "pcb", name).unwrap_or(default);
+    let mirroring = match pcb("mirroring", "H") {
+        m @ "V" | m @ "4" => m,
+        _ => "H",
+    };
+    // console region: NTSC, PAL, multi-region, Dendy
+    let region = match attribute(game, "console", "region").unwrap_or("0") {
+        "1" => "pal",
+        "3" => "dendy",
+        _ => "ntsc",
+    };
+    let line = format!("{}   {:<7} {:<10} {:<8} {:<8} {:<8} {:<7} {}",
+                       crc,
+                       format!("{}.{}", pcb("mapper", "0"), pcb("submapper", "0")),
+                       mirroring,
+                       if pcb("battery", "0") == "1" { "B" } else { "-" },
+                       ram(size(game, &["prgram", "prgnvram"])),
+                       ram(size(game, &["chrram", "chrnvram"])),
+                       region,
+                       name(game));
+    Some((crc, line))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 1 {
+        eprintln!("usage: cargo run --example romdb -- nes20db.xml > src/romdb.txt");
+        process::exit(1);
+    }
+    let xml = match fs::read_to_string(&args[0]) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}: {}", args[0], e);
+            process::exit(1);
+        }
+    };
+    // the first entry for a checksum wins, and the output is sorted by it
+    let mut lines = BTreeMap::new();
+    for game in xml.split("<game>").skip(1) {
+        let game = &game[..game.find("</game>").unwrap_or(game.len())];
+        if let Some((crc, line)) = entry(game) {
+            lines.entry(crc).or_insert(line);
+        }
+    }
+    println!("{}", HEADER);
+    for line in lines.values() {
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,917 @@
+// Audio processing unit, the sound half of the 2A03
+//
+//   $4000-$4003  pulse 1: duty and envelope, sweep, period low,
+//                length and period high
+//   $4004-$4007  pulse 2, the same
+//   $4008-$400B  triangle: linear counter, unused, period low,
+//                length and period high
+//   $400C-$400F  noise: envelope, unused, mode and period, length
+//   $4010-$4013  DMC: IRQ, loop and rate, output level, sample address,
+//                sample length
+//   $4015        channel enables; reads back which length counters are
+//                running and the two IRQ flags
+//   $4017        frame counter: 4 or 5 steps, IRQ inhibit
+//
+// The pulses step every other CPU cycle, which their periods count in, and
+// the other channels every cycle. The frame counter clocks the
+// envelopes and the triangle's linear counter each quarter frame, and the
+// length counters and sweeps each half frame; in 4-step mode it also
+// raises an IRQ at the end of the sequence. The region decides where the
+// steps fall and the noise and DMC periods (see region.rs).
+//
+// The DMC asks for its sample bytes through dmc_fetch(), and MemMap reads
+// them and charges the CPU the cycles the 2A03 halts it for.
+//
+// output() mixes the channels with the nonlinear formulas from the NESdev
+// wiki, for a level between 0 and about 1. Expansion audio is mixed in at
+// the level of one pulse at full volume times these ratios, roughly what
+// the boards' resistors give next to the 2A03:
+//
+//   VRC6 pulse            1.0
+//   MMC5 pulse            1.0 (PCM 2.0)
+//   Namco 163 channel     1.5
+//   Sunsoft 5B channel    1.0
+//   FDS wave              2.4
+
+use region::Region;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+// output() for one pulse channel at volume 15, the reference for expansion
+// audio.
+pub const PULSE_FULL_VOLUME: f32 = 95.88 / (8128.0 / 15.0 + 100.0);
+
+static LENGTHS: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+static DUTIES: [u8; 4] = [0b0100_0000, 0b0110_0000, 0b0111_1000, 0b1001_1111];
+
+static TRIANGLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    // The constant volume, or the decay's period.
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.looping = value & 0x20 != 0;
+        self.constant = value & 0x10 != 0;
+        self.volume = value & 0x0F;
+    }
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        }
+        else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            }
+            else if self.looping {
+                self.decay = 15;
+            }
+        }
+        else {
+            self.divider -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+}
+
+impl Savable for Envelope {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        w.write_bool(self.looping);
+        w.write_bool(self.constant);
+        w.write_u8(self.volume);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.start = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.constant = r.read_bool()?;
+        self.volume = r.read_u8()? & 0x0F;
+        self.divider = r.read_u8()? & 0x0F;
+        self.decay = r.read_u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    fn load_index(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTHS[index as usize & 0x1F];
+        }
+    }
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+    fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+}
+
+impl Savable for LengthCounter {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.halt);
+        w.write_u8(self.counter);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.read_bool()?;
+        self.halt = r.read_bool()?;
+        self.counter = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Pulse {
+    // Pulse 1 negates its sweep with one's complement, pulse 2 with two's.
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = value >> 6;
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => {
+                self.sweep_enabled = value & 0x80 != 0;
+                self.sweep_period = (value >> 4) & 0x07;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value & 0x07) as u16) << 8;
+                self.length.load_index(value >> 3);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 0x07;
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+    // The period the sweep unit would change to. It's worked out all the
+    // time, and mutes the channel when it overflows, even with the sweep
+    // disabled.
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if self.sweep_negate {
+            self.period.saturating_sub(change + self.ones_complement as u16)
+        }
+        else {
+            self.period + change
+        }
+    }
+    fn muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        }
+        else {
+            self.sweep_divider -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.muted() || self.length.counter == 0 || DUTIES[self.duty as usize] & (0x80 >> self.step) == 0 {
+            0
+        }
+        else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Savable for Pulse {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u8(self.duty);
+        w.write_u8(self.step);
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        self.envelope.save(w);
+        self.length.save(w);
+        w.write_bool(self.sweep_enabled);
+        w.write_u8(self.sweep_period);
+        w.write_bool(self.sweep_negate);
+        w.write_u8(self.sweep_shift);
+        w.write_u8(self.sweep_divider);
+        w.write_bool(self.sweep_reload);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.duty = r.read_u8()? & 0x03;
+        self.step = r.read_u8()? & 0x07;
+        self.period = r.read_u16()? & 0x07FF;
+        self.timer = r.read_u16()? & 0x07FF;
+        self.envelope.load(r)?;
+        self.length.load(r)?;
+        self.sweep_enabled = r.read_bool()?;
+        self.sweep_period = r.read_u8()? & 0x07;
+        self.sweep_negate = r.read_bool()?;
+        self.sweep_shift = r.read_u8()? & 0x07;
+        self.sweep_divider = r.read_u8()? & 0x07;
+        self.sweep_reload = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Triangle {
+    period: u16,
+    timer: u16,
+    step: u8,
+    length: LengthCounter,
+    // Bit 7 of $4008 halts the length counter and keeps reloading the
+    // linear counter.
+    control: bool,
+    linear_period: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.control = value & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_period = value & 0x7F;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value & 0x07) as u16) << 8;
+                self.length.load_index(value >> 3);
+                self.linear_reload = true;
+            }
+        }
+    }
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.counter > 0 && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1F;
+            }
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_period;
+        }
+        else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+    // Stopping the sequencer leaves the output where it was.
+    fn output(&self) -> u8 {
+        TRIANGLE[self.step as usize]
+    }
+}
+
+impl Savable for Triangle {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        w.write_u8(self.step);
+        self.length.save(w);
+        w.write_bool(self.control);
+        w.write_u8(self.linear_period);
+        w.write_u8(self.linear_counter);
+        w.write_bool(self.linear_reload);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.period = r.read_u16()? & 0x07FF;
+        self.timer = r.read_u16()? & 0x07FF;
+        self.step = r.read_u8()? & 0x1F;
+        self.length.load(r)?;
+        self.control = r.read_bool()?;
+        self.linear_period = r.read_u8()? & 0x7F;
+        self.linear_counter = r.read_u8()? & 0x7F;
+        self.linear_reload = r.read_bool()?;
+        Ok(())
+    }
+}
+
+struct Noise {
+    // Bit 7 of $400E: a 93-step sequence instead of 32767 steps.
+    short_mode: bool,
+    period: u16,
+    timer: u16,
+    shift: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Noise {
+    fn write(&mut self, register: u16, value: u8, periods: &[u16; 16]) {
+        match register {
+            0 => {
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = value & 0x80 != 0;
+                self.period = periods[value as usize & 0x0F];
+            }
+            _ => {
+                self.length.load_index(value >> 3);
+                self.envelope.start = true;
+            }
+        }
+    }
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = self.shift >> 1 | feedback << 14;
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.shift & 1 != 0 || self.length.counter == 0 { 0 } else { self.envelope.output() }
+    }
+}
+
+impl Savable for Noise {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bool(self.short_mode);
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        w.write_u16(self.shift);
+        self.envelope.save(w);
+        self.length.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.short_mode = r.read_bool()?;
+        self.period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        if self.period == 0 || self.timer >= self.period {
+            return Err(StateError::Invalid("noise period"));
+        }
+        self.shift = r.read_u16()? & 0x7FFF;
+        self.envelope.load(r)?;
+        self.length.load(r)
+    }
+}
+
+struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    address: u16,
+    bytes_remaining: u16,
+    // The byte fetched for the output unit, once it's been fetched.
+    buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silent: bool,
+    irq: bool,
+}
+
+impl Dmc {
+    fn write(&mut self, register: u16, value: u8, periods: &[u16; 16]) {
+        match register {
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = value & 0x40 != 0;
+                self.period = periods[value as usize & 0x0F];
+            }
+            1 => self.level = value & 0x7F,
+            2 => self.sample_address = 0xC000 | (value as u16) << 6,
+            _ => self.sample_length = (value as u16) << 4 | 1,
+        }
+    }
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        }
+        else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+        if !self.silent {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            }
+            else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(value) => {
+                    self.shift = value;
+                    self.silent = false;
+                }
+                None => self.silent = true,
+            }
+        }
+    }
+    fn fetch_address(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 { Some(self.address) } else { None }
+    }
+    fn fill(&mut self, value: u8) {
+        self.buffer = Some(value);
+        // the address wraps around to $8000, not $0000
+        self.address = if self.address == 0xFFFF { 0x8000 } else { self.address + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            }
+            else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+}
+
+impl Savable for Dmc {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.looping);
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        w.write_u8(self.level);
+        w.write_u16(self.sample_address);
+        w.write_u16(self.sample_length);
+        w.write_u16(self.address);
+        w.write_u16(self.bytes_remaining);
+        w.write_bool(self.buffer.is_some());
+        w.write_u8(self.buffer.unwrap_or(0));
+        w.write_u8(self.shift);
+        w.write_u8(self.bits_remaining);
+        w.write_bool(self.silent);
+        w.write_bool(self.irq);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        if self.period == 0 || self.timer >= self.period {
+            return Err(StateError::Invalid("DMC period"));
+        }
+        self.level = r.read_u8()? & 0x7F;
+        self.sample_address = r.read_u16()?;
+        self.sample_length = r.read_u16()?;
+        self.address = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        let full = r.read_bool()?;
+        let value = r.read_u8()?;
+        self.buffer = if full { Some(value) } else { None };
+        self.shift = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?;
+        if self.bits_remaining == 0 || self.bits_remaining > 8 {
+            return Err(StateError::Invalid("DMC bits"));
+        }
+        self.silent = r.read_bool()?;
+        self.irq = r.read_bool()?;
+        Ok(())
+    }
+}
+
+pub struct Apu {
+    region: Region,
+    pulses: [Pulse; 2],
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    // CPU cycles since power-on; the pulses step on even ones.
+    cycle: u64,
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    // CPU cycles into the frame counter's sequence.
+    frame_cycle: u32,
+    // A $4017 write restarts the sequence 3 or 4 cycles later.
+    frame_reset_delay: u8,
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Apu {
+        Apu {
+            region,
+            pulses: [Pulse { ones_complement: true, ..Default::default() }, Pulse::default()],
+            triangle: Triangle::default(),
+            noise: Noise {
+                short_mode: false,
+                period: region.noise_periods()[0],
+                timer: 0,
+                shift: 1,
+                envelope: Envelope::default(),
+                length: LengthCounter::default(),
+            },
+            dmc: Dmc {
+                irq_enabled: false,
+                looping: false,
+                period: region.dmc_periods()[0],
+                timer: 0,
+                level: 0,
+                sample_address: 0xC000,
+                sample_length: 1,
+                address: 0xC000,
+                bytes_remaining: 0,
+                buffer: None,
+                shift: 0,
+                bits_remaining: 8,
+                silent: true,
+                irq: false,
+            },
+            cycle: 0,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            frame_reset_delay: 0,
+        }
+    }
+    // Everything silent, as after writing 0 to $4015 and $4017.
+    pub fn power_on(&mut self) {
+        *self = Apu::new(self.region);
+    }
+    // The new tables apply from the next $400E and $4010 writes.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+    // $4000-$4013, $4015 and $4017
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulses[0].write(address & 0x03, value),
+            0x4004..=0x4007 => self.pulses[1].write(address & 0x03, value),
+            0x4008..=0x400B => self.triangle.write(address & 0x03, value),
+            0x400C..=0x400F => self.noise.write(address & 0x03, value, self.region.noise_periods()),
+            0x4010..=0x4013 => self.dmc.write(address & 0x03, value, self.region.dmc_periods()),
+            0x4015 => {
+                self.pulses[0].length.set_enabled(value & 0x01 != 0);
+                self.pulses[1].length.set_enabled(value & 0x02 != 0);
+                self.triangle.length.set_enabled(value & 0x04 != 0);
+                self.noise.length.set_enabled(value & 0x08 != 0);
+                self.dmc.set_enabled(value & 0x10 != 0);
+            }
+            0x4017 => {
+                self.five_step = value & 0x80 != 0;
+                self.irq_inhibit = value & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_reset_delay = if self.cycle & 1 != 0 { 4 } else { 3 };
+            }
+            _ => {}
+        }
+    }
+    // $4015: bits 0-4 are set while the channels' length counters (the
+    // DMC's bytes remaining) are non-zero, bit 6 is the frame IRQ and bit 7
+    // the DMC's. Bit 5 isn't driven.
+    pub fn status(&self) -> u8 {
+        let mut status = 0;
+        for (i, length) in [&self.pulses[0].length, &self.pulses[1].length, &self.triangle.length, &self.noise.length].iter().enumerate() {
+            if length.counter > 0 {
+                status |= 1 << i;
+            }
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0x10;
+        }
+        if self.frame_irq {
+            status |= 0x40;
+        }
+        if self.dmc.irq {
+            status |= 0x80;
+        }
+        status
+    }
+    // Reading $4015 acknowledges the frame IRQ.
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.status();
+        self.frame_irq = false;
+        status
+    }
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+    // The address the DMC wants its next sample byte from, if it needs one.
+    pub fn dmc_fetch(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+    pub fn dmc_fill(&mut self, value: u8) {
+        self.dmc.fill(value);
+    }
+    // One CPU cycle.
+    pub fn tick(&mut self) {
+        self.cycle += 1;
+        if self.cycle & 1 == 0 {
+            self.pulses[0].clock_timer();
+            self.pulses[1].clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        self.clock_frame_counter();
+    }
+    fn clock_frame_counter(&mut self) {
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+            if self.frame_reset_delay == 0 {
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+                return;
+            }
+        }
+        self.frame_cycle += 1;
+        let steps = self.region.frame_counter_steps();
+        let last = if self.five_step { steps[4] } else { steps[3] };
+        let cycle = self.frame_cycle;
+        if cycle == steps[0] || cycle == steps[2] {
+            self.quarter_frame();
+        }
+        else if cycle == steps[1] || cycle == last {
+            self.quarter_frame();
+            self.half_frame();
+        }
+        // the flag goes up over three cycles around the last step
+        if !self.five_step && !self.irq_inhibit && cycle + 1 >= last && cycle <= last + 1 {
+            self.frame_irq = true;
+        }
+        if cycle == last + 1 {
+            self.frame_cycle = 0;
+        }
+    }
+    fn quarter_frame(&mut self) {
+        self.pulses[0].envelope.clock();
+        self.pulses[1].envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+    fn half_frame(&mut self) {
+        for pulse in &mut self.pulses {
+            pulse.length.clock();
+            pulse.clock_sweep();
+        }
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+    // The mixed output level, 0 to about 1.
+    pub fn output(&self) -> f32 {
+        let pulses = (self.pulses[0].output() + self.pulses[1].output()) as f32;
+        let pulse_out = if pulses == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulses + 100.0) };
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.level as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+        pulse_out + tnd_out
+    }
+}
+
+impl Savable for Apu {
+    fn save(&self, w: &mut StateWriter) {
+        for pulse in &self.pulses {
+            pulse.save(w);
+        }
+        self.triangle.save(w);
+        self.noise.save(w);
+        self.dmc.save(w);
+        w.write_u64(self.cycle);
+        w.write_bool(self.five_step);
+        w.write_bool(self.irq_inhibit);
+        w.write_bool(self.frame_irq);
+        w.write_u16(self.frame_cycle as u16);
+        w.write_u8(self.frame_reset_delay);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for pulse in &mut self.pulses {
+            pulse.load(r)?;
+        }
+        self.triangle.load(r)?;
+        self.noise.load(r)?;
+        self.dmc.load(r)?;
+        self.cycle = r.read_u64()?;
+        self.five_step = r.read_bool()?;
+        self.irq_inhibit = r.read_bool()?;
+        self.frame_irq = r.read_bool()?;
+        self.frame_cycle = r.read_u16()? as u32;
+        self.frame_reset_delay = r.read_u8()?.min(4);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.tick();
+        }
+    }
+
+    #[test]
+    fn length_counter_needs_enabling() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x4003, 0x08);
+        assert_eq!(apu.status() & 0x01, 0);
+        apu.write(0x4015, 0x0F);
+        apu.write(0x4003, 0x08); // index 1: 254
+        apu.write(0x400F, 0x00); // index 0: 10
+        assert_eq!(apu.status() & 0x0F, 0x09);
+        apu.write(0x4015, 0x01);
+        assert_eq!(apu.status() & 0x0F, 0x01);
+    }
+
+    #[test]
+    fn length_counter_counts_half_frames() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x4017, 0x40);
+        apu.write(0x4015, 0x01);
+        apu.write(0x4003, 0x18); // index 3: 2
+        run(&mut apu, 14913 + 4);
+        assert_eq!(apu.status() & 0x01, 0x01);
+        run(&mut apu, 29829 - 14913);
+        assert_eq!(apu.status() & 0x01, 0);
+    }
+
+    #[test]
+    fn frame_irq() {
+        for &(region, last) in &[(Region::Ntsc, 29829), (Region::Pal, 33253)] {
+            let mut apu = Apu::new(region);
+            run(&mut apu, last - 2);
+            assert!(!apu.irq());
+            run(&mut apu, 1);
+            assert!(apu.irq(), "{}", region);
+            assert_eq!(apu.read_status() & 0x40, 0x40);
+            assert!(!apu.irq());
+            apu.write(0x4017, 0x40);
+            run(&mut apu, last * 2);
+            assert!(!apu.irq());
+            apu.write(0x4017, 0x80);
+            run(&mut apu, 41565 * 2);
+            assert!(!apu.irq());
+        }
+    }
+
+    #[test]
+    fn sweep_mutes_on_overflow() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x4015, 0x01);
+        apu.write(0x4000, 0xBF); // duty 2, constant volume 15
+        apu.write(0x4002, 0xFF);
+        apu.write(0x4003, 0x07);
+        apu.write(0x4001, 0x01); // target 0x7FF + 0x3FF
+        run(&mut apu, 64);
+        assert_eq!(apu.pulses[0].output(), 0);
+        apu.write(0x4001, 0x08 | 0x01);
+        run(&mut apu, 64);
+        assert_eq!(apu.pulses[0].output(), 15);
+        // the triangle rests at 15, not 0
+        let silent = Apu::new(Region::Ntsc).output();
+        assert!((apu.output() - silent - PULSE_FULL_VOLUME).abs() < 1e-6);
+    }
+
+    #[test]
+    fn dmc_fetches_and_irq() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x4010, 0x8F);
+        apu.write(0x4012, 0x01);
+        apu.write(0x4013, 0x00); // one byte
+        apu.write(0x4015, 0x10);
+        assert_eq!(apu.status() & 0x10, 0x10);
+        assert_eq!(apu.dmc_fetch(), Some(0xC040));
+        apu.dmc_fill(0xFF);
+        assert_eq!(apu.dmc_fetch(), None);
+        assert_eq!(apu.status() & 0x90, 0x80);
+        assert!(apu.irq());
+        apu.write(0x4015, 0x00);
+        assert!(!apu.irq());
+        // the output unit takes the byte and counts up
+        run(&mut apu, 54 * 16);
+        assert!(apu.output() > 0.0);
+    }
+
+    #[test]
+    fn region_noise_periods() {
+        for &region in &[Region::Ntsc, Region::Pal] {
+            let mut apu = Apu::new(region);
+            apu.write(0x400E, 0x0F);
+            assert_eq!(apu.noise.period, region.noise_periods()[15]);
+            apu.write(0x4010, 0x00);
+            assert_eq!(apu.dmc.period, region.dmc_periods()[0]);
+        }
+        assert_eq!(Region::Pal.noise_periods()[15], 3778);
+        assert_eq!(Region::Dendy.dmc_periods(), Region::Ntsc.dmc_periods());
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x4015, 0x1F);
+        apu.write(0x4000, 0x3F);
+        apu.write(0x4003, 0x08);
+        apu.write(0x400E, 0x05);
+        run(&mut apu, 1000);
+        let mut w = StateWriter::new(0, Region::Ntsc);
+        apu.save(&mut w);
+        let data = w.finish();
+        let mut copy = Apu::new(Region::Ntsc);
+        copy.load(&mut StateReader::new(&data, 0, Region::Ntsc).unwrap()).unwrap();
+        for _ in 0..20000 {
+            apu.tick();
+            copy.tick();
+            assert_eq!(apu.output(), copy.output());
+        }
+        assert_eq!(apu.status(), copy.status());
+    }
+}
@@ -0,0 +1,295 @@
+// ROMs inside zip and gzip files
+//
+// The containers are simple enough to read here; only the deflate
+// decompression comes from miniz_oxide, and only when built with the
+// "archive" feature. Without it, archives are still recognized but anything
+// compressed fails with NotBuiltIn.
+//
+// From a zip, the entry named after the ':' in "games.zip:game.nes" is
+// used, or otherwise the first one that looks like a ROM.
+
+use std::fmt;
+
+use crc32::Crc32;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const ZIP_END_MAGIC: &[u8] = b"PK\x05\x06";
+const ZIP_CENTRAL_MAGIC: &[u8] = b"PK\x01\x02";
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+
+const ROM_EXTENSIONS: [&str; 3] = [".nes", ".unf", ".fds"];
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    #[cfg(not(feature = "archive"))]
+    NotBuiltIn,
+    Corrupt(&'static str),
+    Unsupported(&'static str),
+    NoRom,
+    EntryNotFound(String),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            #[cfg(not(feature = "archive"))]
+            ArchiveError::NotBuiltIn => write!(f, "compressed archives need the \"archive\" feature"),
+            ArchiveError::Corrupt(what) => write!(f, "corrupt archive: {}", what),
+            ArchiveError::Unsupported(what) => write!(f, "unsupported archive: {}", what),
+            ArchiveError::NoRom => write!(f, "no .nes, .unf or .fds file in the archive"),
+            ArchiveError::EntryNotFound(ref name) => write!(f, "no {} in the archive", name),
+        }
+    }
+}
+
+pub fn is_archive(data: &[u8]) -> bool {
+    data.starts_with(ZIP_MAGIC) || data.starts_with(ZIP_END_MAGIC) || data.starts_with(GZIP_MAGIC)
+}
+
+// The ROM inside `data`. `entry` picks a file from a zip.
+pub fn extract(data: &[u8], entry: Option<&str>) -> Result<Vec<u8>, ArchiveError> {
+    if data.starts_with(GZIP_MAGIC) {
+        gunzip(data)
+    } else {
+        unzip(data, entry)
+    }
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Result<u16, ArchiveError> {
+    data.get(pos..pos + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(ArchiveError::Corrupt("truncated"))
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Result<u32, ArchiveError> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(ArchiveError::Corrupt("truncated"))
+}
+
+fn check_crc(data: &[u8], expected: u32) -> Result<(), ArchiveError> {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    if crc.finish() != expected {
+        return Err(ArchiveError::Corrupt("CRC mismatch"));
+    }
+    Ok(())
+}
+
+// RFC 1952: a 10 byte header with optional fields, raw deflate data, then
+// the CRC32 and length of the original.
+fn gunzip(data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    if data.len() < 18 {
+        return Err(ArchiveError::Corrupt("truncated"));
+    }
+    if data[2] != 8 {
+        return Err(ArchiveError::Unsupported("gzip compression method"));
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & 0x04 != 0 {
+        pos += 2 + u16_at(data, pos)? as usize;
+    }
+    // file name and comment, both zero terminated
+    for &flag in &[0x08, 0x10] {
+        if flags & flag != 0 {
+            let rest = data.get(pos..).ok_or(ArchiveError::Corrupt("truncated"))?;
+            pos += rest.iter().position(|&b| b == 0).ok_or(ArchiveError::Corrupt("truncated"))? + 1;
+        }
+    }
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    let end = data.len() - 8;
+    let compressed = data.get(pos..end).ok_or(ArchiveError::Corrupt("truncated"))?;
+    let out = inflate(compressed)?;
+    check_crc(&out, u32_at(data, end)?)?;
+    Ok(out)
+}
+
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: usize,
+    local_header: usize,
+}
+
+fn unzip(data: &[u8], entry: Option<&str>) -> Result<Vec<u8>, ArchiveError> {
+    let entries = zip_entries(data)?;
+    let found = match entry {
+        Some(name) => entries.iter().find(|e| e.name == name)
+            .ok_or_else(|| ArchiveError::EntryNotFound(name.to_string()))?,
+        None => entries.iter()
+            .find(|e| {
+                let name = e.name.to_lowercase();
+                ROM_EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+            })
+            .ok_or(ArchiveError::NoRom)?,
+    };
+    // the local header repeats the name and has its own extra field
+    let pos = found.local_header;
+    if data.get(pos..pos + 4) != Some(ZIP_MAGIC) {
+        return Err(ArchiveError::Corrupt("bad local header"));
+    }
+    let start = pos + 30 + u16_at(data, pos + 26)? as usize + u16_at(data, pos + 28)? as usize;
+    let compressed = data.get(start..start + found.compressed_size)
+        .ok_or(ArchiveError::Corrupt("truncated"))?;
+    let out = match found.method {
+        0 => compressed.to_vec(),
+        8 => inflate(compressed)?,
+        _ => return Err(ArchiveError::Unsupported("zip compression method")),
+    };
+    check_crc(&out, found.crc)?;
+    Ok(out)
+}
+
+// Reads the central directory, found through the end record at the end of
+// the file (before a comment of up to 64K).
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry>, ArchiveError> {
+    let search_from = data.len().saturating_sub(22 + 0xFFFF);
+    let end = (search_from..data.len().saturating_sub(21)).rev()
+        .find(|&i| data[i..].starts_with(ZIP_END_MAGIC))
+        .ok_or(ArchiveError::Corrupt("no end of central directory"))?;
+    let count = u16_at(data, end + 10)? as usize;
+    let mut pos = u32_at(data, end + 16)? as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if data.get(pos..pos + 4) != Some(ZIP_CENTRAL_MAGIC) {
+            return Err(ArchiveError::Corrupt("bad central directory"));
+        }
+        let name_len = u16_at(data, pos + 28)? as usize;
+        let skip = u16_at(data, pos + 30)? as usize + u16_at(data, pos + 32)? as usize;
+        let name = data.get(pos + 46..pos + 46 + name_len).ok_or(ArchiveError::Corrupt("truncated"))?;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: u16_at(data, pos + 10)?,
+            crc: u32_at(data, pos + 16)?,
+            compressed_size: u32_at(data, pos + 20)? as usize,
+            local_header: u32_at(data, pos + 42)? as usize,
+        });
+        pos += 46 + name_len + skip;
+    }
+    Ok(entries)
+}
+
+#[cfg(feature = "archive")]
+fn inflate(data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    ::miniz_oxide::inflate::decompress_to_vec(data).map_err(|_| ArchiveError::Corrupt("bad deflate data"))
+}
+
+#[cfg(not(feature = "archive"))]
+fn inflate(_data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    Err(ArchiveError::NotBuiltIn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crc(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finish()
+    }
+
+    // A single stored deflate block.
+    fn deflate_stored(data: &[u8]) -> Vec<u8> {
+        let len = data.len() as u16;
+        let mut out = vec![0x01];
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    // (name, method, contents)
+    fn zip(files: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for &(name, method, contents) in files {
+            let stored = if method == 8 { deflate_stored(contents) } else { contents.to_vec() };
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);
+            fields.extend_from_slice(&crc(contents).to_le_bytes());
+            fields.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+            central.extend_from_slice(ZIP_CENTRAL_MAGIC);
+            central.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            central.extend_from_slice(&fields);
+            central.extend_from_slice(&[0; 12]);
+            central.extend_from_slice(&(out.len() as u32).to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+
+            out.extend_from_slice(ZIP_MAGIC);
+            out.extend_from_slice(&[20, 0, 0, 0]);
+            out.extend_from_slice(&fields);
+            // an extra field only in the local header
+            out.extend_from_slice(&[3, 0]);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&[1, 2, 3]);
+            out.extend_from_slice(&stored);
+        }
+        let start = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(ZIP_END_MAGIC);
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&start.to_le_bytes());
+        out.extend_from_slice(b"\x05\x00hello");
+        out
+    }
+
+    #[test]
+    fn stored_zip() {
+        let data = zip(&[("readme.txt", 0, b"hi"), ("Game.NES", 0, b"NES\x1A"), ("b.fds", 0, b"FDS")]);
+        assert!(is_archive(&data));
+        assert_eq!(extract(&data, None).unwrap(), b"NES\x1A");
+        assert_eq!(extract(&data, Some("b.fds")).unwrap(), b"FDS");
+        assert!(matches!(extract(&data, Some("c.nes")), Err(ArchiveError::EntryNotFound(ref n)) if n == "c.nes"));
+
+        let data = zip(&[("readme.txt", 0, b"hi")]);
+        assert!(matches!(extract(&data, None), Err(ArchiveError::NoRom)));
+    }
+
+    #[test]
+    fn corrupt_zip() {
+        let mut data = zip(&[("a.nes", 0, b"NES\x1A")]);
+        let pos = data.windows(4).position(|w| w == b"NES\x1A").unwrap();
+        data[pos] ^= 1;
+        assert!(matches!(extract(&data, None), Err(ArchiveError::Corrupt(_))));
+
+        let data = zip(&[("a.nes", 0, b"NES\x1A")]);
+        assert!(matches!(extract(&data[..data.len() - 30], None), Err(ArchiveError::Corrupt(_))));
+        assert!(matches!(extract(&zip(&[("a.nes", 12, b"x")]), None), Err(ArchiveError::Unsupported(_))));
+    }
+
+    #[cfg(feature = "archive")]
+    #[test]
+    fn deflated_zip_and_gzip() {
+        let data = zip(&[("a.nes", 8, b"NES\x1A rom")]);
+        assert_eq!(extract(&data, None).unwrap(), b"NES\x1A rom");
+
+        // with a file name
+        let mut gz = vec![0x1F, 0x8B, 8, 0x08, 0, 0, 0, 0, 0, 3];
+        gz.extend_from_slice(b"a.nes\0");
+        gz.extend_from_slice(&deflate_stored(b"NES\x1A rom"));
+        gz.extend_from_slice(&crc(b"NES\x1A rom").to_le_bytes());
+        gz.extend_from_slice(&8u32.to_le_bytes());
+        assert!(is_archive(&gz));
+        assert_eq!(extract(&gz, None).unwrap(), b"NES\x1A rom");
+    }
+
+    #[cfg(not(feature = "archive"))]
+    #[test]
+    fn compressed_needs_the_feature() {
+        let data = zip(&[("a.nes", 8, b"NES\x1A rom")]);
+        assert!(matches!(extract(&data, None), Err(ArchiveError::NotBuiltIn)));
+    }
+}
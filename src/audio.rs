@@ -0,0 +1,89 @@
+// Audio output
+//
+// The machine produces one sample per CPU cycle. SampleBuffer averages
+// those down to SAMPLE_RATE, runs them through the filters the console's
+// output stage has, and holds them until the frontend (or a recorder)
+// takes them. The high-pass filters take out the APU's DC offset, so
+// samples swing around 0.
+
+use std::collections::VecDeque;
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+// Keep at most the last second of audio around when nobody is draining it.
+const MAX_BUFFERED: usize = SAMPLE_RATE as usize;
+
+// A first-order RC filter, at SAMPLE_RATE.
+struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    last_in: f32,
+    last_out: f32,
+}
+
+impl Filter {
+    fn new(high_pass: bool, cutoff: f32) -> Filter {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / SAMPLE_RATE as f32;
+        let alpha = if high_pass { rc / (rc + dt) } else { dt / (rc + dt) };
+        Filter { high_pass, alpha, last_in: 0.0, last_out: 0.0 }
+    }
+    fn filter(&mut self, x: f32) -> f32 {
+        let y = if self.high_pass {
+            self.alpha * (self.last_out + x - self.last_in)
+        }
+        else {
+            self.last_out + self.alpha * (x - self.last_out)
+        };
+        self.last_in = x;
+        self.last_out = y;
+        y
+    }
+}
+
+pub struct SampleBuffer {
+    cycles_per_sample: f64,
+    phase: f64,
+    sum: f32,
+    count: u32,
+    // 90Hz and 440Hz high-pass, then 14kHz low-pass
+    filters: [Filter; 3],
+    samples: VecDeque<f32>,
+}
+
+impl SampleBuffer {
+    pub fn new(clock_rate: u64) -> SampleBuffer {
+        SampleBuffer {
+            cycles_per_sample: clock_rate as f64 / SAMPLE_RATE as f64,
+            phase: 0.0,
+            sum: 0.0,
+            count: 0,
+            filters: [Filter::new(true, 90.0), Filter::new(true, 440.0), Filter::new(false, 14000.0)],
+            samples: VecDeque::with_capacity(MAX_BUFFERED),
+        }
+    }
+    pub fn set_clock_rate(&mut self, clock_rate: u64) {
+        self.cycles_per_sample = clock_rate as f64 / SAMPLE_RATE as f64;
+    }
+    // Adds the output level for one CPU cycle.
+    pub fn push(&mut self, level: f32) {
+        self.sum += level;
+        self.count += 1;
+        self.phase += 1.0;
+        if self.phase >= self.cycles_per_sample {
+            self.phase -= self.cycles_per_sample;
+            // the oldest sample makes way, so what's kept stays continuous
+            if self.samples.len() == MAX_BUFFERED {
+                self.samples.pop_front();
+            }
+            let sample = self.filters.iter_mut().fold(self.sum / self.count as f32, |x, f| f.filter(x));
+            self.samples.push_back(sample);
+            self.sum = 0.0;
+            self.count = 0;
+        }
+    }
+    // Moves all the finished samples into `out`.
+    pub fn take(&mut self, out: &mut Vec<f32>) {
+        out.extend(self.samples.drain(..));
+    }
+}
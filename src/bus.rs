@@ -0,0 +1,34 @@
+// What the CPU sees on the other side of its address and data pins. MemMap
+// is the NES implementation; the CPU tests use a flat 64K one.
+//
+// The CPU calls tick() once at the start of every cycle, before that
+// cycle's read or write, so the rest of the machine is always caught up
+// when it sees an access.
+
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    // What read() would return right now, without any side effects. For
+    // debuggers, tracing and other tools.
+    fn peek(&self, address: u16) -> u8;
+    fn tick(&mut self) {}
+    // The level of the IRQ line, true when something is pulling it low.
+    fn irq(&self) -> bool {
+        false
+    }
+    // The level of the NMI line. The CPU takes an NMI when it goes from
+    // false to true.
+    fn nmi(&self) -> bool {
+        false
+    }
+    // After a $4014 write, the page the 2A03 wants copied to OAM, once.
+    // The CPU halts and does the copy before its next instruction.
+    fn take_oam_dma(&mut self) -> Option<u8> {
+        None
+    }
+    // Cycles the 2A03 has halted the CPU for on its own since the last call,
+    // for the DMC's sample fetches.
+    fn take_stall(&mut self) -> u32 {
+        0
+    }
+}
@@ -0,0 +1,270 @@
+// Encoders for headless capture: PNG screenshots, Y4M video and WAV audio.
+// All of them are simple enough to write by hand, so no extra crates are
+// needed. Pixels come in as 0x00RRGGBB, the way Palette produces them.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use audio;
+use crc32::Crc32;
+
+pub fn write_png<P: AsRef<Path>>(path: P, width: usize, height: usize, pixels: &[u32]) -> io::Result<()> {
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for row in pixels.chunks(width).take(height) {
+        raw.push(0); // filter type: none
+        for &p in row {
+            raw.push((p >> 16) as u8);
+            raw.push((p >> 8) as u8);
+            raw.push(p as u8);
+        }
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bit truecolour, default compression and filtering, no interlace
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A])?;
+    write_png_chunk(&mut out, b"IHDR", &ihdr)?;
+    write_png_chunk(&mut out, b"IDAT", &zlib_stored(&raw))?;
+    write_png_chunk(&mut out, b"IEND", &[])?;
+    out.flush()
+}
+
+fn write_png_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&crc.finish().to_be_bytes())
+}
+
+// A zlib stream made of uncompressed deflate blocks. Screenshots are small
+// enough that compressing them isn't worth the code.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 0xFFFF * 5 + 11);
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(if blocks.peek().is_none() { 0x01 } else { 0x00 });
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &x in chunk {
+            a += x as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+// YUV4MPEG2 video with 4:2:0 chroma, readable by ffmpeg, mpv and friends.
+pub struct Y4mWriter {
+    out: BufWriter<File>,
+    width: usize,
+    height: usize,
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+}
+
+impl Y4mWriter {
+    // The frame rate is given as a fraction, e.g. CPU clock / cycles per frame.
+    pub fn create<P: AsRef<Path>>(path: P, width: usize, height: usize,
+                                  rate_num: u64, rate_den: u64) -> io::Result<Y4mWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C420jpeg", width, height, rate_num, rate_den)?;
+        Ok(Y4mWriter {
+            out,
+            width,
+            height,
+            y: vec![0; width * height],
+            u: vec![0; width * height / 4],
+            v: vec![0; width * height / 4],
+        })
+    }
+
+    pub fn write_frame(&mut self, pixels: &[u32]) -> io::Result<()> {
+        for (y, &p) in self.y.iter_mut().zip(pixels) {
+            let (r, g, b) = split(p);
+            *y = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+        }
+        let cw = self.width / 2;
+        for cy in 0..self.height / 2 {
+            for cx in 0..cw {
+                let i = cy * 2 * self.width + cx * 2;
+                let quad = [pixels[i], pixels[i + 1], pixels[i + self.width], pixels[i + self.width + 1]];
+                let (mut r, mut g, mut b) = (0, 0, 0);
+                for &p in &quad {
+                    let c = split(p);
+                    r += c.0;
+                    g += c.1;
+                    b += c.2;
+                }
+                let (r, g, b) = (r / 4, g / 4, b / 4);
+                self.u[cy * cw + cx] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+                self.v[cy * cw + cx] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+            }
+        }
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&self.y)?;
+        self.out.write_all(&self.u)?;
+        self.out.write_all(&self.v)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn split(p: u32) -> (i32, i32, i32) {
+    ((p >> 16 & 0xFF) as i32, (p >> 8 & 0xFF) as i32, (p & 0xFF) as i32)
+}
+
+// Mono 16-bit PCM at audio::SAMPLE_RATE.
+pub struct WavWriter {
+    out: BufWriter<File>,
+    samples: u32,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<WavWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        // sizes are filled in by finish()
+        out.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&1u16.to_le_bytes())?; // mono
+        out.write_all(&audio::SAMPLE_RATE.to_le_bytes())?;
+        out.write_all(&(audio::SAMPLE_RATE * 2).to_le_bytes())?; // bytes per second
+        out.write_all(&2u16.to_le_bytes())?; // block align
+        out.write_all(&16u16.to_le_bytes())?; // bits per sample
+        out.write_all(b"data\0\0\0\0")?;
+        Ok(WavWriter { out, samples: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &s in samples {
+            let v = (s.clamp(-1.0, 1.0) * 32767.0) as i16;
+            self.out.write_all(&v.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let data_len = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data_len.to_le_bytes())?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("futilenes-capture-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn adler32_of_wikipedia() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn zlib_stored_blocks() {
+        let data: Vec<u8> = (0..0x10000 + 10).map(|i| i as u8).collect();
+        let z = zlib_stored(&data);
+        assert_eq!(&z[..2], &[0x78, 0x01]);
+        // a full block, then a final one with the last 11 bytes
+        assert_eq!(&z[2..7], &[0x00, 0xFF, 0xFF, 0x00, 0x00]);
+        let last = 7 + 0xFFFF;
+        assert_eq!(&z[last..last + 5], &[0x01, 0x0B, 0x00, 0xF4, 0xFF]);
+        assert_eq!(&z[z.len() - 4..], &adler32(&data).to_be_bytes());
+        assert_eq!(zlib_stored(&[]), [0x78, 0x01, 0x01, 0x00, 0x00, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn png() {
+        let path = temp_path("shot.png");
+        write_png(&path, 2, 2, &[0xFF0000, 0x00FF00, 0x0000FF, 0xFFFFFF]).unwrap();
+        let png = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1A\n");
+        assert_eq!(&png[8..16], b"\0\0\0\x0DIHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        let mut crc = Crc32::new();
+        crc.update(&png[12..29]);
+        assert_eq!(&png[29..33], &crc.finish().to_be_bytes());
+        // the IDAT holds the rows, each after a filter byte, uncompressed
+        assert_eq!(&png[37..41], b"IDAT");
+        let raw = [0, 255, 0, 0, 0, 255, 0, 0, 0, 0, 255, 255, 255, 255];
+        assert_eq!(&png[41 + 7..41 + 7 + raw.len()], &raw);
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xAE\x42\x60\x82");
+    }
+
+    #[test]
+    fn y4m() {
+        let path = temp_path("video.y4m");
+        let mut y4m = Y4mWriter::create(&path, 4, 2, 60, 1).unwrap();
+        y4m.write_frame(&[0xFFFFFF; 8]).unwrap();
+        y4m.write_frame(&[0x000000; 8]).unwrap();
+        y4m.finish().unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let header = b"YUV4MPEG2 W4 H2 F60:1 Ip A1:1 C420jpeg\n";
+        assert_eq!(&data[..header.len()], header);
+        let frames = &data[header.len()..];
+        // 8 luma and 2 + 2 chroma bytes a frame
+        assert_eq!(frames.len(), 2 * (6 + 12));
+        assert_eq!(&frames[..18], b"FRAME\n\xEB\xEB\xEB\xEB\xEB\xEB\xEB\xEB\x80\x80\x80\x80");
+        assert_eq!(&frames[18..24], b"FRAME\n");
+        assert_eq!(&frames[24..32], &[0x10; 8]);
+        assert_eq!(&frames[32..], &[0x80; 4]);
+    }
+
+    #[test]
+    fn wav() {
+        let path = temp_path("audio.wav");
+        let mut wav = WavWriter::create(&path).unwrap();
+        wav.write_samples(&[0.0, 0.5, -1.0]).unwrap();
+        wav.write_samples(&[2.0]).unwrap();
+        wav.finish().unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(&data[4..8], &(36u32 + 8).to_le_bytes());
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(&data[24..28], &audio::SAMPLE_RATE.to_le_bytes());
+        assert_eq!(&data[36..44], b"data\x08\0\0\0");
+        let samples: Vec<i16> = data[44..].chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        assert_eq!(samples, [0, 16383, -32767, 32767]);
+    }
+}
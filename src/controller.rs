@@ -0,0 +1,68 @@
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+// Button bits, in the order the controller shifts them out.
+pub const BUTTON_A: u8 = 0x01;
+pub const BUTTON_B: u8 = 0x02;
+pub const BUTTON_SELECT: u8 = 0x04;
+pub const BUTTON_START: u8 = 0x08;
+pub const BUTTON_UP: u8 = 0x10;
+pub const BUTTON_DOWN: u8 = 0x20;
+pub const BUTTON_LEFT: u8 = 0x40;
+pub const BUTTON_RIGHT: u8 = 0x80;
+
+// Standard controller: a 4021 shift register that is loaded from the
+// buttons while the strobe bit written to $4016 is high, and shifted out
+// one bit per read of $4016/$4017 once it goes low.
+#[derive(Default)]
+pub struct Controller {
+    buttons: u8,
+    shift: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift = buttons;
+        }
+    }
+    pub fn write(&mut self, value: u8) {
+        self.strobe = value & 0x01 != 0;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+    // The bit the next read will return.
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons & 0x01
+        }
+        else {
+            self.shift & 0x01
+        }
+    }
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 0x01;
+        }
+        let bit = self.shift & 0x01;
+        // official controllers return 1 once all 8 buttons have been read
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+}
+
+impl Savable for Controller {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u8(self.buttons);
+        w.write_u8(self.shift);
+        w.write_bool(self.strobe);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = r.read_u8()?;
+        self.shift = r.read_u8()?;
+        self.strobe = r.read_bool()?;
+        Ok(())
+    }
+}
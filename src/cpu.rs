@@ -1,5 +1,12 @@
 use super::memory;
 use super::mapper;
+use bus::Bus;
+use region::Region;
+use rom;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+use trace;
+
+use std::io;
 
 #[allow(dead_code)]
 static INSTRUCTION_SIZE: [u8; 256] = [
@@ -21,7 +28,6 @@ static INSTRUCTION_SIZE: [u8; 256] = [
     2, 2, 1, 2, 2, 2, 2, 2, 1, 3, 1, 3, 3, 3, 3, 3, //0xF0
 ];
 
-#[allow(dead_code)]
 static INSTRUCTION_CYCLES: [u8; 256] = [
     7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, //0x00
     2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, //0x10
@@ -41,74 +47,128 @@ static INSTRUCTION_CYCLES: [u8; 256] = [
     2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, //0xF0
 ];
 
-#[derive(Default)]
-struct CpuState {
-    reg_pc: u16,
-    reg_sp: u8,
-    reg_a: u8,
-    reg_x: u8,
-    reg_y: u8,
-    reg_p: RegP, //Processor Status register: NV-BDIZC
+// The 65C02 fixed most of the NMOS timing quirks, and turned the unused
+// opcodes into NOPs of various lengths.
+static CMOS_INSTRUCTION_CYCLES: [u8; 256] = [
+    7, 6, 2, 1, 5, 3, 5, 5, 3, 2, 2, 1, 6, 4, 6, 5, //0x00
+    2, 5, 5, 1, 5, 4, 6, 5, 2, 4, 2, 1, 6, 4, 6, 5, //0x10
+    6, 6, 2, 1, 3, 3, 5, 5, 4, 2, 2, 1, 4, 4, 6, 5, //0x20
+    2, 5, 5, 1, 4, 4, 6, 5, 2, 4, 2, 1, 4, 4, 6, 5, //0x30
+    6, 6, 2, 1, 3, 3, 5, 5, 3, 2, 2, 1, 3, 4, 6, 5, //0x40
+    2, 5, 5, 1, 4, 4, 6, 5, 2, 4, 3, 1, 8, 4, 6, 5, //0x50
+    6, 6, 2, 1, 3, 3, 5, 5, 4, 2, 2, 1, 6, 4, 6, 5, //0x60
+    2, 5, 5, 1, 4, 4, 6, 5, 2, 4, 4, 1, 6, 4, 6, 5, //0x70
+    3, 6, 2, 1, 3, 3, 3, 5, 2, 2, 2, 1, 4, 4, 4, 5, //0x80
+    2, 6, 5, 1, 4, 4, 4, 5, 2, 5, 2, 1, 4, 5, 5, 5, //0x90
+    2, 6, 2, 1, 3, 3, 3, 5, 2, 2, 2, 1, 4, 4, 4, 5, //0xA0
+    2, 5, 5, 1, 4, 4, 4, 5, 2, 4, 2, 1, 4, 4, 4, 5, //0xB0
+    2, 6, 2, 1, 3, 3, 5, 5, 2, 2, 2, 3, 4, 4, 6, 5, //0xC0
+    2, 5, 5, 1, 4, 4, 6, 5, 2, 4, 3, 3, 4, 4, 7, 5, //0xD0
+    2, 6, 2, 1, 3, 3, 5, 5, 2, 2, 2, 1, 4, 4, 6, 5, //0xE0
+    2, 5, 5, 1, 4, 4, 6, 5, 2, 4, 4, 1, 4, 4, 7, 5, //0xF0
+];
+
+// Which member of the 6502 family the core behaves like. The NES has a
+// Ricoh 2A03, an NMOS 6502 with the BCD adder cut out: the decimal flag can
+// be set and pushed, but ADC and SBC ignore it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Variant {
+    Ricoh2A03,
+    Nmos6502,
+    Cmos65C02,
 }
 
-impl CpuState {
-    fn store(&mut self, cpu: &CPU) {
-        self.reg_pc = cpu.reg_pc;
-        self.reg_sp = cpu.reg_sp;
-        self.reg_a = cpu.reg_a;
-        self.reg_x = cpu.reg_x;
-        self.reg_y = cpu.reg_y;
-        self.reg_p = cpu.reg_p;
+impl Variant {
+    pub fn from_name(name: &str) -> Option<Variant> {
+        match name.to_lowercase().as_str() {
+            "2a03" | "nes" => Some(Variant::Ricoh2A03),
+            "6502" | "nmos" => Some(Variant::Nmos6502),
+            "65c02" | "cmos" => Some(Variant::Cmos65C02),
+            _ => None,
+        }
     }
-    fn get_p(&self) -> u8 {
-        let mut value: u8 = 0;
-        if self.reg_p.negative { value |= 0x80; }
-        if self.reg_p.overflow { value |= 0x40; }
-        if self.reg_p.expansion { value  |= 0x20; }
-        if self.reg_p.branch { value |= 0x10; }
-        if self.reg_p.decimal { value |= 0x08; }
-        if self.reg_p.int_disable { value |= 0x04; }
-        if self.reg_p.zero { value |= 0x02; }
-        if self.reg_p.carry { value |= 0x01; }
-        value
+    fn decimal_mode(self) -> bool {
+        self != Variant::Ricoh2A03
+    }
+    fn cycles(self, opcode: u8) -> u64 {
+        match self {
+            Variant::Cmos65C02 => CMOS_INSTRUCTION_CYCLES[opcode as usize] as u64,
+            _ => INSTRUCTION_CYCLES[opcode as usize] as u64,
+        }
     }
 }
 
-trait AddressingMode {
-    fn read(&self, cpu: &mut CPU) -> u8;
-    fn write(&self, cpu: &mut CPU, value: u8);
+// Including the 65C02's (zp) forms.
+fn is_adc_or_sbc(opcode: u8) -> bool {
+    matches!(opcode, 0x61 | 0x65 | 0x69 | 0x6D | 0x71 | 0x72 | 0x75 | 0x79 | 0x7D
+                   | 0xE1 | 0xE5 | 0xE9 | 0xED | 0xF1 | 0xF2 | 0xF5 | 0xF9 | 0xFD)
+}
+
+trait AddressingMode<B: Bus> {
+    fn read(&self, cpu: &mut CPU<B>) -> u8;
+    fn write(&self, cpu: &mut CPU<B>, value: u8);
+    // The write half of a read-modify-write instruction.
+    fn modify(&self, cpu: &mut CPU<B>, _old: u8, new: u8) {
+        self.write(cpu, new);
+    }
 }
 
 struct ImmediateAddressingMode;
-impl AddressingMode for ImmediateAddressingMode {
-    fn read(&self, cpu: &mut CPU) -> u8 {
+impl<B: Bus> AddressingMode<B> for ImmediateAddressingMode {
+    fn read(&self, cpu: &mut CPU<B>) -> u8 {
         cpu.read_inc_pc()
     }
-    fn write(&self, _cpu: &mut CPU, _value: u8) {
+    fn write(&self, _cpu: &mut CPU<B>, _value: u8) {
         panic!("write is not possible in immediate addressing");
     }
 }
 
 struct AccumulatorAddressingMode;
-impl AddressingMode for AccumulatorAddressingMode {
-    fn read(&self, cpu: &mut CPU) -> u8 {
+impl<B: Bus> AddressingMode<B> for AccumulatorAddressingMode {
+    fn read(&self, cpu: &mut CPU<B>) -> u8 {
         cpu.reg_a
     }
-    fn write(&self, cpu: &mut CPU, value: u8) {
+    fn write(&self, cpu: &mut CPU<B>, value: u8) {
         cpu.reg_a = value;
     }
 }
 
+// `fixup` is the address an indexed mode reads from while it is still
+// carrying into the high byte. Reads only spend that cycle when the index
+// crosses a page; writes and read-modify-writes always do.
 struct MemoryAddressingMode {
     address: u16,
+    fixup: Option<u16>,
 }
 
-impl AddressingMode for MemoryAddressingMode {
-    fn read(&self, cpu: &mut CPU) -> u8 {
-        cpu.memory.read(self.address)
+impl MemoryAddressingMode {
+    fn new(address: u16) -> MemoryAddressingMode {
+        MemoryAddressingMode { address, fixup: None }
     }
-    fn write(&self, cpu: &mut CPU, value: u8) {
-        cpu.memory.write(self.address, value);
+}
+
+impl<B: Bus> AddressingMode<B> for MemoryAddressingMode {
+    fn read(&self, cpu: &mut CPU<B>) -> u8 {
+        if let Some(address) = self.fixup {
+            cpu.bus_read(address);
+        }
+        cpu.bus_read(self.address)
+    }
+    fn write(&self, cpu: &mut CPU<B>, value: u8) {
+        if let Some(address) = self.fixup {
+            cpu.bus_read(address);
+        }
+        cpu.bus_write(self.address, value);
+    }
+    // The NMOS 6502 writes the unmodified value back while it works out
+    // the new one; the 65C02 reads it again instead, and writes only once.
+    fn modify(&self, cpu: &mut CPU<B>, old: u8, new: u8) {
+        if cpu.variant == Variant::Cmos65C02 {
+            cpu.bus_read(self.address);
+        } else {
+            cpu.bus_write(self.address, old);
+        }
+        cpu.bus_write(self.address, new);
     }
 }
 
@@ -125,9 +185,8 @@ struct RegP {
     negative: bool
 }
 
-pub struct CPU {
-    // TODO move this to the debugger
-    op_cnt: u32,
+pub struct CPU<B: Bus = memory::MemMap> {
+    trace: bool,
 
     reg_pc: u16,
     reg_sp: u8,
@@ -135,39 +194,256 @@ pub struct CPU {
     reg_x: u8,
     reg_y: u8,
     reg_p: RegP, //Processor Status register: NV-BDIZC
-    memory: memory::MemMap
+    variant: Variant,
+    // Set by the KIL opcodes; only a reset gets the CPU going again.
+    jammed: bool,
+    cycles: u64,
+    frame: u64,
+    // The NMI line as last seen, and whether it has gone active since the
+    // last instruction.
+    nmi_line: bool,
+    nmi_pending: bool,
+    memory: B
+}
+
+// The programmer visible registers, with P packed as NV-BDIZC.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Registers {
+    pub pc: u16,
+    pub sp: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
 }
 
+// The NES side of the CPU: the 2A03 wired up to MemMap.
 impl CPU {
-    pub fn new(mut mapper: mapper::Mapper) -> CPU {
+    pub fn new(mapper: Box<dyn mapper::Mapper>) -> CPU {
+        let mut cpu = CPU::with_bus(memory::MemMap::new(mapper));
+        cpu.power_on();
+        cpu
+    }
+    // Puts the whole machine back into its power-on state.
+    pub fn power_on(&mut self) {
+        self.memory.power_on();
+        self.reg_pc = self.readw(0xFFFC);
+        self.reg_sp = 0xFD;
+        self.reg_a = 0;
+        self.reg_x = 0;
+        self.reg_y = 0;
+        self.reg_p = RegP::default();
+        self.reg_p.expansion = true;
+        self.reg_p.int_disable = true;
+        self.jammed = false;
+        self.cycles = 0;
+        self.frame = 0;
+        self.nmi_line = false;
+        self.nmi_pending = false;
+    }
+    // Takes effect at the next power_on().
+    pub fn set_ram_init(&mut self, init: memory::RamInit) {
+        self.memory.set_ram_init(init);
+    }
+    pub fn ram_init(&self) -> memory::RamInit {
+        self.memory.ram_init()
+    }
+    pub fn rom(&self) -> &rom::Cartridge {
+        self.memory.rom()
+    }
+    // For the Disk System: ejects the disk, and puts the next side in a
+    // moment later. Returns the name of that side.
+    #[cfg(feature = "frontend")]
+    pub fn switch_disk_side(&mut self) -> Option<String> {
+        self.memory.switch_disk_side()
+    }
+    // Writes battery RAM or disk changes to the save file.
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        self.memory.flush_save()
+    }
+    pub fn set_input(&mut self, port: usize, buttons: u8) {
+        self.memory.set_input(port, buttons);
+    }
+    pub fn framebuffer(&self) -> &[u16] {
+        self.memory.ppu().framebuffer()
+    }
+    pub fn take_audio(&mut self, out: &mut Vec<f32>) {
+        self.memory.take_audio(out);
+    }
+    // Snapshot of the whole machine, see savestate.rs for the format.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(self.rom().hash(), self.region());
+        self.save(&mut w);
+        w.finish()
+    }
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data, self.rom().hash(), self.region())?;
+        self.load(&mut r)
+    }
+    pub fn run(&mut self) {
+        loop {
+            self.run_frame();
+        }
+    }
+    // Runs until the PPU starts vblank.
+    pub fn run_frame(&mut self) {
+        while !self.memory.take_frame() {
+            self.step();
+        }
+        self.frame += 1;
+    }
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+    // Frames per second, as a fraction.
+    pub fn frame_rate(&self) -> (u64, u64) {
+        self.region().frame_rate()
+    }
+    // Chosen from the ROM header when the cartridge is loaded.
+    pub fn set_region(&mut self, region: Region) {
+        self.memory.set_region(region);
+    }
+    pub fn region(&self) -> Region {
+        self.memory.region()
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    // A CPU with every register zeroed, for buses other than the NES.
+    pub fn with_bus(bus: B) -> CPU<B> {
         //TODO: impl Default for CPU
-        // TODO: normally, set the PC to the value at the reset vector
-        //self.reg_pc = self.memory.readw(0xFFFC);
-        // but for nestest.nes, set it to 0xC000
-        let reg_pc = 0xC000;
-        let reg_sp = 0xFD;
-        let mut reg_p = RegP::default();
-        reg_p.expansion = true;
-        reg_p.int_disable = true;
-        mapper.load();
         CPU {
-            op_cnt: 1, // TODO move this to the debugger
-            reg_pc: reg_pc,
-            reg_sp: reg_sp,
+            trace: true,
+            reg_pc: 0,
+            reg_sp: 0,
             reg_a: 0,
             reg_x: 0,
             reg_y: 0,
-            reg_p: reg_p,
-            memory: memory::MemMap::new(mapper)
+            reg_p: RegP::default(),
+            variant: Variant::Ricoh2A03,
+            jammed: false,
+            cycles: 0,
+            frame: 0,
+            nmi_line: false,
+            nmi_pending: false,
+            memory: bus
         }
     }
+    // nestest.nes runs its automated tests when started at $C000 instead of
+    // the reset vector.
+    pub fn set_pc(&mut self, pc: u16) {
+        self.reg_pc = pc;
+    }
+    // Variants other than the 2A03 are for running 6502 test suites and
+    // other machines on the same core.
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+    }
+    // Looks at memory without disturbing anything, for tools outside the
+    // CPU.
+    pub fn peek(&self, address: u16) -> u8 {
+        self.memory.peek(address)
+    }
+    // The reset button: registers other than SP, I and PC are left alone,
+    // and so is memory.
+    pub fn reset(&mut self) {
+        // The interrupt sequence, with the three pushes turned into reads.
+        let pc = self.reg_pc;
+        self.bus_read(pc);
+        self.bus_read(pc);
+        for _ in 0..3 {
+            let address = 0x0100 | self.reg_sp as u16;
+            self.bus_read(address);
+            self.reg_sp = self.reg_sp.wrapping_sub(1);
+        }
+        self.reg_p.int_disable = true;
+        self.reg_pc = self.readw(0xFFFC);
+        self.jammed = false;
+    }
+    pub fn jammed(&self) -> bool {
+        self.jammed
+    }
+    pub fn pc(&self) -> u16 {
+        self.reg_pc
+    }
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.reg_pc,
+            sp: self.reg_sp,
+            a: self.reg_a,
+            x: self.reg_x,
+            y: self.reg_y,
+            p: self.get_p(),
+        }
+    }
+    pub fn set_registers(&mut self, r: &Registers) {
+        self.reg_pc = r.pc;
+        self.reg_sp = r.sp;
+        self.reg_a = r.a;
+        self.reg_x = r.x;
+        self.reg_y = r.y;
+        self.set_p(r.p);
+    }
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    pub fn bus(&self) -> &B {
+        &self.memory
+    }
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.memory
+    }
+    pub fn into_bus(self) -> B {
+        self.memory
+    }
+    // Turns the per-instruction trace output on or off.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
     pub fn step(&mut self) {
-        // TODO move this to the debugger
-        let mut state = CpuState::default();
-        state.store(&self);
-
-
+        if self.jammed {
+            // Nothing happens until a reset, but the rest of the machine
+            // keeps running.
+            self.tick();
+            return;
+        }
+        if let Some(page) = self.memory.take_oam_dma() {
+            self.oam_dma(page);
+        }
+        for _ in 0..self.memory.take_stall() {
+            self.tick();
+        }
+        // The lines are only looked at between instructions.
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.interrupt(0xFFFA);
+            return;
+        }
+        if self.memory.irq() && !self.reg_p.int_disable {
+            self.interrupt(0xFFFE);
+            return;
+        }
+        if self.trace {
+            println!("{}", trace::trace_line(self));
+        }
+        let start = self.cycles;
         let opcode = self.read_inc_pc();
+        if self.variant != Variant::Cmos65C02 || !self.execute_cmos(opcode) {
+            self.execute(opcode);
+        }
+        // Every cycle of an NMOS instruction is a bus access, so this only
+        // makes up the 65C02's cycles, whose access pattern isn't modelled
+        // beyond its read-modify-writes.
+        let mut cycles = self.variant.cycles(opcode);
+        if self.variant == Variant::Cmos65C02 && self.reg_p.decimal && is_adc_or_sbc(opcode) {
+            // the 65C02 spends a cycle fixing up the flags of a BCD result
+            cycles += 1;
+        }
+        while self.cycles - start < cycles {
+            self.tick();
+        }
+    }
+    fn execute(&mut self, opcode: u8) {
         match opcode {
             0x69 => { let v = self.imm(); self.adc(v) },
             0x65 => { let v = self.zp();  self.adc(v) },
@@ -191,7 +467,7 @@ impl CPU {
             0x06 => { let v = self.zp();  self.asl(v) },
             0x16 => { let v = self.zp_x();  self.asl(v) },
             0x0E => { let v = self.abs(); self.asl(v) },
-            0x1E => { let v = self.abs_x(); self.asl(v) },
+            0x1E => { let v = self.abs_x_write(); self.asl(v) },
 
             0x90 => { self.bcc() },
             0xB0 => { self.bcs() },
@@ -232,7 +508,7 @@ impl CPU {
             0xC6 => { let v = self.zp(); self.dec(v) },
             0xD6 => { let v = self.zp_x(); self.dec(v) },
             0xCE => { let v = self.abs(); self.dec(v) },
-            0xDE => { let v = self.abs_x(); self.dec(v) },
+            0xDE => { let v = self.abs_x_write(); self.dec(v) },
 
             0xCA => { self.dex(); },
 
@@ -250,7 +526,7 @@ impl CPU {
             0xE6 => { let v = self.zp(); self.inc(v) },
             0xF6 => { let v = self.zp_x(); self.inc(v) },
             0xEE => { let v = self.abs(); self.inc(v) },
-            0xFE => { let v = self.abs_x(); self.inc(v) },
+            0xFE => { let v = self.abs_x_write(); self.inc(v) },
 
             0xE8 => { self.inx(); },
 
@@ -286,9 +562,9 @@ impl CPU {
             0x46 => { let v = self.zp();  self.lsr(v) },
             0x56 => { let v = self.zp_x();  self.lsr(v) },
             0x4E => { let v = self.abs(); self.lsr(v) },
-            0x5E => { let v = self.abs_x(); self.lsr(v) },
+            0x5E => { let v = self.abs_x_write(); self.lsr(v) },
 
-            0xEA => {  }, // NOP
+            0xEA => { self.implied() }, // NOP
 
             0x09 => { let v = self.imm(); self.ora(v) },
             0x05 => { let v = self.zp(); self.ora(v) },
@@ -311,13 +587,13 @@ impl CPU {
             0x26 => { let v = self.zp();  self.rol(v) },
             0x36 => { let v = self.zp_x();  self.rol(v) },
             0x2E => { let v = self.abs(); self.rol(v) },
-            0x3E => { let v = self.abs_x(); self.rol(v) },
+            0x3E => { let v = self.abs_x_write(); self.rol(v) },
 
             0x6A => { let v = self.acc(); self.ror(v) },
             0x66 => { let v = self.zp();  self.ror(v) },
             0x76 => { let v = self.zp_x();  self.ror(v) },
             0x6E => { let v = self.abs(); self.ror(v) },
-            0x7E => { let v = self.abs_x(); self.ror(v) },
+            0x7E => { let v = self.abs_x_write(); self.ror(v) },
 
             0x40 => { self.rti() },
 
@@ -341,10 +617,10 @@ impl CPU {
             0x85 => { let v = self.zp(); self.sta(v) },
             0x95 => { let v = self.zp_x(); self.sta(v) },
             0x8D => { let v = self.abs(); self.sta(v) },
-            0x9D => { let v = self.abs_x(); self.sta(v) },
-            0x99 => { let v = self.abs_y(); self.sta(v) },
+            0x9D => { let v = self.abs_x_write(); self.sta(v) },
+            0x99 => { let v = self.abs_y_write(); self.sta(v) },
             0x81 => { let v = self.indirect_x(); self.sta(v) },
-            0x91 => { let v = self.indirect_y(); self.sta(v) },
+            0x91 => { let v = self.indirect_y_write(); self.sta(v) },
 
             0x86 => { let v = self.zp();  self.stx(v) },
             0x96 => { let v = self.zp_y();  self.stx(v) },
@@ -370,18 +646,18 @@ impl CPU {
             0xC7 => { let v = self.zp(); self.dcp(v) },
             0xD7 => { let v = self.zp_x(); self.dcp(v) },
             0xCF => { let v = self.abs(); self.dcp(v) },
-            0xDF => { let v = self.abs_x(); self.dcp(v) },
-            0xDB => { let v = self.abs_y(); self.dcp(v) },
+            0xDF => { let v = self.abs_x_write(); self.dcp(v) },
+            0xDB => { let v = self.abs_y_write(); self.dcp(v) },
             0xC3 => { let v = self.indirect_x(); self.dcp(v) },
-            0xD3 => { let v = self.indirect_y(); self.dcp(v) },
+            0xD3 => { let v = self.indirect_y_write(); self.dcp(v) },
 
             0xE7 => { let v = self.zp(); self.isc(v) },
             0xF7 => { let v = self.zp_x(); self.isc(v) },
             0xEF => { let v = self.abs(); self.isc(v) },
-            0xFF => { let v = self.abs_x(); self.isc(v) },
-            0xFB => { let v = self.abs_y(); self.isc(v) },
+            0xFF => { let v = self.abs_x_write(); self.isc(v) },
+            0xFB => { let v = self.abs_y_write(); self.isc(v) },
             0xE3 => { let v = self.indirect_x(); self.isc(v) },
-            0xF3 => { let v = self.indirect_y(); self.isc(v) },
+            0xF3 => { let v = self.indirect_y_write(); self.isc(v) },
 
             0xA7 => { let v = self.zp(); self.lax(v) },
             0xB7 => { let v = self.zp_y(); self.lax(v) },
@@ -393,18 +669,18 @@ impl CPU {
             0x27 => { let v = self.zp(); self.rla(v) },
             0x37 => { let v = self.zp_x(); self.rla(v) },
             0x2F => { let v = self.abs(); self.rla(v) },
-            0x3F => { let v = self.abs_x(); self.rla(v) },
-            0x3B => { let v = self.abs_y(); self.rla(v) },
+            0x3F => { let v = self.abs_x_write(); self.rla(v) },
+            0x3B => { let v = self.abs_y_write(); self.rla(v) },
             0x23 => { let v = self.indirect_x(); self.rla(v) },
-            0x33 => { let v = self.indirect_y(); self.rla(v) },
+            0x33 => { let v = self.indirect_y_write(); self.rla(v) },
 
             0x67 => { let v = self.zp(); self.rra(v) },
             0x77 => { let v = self.zp_x(); self.rra(v) },
             0x6F => { let v = self.abs(); self.rra(v) },
-            0x7F => { let v = self.abs_x(); self.rra(v) },
-            0x7B => { let v = self.abs_y(); self.rra(v) },
+            0x7F => { let v = self.abs_x_write(); self.rra(v) },
+            0x7B => { let v = self.abs_y_write(); self.rra(v) },
             0x63 => { let v = self.indirect_x(); self.rra(v) },
-            0x73 => { let v = self.indirect_y(); self.rra(v) },
+            0x73 => { let v = self.indirect_y_write(); self.rra(v) },
 
             0x87 => { let v = self.zp(); self.sax(v) },
             0x97 => { let v = self.zp_y(); self.sax(v) },
@@ -416,111 +692,209 @@ impl CPU {
             0x07 => { let v = self.zp(); self.slo(v) },
             0x17 => { let v = self.zp_x(); self.slo(v) },
             0x0F => { let v = self.abs(); self.slo(v) },
-            0x1F => { let v = self.abs_x(); self.slo(v) },
-            0x1B => { let v = self.abs_y(); self.slo(v) },
+            0x1F => { let v = self.abs_x_write(); self.slo(v) },
+            0x1B => { let v = self.abs_y_write(); self.slo(v) },
             0x03 => { let v = self.indirect_x(); self.slo(v) },
-            0x13 => { let v = self.indirect_y(); self.slo(v) },
+            0x13 => { let v = self.indirect_y_write(); self.slo(v) },
 
             0x47 => { let v = self.zp(); self.sre(v) },
             0x57 => { let v = self.zp_x(); self.sre(v) },
             0x4F => { let v = self.abs(); self.sre(v) },
-            0x5F => { let v = self.abs_x(); self.sre(v) },
-            0x5B => { let v = self.abs_y(); self.sre(v) },
+            0x5F => { let v = self.abs_x_write(); self.sre(v) },
+            0x5B => { let v = self.abs_y_write(); self.sre(v) },
             0x43 => { let v = self.indirect_x(); self.sre(v) },
-            0x53 => { let v = self.indirect_y(); self.sre(v) },
-
-            // Unofficial NOPs
-            0x80 => { self.read_inc_pc(); },
-            0x82 => { self.read_inc_pc(); },
-            0xC2 => { self.read_inc_pc(); },
-            0xE2 => { self.read_inc_pc(); },
-            0x04 => { self.read_inc_pc(); },
-            0x44 => { self.read_inc_pc(); },
-            0x64 => { self.read_inc_pc(); },
-            0x89 => { self.read_inc_pc(); },
-            0x0C => { self.readw_inc_pc(); },
-            0x14 => { self.read_inc_pc(); },
-            0x34 => { self.read_inc_pc(); },
-            0x54 => { self.read_inc_pc(); },
-            0x74 => { self.read_inc_pc(); },
-            0xD4 => { self.read_inc_pc(); },
-            0xF4 => { self.read_inc_pc(); },
-            0x1A => {},
-            0x3A => {},
-            0x5A => {},
-            0x7A => {},
-            0xDA => {},
-            0xFA => {},
-            0x1C => { self.readw_inc_pc(); },
-            0x3C => { self.readw_inc_pc(); },
-            0x5C => { self.readw_inc_pc(); },
-            0x7C => { self.readw_inc_pc(); },
-            0xDC => { self.readw_inc_pc(); },
-            0xFC => { self.readw_inc_pc(); },
-
-            _ => { self.unknow_opcode(opcode); },
+            0x53 => { let v = self.indirect_y_write(); self.sre(v) },
+
+            0x0B => { let v = self.imm(); self.anc(v) },
+            0x2B => { let v = self.imm(); self.anc(v) },
+
+            0x4B => { let v = self.imm(); self.alr(v) },
+
+            0x6B => { let v = self.imm(); self.arr(v) },
+
+            0x8B => { let v = self.imm(); self.xaa(v) },
+
+            0xAB => { let v = self.imm(); self.lxa(v) },
+
+            0xCB => { let v = self.imm(); self.axs(v) },
+
+            0xBB => { let v = self.abs_y(); self.las(v) },
+
+            // The unstable stores, which AND the value with the high byte
+            // of the base address plus one
+            0x93 => { let base = self.indirect_base(); let v = self.reg_a & self.reg_x; self.store_and_high(base, self.reg_y, v) }, // SHA
+            0x9F => { let base = self.readw_inc_pc(); let v = self.reg_a & self.reg_x; self.store_and_high(base, self.reg_y, v) }, // SHA
+            0x9E => { let base = self.readw_inc_pc(); let v = self.reg_x; self.store_and_high(base, self.reg_y, v) }, // SHX
+            0x9C => { let base = self.readw_inc_pc(); let v = self.reg_y; self.store_and_high(base, self.reg_x, v) }, // SHY
+            0x9B => { // TAS
+                let base = self.readw_inc_pc();
+                self.reg_sp = self.reg_a & self.reg_x;
+                let v = self.reg_sp;
+                self.store_and_high(base, self.reg_y, v)
+            },
+
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 |
+            0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => { self.kil() },
+
+            // Unofficial NOPs, which still make their reads
+            0x80 => { let v = self.imm(); self.nop(v) },
+            0x82 => { let v = self.imm(); self.nop(v) },
+            0xC2 => { let v = self.imm(); self.nop(v) },
+            0xE2 => { let v = self.imm(); self.nop(v) },
+            0x04 => { let v = self.zp(); self.nop(v) },
+            0x44 => { let v = self.zp(); self.nop(v) },
+            0x64 => { let v = self.zp(); self.nop(v) },
+            0x89 => { let v = self.imm(); self.nop(v) },
+            0x0C => { let v = self.abs(); self.nop(v) },
+            0x14 => { let v = self.zp_x(); self.nop(v) },
+            0x34 => { let v = self.zp_x(); self.nop(v) },
+            0x54 => { let v = self.zp_x(); self.nop(v) },
+            0x74 => { let v = self.zp_x(); self.nop(v) },
+            0xD4 => { let v = self.zp_x(); self.nop(v) },
+            0xF4 => { let v = self.zp_x(); self.nop(v) },
+            0x1A => { self.implied() },
+            0x3A => { self.implied() },
+            0x5A => { self.implied() },
+            0x7A => { self.implied() },
+            0xDA => { self.implied() },
+            0xFA => { self.implied() },
+            0x1C => { let v = self.abs_x(); self.nop(v) },
+            0x3C => { let v = self.abs_x(); self.nop(v) },
+            0x5C => { let v = self.abs_x(); self.nop(v) },
+            0x7C => { let v = self.abs_x(); self.nop(v) },
+            0xDC => { let v = self.abs_x(); self.nop(v) },
+            0xFC => { let v = self.abs_x(); self.nop(v) },
         }
-        // TODO move this to the debugger
-        println!("{} {:4X} {:2X}        A: {:2X} X: {:2X} Y: {:2X} P:{:2X} SP: {:2X} ", self.op_cnt, state.reg_pc, opcode, state.reg_a, state.reg_x, state.reg_y, state.get_p(), state.reg_sp);
-        self.op_cnt += 1;
     }
-    pub fn run(&mut self) {
-        loop {
-            self.step();
+    // Opcodes the 65C02 added or changed. Returns false for the ones it
+    // shares with the NMOS 6502.
+    fn execute_cmos(&mut self, opcode: u8) -> bool {
+        match opcode {
+            0x80 => { let rel = self.read_inc_pc() as i8; self.branch(rel) }, // BRA
+
+            0x12 => { let v = self.zp_indirect(); self.ora(v) },
+            0x32 => { let v = self.zp_indirect(); self.and(v) },
+            0x52 => { let v = self.zp_indirect(); self.eor(v) },
+            0x72 => { let v = self.zp_indirect(); self.adc(v) },
+            0x92 => { let v = self.zp_indirect(); self.sta(v) },
+            0xB2 => { let v = self.zp_indirect(); self.lda(v) },
+            0xD2 => { let v = self.zp_indirect(); self.cmp(v) },
+            0xF2 => { let v = self.zp_indirect(); self.sbc(v) },
+
+            0x89 => { let v = self.imm(); self.bit_imm(v) },
+            0x34 => { let v = self.zp_x(); self.bit(v) },
+            0x3C => { let v = self.abs_x(); self.bit(v) },
+
+            0x1A => { let v = self.acc(); self.inc(v) },
+            0x3A => { let v = self.acc(); self.dec(v) },
+
+            0x5A => { self.implied(); let v = self.reg_y; self.push(v) }, // PHY
+            0x7A => { self.start_pull(); let v = self.pop(); self.reg_y = self.set_zn(v) }, // PLY
+            0xDA => { self.implied(); let v = self.reg_x; self.push(v) }, // PHX
+            0xFA => { self.start_pull(); let v = self.pop(); self.reg_x = self.set_zn(v) }, // PLX
+
+            0x64 => { let v = self.zp(); self.stz(v) },
+            0x74 => { let v = self.zp_x(); self.stz(v) },
+            0x9C => { let v = self.abs(); self.stz(v) },
+            0x9E => { let v = self.abs_x_write(); self.stz(v) },
+
+            0x04 => { let v = self.zp(); self.tsb(v) },
+            0x0C => { let v = self.abs(); self.tsb(v) },
+            0x14 => { let v = self.zp(); self.trb(v) },
+            0x1C => { let v = self.abs(); self.trb(v) },
+
+            0x7C => { self.jmp_indexed_indirect() },
+
+            // Rockwell and WDC bit instructions
+            _ if opcode & 0x0F == 0x07 => { let v = self.zp(); self.rmb_smb(v, opcode) },
+            _ if opcode & 0x0F == 0x0F => { self.bbr_bbs(opcode) },
+
+            // Everything else that was unused is a NOP
+            _ if opcode & 0x07 == 0x03 => {},
+            0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xC2 | 0xE2 => { self.read_inc_pc(); },
+            0x44 | 0x54 | 0xD4 | 0xF4 => { self.read_inc_pc(); },
+            0x5C | 0xDC | 0xFC => { self.readw_inc_pc(); },
+
+            _ => return false,
         }
-    }
-
-    fn unknow_opcode(&mut self, opcode: u8) {
-        let size = INSTRUCTION_SIZE[opcode as usize];
-        match size {
-            2 => { self.read_inc_pc(); },
-            3 => { self.readw_inc_pc(); },
-            _ => { panic!("unknown opcode size") }
+        true
+    }
+
+    // IRQ and NMI: BRK without the opcode fetch. PC is read twice without
+    // moving on, and the break bit is pushed clear.
+    fn interrupt(&mut self, vector: u16) {
+        let pc = self.reg_pc;
+        self.bus_read(pc);
+        self.bus_read(pc);
+        self.push((pc >> 8) as u8);
+        self.push(pc as u8);
+        let p = self.get_p() & !0x10;
+        self.push(p);
+        self.reg_p.int_disable = true;
+        if self.variant == Variant::Cmos65C02 {
+            self.reg_p.decimal = false;
+        }
+        self.reg_pc = self.readw(vector);
+    }
+    // The 2A03 halts the CPU for a cycle, and another to line up with a
+    // get cycle, then reads each byte of the page and writes it to $2004.
+    fn oam_dma(&mut self, page: u8) {
+        self.tick();
+        if self.cycles & 1 == 1 {
+            self.tick();
+        }
+        for i in 0..0x100 {
+            let value = self.bus_read((page as u16) << 8 | i);
+            self.bus_write(0x2004, value);
         }
-        println!("Unknown opcode {:2X}, size {}", opcode, size);
     }
 
     // Instructions start here!
     fn brk(&mut self) {
-        let pc = self.reg_pc + 1;
+        self.read_inc_pc(); // BRK is followed by a padding byte
+        let pc = self.reg_pc;
         let pch = ((pc & 0xFF00) >> 8) as u8;
         let pcl = (pc & 0x00FF) as u8;
         let p = self.get_p() | 0x10; // set the break bit when pushing the processor status register
         self.push(pch);
         self.push(pcl);
         self.push(p);
-        self.reg_pc = 0xFFFE;
+        self.reg_p.int_disable = true;
+        if self.variant == Variant::Cmos65C02 {
+            self.reg_p.decimal = false;
+        }
+        self.reg_pc = self.readw(0xFFFE);
     }
-    fn inc<AM: AddressingMode>(&mut self, am: AM) {
+    fn inc<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
         let result = (value as u16 + 1) as u8;
         self.set_zn(result);
-        am.write(self, result);
+        am.modify(self, value, result);
     }
-    fn dec<AM: AddressingMode>(&mut self, am: AM) {
+    fn dec<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
         let result = (value as i16 - 1) as u8;
         self.set_zn(result);
-        am.write(self, result);
+        am.modify(self, value, result);
     }
 
-    fn asl<AM: AddressingMode>(&mut self, am: AM) {
+    fn asl<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
         if value & 0x80 != 0 { self.reg_p.carry = true; }
         else { self.reg_p.carry = false; }
         let result = value << 1;
         self.set_zn(result);
-        am.write(self, result);
+        am.modify(self, value, result);
     }
-    fn lsr<AM: AddressingMode>(&mut self, am: AM) {
+    fn lsr<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
         if value & 0x01 != 0 { self.reg_p.carry = true; }
         else { self.reg_p.carry = false; }
         let result = value >> 1;
         self.set_zn(result);
-        am.write(self, result);
+        am.modify(self, value, result);
     }
-    fn rol<AM: AddressingMode>(&mut self, am: AM) {
+    fn rol<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
         let bit0 = match self.reg_p.carry {
             true => 0x01,
@@ -530,9 +904,9 @@ impl CPU {
         else { self.reg_p.carry = false; }
         let result = (value << 1) | bit0;
         self.set_zn(result);
-        am.write(self, result);
+        am.modify(self, value, result);
     }
-    fn ror<AM: AddressingMode>(&mut self, am: AM) {
+    fn ror<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
         let bit7 = match self.reg_p.carry {
             true => 0x80,
@@ -542,9 +916,10 @@ impl CPU {
         else { self.reg_p.carry = false; }
         let result = (value >> 1) | bit7;
         self.set_zn(result);
-        am.write(self, result);
+        am.modify(self, value, result);
     }
     fn rti(&mut self) {
+        self.start_pull();
         let p = self.pop();
         self.set_p(p);
         let pcl = self.pop();
@@ -552,72 +927,91 @@ impl CPU {
         self.reg_pc = (pch as u16) << 8 | pcl as u16;
     }
     fn tax(&mut self) {
+        self.implied();
         let value = self.reg_a;
         self.reg_x = self.set_zn(value);
     }
     fn tay(&mut self) {
+        self.implied();
         let value = self.reg_a;
         self.reg_y = self.set_zn(value);
     }
     fn tsx(&mut self) {
+        self.implied();
         let value = self.reg_sp;
         self.reg_x = self.set_zn(value);
     }
     fn txa(&mut self) {
+        self.implied();
         let value = self.reg_x;
         self.reg_a = self.set_zn(value);
     }
     fn txs(&mut self) {
+        self.implied();
         let value = self.reg_x;
         self.reg_sp = value;
     }
     fn tya(&mut self) {
+        self.implied();
         let value = self.reg_y;
         self.reg_a = self.set_zn(value);
     }
-    fn lda<AM: AddressingMode>(&mut self, am: AM) {
+    fn lda<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
         self.reg_a = self.set_zn(value);
     }
-    fn ldx<AM: AddressingMode>(&mut self, am: AM) {
+    fn ldx<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
         self.reg_x = self.set_zn(value);
     }
-    fn ldy<AM: AddressingMode>(&mut self, am: AM) {
+    fn ldy<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
         self.reg_y = self.set_zn(value);
     }
-    fn sta<AM: AddressingMode>(&mut self, am: AM) {
+    fn sta<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = self.reg_a;
         am.write(self, value);
     }
-    fn stx<AM: AddressingMode>(&mut self, am: AM) {
+    fn stx<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = self.reg_x;
         am.write(self, value);
     }
-    fn sty<AM: AddressingMode>(&mut self, am: AM) {
+    fn sty<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = self.reg_y;
         am.write(self, value);
     }
     fn inx(&mut self) {
+        self.implied();
         let result = self.reg_x as u16 + 1;
         self.reg_x = self.set_zn(result as u8);
     }
     fn iny(&mut self) {
+        self.implied();
         let result = self.reg_y as u16 + 1;
         self.reg_y = self.set_zn(result as u8);
     }
     fn dex(&mut self) {
+        self.implied();
         let result = self.reg_x as i16 - 1;
         self.reg_x = self.set_zn(result as u8);
     }
     fn dey(&mut self) {
+        self.implied();
         let result = self.reg_y as i16 - 1;
         self.reg_y = self.set_zn(result as u8);
     }
-    fn adc<AM: AddressingMode>(&mut self, am: AM) {
+    fn adc<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
-        let mut tmp_result = self.reg_a as u16 + value as u16;
+        self.add(value);
+    }
+    fn sbc<AM: AddressingMode<B>>(&mut self, am: AM) {
+        let m = am.read(self);
+        self.subtract(m);
+    }
+    fn add(&mut self, value: u8) {
+        let a = self.reg_a;
+        let carry = self.reg_p.carry;
+        let mut tmp_result = a as u16 + value as u16;
 
         if self.reg_p.carry { tmp_result += 1; }
 
@@ -626,20 +1020,23 @@ impl CPU {
 
         let result = tmp_result as u8;
 
-        if (self.reg_a & 0x80) == 0 && (value & 0x80) == 0 && (result & 0x80) != 0 {
+        if (a & 0x80) == 0 && (value & 0x80) == 0 && (result & 0x80) != 0 {
             self.reg_p.overflow = true;
         }
-        else if (self.reg_a & 0x80) != 0 && (value & 0x80) != 0 && (result & 0x80) == 0 {
+        else if (a & 0x80) != 0 && (value & 0x80) != 0 && (result & 0x80) == 0 {
             self.reg_p.overflow = true;
         }
         else {
             self.reg_p.overflow = false;
         }
         self.reg_a = self.set_zn(result);
+        if self.reg_p.decimal && self.variant.decimal_mode() {
+            self.add_decimal(a, value, carry);
+        }
     }
-    fn sbc<AM: AddressingMode>(&mut self, am: AM) {
+    fn subtract(&mut self, m: u8) {
         let a = self.reg_a;
-        let m = am.read(self);
+        let carry = self.reg_p.carry;
         let mut result = a as i16 - m as i16;
         if !self.reg_p.carry { result -= 1; }
 
@@ -658,82 +1055,158 @@ impl CPU {
             self.reg_p.overflow = false;
         }
         self.reg_a = self.set_zn(result);
+        if self.reg_p.decimal && self.variant.decimal_mode() {
+            self.subtract_decimal(a, m, carry);
+        }
+    }
+    // BCD addition, following Bruce Clark's "Decimal Mode" tutorial on
+    // 6502.org. The binary flags are already set. On the NMOS parts Z keeps
+    // the binary result, N and V come from the sum before the high digit is
+    // adjusted, and only C and A are decimal. The 65C02 sets N and Z from
+    // the decimal result.
+    fn add_decimal(&mut self, a: u8, value: u8, carry: bool) {
+        let mut lo = (a & 0x0F) as i16 + (value & 0x0F) as i16 + carry as i16;
+        if lo >= 0x0A {
+            lo = ((lo + 0x06) & 0x0F) + 0x10;
+        }
+        let signed = (a & 0xF0) as i8 as i16 + (value & 0xF0) as i8 as i16 + lo;
+        self.reg_p.negative = signed & 0x80 != 0;
+        self.reg_p.overflow = !(-128..=127).contains(&signed);
+
+        let mut sum = (a & 0xF0) as i16 + (value & 0xF0) as i16 + lo;
+        if sum >= 0xA0 {
+            sum += 0x60;
+        }
+        self.reg_p.carry = sum >= 0x100;
+        self.reg_a = sum as u8;
+        if self.variant == Variant::Cmos65C02 {
+            self.set_zn(sum as u8);
+        }
+    }
+    // BCD subtraction. All the NMOS flags are the binary ones; the 65C02
+    // sets N and Z from the decimal result.
+    fn subtract_decimal(&mut self, a: u8, m: u8, carry: bool) {
+        let borrow = !carry as i16;
+        let mut lo = (a & 0x0F) as i16 - (m & 0x0F) as i16 - borrow;
+        if self.variant == Variant::Cmos65C02 {
+            let mut result = a as i16 - m as i16 - borrow;
+            if result < 0 {
+                result -= 0x60;
+            }
+            if lo < 0 {
+                result -= 0x06;
+            }
+            self.reg_a = self.set_zn(result as u8);
+        }
+        else {
+            if lo < 0 {
+                lo = ((lo - 0x06) & 0x0F) - 0x10;
+            }
+            let mut result = (a & 0xF0) as i16 - (m & 0xF0) as i16 + lo;
+            if result < 0 {
+                result -= 0x60;
+            }
+            self.reg_a = result as u8;
+        }
     }
     fn jmp(&mut self) {
-        let address = self.memory.readw(self.reg_pc);
+        let address = self.readw(self.reg_pc);
         self.reg_pc = address;
     }
     fn jmp_indirect(&mut self) {
-        let indirect_address = self.memory.readw(self.reg_pc);
+        let indirect_address = self.readw(self.reg_pc);
         let address: u16;
-        if indirect_address & 0x00FF == 0x00FF {
+        if indirect_address & 0x00FF == 0x00FF && self.variant != Variant::Cmos65C02 {
             // implement CPU bug
             let page = indirect_address & 0xFF00;
-            let lsb = self.memory.read(page | indirect_address & 0x00FF);
-            let msb = self.memory.read(page);
+            let lsb = self.bus_read(page | indirect_address & 0x00FF);
+            let msb = self.bus_read(page);
             address = ((msb as u16) << 8) | lsb as u16;
         }
         else {
-            address = self.memory.readw(indirect_address);
+            address = self.readw(indirect_address);
         }
         self.reg_pc = address;
     }
+    // JSR pushes the address of its own last byte, and only reads that
+    // byte once the return address is on the stack.
     fn jsr(&mut self) {
-        let address = self.readw_inc_pc();
-        self.reg_pc = self.reg_pc - 1;
+        let lo = self.read_inc_pc();
+        let address = 0x0100 | self.reg_sp as u16;
+        self.bus_read(address);
         let pc_hi: u8 = ((self.reg_pc & 0xFF00) >> 8) as u8;
         let pc_lo: u8 = (self.reg_pc & 0x00FF) as u8;
         self.push(pc_hi);
         self.push(pc_lo);
-        self.reg_pc = address;
+        let hi = self.bus_read(self.reg_pc);
+        self.reg_pc = ((hi as u16) << 8) | lo as u16;
+    }
+    // 65C02 JMP (abs,X)
+    fn jmp_indexed_indirect(&mut self) {
+        let indirect_address = self.readw_inc_pc().wrapping_add(self.reg_x as u16);
+        self.reg_pc = self.readw(indirect_address);
     }
     fn rts(&mut self) {
+        self.start_pull();
         let pc_lo = self.pop();
         let pc_hi = self.pop();
-        self.reg_pc = (((pc_hi as u16) << 8) | pc_lo as u16) + 1;
+        let address = ((pc_hi as u16) << 8) | pc_lo as u16;
+        self.bus_read(address);
+        self.reg_pc = address.wrapping_add(1);
     }
     fn php(&mut self) {
+        self.implied();
         let value = self.get_p();
         self.push(value | 0x10); // always set the break bit on the stack
     }
     fn plp(&mut self) {
+        self.start_pull();
         let value = self.pop();
         self.set_p(value & (!0x10)); // always clear the break bit when popping from the stack
     }
     fn pha(&mut self) {
+        self.implied();
         let value = self.reg_a;
         self.push(value);
     }
     fn pla(&mut self) {
+        self.start_pull();
         let value = self.pop();
         self.reg_a = self.set_zn(value);
     }
     fn clc(&mut self) {
+        self.implied();
         self.reg_p.carry = false;
     }
     fn cld(&mut self) {
+        self.implied();
         self.reg_p.decimal = false;
     }
     fn cli(&mut self) {
+        self.implied();
         self.reg_p.int_disable = false;
     }
     fn clv(&mut self) {
+        self.implied();
         self.reg_p.overflow = false;
     }
     fn sec(&mut self) {
+        self.implied();
         self.reg_p.carry = true;
     }
     fn sed(&mut self) {
+        self.implied();
         self.reg_p.decimal = true;
     }
     fn sei(&mut self) {
+        self.implied();
         self.reg_p.int_disable = true;
     }
-    fn and<AM: AddressingMode>(&mut self, am: AM) {
+    fn and<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = self.reg_a & am.read(self);
         self.reg_a = self.set_zn(value);
     }
-    fn bit<AM: AddressingMode>(&mut self, am: AM) {
+    fn bit<AM: AddressingMode<B>>(&mut self, am: AM) {
         let a = self.reg_a;
         let value = am.read(self);
         self.reg_p.zero = match a & value {
@@ -749,39 +1222,41 @@ impl CPU {
             _ => true
         };
     }
-    fn cmp<AM: AddressingMode>(&mut self, am: AM) {
+    // 65C02 BIT #imm only affects Z.
+    fn bit_imm<AM: AddressingMode<B>>(&mut self, am: AM) {
+        let value = am.read(self);
+        self.reg_p.zero = self.reg_a & value == 0;
+    }
+    fn cmp<AM: AddressingMode<B>>(&mut self, am: AM) {
         let register = self.reg_a;
         let value = am.read(self);
         self.compare(register, value);
     }
-    fn cpx<AM: AddressingMode>(&mut self, am: AM) {
+    fn cpx<AM: AddressingMode<B>>(&mut self, am: AM) {
         let register = self.reg_x;
         let value = am.read(self);
         self.compare(register, value);
     }
-    fn cpy<AM: AddressingMode>(&mut self, am: AM) {
+    fn cpy<AM: AddressingMode<B>>(&mut self, am: AM) {
         let register = self.reg_y;
         let value = am.read(self);
         self.compare(register, value);
     }
     fn compare(&mut self, register: u8, value: u8) {
         let v = (register as i16) - (value as i16);
-        println!("COMPARE: {:X} - {:X}", register, value);
         if (v & 0x100) == 0 { self.reg_p.carry = true; }
         else { self.reg_p.carry = false; }
 
         self.set_zn(v as u8);
     }
-    fn eor<AM: AddressingMode>(&mut self, am: AM) {
+    fn eor<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
         let v = self.reg_a ^ value;
         self.reg_a = self.set_zn(v);
     }
-    fn ora<AM: AddressingMode>(&mut self, am: AM) {
+    fn ora<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
-        print!("ORA: {:X} | {:X} = ", self.reg_a, value);
         let v = self.reg_a | value;
-        println!("{:X}", v);
         self.reg_a = self.set_zn(v);
     }
     fn bcs(&mut self) {
@@ -834,7 +1309,7 @@ impl CPU {
     }
 
     // Unofficial instructions
-    fn dcp<AM: AddressingMode>(&mut self, am: AM) {
+    fn dcp<AM: AddressingMode<B>>(&mut self, am: AM) {
         // TODO: this opcode does a DEC followed by a CMP.
         // It would be nice to just call these two functions,
         // but when passing 'am' to dec, ownership is transfered and isn't returned afterwards
@@ -844,47 +1319,28 @@ impl CPU {
         // dec
         let value = am.read(self);
         let result = (value as i16 - 1) as u8;
-        am.write(self, result);
+        am.modify(self, value, result);
 
         // cmp
         let register = self.reg_a;
         self.compare(register, result);
     }
-    fn isc<AM: AddressingMode>(&mut self, am: AM) {
+    fn isc<AM: AddressingMode<B>>(&mut self, am: AM) {
         // inc
         let value = am.read(self);
         let result = (value as u16 + 1) as u8;
-        am.write(self, result);
+        am.modify(self, value, result);
 
         // sbc
-        let a = self.reg_a;
-        let m = am.read(self);
-        let mut result = a as i16 - m as i16;
-        if !self.reg_p.carry { result -= 1; }
-
-        if result & 0x100 == 0 { self.reg_p.carry = true; }
-        else { self.reg_p.carry = false; }
-
-        let result = result as u8;
-
-        if (a & 0x80) == 0 && (m & 0x80) != 0 && (result & 0x80) != 0 {
-            self.reg_p.overflow = true;
-        }
-        else if (a & 0x80) != 0 && (m & 0x80) == 0 && (result & 0x80) == 0 {
-            self.reg_p.overflow = true;
-        }
-        else {
-            self.reg_p.overflow = false;
-        }
-        self.reg_a = self.set_zn(result);
+        self.subtract(result);
     }
-    fn lax<AM: AddressingMode>(&mut self, am: AM) {
+    fn lax<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = am.read(self);
         self.reg_a = value;
         self.reg_x = value;
         self.set_zn(value);
     }
-    fn rla<AM: AddressingMode>(&mut self, am: AM) {
+    fn rla<AM: AddressingMode<B>>(&mut self, am: AM) {
         // rol
         let value = am.read(self);
         let bit0 = match self.reg_p.carry {
@@ -895,13 +1351,13 @@ impl CPU {
         else { self.reg_p.carry = false; }
         let result = (value << 1) | bit0;
         self.set_zn(result);
-        am.write(self, result);
+        am.modify(self, value, result);
 
         // and
-        let value = self.reg_a & am.read(self);
+        let value = self.reg_a & result;
         self.reg_a = self.set_zn(value);
     }
-    fn rra<AM: AddressingMode>(&mut self, am: AM) {
+    fn rra<AM: AddressingMode<B>>(&mut self, am: AM) {
         // ror
         let value = am.read(self);
         let bit7 = match self.reg_p.carry {
@@ -912,101 +1368,238 @@ impl CPU {
         else { self.reg_p.carry = false; }
         let result = (value >> 1) | bit7;
         self.set_zn(result);
-        am.write(self, result);
+        am.modify(self, value, result);
 
         // adc
-        let value = am.read(self);
-        let mut tmp_result = self.reg_a as u16 + value as u16;
-
-        if self.reg_p.carry { tmp_result += 1; }
-
-        if tmp_result & 0x100 != 0 { self.reg_p.carry = true; }
-        else { self.reg_p.carry = false; }
-
-        let result = tmp_result as u8;
-
-        if (self.reg_a & 0x80) == 0 && (value & 0x80) == 0 && (result & 0x80) != 0 {
-            self.reg_p.overflow = true;
-        }
-        else if (self.reg_a & 0x80) != 0 && (value & 0x80) != 0 && (result & 0x80) == 0 {
-            self.reg_p.overflow = true;
-        }
-        else {
-            self.reg_p.overflow = false;
-        }
-        self.reg_a = self.set_zn(result);
+        self.add(result);
     }
-    fn slo<AM: AddressingMode>(&mut self, am: AM) {
+    fn slo<AM: AddressingMode<B>>(&mut self, am: AM) {
         // asl
         let value = am.read(self);
         if value & 0x80 != 0 { self.reg_p.carry = true; }
         else { self.reg_p.carry = false; }
         let result = value << 1;
         self.set_zn(result);
-        am.write(self, result);
+        am.modify(self, value, result);
 
         // ora
-        let value = am.read(self);
-        let v = self.reg_a | value;
+        let v = self.reg_a | result;
         self.reg_a = self.set_zn(v);
     }
-    fn sre<AM: AddressingMode>(&mut self, am: AM) {
+    fn sre<AM: AddressingMode<B>>(&mut self, am: AM) {
         // lsr
         let value = am.read(self);
         if value & 0x01 != 0 { self.reg_p.carry = true; }
         else { self.reg_p.carry = false; }
         let result = value >> 1;
         self.set_zn(result);
-        am.write(self, result);
+        am.modify(self, value, result);
 
         // eor
-        let value = am.read(self);
-        let v = self.reg_a ^ value;
+        let v = self.reg_a ^ result;
         self.reg_a = self.set_zn(v);
     }
-    fn sax<AM: AddressingMode>(&mut self, am: AM) {
+    fn nop<AM: AddressingMode<B>>(&mut self, am: AM) {
+        am.read(self);
+    }
+    fn sax<AM: AddressingMode<B>>(&mut self, am: AM) {
         let value = self.reg_a & self.reg_x;
         am.write(self, value);
     }
+    fn anc<AM: AddressingMode<B>>(&mut self, am: AM) {
+        let value = self.reg_a & am.read(self);
+        self.reg_a = self.set_zn(value);
+        self.reg_p.carry = self.reg_p.negative;
+    }
+    fn alr<AM: AddressingMode<B>>(&mut self, am: AM) {
+        let value = self.reg_a & am.read(self);
+        self.reg_p.carry = value & 0x01 != 0;
+        self.reg_a = self.set_zn(value >> 1);
+    }
+    fn arr<AM: AddressingMode<B>>(&mut self, am: AM) {
+        let value = self.reg_a & am.read(self);
+        let carry_in = (self.reg_p.carry as u8) << 7;
+        let result = (value >> 1) | carry_in;
+        self.set_zn(result);
+        if self.reg_p.decimal && self.variant.decimal_mode() {
+            // N comes from the carry, V from bit 6 changing, and each digit
+            // gets a BCD fixup like ADC's
+            self.reg_p.overflow = (value ^ result) & 0x40 != 0;
+            let mut result = result;
+            if (value & 0x0F) + (value & 0x01) > 0x05 {
+                result = (result & 0xF0) | (result.wrapping_add(0x06) & 0x0F);
+            }
+            self.reg_p.carry = (value as u16 & 0xF0) + (value as u16 & 0x10) > 0x50;
+            if self.reg_p.carry {
+                result = result.wrapping_add(0x60);
+            }
+            self.reg_a = result;
+        }
+        else {
+            self.reg_p.carry = result & 0x40 != 0;
+            self.reg_p.overflow = ((result >> 6) ^ (result >> 5)) & 0x01 != 0;
+            self.reg_a = result;
+        }
+    }
+    // XAA and LXA mix in the bus value left over from A, which varies
+    // between chips and with temperature. $EE is the value most often seen.
+    fn xaa<AM: AddressingMode<B>>(&mut self, am: AM) {
+        let value = (self.reg_a | 0xEE) & self.reg_x & am.read(self);
+        self.reg_a = self.set_zn(value);
+    }
+    fn lxa<AM: AddressingMode<B>>(&mut self, am: AM) {
+        let value = (self.reg_a | 0xEE) & am.read(self);
+        self.reg_a = value;
+        self.reg_x = self.set_zn(value);
+    }
+    fn axs<AM: AddressingMode<B>>(&mut self, am: AM) {
+        let register = self.reg_a & self.reg_x;
+        let value = am.read(self);
+        self.compare(register, value);
+        self.reg_x = register.wrapping_sub(value);
+    }
+    fn las<AM: AddressingMode<B>>(&mut self, am: AM) {
+        let value = am.read(self) & self.reg_sp;
+        self.reg_a = value;
+        self.reg_x = value;
+        self.reg_sp = self.set_zn(value);
+    }
+    // SHA, SHX, SHY and TAS store value & (high byte of base + 1). When the
+    // index crosses a page the high byte of the address is replaced by the
+    // stored value too. Like every indexed store they read from the
+    // unfixed address first.
+    fn store_and_high(&mut self, base: u16, index: u8, value: u8) {
+        let address = base.wrapping_add(index as u16);
+        self.bus_read((base & 0xFF00) | (address & 0x00FF));
+        let value = value & ((base >> 8) as u8).wrapping_add(1);
+        let address = if address & 0xFF00 != base & 0xFF00 {
+            (value as u16) << 8 | (address & 0x00FF)
+        }
+        else {
+            address
+        };
+        self.bus_write(address, value);
+    }
+    fn kil(&mut self) {
+        self.reg_pc = self.reg_pc.wrapping_sub(1);
+        self.jammed = true;
+    }
+
+    // 65C02 instructions
+    fn stz<AM: AddressingMode<B>>(&mut self, am: AM) {
+        am.write(self, 0);
+    }
+    fn tsb<AM: AddressingMode<B>>(&mut self, am: AM) {
+        let value = am.read(self);
+        self.reg_p.zero = self.reg_a & value == 0;
+        let a = self.reg_a;
+        am.write(self, value | a);
+    }
+    fn trb<AM: AddressingMode<B>>(&mut self, am: AM) {
+        let value = am.read(self);
+        self.reg_p.zero = self.reg_a & value == 0;
+        let a = self.reg_a;
+        am.write(self, value & !a);
+    }
+    // RMBn is $n7 and SMBn is $(n+8)7
+    fn rmb_smb<AM: AddressingMode<B>>(&mut self, am: AM, opcode: u8) {
+        let bit = 1 << ((opcode >> 4) & 0x07);
+        let value = am.read(self);
+        let result = if opcode & 0x80 != 0 { value | bit } else { value & !bit };
+        am.write(self, result);
+    }
+    // BBRn is $nF and BBSn is $(n+8)F: zero page operand, then the branch
+    fn bbr_bbs(&mut self, opcode: u8) {
+        let bit = (opcode >> 4) & 0x07;
+        let address = self.read_inc_pc() as u16;
+        let value = self.bus_read(address);
+        let rel = self.read_inc_pc() as i8;
+        if (value >> bit) & 1 == opcode >> 7 {
+            self.branch(rel);
+        }
+    }
 
     // Address mode functions. Each one should return an AddressingMode
     fn acc(&mut self) -> AccumulatorAddressingMode {
+        self.implied();
         AccumulatorAddressingMode
     }
     fn imm(&mut self) -> ImmediateAddressingMode {
         ImmediateAddressingMode
     }
     fn zp(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode { address: self.read_inc_pc() as u16 }
+        MemoryAddressingMode::new(self.read_inc_pc() as u16)
     }
+    // Zero page indexing spends a cycle reading the unindexed address.
     fn zp_x(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode { address: (self.read_inc_pc() as u16 + self.reg_x as u16) & 0x00FF }
+        let base = self.read_inc_pc();
+        self.bus_read(base as u16);
+        MemoryAddressingMode::new(base.wrapping_add(self.reg_x) as u16)
     }
     fn zp_y(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode { address: (self.read_inc_pc() as u16 + self.reg_y as u16) & 0x00FF }
+        let base = self.read_inc_pc();
+        self.bus_read(base as u16);
+        MemoryAddressingMode::new(base.wrapping_add(self.reg_y) as u16)
     }
     fn abs(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode { address: self.readw_inc_pc() }
+        MemoryAddressingMode::new(self.readw_inc_pc())
     }
     fn abs_x(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode { address: self.readw_inc_pc() + self.reg_x as u16 }
+        let base = self.readw_inc_pc();
+        let index = self.reg_x;
+        self.indexed(base, index, false)
     }
     fn abs_y(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode { address: (self.readw_inc_pc() as u32 + self.reg_y as u32) as u16 }
+        let base = self.readw_inc_pc();
+        let index = self.reg_y;
+        self.indexed(base, index, false)
+    }
+    // abs,X and abs,Y for stores and read-modify-writes
+    fn abs_x_write(&mut self) -> MemoryAddressingMode {
+        let base = self.readw_inc_pc();
+        let index = self.reg_x;
+        self.indexed(base, index, true)
+    }
+    fn abs_y_write(&mut self) -> MemoryAddressingMode {
+        let base = self.readw_inc_pc();
+        let index = self.reg_y;
+        self.indexed(base, index, true)
     }
     fn indirect_x(&mut self) -> MemoryAddressingMode {
-        let x = self.reg_x as u16;
-        let ial = self.read_inc_pc() as u16;
-        let adl = (ial +x) & 0x00FF;
-        let address = self.memory.readw(adl);
-        println!("INDIRECT_X: {:X} + {:X} = {:X} -> {:X} = {:X}", x, ial, adl, address, self.memory.readw(address));
-        MemoryAddressingMode { address: address }
+        let x = self.reg_x;
+        let ial = self.read_inc_pc();
+        self.bus_read(ial as u16);
+        let adl = ial.wrapping_add(x);
+        let address = self.readw_zp(adl as u16);
+        MemoryAddressingMode::new(address)
+    }
+    // 65C02 (zp)
+    fn zp_indirect(&mut self) -> MemoryAddressingMode {
+        let ial = self.read_inc_pc();
+        MemoryAddressingMode::new(self.readw_zp(ial as u16))
     }
     fn indirect_y(&mut self) -> MemoryAddressingMode {
-        let y = self.reg_y;
+        let base = self.indirect_base();
+        let index = self.reg_y;
+        self.indexed(base, index, false)
+    }
+    fn indirect_y_write(&mut self) -> MemoryAddressingMode {
+        let base = self.indirect_base();
+        let index = self.reg_y;
+        self.indexed(base, index, true)
+    }
+    // The (zp),Y pointer before Y is added
+    fn indirect_base(&mut self) -> u16 {
         let ial = self.read_inc_pc();
-        let address = (self.memory.readw_zp(ial as u16) as u32) + y as u32;
-        MemoryAddressingMode { address: address as u16 }
+        self.readw_zp(ial as u16)
+    }
+    // The index is added to the low byte first; the high byte is fixed up
+    // a cycle later, after a read from the half-finished address.
+    fn indexed(&self, base: u16, index: u8, write: bool) -> MemoryAddressingMode {
+        let address = base.wrapping_add(index as u16);
+        let partial = (base & 0xFF00) | (address & 0x00FF);
+        let fixup = if write || partial != address { Some(partial) } else { None };
+        MemoryAddressingMode { address, fixup }
     }
 
     // Utility functions (not instructions)
@@ -1032,18 +1625,71 @@ impl CPU {
         self.reg_p.zero = match value & 0x02 { 0 => false, _ => true };
         self.reg_p.carry = match value & 0x01 { 0 => false, _ => true };
     }
+    // A taken branch spends a cycle adding the offset to PCL, and another
+    // fixing up PCH if that crossed a page, reading the next opcode each time.
     fn branch(&mut self, rel: i8) {
-        let newpc = (self.reg_pc as i32 + rel as i32) as u16;
+        let pc = self.reg_pc;
+        self.bus_read(pc);
+        let newpc = pc.wrapping_add(rel as u16);
+        if newpc & 0xFF00 != pc & 0xFF00 {
+            self.bus_read((pc & 0xFF00) | (newpc & 0x00FF));
+        }
         self.reg_pc = newpc;
     }
+    // One byte instructions still read the byte after the opcode, and
+    // throw it away.
+    fn implied(&mut self) {
+        let pc = self.reg_pc;
+        self.bus_read(pc);
+    }
+    // PLA, PLP, RTS and RTI spend two cycles before the first pull: the
+    // implied read, then a read of the stack slot S points at.
+    fn start_pull(&mut self) {
+        self.implied();
+        let address = 0x0100 | self.reg_sp as u16;
+        self.bus_read(address);
+    }
+    // One CPU cycle: everything else on the bus runs first, then the access.
+    fn tick(&mut self) {
+        self.cycles += 1;
+        self.memory.tick();
+        let nmi = self.memory.nmi();
+        if nmi && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = nmi;
+    }
+    fn bus_read(&mut self, address: u16) -> u8 {
+        self.tick();
+        self.memory.read(address)
+    }
+    fn bus_write(&mut self, address: u16, value: u8) {
+        self.tick();
+        self.memory.write(address, value);
+    }
+    // Low byte first, the order the 6502 reads them in.
+    fn readw(&mut self, address: u16) -> u16 {
+        let lo = self.bus_read(address) as u16;
+        let hi = self.bus_read(address.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+    // Zero page pointers wrap around within the zero page.
+    fn readw_zp(&mut self, address: u16) -> u16 {
+        if address > 0xFF {
+            panic!("readw_zp address should be less than or equal to 0x00FF");
+        }
+        let lo = self.bus_read(address) as u16;
+        let hi = self.bus_read((address + 1) & 0x00FF) as u16;
+        (hi << 8) | lo
+    }
     fn read_inc_pc(&mut self) -> u8 {
-        let value = self.memory.read(self.reg_pc);
-        self.reg_pc = self.reg_pc + 1;
+        let value = self.bus_read(self.reg_pc);
+        self.reg_pc = self.reg_pc.wrapping_add(1);
         value
     }
     fn readw_inc_pc(&mut self) -> u16 {
-        let value = self.memory.readw(self.reg_pc);
-        self.reg_pc = self.reg_pc + 2;
+        let value = self.readw(self.reg_pc);
+        self.reg_pc = self.reg_pc.wrapping_add(2);
         value
     }
     fn set_zn(&mut self, value: u8) -> u8 {
@@ -1053,12 +1699,257 @@ impl CPU {
     }
     fn push(&mut self, value: u8) {
         let address = 0x0100 | self.reg_sp as u16;
-        self.memory.write(address, value);
-        self.reg_sp = self.reg_sp - 1;
+        self.bus_write(address, value);
+        self.reg_sp = self.reg_sp.wrapping_sub(1);
     }
     fn pop(&mut self) -> u8 {
-        self.reg_sp = self.reg_sp + 1;
+        self.reg_sp = self.reg_sp.wrapping_add(1);
         let address = 0x0100 | self.reg_sp as u16;
-        self.memory.read(address)
+        self.bus_read(address)
+    }
+}
+
+impl<B: Bus + Savable> Savable for CPU<B> {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u16(self.reg_pc);
+        w.write_u8(self.reg_sp);
+        w.write_u8(self.reg_a);
+        w.write_u8(self.reg_x);
+        w.write_u8(self.reg_y);
+        w.write_u8(self.get_p());
+        w.write_u64(self.cycles);
+        w.write_u64(self.frame);
+        w.write_bool(self.jammed);
+        w.write_bool(self.nmi_line);
+        w.write_bool(self.nmi_pending);
+        self.memory.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.reg_pc = r.read_u16()?;
+        self.reg_sp = r.read_u8()?;
+        self.reg_a = r.read_u8()?;
+        self.reg_x = r.read_u8()?;
+        self.reg_y = r.read_u8()?;
+        let p = r.read_u8()?;
+        self.set_p(p);
+        self.cycles = r.read_u64()?;
+        self.frame = r.read_u64()?;
+        self.jammed = r.read_bool()?;
+        self.nmi_line = r.read_bool()?;
+        self.nmi_pending = r.read_bool()?;
+        self.memory.load(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use singlestep;
+
+    // Runs `program` from $E000 on an NROM board until it has run past the
+    // end of it, or jammed.
+    fn run(variant: Variant, program: &[u8]) -> CPU {
+        let mut image = b"NES\x1A\x01\x01".to_vec();
+        image.resize(16, 0);
+        let mut prg = vec![0xEA; 0x4000];
+        prg[0x2000..0x2000 + program.len()].copy_from_slice(program);
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0xE0, 0x00, 0xE0, 0x00, 0xE0]);
+        image.extend_from_slice(&prg);
+        image.extend_from_slice(&[0; 0x2000]);
+        let mut cpu = CPU::new(mapper::new(rom::Cartridge::load(image).unwrap(), None).unwrap());
+        cpu.set_variant(variant);
+        cpu.set_trace(false);
+        while cpu.reg_pc < 0xE000 + program.len() as u16 && !cpu.jammed {
+            cpu.step();
+        }
+        cpu
+    }
+
+    #[test]
+    fn decimal_mode() {
+        let program = [
+            0xF8,               // SED
+            0x18,               // CLC
+            0xA9, 0x19,         // LDA #$19
+            0x69, 0x28,         // ADC #$28
+            0x85, 0x00,         // STA $00
+            0x38,               // SEC
+            0xA9, 0x50,         // LDA #$50
+            0xE9, 0x01,         // SBC #$01
+            0x85, 0x01,         // STA $01
+        ];
+        // the 2A03 has the flag but no BCD adder
+        let cpu = run(Variant::Ricoh2A03, &program);
+        assert_eq!((cpu.peek(0x00), cpu.peek(0x01)), (0x41, 0x4F));
+        for &variant in &[Variant::Nmos6502, Variant::Cmos65C02] {
+            let cpu = run(variant, &program);
+            assert_eq!((cpu.peek(0x00), cpu.peek(0x01)), (0x47, 0x49), "{:?}", variant);
+        }
+    }
+
+    #[test]
+    fn cmos_flags_after_decimal_add() {
+        // $99 + $01 = $00 with carry: the NMOS 6502 sets Z and N from the
+        // binary sum, the 65C02 from the decimal one
+        let program = [0xF8, 0x18, 0xA9, 0x99, 0x69, 0x01];
+        let nmos = run(Variant::Nmos6502, &program);
+        let cmos = run(Variant::Cmos65C02, &program);
+        assert_eq!((nmos.reg_a, cmos.reg_a), (0x00, 0x00));
+        assert_eq!(nmos.get_p() & 0x83, 0x81);
+        assert_eq!(cmos.get_p() & 0x83, 0x03);
+    }
+
+    #[test]
+    fn cmos_opcodes() {
+        let program = [
+            0xA9, 0x05,         // LDA #$05
+            0x85, 0x10,         // STA $10
+            0x64, 0x10,         // STZ $10 (NOP zp)
+            0x1A,               // INC A (NOP)
+            0x80, 0x02,         // BRA +2 (NOP #)
+            0xA9, 0xFF,         // LDA #$FF
+            0x85, 0x11,         // STA $11
+        ];
+        let cpu = run(Variant::Cmos65C02, &program);
+        assert_eq!((cpu.peek(0x10), cpu.peek(0x11)), (0x00, 0x06));
+        let cpu = run(Variant::Ricoh2A03, &program);
+        assert_eq!((cpu.peek(0x10), cpu.peek(0x11)), (0x05, 0xFF));
+    }
+
+    #[test]
+    fn arr() {
+        let program = [
+            0x18,               // CLC
+            0xA9, 0xC0,         // LDA #$C0
+            0x6B, 0xC0,         // ARR #$C0
+            0x08,               // PHP
+            0x85, 0x00,         // STA $00
+            0x68,               // PLA
+            0x85, 0x01,         // STA $01
+        ];
+        let cpu = run(Variant::Ricoh2A03, &program);
+        // $C0 rotated right: C from bit 6, V from bit 6 xor bit 5
+        assert_eq!((cpu.peek(0x00), cpu.peek(0x01)), (0x60, 0x35));
+    }
+
+    #[test]
+    fn shx_and_shy() {
+        let program = [
+            0xA2, 0x03,         // LDX #$03
+            0xA0, 0x20,         // LDY #$20
+            0x9E, 0xF0, 0x12,   // SHX $12F0,Y: crosses a page
+            0xA0, 0x00,         // LDY #$00
+            0x9E, 0x00, 0x02,   // SHX $0200,Y
+            0xA0, 0x07,         // LDY #$07
+            0xA2, 0x00,         // LDX #$00
+            0x9C, 0x00, 0x04,   // SHY $0400,X
+        ];
+        let cpu = run(Variant::Ricoh2A03, &program);
+        // X AND the high byte plus one, which replaces the high byte of a
+        // page crossing address
+        assert_eq!(cpu.peek(0x0310), 0x03);
+        assert_eq!(cpu.peek(0x1310), 0x03);
+        assert_eq!(cpu.peek(0x0200), 0x03);
+        assert_eq!(cpu.peek(0x0400), 0x05);
+    }
+
+    #[test]
+    fn lax_and_sax() {
+        let program = [
+            0xA9, 0x3C,         // LDA #$3C
+            0x85, 0x20,         // STA $20
+            0xA9, 0x00,         // LDA #$00
+            0xA7, 0x20,         // LAX $20
+            0xA2, 0x0F,         // LDX #$0F
+            0x87, 0x21,         // SAX $21
+        ];
+        let cpu = run(Variant::Ricoh2A03, &program);
+        assert_eq!(cpu.reg_a, 0x3C);
+        assert_eq!(cpu.peek(0x21), 0x0C);
+    }
+
+    #[test]
+    fn kil_jams_until_reset() {
+        let mut cpu = run(Variant::Ricoh2A03, &[0xEA, 0x02, 0xEA]);
+        assert!(cpu.jammed());
+        let pc = cpu.reg_pc;
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!(cpu.reg_pc, pc);
+        cpu.reset();
+        assert!(!cpu.jammed());
+        assert_eq!(cpu.reg_pc, 0xE000);
+        // the 65C02 treats it as a two-byte NOP
+        assert!(!run(Variant::Cmos65C02, &[0xEA, 0x02, 0xEA]).jammed());
+    }
+
+    // The accesses `code` at $0200 makes in one instruction, with X = $10.
+    fn accesses(variant: Variant, code: &[u8]) -> Vec<(u16, u8, bool)> {
+        let mut bus = singlestep::TestBus::new();
+        for (i, &b) in code.iter().enumerate() {
+            bus.poke(0x0200 + i as u16, b);
+        }
+        bus.poke(0x1300, 0x41);
+        let mut cpu = CPU::with_bus(bus);
+        cpu.set_variant(variant);
+        cpu.set_trace(false);
+        cpu.set_registers(&Registers { pc: 0x0200, sp: 0xFD, a: 0x99, x: 0x10, y: 0, p: 0x24 });
+        cpu.step();
+        cpu.bus().log().iter().map(|a| (a.address, a.value, a.write)).collect()
+    }
+
+    #[test]
+    fn dummy_accesses() {
+        // INC $12F0,X: a read of the unfixed address, then the old value
+        // written back before the new one
+        assert_eq!(accesses(Variant::Ricoh2A03, &[0xFE, 0xF0, 0x12]), [
+            (0x0200, 0xFE, false), (0x0201, 0xF0, false), (0x0202, 0x12, false),
+            (0x1200, 0x00, false), (0x1300, 0x41, false),
+            (0x1300, 0x41, true), (0x1300, 0x42, true),
+        ]);
+        // LDA only pays for the extra read when the page changes
+        assert_eq!(accesses(Variant::Ricoh2A03, &[0xBD, 0xF0, 0x12]).len(), 5);
+        assert_eq!(accesses(Variant::Ricoh2A03, &[0xBD, 0x00, 0x13]).len(), 4);
+        // STA always does it
+        assert_eq!(accesses(Variant::Ricoh2A03, &[0x9D, 0x00, 0x13]), [
+            (0x0200, 0x9D, false), (0x0201, 0x00, false), (0x0202, 0x13, false),
+            (0x1310, 0x00, false), (0x1310, 0x99, true),
+        ]);
+        // implied instructions read the next byte and throw it away
+        assert_eq!(accesses(Variant::Ricoh2A03, &[0xE8, 0x55]), [(0x0200, 0xE8, false), (0x0201, 0x55, false)]);
+    }
+
+    // The cycles one instruction at $0200 takes with P = `p`.
+    fn cycles(variant: Variant, p: u8, code: &[u8]) -> u64 {
+        let mut bus = singlestep::TestBus::new();
+        for (i, &b) in code.iter().enumerate() {
+            bus.poke(0x0200 + i as u16, b);
+        }
+        let mut cpu = CPU::with_bus(bus);
+        cpu.set_variant(variant);
+        cpu.set_trace(false);
+        cpu.set_registers(&Registers { pc: 0x0200, sp: 0xFD, a: 0x19, x: 0, y: 0, p });
+        let start = cpu.cycles();
+        cpu.step();
+        cpu.cycles() - start
+    }
+
+    #[test]
+    fn cmos_timing() {
+        // INC $1300 reads the value twice and writes it once
+        assert_eq!(accesses(Variant::Cmos65C02, &[0xEE, 0x00, 0x13]), [
+            (0x0200, 0xEE, false), (0x0201, 0x00, false), (0x0202, 0x13, false),
+            (0x1300, 0x41, false), (0x1300, 0x41, false), (0x1300, 0x42, true),
+        ]);
+        // ADC and SBC take a cycle longer in decimal mode, on the 65C02 only
+        for &code in &[[0x69, 0x28], [0xE9, 0x01]] {
+            assert_eq!(cycles(Variant::Cmos65C02, 0x24, &code), 2);
+            assert_eq!(cycles(Variant::Cmos65C02, 0x2C, &code), 3);
+            assert_eq!(cycles(Variant::Nmos6502, 0x2C, &code), 2);
+        }
+        assert_eq!(cycles(Variant::Cmos65C02, 0x2C, &[0x65, 0x10]), 4);
+        assert_eq!(cycles(Variant::Cmos65C02, 0x2C, &[0x72, 0x10]), 6);
+        assert_eq!(cycles(Variant::Cmos65C02, 0x2C, &[0xA9, 0x10]), 2);
     }
 }
@@ -0,0 +1,789 @@
+// Famicom Disk System
+//
+// Disk images are a list of 65500 byte sides, optionally after a 16 byte
+// fwNES header ("FDS\x1A" and the side count). Each side holds the blocks
+// the BIOS reads, one after the other:
+//   1  disk info, 56 bytes, starting "*NINTENDO-HVC*"
+//   2  file count, 2 bytes
+//   3  file header, 16 bytes, with the file size at 13-14
+//   4  file data, the size from the header before it
+// then a 3 and a 4 for every file. The real disk also has gaps of zeros
+// between the blocks, a 0x80 mark in front of each one and a CRC16 after
+// it; images leave those out, so they are put back in while the disk is in
+// the drive and taken out again when saving.
+//
+// The RAM adapter in the cartridge slot has 32K of PRG-RAM at $6000-$DFFF,
+// 8K of CHR-RAM, the BIOS at $E000 (disksys.rom, which isn't included), a
+// timer IRQ, the drive interface and a wavetable sound channel. The drive
+// streams one byte every 150 CPU cycles or so while the motor is on, and
+// raises an IRQ as each one arrives or is wanted.
+//
+// Writes change the disk in memory. Images are never written to; the
+// differences are kept as an IPS patch in a .fdssav file next to them,
+// written when the disk is switched or the game is closed, which is
+// applied the next time the image is loaded.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use fds_audio::FdsAudio;
+use mapper::{self, PpuAccess, PpuFetch};
+use memory::RamInit;
+use patch::{self, PatchError};
+use rom::{self, RomError};
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+pub const MAGIC: &[u8] = b"FDS\x1A";
+pub const SIDE_SIZE: usize = 65500;
+const HEADER_SIZE: usize = 16;
+const DISK_INFO: &[u8] = b"\x01*NINTENDO-HVC*";
+pub const BIOS_SIZE: usize = 0x2000;
+
+// Bytes of gap before the first block, and after every block.
+const LEAD_IN: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+// CPU cycles for the head to come back to the start of the disk, and to
+// read or write one byte.
+const REWIND_CYCLES: u32 = 50000;
+const BYTE_CYCLES: u32 = 150;
+// About a second: long enough for the BIOS to notice the disk is gone.
+#[cfg(feature = "frontend")]
+const SWAP_CYCLES: u32 = 1_800_000;
+
+#[derive(Debug)]
+pub enum FdsError {
+    BadBios(usize),
+    Save(io::Error),
+    SavePatch(PatchError),
+}
+
+impl fmt::Display for FdsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FdsError::BadBios(size) => write!(f, "FDS BIOS is {} bytes, expected {}", size, BIOS_SIZE),
+            FdsError::Save(ref e) => write!(f, "problem reading FDS save: {}", e),
+            FdsError::SavePatch(ref e) => write!(f, "FDS save doesn't fit this disk: {}", e),
+        }
+    }
+}
+
+// Both image layouts: with the fwNES header, or bare sides.
+pub fn is_image(bin: &[u8]) -> bool {
+    bin.starts_with(MAGIC) || (bin.len().is_multiple_of(SIDE_SIZE) && bin.starts_with(DISK_INFO))
+}
+
+// Splits an image into its sides.
+pub fn parse_image(bin: &[u8]) -> Result<Vec<Vec<u8>>, RomError> {
+    let data = if bin.starts_with(MAGIC) {
+        let count = *bin.get(4).ok_or(RomError::Truncated)? as usize;
+        bin.get(HEADER_SIZE..HEADER_SIZE + count * SIDE_SIZE).ok_or(RomError::Truncated)?
+    }
+    else {
+        bin
+    };
+    if data.is_empty() {
+        return Err(RomError::Invalid("FDS image has no disk sides"));
+    }
+    let sides: Vec<Vec<u8>> = data.chunks(SIDE_SIZE).map(|s| s.to_vec()).collect();
+    if sides.iter().any(|s| !s.starts_with(DISK_INFO)) {
+        return Err(RomError::Invalid("FDS disk side has no disk info block"));
+    }
+    Ok(sides)
+}
+
+// The three game name characters from the disk info block.
+pub fn title(side: &[u8]) -> Vec<u8> {
+    side[16..19].to_vec()
+}
+
+// The size of the block starting at `pos`, or None past the last one.
+fn block_size(side: &[u8], pos: usize, file_size: usize) -> Option<usize> {
+    match *side.get(pos)? {
+        1 => Some(56),
+        2 => Some(2),
+        3 => Some(16),
+        4 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+fn file_size(header: &[u8]) -> usize {
+    header[13] as usize | (header[14] as usize) << 8
+}
+
+// CRC-16/KERMIT one byte at a time, as the adapter computes it. Feeding a
+// block followed by two zeros leaves its CRC in the accumulator.
+fn update_crc(crc: u16, value: u8) -> u16 {
+    let mut crc = crc;
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if value & (1 << bit) != 0 {
+            crc ^= 0x8000;
+        }
+    }
+    crc
+}
+
+// A side as the head sees it, with gaps, marks and CRCs. Whatever is left
+// of the side after the last block stays as free space at the end.
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN];
+    let mut pos = 0;
+    let mut size = 0;
+    while let Some(len) = block_size(side, pos, size) {
+        let block = match side.get(pos..pos + len) {
+            Some(b) => b,
+            None => break,
+        };
+        if block[0] == 3 {
+            size = file_size(block);
+        }
+        raw.push(0x80);
+        raw.extend_from_slice(block);
+        let crc = block.iter().chain(&[0, 0]).fold(update_crc(0, 0x80), |crc, &b| update_crc(crc, b));
+        raw.push(crc as u8);
+        raw.push((crc >> 8) as u8);
+        raw.extend_from_slice(&[0; BLOCK_GAP]);
+        pos += len;
+    }
+    raw.resize(raw.len() + SIDE_SIZE.saturating_sub(pos), 0);
+    raw
+}
+
+// The image form of a side: the blocks found after each 0x80 mark, up to
+// the first one that doesn't make sense.
+fn remove_gaps(raw: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(SIDE_SIZE);
+    let mut pos = 0;
+    let mut size = 0;
+    loop {
+        match raw[pos.min(raw.len())..].iter().position(|&b| b != 0) {
+            Some(start) if raw[pos + start] == 0x80 => pos += start + 1,
+            _ => break,
+        }
+        let len = match block_size(raw, pos, size) {
+            Some(len) if pos + len <= raw.len() => len,
+            _ => break,
+        };
+        if raw[pos] == 3 {
+            size = file_size(&raw[pos..]);
+        }
+        side.extend_from_slice(&raw[pos..pos + len]);
+        pos += len + 2;
+    }
+    side.resize(SIDE_SIZE, 0);
+    side
+}
+
+pub struct Fds {
+    rom: rom::Cartridge,
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    // Every side with its gaps, as the drive reads and writes them.
+    disks: Vec<Vec<u8>>,
+    // The image the save file is a patch against.
+    original: Vec<u8>,
+    save_path: Option<PathBuf>,
+    dirty: bool,
+    // None while ejected.
+    side: Option<usize>,
+    next_side: usize,
+    swap_delay: u32,
+
+    // $4020-$4022
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_repeat: bool,
+    irq_enabled: bool,
+    timer_irq: bool,
+    // $4023
+    disk_regs_enabled: bool,
+    sound_regs_enabled: bool,
+    // $4025
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    horizontal_mirroring: bool,
+    crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+    // $4026, read back through $4033
+    ext_port: u8,
+
+    write_data: u8,
+    read_data: u8,
+    transfer_complete: bool,
+    disk_irq: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    previous_crc_control: bool,
+    crc: u16,
+    position: usize,
+    delay: u32,
+
+    audio: FdsAudio,
+}
+
+impl Fds {
+    // `save_path` is where disk writes are kept, and read back from.
+    pub fn new(rom: rom::Cartridge, bios: Vec<u8>, save_path: Option<PathBuf>) -> Result<Fds, FdsError> {
+        if bios.len() != BIOS_SIZE {
+            return Err(FdsError::BadBios(bios.len()));
+        }
+        let original = rom.disk_sides().concat();
+        let mut image = original.clone();
+        if let Some(ref path) = save_path {
+            if path.is_file() {
+                let diff = fs::read(path).map_err(FdsError::Save)?;
+                image = patch::apply(&image, &diff).map_err(FdsError::SavePatch)?;
+            }
+        }
+        let disks = image.chunks(SIDE_SIZE).map(add_gaps).collect();
+        Ok(Fds {
+            rom,
+            bios,
+            prg_ram: vec![0; 0x8000],
+            chr_ram: vec![0; 0x2000],
+            disks,
+            original,
+            save_path,
+            dirty: false,
+            side: Some(0),
+            next_side: 0,
+            swap_delay: 0,
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_repeat: false,
+            irq_enabled: false,
+            timer_irq: false,
+            disk_regs_enabled: false,
+            sound_regs_enabled: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            horizontal_mirroring: false,
+            crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            ext_port: 0,
+            write_data: 0,
+            read_data: 0,
+            transfer_complete: false,
+            disk_irq: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            previous_crc_control: false,
+            crc: 0,
+            position: 0,
+            delay: 0,
+            audio: FdsAudio::new(),
+        })
+    }
+    // "1A", "1B", "2A", ... as printed on the disk labels.
+    #[cfg(feature = "frontend")]
+    fn side_name(side: usize) -> String {
+        format!("{}{}", side / 2 + 1, if side.is_multiple_of(2) { 'A' } else { 'B' })
+    }
+    fn clock_timer(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.timer_irq = true;
+            self.irq_counter = self.irq_reload;
+            if !self.irq_repeat {
+                self.irq_enabled = false;
+            }
+        }
+        else {
+            self.irq_counter -= 1;
+        }
+    }
+    fn clock_drive(&mut self) {
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let mut need_irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let data = self.disks[side][self.position];
+            if !self.previous_crc_control {
+                self.crc = update_crc(self.crc, data);
+            }
+            if !self.disk_ready {
+                self.gap_ended = false;
+                self.crc = 0;
+            }
+            else if data != 0 && !self.gap_ended {
+                // the 0x80 mark; the block starts with the next byte
+                self.gap_ended = true;
+                need_irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                self.disk_irq |= need_irq;
+            }
+        }
+        else {
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                self.disk_irq |= need_irq;
+            }
+            if !self.disk_ready {
+                data = 0;
+            }
+            if !self.crc_control {
+                self.crc = update_crc(self.crc, data);
+            }
+            else {
+                if !self.previous_crc_control {
+                    self.crc = update_crc(update_crc(self.crc, 0), 0);
+                }
+                data = self.crc as u8;
+                self.crc >>= 8;
+            }
+            if self.disks[side][self.position] != data {
+                self.disks[side][self.position] = data;
+                self.dirty = true;
+            }
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = self.crc_control;
+
+        self.position += 1;
+        if self.position >= self.disks[side].len() {
+            // the head goes back to the start once the motor is on again
+            self.motor_on = false;
+            self.position = 0;
+        }
+        else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+    // $4025 bit 3 picks the mirroring.
+    fn ciram_offset(&self, address: u16) -> u16 {
+        let mirroring = if self.horizontal_mirroring { rom::Mirroring::Horizontal } else { rom::Mirroring::Vertical };
+        mapper::ciram_offset(address, mirroring)
+    }
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4020 => self.irq_reload = (self.irq_reload & 0xFF00) | value as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00FF) | (value as u16) << 8,
+            0x4022 => {
+                self.irq_repeat = value & 0x01 != 0;
+                self.irq_enabled = value & 0x02 != 0 && self.disk_regs_enabled;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_reload;
+                }
+                else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_regs_enabled = value & 0x01 != 0;
+                self.sound_regs_enabled = value & 0x02 != 0;
+                if !self.disk_regs_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            _ if !self.disk_regs_enabled => {}
+            0x4024 => {
+                self.write_data = value;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.disk_irq = false;
+                self.motor_on = value & 0x01 != 0;
+                self.reset_transfer = value & 0x02 != 0;
+                self.read_mode = value & 0x04 != 0;
+                self.horizontal_mirroring = value & 0x08 != 0;
+                self.crc_control = value & 0x10 != 0;
+                self.disk_ready = value & 0x40 != 0;
+                self.disk_irq_enabled = value & 0x80 != 0;
+            }
+            0x4026 => self.ext_port = value,
+            _ => {}
+        }
+    }
+    fn read_register(&self, address: u16) -> Option<u8> {
+        // the upper bits that the adapter doesn't drive are usually left
+        // over from the high byte of the address
+        match address {
+            0x4030 => {
+                let mut value = 0;
+                value |= if self.timer_irq { 0x01 } else { 0 };
+                value |= if self.transfer_complete { 0x02 } else { 0 };
+                value |= if self.end_of_head { 0x40 } else { 0 };
+                Some(value)
+            }
+            0x4031 => Some(self.read_data),
+            0x4032 => {
+                let mut value = 0x40;
+                if self.side.is_none() {
+                    // no disk, not ready, and not writable
+                    value |= 0x07;
+                }
+                else if !self.scanning {
+                    value |= 0x02;
+                }
+                Some(value)
+            }
+            0x4033 => Some(0x80 | (self.ext_port & 0x7F)),
+            _ => None,
+        }
+    }
+}
+
+impl ::mapper::Mapper for Fds {
+    fn read(&mut self, address: u16) -> Option<u8> {
+        let value = self.peek(address);
+        match address {
+            // reading the status or data acknowledges the IRQs
+            0x4030 => {
+                self.timer_irq = false;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            _ => {}
+        }
+        value
+    }
+    fn peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x4030..=0x4033 if self.disk_regs_enabled => self.read_register(address),
+            0x4040..=0x4097 => self.audio.read(address),
+            0x6000..=0xDFFF => Some(self.prg_ram[(address - 0x6000) as usize]),
+            0xE000..=0xFFFF => Some(self.bios[(address - 0xE000) as usize]),
+            _ => None,
+        }
+    }
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4020..=0x4026 => self.write_register(address, value),
+            0x4040..=0x408A if self.sound_regs_enabled => self.audio.write(address, value),
+            0x6000..=0xDFFF => self.prg_ram[(address - 0x6000) as usize] = value,
+            _ => {}
+        }
+    }
+    fn power_on(&mut self, init: RamInit) {
+        init.fill(&mut self.prg_ram, 1);
+        init.fill(&mut self.chr_ram, 2);
+    }
+    fn rom(&self) -> &rom::Cartridge {
+        &self.rom
+    }
+    fn ppu_read(&mut self, address: u16, _fetch: PpuFetch) -> PpuAccess {
+        if address >= 0x2000 {
+            PpuAccess::Ciram(self.ciram_offset(address))
+        }
+        else {
+            PpuAccess::Cartridge(self.chr_ram[address as usize])
+        }
+    }
+    fn ppu_write(&mut self, address: u16, value: u8) -> PpuAccess {
+        if address >= 0x2000 {
+            return PpuAccess::Ciram(self.ciram_offset(address));
+        }
+        self.chr_ram[address as usize] = value;
+        PpuAccess::Cartridge(value)
+    }
+    fn tick(&mut self) {
+        self.clock_timer();
+        if self.side.is_none() && self.swap_delay > 0 {
+            self.swap_delay -= 1;
+            if self.swap_delay == 0 {
+                self.side = Some(self.next_side);
+            }
+        }
+        self.clock_drive();
+        self.audio.clock();
+    }
+    fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+    #[cfg(feature = "frontend")]
+    fn switch_disk_side(&mut self) -> Option<String> {
+        let side = self.side?;
+        self.next_side = (side + 1) % self.disks.len();
+        self.side = None;
+        self.position = 0;
+        self.swap_delay = SWAP_CYCLES;
+        Some(Fds::side_name(self.next_side))
+    }
+    // Writes the disk's differences from the image to the save file, if
+    // anything was written since last time.
+    fn flush_save(&mut self) -> io::Result<()> {
+        let path = match self.save_path {
+            Some(ref p) if self.dirty => p,
+            _ => return Ok(()),
+        };
+        let image: Vec<u8> = self.disks.iter().flat_map(|raw| remove_gaps(raw)).collect();
+        fs::write(path, patch::make_ips(&self.original, &image))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Savable for Fds {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        w.write_bytes(&self.chr_ram);
+        for raw in &self.disks {
+            w.write_bytes(raw);
+        }
+        w.write_u8(self.side.map_or(0xFF, |s| s as u8));
+        w.write_u8(self.next_side as u8);
+        w.write_u64(self.swap_delay as u64);
+        w.write_u16(self.irq_reload);
+        w.write_u16(self.irq_counter);
+        w.write_bool(self.irq_repeat);
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.timer_irq);
+        w.write_bool(self.disk_regs_enabled);
+        w.write_bool(self.sound_regs_enabled);
+        w.write_bool(self.motor_on);
+        w.write_bool(self.reset_transfer);
+        w.write_bool(self.read_mode);
+        w.write_bool(self.horizontal_mirroring);
+        w.write_bool(self.crc_control);
+        w.write_bool(self.disk_ready);
+        w.write_bool(self.disk_irq_enabled);
+        w.write_u8(self.ext_port);
+        w.write_u8(self.write_data);
+        w.write_u8(self.read_data);
+        w.write_bool(self.transfer_complete);
+        w.write_bool(self.disk_irq);
+        w.write_bool(self.end_of_head);
+        w.write_bool(self.scanning);
+        w.write_bool(self.gap_ended);
+        w.write_bool(self.previous_crc_control);
+        w.write_u16(self.crc);
+        w.write_u64(self.position as u64);
+        w.write_u64(self.delay as u64);
+        self.audio.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.prg_ram)?;
+        r.read_bytes(&mut self.chr_ram)?;
+        for raw in &mut self.disks {
+            r.read_bytes(raw)?;
+        }
+        // the disk may have changed, so the save file should follow it
+        self.dirty = true;
+        self.side = match r.read_u8()? {
+            0xFF => None,
+            s if (s as usize) < self.disks.len() => Some(s as usize),
+            _ => return Err(StateError::Invalid("disk side")),
+        };
+        self.next_side = r.read_u8()? as usize % self.disks.len();
+        self.swap_delay = r.read_u64()? as u32;
+        self.irq_reload = r.read_u16()?;
+        self.irq_counter = r.read_u16()?;
+        self.irq_repeat = r.read_bool()?;
+        self.irq_enabled = r.read_bool()?;
+        self.timer_irq = r.read_bool()?;
+        self.disk_regs_enabled = r.read_bool()?;
+        self.sound_regs_enabled = r.read_bool()?;
+        self.motor_on = r.read_bool()?;
+        self.reset_transfer = r.read_bool()?;
+        self.read_mode = r.read_bool()?;
+        self.horizontal_mirroring = r.read_bool()?;
+        self.crc_control = r.read_bool()?;
+        self.disk_ready = r.read_bool()?;
+        self.disk_irq_enabled = r.read_bool()?;
+        self.ext_port = r.read_u8()?;
+        self.write_data = r.read_u8()?;
+        self.read_data = r.read_u8()?;
+        self.transfer_complete = r.read_bool()?;
+        self.disk_irq = r.read_bool()?;
+        self.end_of_head = r.read_bool()?;
+        self.scanning = r.read_bool()?;
+        self.gap_ended = r.read_bool()?;
+        self.previous_crc_control = r.read_bool()?;
+        self.crc = r.read_u16()?;
+        // while ejected, the side going in next is the one it has to fit
+        let position = r.read_u64()? as usize;
+        if position >= self.disks[self.side.unwrap_or(self.next_side)].len() {
+            return Err(StateError::Invalid("disk position"));
+        }
+        self.position = position;
+        self.delay = r.read_u64()? as u32;
+        self.audio.load(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::Mapper;
+    use std::env;
+
+    // Disk info, a file count of one, and a 5 byte file.
+    fn side() -> Vec<u8> {
+        let mut side = DISK_INFO.to_vec();
+        side.extend_from_slice(b"\x00ABC");
+        side.resize(56, 0);
+        side.extend_from_slice(&[2, 1]);
+        let mut header = vec![3; 16];
+        header[13] = 5;
+        header[14] = 0;
+        side.extend_from_slice(&header);
+        side.extend_from_slice(&[4, 10, 20, 30, 40, 50]);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    fn adapter(save_path: Option<PathBuf>) -> Fds {
+        let rom = rom::Cartridge::load(side()).unwrap();
+        Fds::new(rom, vec![0; BIOS_SIZE], save_path).unwrap()
+    }
+
+    #[test]
+    fn images() {
+        let mut image = MAGIC.to_vec();
+        image.push(2);
+        image.resize(HEADER_SIZE, 0);
+        image.extend_from_slice(&side());
+        image.extend_from_slice(&side());
+        assert!(is_image(&image));
+        assert!(is_image(&side()));
+        assert_eq!(parse_image(&image).unwrap().len(), 2);
+        assert!(matches!(parse_image(&image[..HEADER_SIZE + SIDE_SIZE]), Err(RomError::Truncated)));
+        assert_eq!(title(&side()), b"ABC");
+
+        let mut bad = side();
+        bad[1] = b'X';
+        assert!(!is_image(&bad));
+        image[HEADER_SIZE + SIDE_SIZE + 1] = b'X';
+        assert!(matches!(parse_image(&image), Err(RomError::Invalid(_))));
+    }
+
+    #[test]
+    fn crc() {
+        // CRC-16/KERMIT's check value, fed the way the adapter does it
+        let crc = b"123456789".iter().chain(&[0, 0]).fold(0, |crc, &b| update_crc(crc, b));
+        assert_eq!(crc, 0x2189);
+    }
+
+    #[test]
+    fn gaps_round_trip() {
+        let raw = add_gaps(&side());
+        assert!(raw[..LEAD_IN].iter().all(|&b| b == 0));
+        assert_eq!(&raw[LEAD_IN..LEAD_IN + 16], &[0x80, 1, b'*', b'N', b'I', b'N', b'T', b'E', b'N', b'D', b'O', b'-', b'H', b'V', b'C', b'*']);
+        let next = LEAD_IN + 1 + 56 + 2 + BLOCK_GAP;
+        assert_eq!(&raw[next..next + 3], &[0x80, 2, 1]);
+        assert_eq!(remove_gaps(&raw), side());
+    }
+
+    #[test]
+    fn timer_irq() {
+        let mut fds = adapter(None);
+        fds.write(0x4023, 0x01);
+        fds.write(0x4020, 0x02);
+        fds.write(0x4021, 0x00);
+        fds.write(0x4022, 0x02);
+        for _ in 0..2 {
+            fds.tick();
+        }
+        assert!(!fds.irq());
+        fds.tick();
+        assert!(fds.irq());
+        assert_eq!(fds.peek(0x4030).unwrap() & 0x01, 0x01);
+        assert!(fds.irq());
+        assert_eq!(fds.read(0x4030).unwrap() & 0x01, 0x01);
+        assert!(!fds.irq());
+        // not repeating
+        for _ in 0..10 {
+            fds.tick();
+        }
+        assert!(!fds.irq());
+    }
+
+    #[test]
+    fn reads_the_disk() {
+        let mut fds = adapter(None);
+        fds.write(0x4023, 0x01);
+        // motor on, read mode, ready, IRQ on every byte
+        fds.write(0x4025, 0xC5);
+        let mut read = Vec::new();
+        let mut cycles = 0;
+        while read.len() < 3 {
+            fds.tick();
+            cycles += 1;
+            if fds.irq() {
+                read.push(fds.read(0x4031).unwrap());
+            }
+        }
+        assert_eq!(read, [1, b'*', b'N']);
+        // the head rewinds, then the lead-in and the mark go by
+        let bytes = (LEAD_IN + 4) as u32;
+        assert!(cycles > REWIND_CYCLES + (bytes - 1) * BYTE_CYCLES);
+        assert!(cycles < REWIND_CYCLES + (bytes + 1) * (BYTE_CYCLES + 1));
+    }
+
+    #[test]
+    fn saves_as_ips() {
+        let path = env::temp_dir().join(format!("futilenes-fds-{}.fdssav", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut fds = adapter(Some(path.clone()));
+        fds.flush_save().unwrap();
+        assert!(!path.exists());
+
+        // the first byte of the file data
+        let pos = add_gaps(&side()).iter().position(|&b| b == 10).unwrap();
+        fds.disks[0][pos] = 99;
+        fds.dirty = true;
+        fds.flush_save().unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(b"PATCH"));
+
+        let fds = adapter(Some(path.clone()));
+        assert_eq!(fds.disks[0][pos], 99);
+        let mut expected = side();
+        expected[56 + 2 + 16 + 1] = 99;
+        assert_eq!(remove_gaps(&fds.disks[0]), expected);
+        fs::remove_file(&path).unwrap();
+    }
+}
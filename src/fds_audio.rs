@@ -0,0 +1,308 @@
+// The Disk System's sound channel
+//
+// One voice playing a 64 step wavetable of 6-bit samples, with a volume
+// envelope, and a second wavetable of pitch offsets that bends its
+// frequency for vibrato and sweeps:
+//   $4040-$407F  wave RAM, writable while $4089 bit 7 is set
+//   $4080        volume envelope: off, increase, speed (or the gain when off)
+//   $4082-$4083  12-bit frequency; $4083 bit 7 halts the wave, bit 6 the
+//                envelopes
+//   $4084        modulation envelope, laid out like $4080
+//   $4085        7-bit signed modulation counter
+//   $4086-$4087  12-bit modulation frequency; $4087 bit 7 halts it
+//   $4088        appends to the modulation table while halted
+//   $4089        wave RAM write enable, master volume
+//   $408A        envelope speed multiplier
+//   $4090/$4092  read back the volume and modulation gains
+//
+// The modulation arithmetic follows the description on the nesdev wiki,
+// including its odd rounding.
+
+use apu;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+// $4089's master volume as a fraction of 36: 2/2, 2/3, 2/4 and 2/5.
+const MASTER_VOLUME: [u32; 4] = [36, 24, 17, 14];
+// What each modulation table entry does to the counter; None resets it.
+const MOD_STEPS: [Option<i8>; 8] = [Some(0), Some(1), Some(2), Some(4), None, Some(-4), Some(-2), Some(-1)];
+
+#[derive(Default)]
+struct Envelope {
+    speed: u8,
+    gain: u8,
+    increase: bool,
+    off: bool,
+    timer: u32,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8, master_speed: u8) {
+        self.speed = value & 0x3F;
+        self.increase = value & 0x40 != 0;
+        self.off = value & 0x80 != 0;
+        if self.off {
+            self.gain = self.speed;
+        }
+        self.reset_timer(master_speed);
+    }
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+    // True when the gain moved.
+    fn clock(&mut self, master_speed: u8) -> bool {
+        if self.off || master_speed == 0 {
+            return false;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return false;
+        }
+        self.reset_timer(master_speed);
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        }
+        else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+        true
+    }
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u8(self.speed);
+        w.write_u8(self.gain);
+        w.write_bool(self.increase);
+        w.write_bool(self.off);
+        w.write_u64(self.timer as u64);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.speed = r.read_u8()?;
+        self.gain = r.read_u8()?;
+        self.increase = r.read_bool()?;
+        self.off = r.read_bool()?;
+        self.timer = r.read_u64()? as u32;
+        Ok(())
+    }
+}
+
+pub struct FdsAudio {
+    wave: [u8; 64],
+    wave_write: bool,
+    wave_halt: bool,
+    envelope_halt: bool,
+    frequency: u16,
+    wave_position: u8,
+    wave_accumulator: u16,
+    volume: Envelope,
+    master_volume: u8,
+    master_speed: u8,
+
+    mod_envelope: Envelope,
+    mod_table: [u8; 64],
+    mod_position: u8,
+    mod_frequency: u16,
+    mod_halt: bool,
+    mod_counter: i8,
+    mod_accumulator: u16,
+    // The pitch offset the modulator is currently applying.
+    mod_output: i32,
+
+    output: u8,
+}
+
+impl FdsAudio {
+    pub fn new() -> FdsAudio {
+        FdsAudio {
+            wave: [0; 64],
+            wave_write: false,
+            wave_halt: true,
+            envelope_halt: false,
+            frequency: 0,
+            wave_position: 0,
+            wave_accumulator: 0,
+            volume: Envelope::default(),
+            master_volume: 0,
+            master_speed: 0xE8,
+            mod_envelope: Envelope::default(),
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_frequency: 0,
+            mod_halt: true,
+            mod_counter: 0,
+            mod_accumulator: 0,
+            mod_output: 0,
+            output: 0,
+        }
+    }
+    pub fn read(&self, address: u16) -> Option<u8> {
+        // the top two bits are open bus, which is usually 0x40 here
+        match address {
+            0x4040..=0x407F => Some(0x40 | self.wave[(address - 0x4040) as usize]),
+            0x4090 => Some(0x40 | self.volume.gain),
+            0x4092 => Some(0x40 | self.mod_envelope.gain),
+            _ => None,
+        }
+    }
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4040..=0x407F if self.wave_write => self.wave[(address - 0x4040) as usize] = value & 0x3F,
+            0x4080 => self.volume.write(value, self.master_speed),
+            0x4082 => self.frequency = (self.frequency & 0x0F00) | value as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value & 0x0F) as u16) << 8;
+                self.wave_halt = value & 0x80 != 0;
+                self.envelope_halt = value & 0x40 != 0;
+                if self.wave_halt {
+                    self.wave_position = 0;
+                    self.wave_accumulator = 0;
+                }
+                if self.envelope_halt {
+                    self.volume.reset_timer(self.master_speed);
+                    self.mod_envelope.reset_timer(self.master_speed);
+                }
+            }
+            0x4084 => self.mod_envelope.write(value, self.master_speed),
+            0x4085 => self.set_mod_counter((value & 0x7F) as i32),
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | value as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((value & 0x0F) as u16) << 8;
+                self.mod_halt = value & 0x80 != 0;
+                if self.mod_halt {
+                    self.mod_accumulator = 0;
+                }
+            }
+            0x4088 if self.mod_halt => {
+                // each write fills two entries
+                for _ in 0..2 {
+                    self.mod_table[self.mod_position as usize] = value & 0x07;
+                    self.mod_position = (self.mod_position + 1) & 0x3F;
+                }
+            }
+            0x4089 => {
+                self.wave_write = value & 0x80 != 0;
+                self.master_volume = value & 0x03;
+            }
+            0x408A => self.master_speed = value,
+            _ => {}
+        }
+    }
+    // 7-bit two's complement, wrapping.
+    fn set_mod_counter(&mut self, value: i32) {
+        self.mod_counter = (((value & 0x7F) ^ 0x40) - 0x40) as i8;
+    }
+    fn update_mod_output(&mut self) {
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.mod_envelope.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        }
+        else if temp < -64 {
+            temp += 256;
+        }
+        temp *= self.frequency as i32;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        self.mod_output = temp;
+    }
+    fn clock_modulator(&mut self) -> bool {
+        if self.mod_halt || self.mod_frequency == 0 {
+            return false;
+        }
+        let (sum, carry) = self.mod_accumulator.overflowing_add(self.mod_frequency);
+        self.mod_accumulator = sum;
+        if !carry {
+            return false;
+        }
+        let counter = match MOD_STEPS[self.mod_table[self.mod_position as usize] as usize] {
+            Some(step) => self.mod_counter as i32 + step as i32,
+            None => 0,
+        };
+        self.set_mod_counter(counter);
+        self.mod_position = (self.mod_position + 1) & 0x3F;
+        true
+    }
+    // One CPU cycle.
+    pub fn clock(&mut self) {
+        if !self.wave_halt && !self.envelope_halt {
+            self.volume.clock(self.master_speed);
+            if self.mod_envelope.clock(self.master_speed) {
+                self.update_mod_output();
+            }
+        }
+        if self.clock_modulator() {
+            self.update_mod_output();
+        }
+        let pitch = self.frequency as i32 + self.mod_output;
+        if !self.wave_halt && !self.wave_write && pitch > 0 {
+            let (sum, carry) = self.wave_accumulator.overflowing_add(pitch as u16);
+            self.wave_accumulator = sum;
+            if carry {
+                self.wave_position = (self.wave_position + 1) & 0x3F;
+            }
+        }
+        // the output holds its last level while the wave RAM is written
+        if !self.wave_write {
+            let level = self.volume.gain.min(32) as u32 * MASTER_VOLUME[self.master_volume as usize];
+            self.output = (self.wave[self.wave_position as usize] as u32 * level / 1152) as u8;
+        }
+    }
+    // On the APU's scale: the wave at full volume is 2.4 times as loud as
+    // an APU pulse at 15.
+    pub fn output(&self) -> f32 {
+        self.output as f32 / 63.0 * 2.4 * apu::PULSE_FULL_VOLUME
+    }
+}
+
+impl Savable for FdsAudio {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.wave);
+        w.write_bool(self.wave_write);
+        w.write_bool(self.wave_halt);
+        w.write_bool(self.envelope_halt);
+        w.write_u16(self.frequency);
+        w.write_u8(self.wave_position);
+        w.write_u16(self.wave_accumulator);
+        self.volume.save(w);
+        w.write_u8(self.master_volume);
+        w.write_u8(self.master_speed);
+        self.mod_envelope.save(w);
+        w.write_bytes(&self.mod_table);
+        w.write_u8(self.mod_position);
+        w.write_u16(self.mod_frequency);
+        w.write_bool(self.mod_halt);
+        w.write_u8(self.mod_counter as u8);
+        w.write_u16(self.mod_accumulator);
+        w.write_u8(self.output);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.wave)?;
+        self.wave_write = r.read_bool()?;
+        self.wave_halt = r.read_bool()?;
+        self.envelope_halt = r.read_bool()?;
+        self.frequency = r.read_u16()? & 0x0FFF;
+        self.wave_position = r.read_u8()? & 0x3F;
+        self.wave_accumulator = r.read_u16()?;
+        self.volume.load(r)?;
+        self.master_volume = r.read_u8()? & 0x03;
+        self.master_speed = r.read_u8()?;
+        self.mod_envelope.load(r)?;
+        r.read_bytes(&mut self.mod_table)?;
+        for entry in &mut self.mod_table {
+            *entry &= 0x07;
+        }
+        self.mod_position = r.read_u8()? & 0x3F;
+        self.mod_frequency = r.read_u16()? & 0x0FFF;
+        self.mod_halt = r.read_bool()?;
+        self.mod_counter = r.read_u8()? as i8;
+        self.mod_accumulator = r.read_u16()?;
+        self.output = r.read_u8()?;
+        self.update_mod_output();
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+// Sunsoft FME-7 and 5B, mapper 69
+//
+// Registers are reached by writing a command number to $8000-$9FFF and
+// then its parameter to $A000-$BFFF:
+//   0-7  1K CHR banks
+//   8    $6000 bank: RAM enable (7), RAM instead of ROM (6), bank (0-5)
+//   9-B  8K PRG banks at $8000, $A000 and $C000
+//   C    mirroring
+//   D    IRQ control: IRQ enable (0), counter enable (7); acknowledges
+//   E-F  16-bit IRQ counter, low byte first
+// with the last 8K of PRG fixed at $E000. The counter counts down every
+// cycle while enabled and raises the IRQ when it wraps from 0 to $FFFF.
+// The 5B adds sound at $C000-$FFFF, see sunsoft5b_audio.rs.
+
+use mapper::{self, PpuAccess, PpuFetch};
+use memory::RamInit;
+use rom;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+use sunsoft5b_audio::Sunsoft5bAudio;
+
+pub struct Fme7 {
+    rom: rom::Cartridge,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    command: u8,
+    // the parameters of commands 0-C
+    registers: [u8; 13],
+    irq_counter: u16,
+    irq_enabled: bool,
+    counter_enabled: bool,
+    irq_pending: bool,
+    audio: Sunsoft5bAudio,
+}
+
+impl Fme7 {
+    pub fn new(rom: rom::Cartridge) -> Fme7 {
+        let prg_ram = vec![0; rom.prg_ram_size().max(0x2000) as usize];
+        let chr_ram = vec![0; rom.chr_ram_size() as usize];
+        Fme7 {
+            rom,
+            prg_ram,
+            chr_ram,
+            command: 0,
+            registers: [0; 13],
+            irq_counter: 0,
+            irq_enabled: false,
+            counter_enabled: false,
+            irq_pending: false,
+            audio: Sunsoft5bAudio::new(),
+        }
+    }
+    // Byte `offset` of 8K PRG bank `bank`, wrapping around the ROM.
+    fn prg(&self, bank: usize, offset: u16) -> u8 {
+        let pages = &self.rom.prg_rom;
+        let bank = bank % (pages.len() * 2);
+        pages[bank / 2][(bank % 2) * 0x2000 + offset as usize]
+    }
+    // Where $6000 + `offset` lands in PRG-RAM, when RAM is mapped there.
+    fn prg_ram_index(&self, offset: u16) -> usize {
+        let bank = (self.registers[8] & 0x3F) as usize;
+        (bank * 0x2000 + offset as usize) % self.prg_ram.len()
+    }
+    fn chr_index(&self, address: u16) -> usize {
+        self.registers[(address >> 10) as usize] as usize * 0x400 + (address & 0x03FF) as usize
+    }
+    fn write_register(&mut self, value: u8) {
+        match self.command {
+            0x0D => {
+                self.irq_enabled = value & 0x01 != 0;
+                self.counter_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x0E => self.irq_counter = (self.irq_counter & 0xFF00) | value as u16,
+            0x0F => self.irq_counter = (self.irq_counter & 0x00FF) | (value as u16) << 8,
+            command => self.registers[command as usize] = value,
+        }
+    }
+}
+
+impl ::mapper::Mapper for Fme7 {
+    fn peek(&self, address: u16) -> Option<u8> {
+        let offset = address & 0x1FFF;
+        match address {
+            0x6000..=0x7FFF => {
+                let bank = self.registers[8];
+                match bank & 0xC0 {
+                    0xC0 => Some(self.prg_ram[self.prg_ram_index(offset)]),
+                    // RAM selected but disabled
+                    0x40 => None,
+                    _ => Some(self.prg((bank & 0x3F) as usize, offset)),
+                }
+            }
+            0x8000..=0xDFFF => {
+                let register = 9 + ((address - 0x8000) >> 13) as usize;
+                Some(self.prg((self.registers[register] & 0x3F) as usize, offset))
+            }
+            0xE000..=0xFFFF => Some(self.prg(self.rom.prg_rom.len() * 2 - 1, offset)),
+            _ => None,
+        }
+    }
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF if self.registers[8] & 0xC0 == 0xC0 => {
+                let index = self.prg_ram_index(address & 0x1FFF);
+                self.prg_ram[index] = value;
+            }
+            0x8000..=0x9FFF => self.command = value & 0x0F,
+            0xA000..=0xBFFF => self.write_register(value),
+            0xC000..=0xDFFF => self.audio.select(value),
+            0xE000..=0xFFFF => self.audio.write(value),
+            _ => {}
+        }
+    }
+    fn power_on(&mut self, init: RamInit) {
+        init.fill(&mut self.prg_ram, 1);
+        init.fill(&mut self.chr_ram, 2);
+    }
+    fn rom(&self) -> &rom::Cartridge {
+        &self.rom
+    }
+    fn ppu_read(&mut self, address: u16, _fetch: PpuFetch) -> PpuAccess {
+        if address >= 0x2000 {
+            PpuAccess::Ciram(mapper::switched_ciram_offset(address, self.registers[12]))
+        }
+        else if self.chr_ram.is_empty() {
+            PpuAccess::Cartridge(mapper::chr_byte(self.rom.chr_rom(), self.chr_index(address)))
+        }
+        else {
+            PpuAccess::Cartridge(mapper::chr_byte(&self.chr_ram, self.chr_index(address)))
+        }
+    }
+    fn ppu_write(&mut self, address: u16, value: u8) -> PpuAccess {
+        if address >= 0x2000 {
+            return PpuAccess::Ciram(mapper::switched_ciram_offset(address, self.registers[12]));
+        }
+        if !self.chr_ram.is_empty() {
+            let index = self.chr_index(address) % self.chr_ram.len();
+            self.chr_ram[index] = value;
+        }
+        PpuAccess::Cartridge(value)
+    }
+    fn tick(&mut self) {
+        if self.counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+        self.audio.clock();
+    }
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+}
+
+impl Savable for Fme7 {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        w.write_bytes(&self.chr_ram);
+        w.write_u8(self.command);
+        w.write_bytes(&self.registers);
+        w.write_u16(self.irq_counter);
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.counter_enabled);
+        w.write_bool(self.irq_pending);
+        self.audio.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.prg_ram)?;
+        r.read_bytes(&mut self.chr_ram)?;
+        self.command = r.read_u8()? & 0x0F;
+        r.read_bytes(&mut self.registers)?;
+        self.irq_counter = r.read_u16()?;
+        self.irq_enabled = r.read_bool()?;
+        self.counter_enabled = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        self.audio.load(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::{test_rom, Mapper};
+
+    fn command(fme7: &mut Fme7, command: u8, value: u8) {
+        fme7.write(0x8000, command);
+        fme7.write(0xA000, value);
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut fme7 = Fme7::new(test_rom(69, 16, 8));
+        command(&mut fme7, 0x09, 3);
+        command(&mut fme7, 0x0A, 4);
+        command(&mut fme7, 0x0B, 0x45);
+        assert_eq!(fme7.peek(0x8000), Some(3));
+        assert_eq!(fme7.peek(0xA000), Some(4));
+        assert_eq!(fme7.peek(0xC000), Some(5));
+        assert_eq!(fme7.peek(0xE000), Some(15));
+
+        // ROM, disabled RAM, then RAM at $6000
+        command(&mut fme7, 0x08, 2);
+        assert_eq!(fme7.peek(0x6000), Some(2));
+        command(&mut fme7, 0x08, 0x40);
+        assert_eq!(fme7.peek(0x6000), None);
+        fme7.write(0x6000, 1);
+        command(&mut fme7, 0x08, 0xC0);
+        assert_eq!(fme7.peek(0x6000), Some(0));
+        fme7.write(0x6000, 1);
+        assert_eq!(fme7.peek(0x6000), Some(1));
+    }
+
+    #[test]
+    fn chr_banks_and_mirroring() {
+        let mut fme7 = Fme7::new(test_rom(69, 4, 16));
+        for i in 0..8 {
+            command(&mut fme7, i, 8 + i);
+        }
+        for i in 0..8 {
+            assert!(matches!(fme7.ppu_read(i as u16 * 0x400, PpuFetch::Background), PpuAccess::Cartridge(v) if v == 8 + i));
+        }
+        command(&mut fme7, 0x0C, 1);
+        assert!(matches!(fme7.ppu_read(0x2C00, PpuFetch::Nametable), PpuAccess::Ciram(0x0400)));
+        command(&mut fme7, 0x0C, 3);
+        assert!(matches!(fme7.ppu_read(0x2000, PpuFetch::Nametable), PpuAccess::Ciram(0x0400)));
+    }
+
+    #[test]
+    fn irq() {
+        let mut fme7 = Fme7::new(test_rom(69, 4, 8));
+        command(&mut fme7, 0x0E, 2);
+        command(&mut fme7, 0x0F, 0);
+        command(&mut fme7, 0x0D, 0x81);
+        for _ in 0..2 {
+            fme7.tick();
+            assert!(!fme7.irq());
+        }
+        fme7.tick();
+        assert!(fme7.irq());
+        // writing the control register acknowledges
+        command(&mut fme7, 0x0D, 0x81);
+        assert!(!fme7.irq());
+
+        // counting without the IRQ
+        command(&mut fme7, 0x0E, 0);
+        command(&mut fme7, 0x0F, 0);
+        command(&mut fme7, 0x0D, 0x80);
+        fme7.tick();
+        assert!(!fme7.irq());
+        assert_eq!(fme7.irq_counter, 0xFFFF);
+        // and the IRQ without counting
+        command(&mut fme7, 0x0D, 0x01);
+        for _ in 0..0x20000 {
+            fme7.tick();
+        }
+        assert!(!fme7.irq());
+    }
+
+    #[test]
+    fn square_wave() {
+        let mut fme7 = Fme7::new(test_rom(69, 4, 8));
+        let mut register = |r: u8, value: u8| {
+            fme7.write(0xC000, r);
+            fme7.write(0xE000, value);
+        };
+        register(0, 1);
+        register(1, 0);
+        // only channel A's tone
+        register(7, 0x3E);
+        register(8, 0x0F);
+        let mut levels = Vec::new();
+        for _ in 0..64 {
+            fme7.tick();
+            levels.push(fme7.audio() > 0.0);
+        }
+        // high and low for 16 cycles each
+        let changes: Vec<usize> = (1..levels.len()).filter(|&i| levels[i] != levels[i - 1]).collect();
+        assert_eq!(changes.len(), 4);
+        assert!(changes.windows(2).all(|w| w[1] - w[0] == 16));
+    }
+}
@@ -0,0 +1,226 @@
+// Windowed player, built with `--features frontend`.
+//
+// Everything is software rendered: the framebuffer goes through the palette
+// into a 256x240 pixel buffer, and minifb scales that up to the window.
+//
+// Keys: arrows = d-pad, X = A, Z = B, Right Shift = Select, Enter = Start,
+// F5 = save state, F6 = switch disk side, F7 = load state, hold Backspace =
+// rewind, F8 = jump back 5 seconds, Escape = quit.
+//
+// When recording a movie, going back with a state load or a rewind drops
+// the input after that point the next time a frame is run.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use cpal;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use minifb::{Key, KeyRepeat, Scale, ScaleMode, Window, WindowOptions};
+
+use audio;
+use controller;
+use cpu::CPU;
+use movie::{FrameInput, Movie};
+use palette::Palette;
+use ppu;
+use rewind::RewindBuffer;
+
+const KEYMAP: [(Key, u8); 8] = [
+    (Key::X, controller::BUTTON_A),
+    (Key::Z, controller::BUTTON_B),
+    (Key::RightShift, controller::BUTTON_SELECT),
+    (Key::Enter, controller::BUTTON_START),
+    (Key::Up, controller::BUTTON_UP),
+    (Key::Down, controller::BUTTON_DOWN),
+    (Key::Left, controller::BUTTON_LEFT),
+    (Key::Right, controller::BUTTON_RIGHT),
+];
+
+// Snapshot every other frame and keep 20 seconds of them.
+const REWIND_INTERVAL: u64 = 2;
+const REWIND_CAPACITY: usize = 600;
+const REWIND_JUMP_SECONDS: u64 = 5;
+
+pub fn run(cpu: &mut CPU, palette: &Palette, mut recording: Option<&mut Movie>) {
+    cpu.set_trace(false);
+    let start_frame = cpu.frame();
+    let options = WindowOptions {
+        resize: true,
+        scale: Scale::X2,
+        scale_mode: ScaleMode::AspectRatioStretch,
+        ..WindowOptions::default()
+    };
+    let mut window = Window::new("futilenes", ppu::WIDTH, ppu::HEIGHT, options)
+        .expect("failed to open window");
+    let (rate_num, rate_den) = cpu.frame_rate();
+    let fps = (rate_num + rate_den / 2) / rate_den;
+    window.set_target_fps(fps as usize);
+
+    let mut audio = AudioOutput::new();
+    if audio.is_none() {
+        println!("No audio output device, running without sound.");
+    }
+    let mut rewind = RewindBuffer::new(REWIND_INTERVAL, REWIND_CAPACITY);
+    let mut quick_save: Option<Vec<u8>> = None;
+    let mut pixels = vec![0u32; ppu::WIDTH * ppu::HEIGHT];
+    let mut samples = Vec::new();
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let mut buttons = 0;
+        for &(key, button) in KEYMAP.iter() {
+            if window.is_key_down(key) {
+                buttons |= button;
+            }
+        }
+        cpu.set_input(0, buttons);
+
+        if window.is_key_pressed(Key::F5, KeyRepeat::No) {
+            quick_save = Some(cpu.save_state());
+        }
+        if window.is_key_pressed(Key::F6, KeyRepeat::No) {
+            if let Some(side) = cpu.switch_disk_side() {
+                println!("Ejected the disk, inserting side {}.", side);
+                if let Err(e) = cpu.flush_save() {
+                    println!("Warning: couldn't write the save file: {}", e);
+                }
+            }
+        }
+        if window.is_key_pressed(Key::F7, KeyRepeat::No) {
+            if let Some(ref state) = quick_save {
+                if let Err(e) = cpu.load_state(state) {
+                    println!("Could not load state: {}", e);
+                }
+            }
+        }
+
+        if window.is_key_pressed(Key::F8, KeyRepeat::No) {
+            // as far back as the buffer goes, if that's less
+            let target = cpu.frame().saturating_sub(REWIND_JUMP_SECONDS * fps);
+            let target = rewind.oldest_frame().map(|oldest| target.max(oldest));
+            if let Some(frame) = target {
+                if let Err(e) = rewind.rewind_to(cpu, frame) {
+                    println!("Could not rewind: {}", e);
+                }
+            }
+        }
+
+        if window.is_key_down(Key::Backspace) {
+            if let Err(e) = rewind.step_back(cpu) {
+                println!("Could not rewind: {}", e);
+            }
+        } else {
+            if let Some(ref mut movie) = recording {
+                let n = cpu.frame().saturating_sub(start_frame) as usize;
+                if movie.len() > n {
+                    movie.truncate(n);
+                }
+                movie.record(FrameInput { commands: 0, ports: [buttons, 0] });
+            }
+            cpu.run_frame();
+            rewind.record(cpu);
+        }
+
+        palette.convert(cpu.framebuffer(), &mut pixels);
+        window.update_with_buffer(&pixels, ppu::WIDTH, ppu::HEIGHT)
+            .expect("failed to update window");
+
+        cpu.take_audio(&mut samples);
+        if let Some(ref mut a) = audio {
+            a.queue(&samples);
+        }
+        samples.clear();
+    }
+}
+
+// How far the resampling ratio may be nudged either way to keep the device
+// queue from draining or piling up.
+const MAX_RATE_DELTA: f64 = 0.005;
+// Aim for this much audio queued for the device.
+const TARGET_LATENCY_MS: usize = 60;
+
+struct AudioOutput {
+    _stream: cpal::Stream,
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    device_rate: f64,
+    capacity: usize,
+    pos: f64,
+    last: f32,
+}
+
+impl AudioOutput {
+    fn new() -> Option<AudioOutput> {
+        let device = cpal::default_host().default_output_device()?;
+        let supported = device.default_output_config().ok()?;
+        let format = supported.sample_format();
+        let config: cpal::StreamConfig = supported.into();
+        let device_rate = config.sample_rate.0 as f64;
+        let capacity = config.sample_rate.0 as usize * TARGET_LATENCY_MS * 2 / 1000;
+        let queue = Arc::new(Mutex::new(VecDeque::with_capacity(capacity * 2)));
+        let stream = match format {
+            cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, queue.clone()),
+            cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, queue.clone()),
+            cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, queue.clone()),
+            _ => None,
+        }?;
+        stream.play().ok()?;
+        Some(AudioOutput {
+            _stream: stream,
+            queue,
+            device_rate,
+            capacity,
+            pos: 0.0,
+            last: 0.0,
+        })
+    }
+
+    // Resamples a frame's worth of audio to the device rate and queues it.
+    // Dynamic rate control: the ratio is adjusted slightly by how full the
+    // queue is, so it settles around half full and never crackles from
+    // running dry or drifts into long latency.
+    fn queue(&mut self, samples: &[f32]) {
+        if samples.is_empty() {
+            return;
+        }
+        let mut queue = self.queue.lock().unwrap();
+        let fill = (queue.len() as f64 / self.capacity as f64).min(1.0);
+        let ratio = self.device_rate / audio::SAMPLE_RATE as f64
+            * (1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill));
+        let step = 1.0 / ratio;
+        let n = samples.len() as f64;
+        while self.pos < n {
+            let i = self.pos as usize;
+            let frac = (self.pos - i as f64) as f32;
+            let a = if i == 0 { self.last } else { samples[i - 1] };
+            let b = samples[i];
+            queue.push_back(a + (b - a) * frac);
+            self.pos += step;
+        }
+        self.pos -= n;
+        self.last = samples[samples.len() - 1];
+        // the window was stalled: drop the backlog rather than lag behind
+        while queue.len() > self.capacity * 2 {
+            queue.pop_front();
+        }
+    }
+}
+
+fn build_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig,
+                   queue: Arc<Mutex<VecDeque<f32>>>) -> Option<cpal::Stream>
+    where T: cpal::SizedSample + cpal::FromSample<f32>
+{
+    let channels = config.channels as usize;
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let mut queue = queue.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                let sample = queue.pop_front().unwrap_or(0.0);
+                for out in frame.iter_mut() {
+                    *out = T::from_sample(sample);
+                }
+            }
+        },
+        |e| println!("Audio error: {}", e),
+        None,
+    ).ok()
+}
@@ -1,41 +1,659 @@
+#[cfg(feature = "frontend")]
+extern crate minifb;
+#[cfg(feature = "frontend")]
+extern crate cpal;
+#[cfg(feature = "archive")]
+extern crate miniz_oxide;
+
 mod rom;
 mod cpu;
 mod memory;
+mod bus;
 mod mapper;
+mod crc32;
+mod savestate;
+#[cfg(any(feature = "frontend", test))]
+mod rewind;
+mod controller;
+mod md5;
+mod movie;
+mod ppu;
+mod apu;
+mod audio;
+mod palette;
+mod capture;
+mod testrom;
+mod json;
+mod singlestep;
+mod trace;
+mod region;
+mod romdb;
+mod unif;
+mod archive;
+mod patch;
+mod fds;
+mod fds_audio;
+mod nsf;
+mod vrc6;
+mod vrc6_audio;
+mod n163;
+mod n163_audio;
+mod fme7;
+mod sunsoft5b_audio;
+mod mmc5;
+mod mmc5_audio;
+#[cfg(feature = "frontend")]
+mod frontend;
 
 use std::env;
 use std::path::Path;
-use std::io::Read;
-use std::fs::File;
+use std::io::{self, Read};
+use std::fs::{self, File};
+use std::process;
 
 fn usage() {
-    println!("Usage: futilenes <rom>");
+    println!("Usage: futilenes <rom> [options]");
+    println!("       futilenes nsf <file.nsf> [--track <n>] [--seconds <s>] [--out <file.wav>]");
+    println!("  --movie <file.fm2>              play back a movie");
+    println!("  --record-movie <file.fm2>       record the input into a movie");
+    println!("  --palette <file.pal>            use a different palette");
+    println!("  --patch <file>                  apply an IPS, UPS or BPS patch (repeatable)");
+    println!("  --fds-bios <disksys.rom>        Disk System BIOS, looked for next to the image by default");
+    println!("  --frames <n>                    run headless for n frames");
+    println!("  --screenshot-at <n> <file.png>  save frame n (1 to --frames) as a PNG");
+    println!("  --record-video <file.y4m>       record video");
+    println!("  --record-audio <file.wav>       record audio");
+    println!("  --cpu <2a03|6502|65c02>         CPU variant, 2a03 unless testing the core");
+    println!("  --region <ntsc|pal|dendy>       console timing, from the ROM header by default");
+    println!("  --ram-init <mode>               power-on RAM: zeros, ff, pattern, random[:seed]");
+    println!("  --nestest                       start at $C000 like nestest's automation mode");
+    println!("  --test                          run as a test ROM reporting through $6000");
+    println!("  --test-hash <n> <crc32>         check the frame hash after n frames");
+    println!("  --test-suite <file>             run every test listed in file");
+    println!("  --single-step <dir|file.json>   run the SingleStepTests CPU test vectors");
+    if cfg!(feature = "frontend") {
+        println!("  --window                        play in a window");
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    rom: Option<String>,
+    movie: Option<String>,
+    record_movie: Option<String>,
+    palette: Option<String>,
+    patches: Vec<String>,
+    fds_bios: Option<String>,
+    windowed: bool,
+    frames: Option<u64>,
+    screenshot: Option<(u64, String)>,
+    video: Option<String>,
+    audio: Option<String>,
+    cpu: Option<cpu::Variant>,
+    ram_init: Option<memory::RamInit>,
+    region: Option<region::Region>,
+    nestest: bool,
+    test: bool,
+    test_hash: Option<(u64, u32)>,
+    test_suite: Option<String>,
+    single_step: Option<String>,
 }
 
-fn read_rom<P: AsRef<Path>>(path: P) -> Vec<u8> {
+impl Options {
+    fn parse(args: &[String]) -> Option<Options> {
+        let mut o = Options::default();
+        let mut i = 0;
+        while i < args.len() {
+            let value = args.get(i + 1).cloned();
+            match args[i].as_str() {
+                "--movie" => { o.movie = Some(value?); i += 1; }
+                "--record-movie" => { o.record_movie = Some(value?); i += 1; }
+                "--palette" => { o.palette = Some(value?); i += 1; }
+                "--patch" => { o.patches.push(value?); i += 1; }
+                "--fds-bios" => { o.fds_bios = Some(value?); i += 1; }
+                "--frames" => { o.frames = Some(value?.parse().ok()?); i += 1; }
+                "--screenshot-at" => {
+                    // frames count from 1
+                    let frame = value?.parse().ok().filter(|&n| n > 0)?;
+                    o.screenshot = Some((frame, args.get(i + 2)?.clone()));
+                    i += 2;
+                }
+                "--record-video" => { o.video = Some(value?); i += 1; }
+                "--record-audio" => { o.audio = Some(value?); i += 1; }
+                "--cpu" => { o.cpu = Some(cpu::Variant::from_name(&value?)?); i += 1; }
+                "--region" => { o.region = Some(region::Region::from_name(&value?)?); i += 1; }
+                "--ram-init" => { o.ram_init = Some(memory::RamInit::from_name(&value?)?); i += 1; }
+                "--nestest" => o.nestest = true,
+                "--test" => o.test = true,
+                "--test-hash" => {
+                    let frames = value?.parse().ok()?;
+                    let hash = u32::from_str_radix(args.get(i + 2)?.trim_start_matches("0x"), 16).ok()?;
+                    o.test_hash = Some((frames, hash));
+                    i += 2;
+                }
+                "--test-suite" => { o.test_suite = Some(value?); i += 1; }
+                "--single-step" => { o.single_step = Some(value?); i += 1; }
+                "--window" if cfg!(feature = "frontend") => o.windowed = true,
+                a if o.rom.is_none() && !a.starts_with("--") => o.rom = Some(a.to_string()),
+                _ => return None,
+            }
+            i += 1;
+        }
+        // a screenshot past the end of the run would never be taken
+        if let (Some(frames), Some((n, _))) = (o.frames, &o.screenshot) {
+            if *n > frames {
+                return None;
+            }
+        }
+        Some(o)
+    }
+    fn headless(&self) -> bool {
+        self.frames.is_some() || self.screenshot.is_some() || self.video.is_some() || self.audio.is_some()
+            || (self.record_movie.is_some() && !self.windowed)
+    }
+}
+
+// Patches next to the ROM with the same name are applied automatically
+// unless others are given.
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+// A ROM file, or a zip or gzip file with one inside. "games.zip:game.nes"
+// picks an entry from a zip. The patches are applied in order.
+fn read_rom(path: &str, patches: &[String]) -> Vec<u8> {
+    let (path, entry) = split_archive_path(path);
     let mut file = File::open(path).unwrap();
     let mut buf: Vec<u8> = Vec::new();
     match file.read_to_end(&mut buf) {
         Ok(bytes) => println!("Read {} bytes from ROM.", bytes),
         Err(_) => panic!("problem reading from ROM.")
     }
+    if archive::is_archive(&buf) {
+        buf = match archive::extract(&buf, entry) {
+            Ok(data) => data,
+            Err(e) => panic!("{}", e),
+        };
+        println!("Unpacked {} bytes.", buf.len());
+    }
+    let auto_patch: Vec<String> = PATCH_EXTENSIONS.iter()
+        .map(|ext| Path::new(path).with_extension(ext))
+        .find(|p| p.is_file())
+        .map(|p| p.to_string_lossy().into_owned())
+        .into_iter()
+        .collect();
+    // explicit patches replace the automatic one rather than stack on it
+    if !patches.is_empty() {
+        for p in auto_patch.iter().filter(|&p| !patches.iter().any(|q| Path::new(q) == Path::new(p))) {
+            println!("Warning: not applying {}, since --patch was given.", p);
+        }
+    }
+    for patch_path in if patches.is_empty() { &auto_patch } else { patches } {
+        let patch_data = fs::read(patch_path).expect("problem reading patch.");
+        buf = match patch::apply(&buf, &patch_data) {
+            Ok(data) => data,
+            Err(e) => panic!("{}: {}", patch_path, e),
+        };
+        println!("Applied patch {}.", patch_path);
+    }
     buf
 }
 
+// "games.zip:game.nes" into the file and the entry, unless a file by that
+// whole name exists.
+fn split_archive_path(path: &str) -> (&str, Option<&str>) {
+    match path.rfind(':') {
+        Some(i) if !Path::new(path).exists() => (&path[..i], Some(&path[i + 1..])),
+        _ => (path, None),
+    }
+}
+
+const FDS_BIOS_NAME: &str = "disksys.rom";
+
+// The Disk System BIOS: the one given, or disksys.rom next to the image or
+// in the current directory.
+fn read_fds_bios(rom_path: &str, bios: Option<&str>) -> Vec<u8> {
+    let path = match bios {
+        Some(b) => Path::new(b).to_path_buf(),
+        None => {
+            let beside = Path::new(rom_path).with_file_name(FDS_BIOS_NAME);
+            if beside.is_file() { beside } else { Path::new(FDS_BIOS_NAME).to_path_buf() }
+        }
+    };
+    match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => panic!("problem reading the FDS BIOS {}: {} (use --fds-bios to give its location)",
+                         path.display(), e),
+    }
+}
+
 fn main() {
-    let mut args = env::args();
-    if args.len() != 2 {
-        usage();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().is_some_and(|a| a == "nsf") {
+        if !run_nsf(&args[1..]) {
+            process::exit(1);
+        }
         return;
     }
-    let rom_filename = match args.nth(1) {
-        Some(a) => a,
-        None => panic!("problem in argument parsing."),
+    let options = match Options::parse(&args) {
+        Some(o) if o.rom.is_some() || o.test_suite.is_some() || o.single_step.is_some() => o,
+        _ => {
+            usage();
+            return;
+        }
     };
+    if let Some(ref path) = options.single_step {
+        if !run_single_step(path, options.cpu.unwrap_or(cpu::Variant::Ricoh2A03)) {
+            process::exit(1);
+        }
+        return;
+    }
+    if let Some(ref suite) = options.test_suite {
+        if !run_test_suite(suite) {
+            process::exit(1);
+        }
+        return;
+    }
+    let rom_filename = options.rom.clone().unwrap();
     println!("ROM: {}", rom_filename);
 
-    let rom_data = read_rom(rom_filename);
-    let mapper = mapper::Mapper::new(rom::INesFile::load(rom_data));
-    let mut cpu = cpu::CPU::new(mapper);
-    cpu.run();
+    let mut cpu = load_cpu(&rom_filename, &options.patches, options.fds_bios.as_deref());
+    if let Some(variant) = options.cpu {
+        cpu.set_variant(variant);
+    }
+    if let Some(region) = options.region {
+        cpu.set_region(region);
+    }
+    if let Some(init) = options.ram_init {
+        println!("RAM init: {}", init);
+        cpu.set_ram_init(init);
+        cpu.power_on();
+    }
+    if options.nestest {
+        cpu.set_pc(0xC000);
+    }
+    if options.test || options.test_hash.is_some() {
+        if !run_test(&mut cpu, &rom_filename, options.test_hash) {
+            process::exit(1);
+        }
+        return;
+    }
+    let palette = match options.palette {
+        Some(ref f) => load_palette(f),
+        None => palette::Palette::default(),
+    };
+    let movie = options.movie.as_ref().map(|f| start_movie(&mut cpu, f));
+
+    if options.headless() {
+        let frames = match (options.frames, &options.screenshot, &movie) {
+            (Some(n), _, _) => n,
+            (None, &Some((n, _)), _) => n,
+            (None, &None, Some(m)) => m.len() as u64,
+            _ => {
+                println!("Recording needs --frames or a movie to know when to stop.");
+                return;
+            }
+        };
+        let mut recording = options.record_movie.as_ref().map(|_| start_recording(&cpu, &rom_filename));
+        if let Err(e) = run_headless(&mut cpu, &options, movie.as_ref(), recording.as_mut(), frames, &palette) {
+            panic!("problem writing capture: {}", e);
+        }
+        if let (Some(ref f), Some(ref m)) = (&options.record_movie, &recording) {
+            save_recording(f, m);
+        }
+        flush_save(&mut cpu);
+        return;
+    }
+    if let Some(ref m) = movie {
+        let mut frame = 0;
+        while m.apply(&mut cpu, frame) {
+            cpu.run_frame();
+            frame += 1;
+        }
+        println!("Movie finished after {} frames.", frame);
+    }
+    if options.windowed {
+        let mut recording = options.record_movie.as_ref().map(|_| start_recording(&cpu, &rom_filename));
+        run_windowed(&mut cpu, &palette, recording.as_mut());
+        if let (Some(ref f), Some(ref m)) = (&options.record_movie, &recording) {
+            save_recording(f, m);
+        }
+        flush_save(&mut cpu);
+    }
+    else {
+        cpu.run();
+    }
+}
+
+// Runs without a window for a fixed number of frames, writing out whatever
+// captures were asked for.
+fn run_headless(cpu: &mut cpu::CPU, options: &Options, movie: Option<&movie::Movie>,
+                mut recording: Option<&mut movie::Movie>, frames: u64,
+                palette: &palette::Palette) -> io::Result<()> {
+    cpu.set_trace(false);
+    let (rate_num, rate_den) = cpu.frame_rate();
+    let mut video = match options.video {
+        Some(ref f) => Some(capture::Y4mWriter::create(f, ppu::WIDTH, ppu::HEIGHT, rate_num, rate_den)?),
+        None => None,
+    };
+    let mut audio = match options.audio {
+        Some(ref f) => Some(capture::WavWriter::create(f)?),
+        None => None,
+    };
+    let mut pixels = vec![0u32; ppu::WIDTH * ppu::HEIGHT];
+    let mut samples = Vec::new();
+    // what the controllers hold; once a movie runs out, its last buttons
+    let mut held = movie::FrameInput::default();
+    for frame in 0..frames {
+        if let Some(m) = movie {
+            m.apply(cpu, frame as usize);
+            held = m.frame(frame as usize).unwrap_or(movie::FrameInput { commands: 0, ..held });
+        }
+        if let Some(ref mut r) = recording {
+            r.record(held);
+        }
+        cpu.run_frame();
+        palette.convert(cpu.framebuffer(), &mut pixels);
+        if let Some(ref mut v) = video {
+            v.write_frame(&pixels)?;
+        }
+        cpu.take_audio(&mut samples);
+        if let Some(ref mut a) = audio {
+            a.write_samples(&samples)?;
+        }
+        samples.clear();
+        if let Some((n, ref f)) = options.screenshot {
+            if n == frame + 1 {
+                capture::write_png(f, ppu::WIDTH, ppu::HEIGHT, &pixels)?;
+            }
+        }
+    }
+    if let Some(v) = video {
+        v.finish()?;
+    }
+    if let Some(a) = audio {
+        a.finish()?;
+    }
+    println!("Ran {} frames.", frames);
+    Ok(())
+}
+
+#[cfg(feature = "frontend")]
+fn run_windowed(cpu: &mut cpu::CPU, palette: &palette::Palette, recording: Option<&mut movie::Movie>) {
+    frontend::run(cpu, palette, recording);
+}
+
+#[cfg(not(feature = "frontend"))]
+fn run_windowed(_cpu: &mut cpu::CPU, _palette: &palette::Palette, _recording: Option<&mut movie::Movie>) {
+    unreachable!();
+}
+
+fn load_palette<P: AsRef<Path>>(path: P) -> palette::Palette {
+    let mut data = Vec::new();
+    File::open(path).unwrap().read_to_end(&mut data).expect("problem reading palette.");
+    match palette::Palette::from_pal(&data) {
+        Ok(p) => p,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+// Loads a movie and puts the machine at its start point.
+fn start_movie<P: AsRef<Path>>(cpu: &mut cpu::CPU, path: P) -> movie::Movie {
+    let mut text = String::new();
+    File::open(path).unwrap().read_to_string(&mut text).expect("problem reading movie.");
+    let movie = match movie::Movie::from_fm2(&text) {
+        Ok(m) => m,
+        Err(e) => panic!("{}", e),
+    };
+    if movie.pal {
+        cpu.set_region(region::Region::Pal);
+    }
+    if !movie.matches_rom(cpu) {
+        println!("Warning: movie was recorded with a different ROM ({}).", movie.rom_filename);
+    }
+    if let Err(e) = movie.rewind(cpu) {
+        panic!("{}", e);
+    }
+    movie
+}
+
+// A new movie taking the input from here on: from power-on if the machine
+// hasn't run a frame yet, otherwise from a save state of where it is now.
+fn start_recording(cpu: &cpu::CPU, rom_filename: &str) -> movie::Movie {
+    let (path, entry) = split_archive_path(rom_filename);
+    let name = Path::new(entry.unwrap_or(path)).file_stem().unwrap_or_default().to_string_lossy();
+    movie::Movie::new(cpu, &name, cpu.frame() == 0)
+}
+
+fn save_recording(path: &str, movie: &movie::Movie) {
+    match fs::write(path, movie.to_fm2()) {
+        Ok(()) => println!("Recorded {} frames to {}.", movie.len(), path),
+        Err(e) => panic!("problem writing movie {}: {}", path, e),
+    }
+}
+
+// Battery RAM and disk writes go to the save file when the game is closed.
+fn flush_save(cpu: &mut cpu::CPU) {
+    if let Err(e) = cpu.flush_save() {
+        println!("Warning: couldn't write the save file: {}", e);
+    }
+}
+
+fn load_cpu(rom_filename: &str, patches: &[String], fds_bios: Option<&str>) -> cpu::CPU {
+    let rom_data = read_rom(rom_filename, patches);
+    let mut rom = match rom::Cartridge::load(rom_data) {
+        Ok(rom) => rom,
+        Err(e) => panic!("{}", e),
+    };
+    if let Some(entry) = rom.apply_database() {
+        println!("ROM database: {}", entry.name);
+    }
+    // saves are kept next to the ROM, or next to its archive
+    let path = split_archive_path(rom_filename).0;
+    if !rom.is_disk() {
+        return match mapper::new(rom, Some(Path::new(path).with_extension("sav"))) {
+            Ok(mapper) => cpu::CPU::new(mapper),
+            Err(e) => panic!("{}", e),
+        };
+    }
+    let bios = read_fds_bios(path, fds_bios);
+    let save = Path::new(path).with_extension("fdssav");
+    if save.is_file() {
+        println!("Loading disk writes from {}.", save.display());
+    }
+    match fds::Fds::new(rom, bios, Some(save)) {
+        Ok(fds) => cpu::CPU::new(Box::new(fds)),
+        Err(e) => panic!("{}", e),
+    }
+}
+
+// Runs one test ROM and prints a PASS/FAIL line for it.
+fn run_test(cpu: &mut cpu::CPU, name: &str, frame_hash: Option<(u64, u32)>) -> bool {
+    cpu.set_trace(false);
+    let result = match frame_hash {
+        Some((frames, hash)) => testrom::run_frame_hash(cpu, frames, hash),
+        None => testrom::run_status(cpu, testrom::DEFAULT_TIMEOUT_FRAMES),
+    };
+    println!("{} {}: {}", if result.passed() { "PASS" } else { "FAIL" }, name, result);
+    result.passed()
+}
+
+// Runs every ROM in a suite file; paths are relative to the file.
+fn run_test_suite(suite: &str) -> bool {
+    let mut text = String::new();
+    File::open(suite).unwrap().read_to_string(&mut text).expect("problem reading test suite.");
+    let entries = match testrom::parse_suite(&text) {
+        Ok(e) => e,
+        Err(e) => panic!("{}: {}", suite, e),
+    };
+    let base = Path::new(suite).parent().unwrap_or_else(|| Path::new(""));
+    let (mut failed, mut skipped) = (0, 0);
+    for entry in &entries {
+        let path = base.join(&entry.rom);
+        if !path.is_file() {
+            println!("SKIP {}: not found", entry.rom);
+            skipped += 1;
+            continue;
+        }
+        let passed = match load_test_rom(&path) {
+            Ok(mut cpu) => run_test(&mut cpu, &entry.rom, entry.frame_hash),
+            Err(e) => {
+                println!("FAIL {}: {}", entry.rom, e);
+                false
+            }
+        };
+        if !passed {
+            failed += 1;
+        }
+    }
+    let run = entries.len() - skipped;
+    if skipped == 0 {
+        println!("{} of {} tests passed.", run - failed, run);
+    }
+    else {
+        println!("{} of {} tests passed, {} skipped.", run - failed, run, skipped);
+    }
+    failed == 0
+}
+
+// A suite's ROM, without a save file. Problems with it fail that one test
+// rather than the whole run.
+fn load_test_rom(path: &Path) -> Result<cpu::CPU, String> {
+    let mut data = fs::read(path).map_err(|e| e.to_string())?;
+    if archive::is_archive(&data) {
+        data = archive::extract(&data, None).map_err(|e| e.to_string())?;
+    }
+    let mut rom = rom::Cartridge::load(data).map_err(|e| e.to_string())?;
+    rom.apply_database();
+    if rom.is_disk() {
+        return Err("disk images can't be run as test ROMs".to_string());
+    }
+    mapper::new(rom, None).map(cpu::CPU::new).map_err(|e| e.to_string())
+}
+
+// Prints what's in an NSF or NSFe file, and renders one of its songs to a
+// WAV file when given --out.
+fn run_nsf(args: &[String]) -> bool {
+    let (mut path, mut track, mut seconds, mut out) = (None, None, 60.0, None);
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        match (args[i].as_str(), value) {
+            ("--track", Some(v)) => match v.parse::<u8>() {
+                Ok(t) => { track = Some(t); i += 1; }
+                Err(_) => {
+                    usage();
+                    return false;
+                }
+            },
+            ("--seconds", Some(v)) => { seconds = v.parse::<f64>().unwrap_or(-1.0); i += 1; }
+            ("--out", Some(v)) => { out = Some(v.clone()); i += 1; }
+            (a, _) if path.is_none() && !a.starts_with("--") => path = Some(a.to_string()),
+            _ => {
+                usage();
+                return false;
+            }
+        }
+        i += 1;
+    }
+    let path = match path {
+        Some(p) if seconds >= 0.0 => p,
+        _ => {
+            usage();
+            return false;
+        }
+    };
+    let data = fs::read(&path).expect("problem reading NSF file.");
+    let nsf = match nsf::NsfFile::load(&data) {
+        Ok(n) => n,
+        Err(e) => panic!("{}: {}", path, e),
+    };
+    let credits: Vec<&str> = [&nsf.artist, &nsf.copyright].iter()
+        .map(|s| s.as_str()).filter(|s| !s.is_empty()).collect();
+    println!("{}", nsf.title);
+    println!("{}", credits.join(", "));
+    println!("{} songs, {}", nsf.songs, nsf.region);
+    if nsf.chips != 0 {
+        println!("Expansion sound: {}", nsf.chip_names().join(", "));
+    }
+    if nsf.unsupported_chips() != 0 {
+        println!("Warning: some of the expansion sound isn't emulated and will be missing.");
+    }
+    let track = track.unwrap_or(nsf.first_song);
+    if track == 0 || track > nsf.songs {
+        println!("There is no track {}.", track);
+        return false;
+    }
+    let out = match out {
+        Some(o) => o,
+        None => return true,
+    };
+    if let Some(name) = nsf.track_name(track) {
+        println!("Track {}: {}", track, name);
+    }
+
+    let mut player = nsf::Player::new(&nsf);
+    player.start(track);
+    let mut wav = capture::WavWriter::create(&out).expect("problem creating WAV file.");
+    let total = (seconds * player.clock_rate() as f64) as u64;
+    // a frame at a time, so the sample buffer never fills up
+    let chunk = player.clock_rate() / 60;
+    let mut samples = Vec::new();
+    let mut done = 0;
+    while done < total {
+        let cycles = chunk.min(total - done);
+        player.run(cycles);
+        done += cycles;
+        player.take_audio(&mut samples);
+        wav.write_samples(&samples).expect("problem writing WAV file.");
+        samples.clear();
+    }
+    wav.finish().expect("problem writing WAV file.");
+    println!("Wrote {} seconds of track {} to {}.", seconds, track, out);
+    true
+}
+
+// Runs one SingleStepTests file, or every .json file in a directory.
+fn run_single_step(path: &str, variant: cpu::Variant) -> bool {
+    let path = Path::new(path);
+    let mut files = Vec::new();
+    if path.is_dir() {
+        for entry in fs::read_dir(path).expect("problem reading test directory.") {
+            let file = entry.expect("problem reading test directory.").path();
+            if file.extension().is_some_and(|e| e == "json") {
+                files.push(file);
+            }
+        }
+        files.sort();
+    }
+    else {
+        files.push(path.to_path_buf());
+    }
+    let (mut total, mut failed) = (0, 0);
+    for file in &files {
+        let name = file.file_name().unwrap().to_string_lossy();
+        let report = match singlestep::run_file(file, variant) {
+            Ok(r) => r,
+            Err(e) => {
+                // counted as one failed test, so it shows in the totals
+                println!("FAIL {}: {}", name, e);
+                total += 1;
+                failed += 1;
+                continue;
+            }
+        };
+        total += report.total;
+        failed += report.failures.len();
+        if report.failures.is_empty() {
+            println!("PASS {}: {} tests", name, report.total);
+            continue;
+        }
+        println!("FAIL {}: {} of {} tests failed", name, report.failures.len(), report.total);
+        for (test, problems) in report.failures.iter().take(3) {
+            println!("  {}:", test);
+            for p in problems {
+                println!("    {}", p);
+            }
+        }
+    }
+    println!("{} of {} tests passed.", total - failed, total);
+    failed == 0
 }
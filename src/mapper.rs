@@ -1,39 +1,250 @@
+use std::io;
+use std::path::PathBuf;
+
+use fme7;
+use memory::RamInit;
+use mmc5;
+use n163;
 use rom;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+use vrc6;
+
+// What the PPU is fetching, for boards that bank CHR differently for
+// sprites and background or count the fetches. Everything fetched during
+// the sprite phase of a line (dots 257-320), including its dummy
+// nametable reads, is a sprite fetch.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PpuFetch {
+    Nametable,
+    Attribute,
+    Background,
+    Sprite,
+    // $2007
+    Cpu,
+}
+
+// Where a PPU access to $0000-$3EFF ends up.
+pub enum PpuAccess {
+    // The cartridge answered (or took the write) itself.
+    Cartridge(u8),
+    // The console's 2K of nametable RAM, at this offset. Four-screen
+    // boards' extra 2K follows it.
+    Ciram(u16),
+}
+
+// Where a nametable address lands in nametable RAM, for boards wired to
+// one fixed arrangement.
+pub fn ciram_offset(address: u16, mirroring: rom::Mirroring) -> u16 {
+    match mirroring {
+        rom::Mirroring::Horizontal => (address >> 1) & 0x0400 | address & 0x03FF,
+        rom::Mirroring::Vertical => address & 0x07FF,
+        rom::Mirroring::FourScreen => address & 0x0FFF,
+        rom::Mirroring::SingleScreenLower => switched_ciram_offset(address, 2),
+        rom::Mirroring::SingleScreenUpper => switched_ciram_offset(address, 3),
+    }
+}
+
+// For boards that pick the mirroring with a register: vertical, horizontal,
+// or all four nametables on the first or the second page.
+pub fn switched_ciram_offset(address: u16, mode: u8) -> u16 {
+    match mode & 0x03 {
+        0 => address & 0x07FF,
+        1 => (address >> 1) & 0x0400 | address & 0x03FF,
+        2 => address & 0x03FF,
+        _ => 0x0400 | address & 0x03FF,
+    }
+}
+
+// Byte `index` of CHR-ROM or CHR-RAM, wrapping around its size.
+pub fn chr_byte(chr: &[u8], index: usize) -> u8 {
+    if chr.is_empty() { 0 } else { chr[index % chr.len()] }
+}
+
+// An iNES cartridge for the boards' tests, with every 8K of PRG-ROM and
+// 1K of CHR-ROM filled with its bank number.
+#[cfg(test)]
+pub fn test_rom(mapper: u16, prg_8k: usize, chr_1k: usize) -> rom::Cartridge {
+    let mut image = b"NES\x1A".to_vec();
+    image.push((prg_8k / 2) as u8);
+    image.push((chr_1k / 8) as u8);
+    image.push((mapper as u8 & 0x0F) << 4);
+    image.push(mapper as u8 & 0xF0);
+    image.resize(16, 0);
+    for bank in 0..prg_8k {
+        image.extend_from_slice(&[bank as u8; 0x2000]);
+    }
+    for bank in 0..chr_1k {
+        image.extend_from_slice(&[bank as u8; 0x400]);
+    }
+    rom::Cartridge::load(image).unwrap()
+}
 
-pub struct Mapper {
+// The cartridge side of the memory map, $4020-$FFFF. Reads return None
+// where the cartridge doesn't drive the data bus.
+pub trait Mapper: Savable {
+    fn read(&mut self, address: u16) -> Option<u8> {
+        self.peek(address)
+    }
+    // What read() would return, without the side effects mappers that watch
+    // reads would have.
+    fn peek(&self, address: u16) -> Option<u8>;
+    fn write(&mut self, address: u16, value: u8);
+    // PRG-RAM and CHR-RAM come up like the console's own RAM.
+    fn power_on(&mut self, init: RamInit);
+    fn rom(&self) -> &rom::Cartridge;
+    // Called once per CPU cycle, before that cycle's access.
+    fn tick(&mut self) {}
+    // The level of the cartridge's IRQ line, true when asserted.
+    fn irq(&self) -> bool {
+        false
+    }
+    // Expansion audio output for the current cycle, on the scale of the
+    // APU's output() (see apu.rs for the levels).
+    fn audio(&self) -> f32 {
+        0.0
+    }
+    // Ejects the disk and puts in the next side a moment later, for disk
+    // systems. Returns the name of that side. Only the frontend does this.
+    #[cfg(feature = "frontend")]
+    fn switch_disk_side(&mut self) -> Option<String> {
+        None
+    }
+    // Writes out whatever the board keeps in its save file, if it changed.
+    fn flush_save(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    // CPU writes to the PPU's registers, $2000-$2007, which some boards
+    // watch.
+    fn ppu_register_write(&mut self, _address: u16, _value: u8) {}
+    // The PPU's accesses to $0000-$3EFF. By default, CHR-ROM and the
+    // header's mirroring; boards with CHR-RAM or banking override them.
+    fn ppu_read(&mut self, address: u16, _fetch: PpuFetch) -> PpuAccess {
+        if address >= 0x2000 {
+            PpuAccess::Ciram(ciram_offset(address, self.rom().mirroring))
+        }
+        else {
+            PpuAccess::Cartridge(chr_byte(self.rom().chr_rom(), address as usize))
+        }
+    }
+    // CHR-ROM ignores writes.
+    fn ppu_write(&mut self, address: u16, value: u8) -> PpuAccess {
+        if address >= 0x2000 {
+            PpuAccess::Ciram(ciram_offset(address, self.rom().mirroring))
+        }
+        else {
+            PpuAccess::Cartridge(value)
+        }
+    }
+}
+
+// Picks the implementation for the cartridge's board. Boards with
+// battery-backed RAM keep it in `save_path`.
+pub fn new(rom: rom::Cartridge, save_path: Option<PathBuf>) -> Result<Box<dyn Mapper>, rom::RomError> {
+    Ok(match rom.mapper {
+        0 => {
+            let mut nrom = Nrom::new(rom);
+            nrom.load();
+            Box::new(nrom)
+        }
+        5 => Box::new(mmc5::Mmc5::new(rom)),
+        19 => Box::new(n163::N163::new(rom, save_path).map_err(rom::RomError::Save)?),
+        24 | 26 => Box::new(vrc6::Vrc6::new(rom)),
+        69 => Box::new(fme7::Fme7::new(rom)),
+        n => return Err(rom::RomError::UnsupportedMapper(n)),
+    })
+}
+
+pub struct Nrom {
     // TODO: investigate the posibility of using
     // slices to reference arrays stored in the rom.prg_rom vector.
     // Are there any pros and cons to this approach?
     upper_bank: [u8; 0x4000],
     lower_bank: [u8; 0x4000],
-    rom: rom::INesFile,
+    // empty when the cartridge has CHR-ROM
+    chr_ram: Vec<u8>,
+    prg_ram: [u8; 0x2000],
+    rom: rom::Cartridge,
 }
 
-impl Mapper {
-    pub fn new(rom: rom::INesFile) -> Mapper {
+impl Nrom {
+    pub fn new(rom: rom::Cartridge) -> Nrom {
         let up = [0u8; 0x4000];
         let lo = [0u8; 0x4000];
-        Mapper { upper_bank: up, lower_bank: lo, rom: rom }
+        let chr_ram = vec![0; rom.chr_ram_size() as usize];
+        Nrom { upper_bank: up, lower_bank: lo, chr_ram, prg_ram: [0; 0x2000], rom }
     }
-    pub fn read(&self, address: u16) -> u8 {
-        if address < 0xC000 {
-            self.lower_bank[(address - 0x8000) as usize]
+    pub fn load(&mut self) {
+        if self.rom.prg_rom_cnt == 1 {
+            // load single bank to upper and lower
+            self.upper_bank = self.rom.prg_rom[0];
+            self.lower_bank = self.rom.prg_rom[0];
         }
         else {
-            self.upper_bank[(address - 0xC000) as usize]
+            self.lower_bank = self.rom.prg_rom[0];
+            self.upper_bank = self.rom.prg_rom[1];
         }
     }
-    pub fn load(&mut self) {
-        if self.rom.mapper == 0 {
-            if self.rom.prg_rom_cnt == 1 {
-                // load single bank to upper and lower
-                self.upper_bank = self.rom.prg_rom[0];
-                self.lower_bank = self.rom.prg_rom[0];
-            }
-            else {
-                self.lower_bank = self.rom.prg_rom[0];
-                self.upper_bank = self.rom.prg_rom[1];
-            }
+}
+
+impl Mapper for Nrom {
+    // $6000-$FFFF
+    fn peek(&self, address: u16) -> Option<u8> {
+        if address < 0x6000 {
+            None
+        }
+        else if address < 0x8000 {
+            Some(self.prg_ram[(address - 0x6000) as usize])
+        }
+        else if address < 0xC000 {
+            Some(self.lower_bank[(address - 0x8000) as usize])
+        }
+        else {
+            Some(self.upper_bank[(address - 0xC000) as usize])
+        }
+    }
+    fn write(&mut self, address: u16, value: u8) {
+        if (0x6000..0x8000).contains(&address) {
+            self.prg_ram[(address - 0x6000) as usize] = value;
         }
     }
+    fn power_on(&mut self, init: RamInit) {
+        init.fill(&mut self.prg_ram, 1);
+        init.fill(&mut self.chr_ram, 2);
+    }
+    fn rom(&self) -> &rom::Cartridge {
+        &self.rom
+    }
+    fn ppu_read(&mut self, address: u16, _fetch: PpuFetch) -> PpuAccess {
+        if address >= 0x2000 {
+            PpuAccess::Ciram(ciram_offset(address, self.rom.mirroring))
+        }
+        else if self.chr_ram.is_empty() {
+            PpuAccess::Cartridge(chr_byte(self.rom.chr_rom(), address as usize))
+        }
+        else {
+            PpuAccess::Cartridge(chr_byte(&self.chr_ram, address as usize))
+        }
+    }
+    fn ppu_write(&mut self, address: u16, value: u8) -> PpuAccess {
+        if address >= 0x2000 {
+            return PpuAccess::Ciram(ciram_offset(address, self.rom.mirroring));
+        }
+        if !self.chr_ram.is_empty() {
+            let index = address as usize % self.chr_ram.len();
+            self.chr_ram[index] = value;
+        }
+        PpuAccess::Cartridge(value)
+    }
+}
+
+impl Savable for Nrom {
+    // NROM has no bank registers: the bank layout is fixed by load().
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        w.write_bytes(&self.chr_ram);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.prg_ram)?;
+        r.read_bytes(&mut self.chr_ram)
+    }
 }
@@ -1,4 +1,113 @@
 use super::mapper;
+use apu;
+use audio;
+use bus::Bus;
+use controller;
+use ppu;
+use region::Region;
+use rom;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+use std::fmt;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// What RAM holds at power-on. Real RAM comes up with whatever its cells
+// settle on, which differs between consoles and even between power cycles,
+// and some games read it before writing it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum RamInit {
+    #[default]
+    Zeros,
+    Ones,
+    // $00 $00 $00 $00 $FF $FF $FF $FF repeated, what FCEUX and many
+    // consoles give
+    Pattern,
+    Random(u64),
+}
+
+impl RamInit {
+    // zeros, ff, pattern, random or random:<seed>. Plain random picks a
+    // seed from the clock.
+    pub fn from_name(name: &str) -> Option<RamInit> {
+        match name {
+            "zeros" | "00" => Some(RamInit::Zeros),
+            "ff" => Some(RamInit::Ones),
+            "pattern" => Some(RamInit::Pattern),
+            "random" => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                Some(RamInit::Random(now.as_secs() ^ (now.subsec_nanos() as u64) << 32))
+            }
+            _ if name.starts_with("random:") => name["random:".len()..].parse().ok().map(RamInit::Random),
+            _ => None,
+        }
+    }
+    // Fills one RAM chip. Each chip gets its own number so that random
+    // contents don't repeat between them.
+    pub fn fill(self, ram: &mut [u8], chip: u64) {
+        match self {
+            RamInit::Zeros => ram.iter_mut().for_each(|b| *b = 0),
+            RamInit::Ones => ram.iter_mut().for_each(|b| *b = 0xFF),
+            RamInit::Pattern => {
+                for (i, b) in ram.iter_mut().enumerate() {
+                    *b = if i & 4 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+            RamInit::Random(seed) => {
+                let mut state = seed ^ chip.wrapping_mul(0xD6E8_FEB8_6659_FD93);
+                for chunk in ram.chunks_mut(8) {
+                    let bytes = splitmix64(&mut state).to_le_bytes();
+                    chunk.copy_from_slice(&bytes[..chunk.len()]);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for RamInit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RamInit::Zeros => write!(f, "zeros"),
+            RamInit::Ones => write!(f, "ff"),
+            RamInit::Pattern => write!(f, "pattern"),
+            RamInit::Random(seed) => write!(f, "random:{}", seed),
+        }
+    }
+}
+
+impl Savable for RamInit {
+    fn save(&self, w: &mut StateWriter) {
+        let (kind, seed) = match *self {
+            RamInit::Zeros => (0, 0),
+            RamInit::Ones => (1, 0),
+            RamInit::Pattern => (2, 0),
+            RamInit::Random(seed) => (3, seed),
+        };
+        w.write_u8(kind);
+        w.write_u64(seed);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let kind = r.read_u8()?;
+        let seed = r.read_u64()?;
+        *self = match kind {
+            0 => RamInit::Zeros,
+            1 => RamInit::Ones,
+            2 => RamInit::Pattern,
+            3 => RamInit::Random(seed),
+            _ => return Err(StateError::Invalid("RAM init")),
+        };
+        Ok(())
+    }
+}
+
+// Sebastiano Vigna's SplitMix64, which is plenty for filling RAM.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
 
 struct RAM {
     ram: [u8; 0x800]
@@ -15,49 +124,361 @@ impl RAM {
 
 pub struct MemMap {
     ram: RAM,
-    //ppu: PPU,
-    //apu: APU,
-    //controllers: Controllers,
-    mapper: mapper::Mapper,
+    ppu: ppu::Ppu,
+    apu: apu::Apu,
+    audio: audio::SampleBuffer,
+    controllers: [controller::Controller; 2],
+    mapper: Box<dyn mapper::Mapper>,
+    // The last value on the data bus. Nothing drives the bus when an
+    // unmapped or write-only address is read, so the value lingers and the
+    // CPU reads it back.
+    open_bus: u8,
+    ram_init: RamInit,
+    region: Region,
+    // The page a $4014 write asked to copy to OAM, until the CPU halts for
+    // it, and the cycles the DMC's fetches have taken from the CPU.
+    oam_dma: Option<u8>,
+    dmc_stall: u32,
 }
 
 impl MemMap {
-    pub fn new(mapper: mapper::Mapper) -> MemMap {
+    pub fn new(mapper: Box<dyn mapper::Mapper>) -> MemMap {
+        let region = mapper.rom().region().unwrap_or_default();
         MemMap {
             ram: RAM { ram: [0; 0x800] },
+            ppu: ppu::Ppu::new(region),
+            apu: apu::Apu::new(region),
+            audio: audio::SampleBuffer::new(region.clock_rate()),
+            controllers: Default::default(),
             mapper: mapper,
+            open_bus: 0,
+            ram_init: RamInit::default(),
+            region,
+            oam_dma: None,
+            dmc_stall: 0,
         }
     }
-    pub fn read(&self, address: u16) -> u8 {
+    // Whether the PPU has reached vblank since the last call.
+    pub fn take_frame(&mut self) -> bool {
+        self.ppu.take_frame()
+    }
+    pub fn ppu(&self) -> &ppu::Ppu {
+        &self.ppu
+    }
+    pub fn take_audio(&mut self, out: &mut Vec<f32>) {
+        self.audio.take(out);
+    }
+    pub fn power_on(&mut self) {
+        self.ram_init.fill(&mut self.ram.ram, 0);
+        self.mapper.power_on(self.ram_init);
+        self.ppu.power_on();
+        self.apu.power_on();
+        self.oam_dma = None;
+        self.dmc_stall = 0;
+        self.controllers = Default::default();
+        self.open_bus = 0;
+    }
+    // Takes effect at the next power_on().
+    pub fn set_ram_init(&mut self, init: RamInit) {
+        self.ram_init = init;
+    }
+    pub fn ram_init(&self) -> RamInit {
+        self.ram_init
+    }
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+        self.audio.set_clock_rate(region.clock_rate());
+    }
+    pub fn region(&self) -> Region {
+        self.region
+    }
+    pub fn rom(&self) -> &rom::Cartridge {
+        self.mapper.rom()
+    }
+    #[cfg(feature = "frontend")]
+    pub fn switch_disk_side(&mut self) -> Option<String> {
+        self.mapper.switch_disk_side()
+    }
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        self.mapper.flush_save()
+    }
+    pub fn set_input(&mut self, port: usize, buttons: u8) {
+        self.controllers[port].set_buttons(buttons);
+    }
+    // $4015 is read inside the 2A03 and never reaches the data bus, so bit 5,
+    // which the APU doesn't drive, is whatever was on the bus before.
+    fn apu_status(&self) -> u8 {
+        self.apu.status() | self.open_bus & 0x20
+    }
+}
+
+impl Bus for MemMap {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = if address < 0x2000 {
+            self.ram.read(address)
+        }
+        else if address == 0x4015 {
+            return self.apu.read_status() | self.open_bus & 0x20;
+        }
+        else if address == 0x4016 || address == 0x4017 {
+            // the controller port only drives the low bits
+            (self.open_bus & 0xE0) | self.controllers[(address - 0x4016) as usize].read()
+        }
+        else if address < 0x4000 {
+            self.ppu.read(address, &mut *self.mapper)
+        }
+        else if address >= 0x4020 {
+            // the cartridge leaves the bus floating where nothing is mapped
+            self.mapper.read(address).unwrap_or(self.open_bus)
+        }
+        else {
+            // the rest of the APU registers are write-only
+            self.open_bus
+        };
+        self.open_bus = value;
+        value
+    }
+    fn write(&mut self, address: u16, value: u8) {
+        self.open_bus = value;
+        if address < 0x2000 {
+            self.ram.write(address, value);
+        }
+        else if address < 0x4000 {
+            self.ppu.write(address, value, &mut *self.mapper);
+            self.mapper.ppu_register_write(0x2000 | (address & 0x07), value);
+        }
+        else if address == 0x4014 {
+            self.oam_dma = Some(value);
+        }
+        else if address == 0x4016 {
+            for c in &mut self.controllers {
+                c.write(value);
+            }
+        }
+        else if address < 0x4018 {
+            self.apu.write(address, value);
+        }
+        else if address >= 0x4020 {
+            self.mapper.write(address, value);
+        }
+    }
+    fn peek(&self, address: u16) -> u8 {
         if address < 0x2000 {
             self.ram.read(address)
         }
-        else if address >= 0x8000 {
-            self.mapper.read(address)
+        else if address == 0x4015 {
+            self.apu_status()
+        }
+        else if address == 0x4016 || address == 0x4017 {
+            (self.open_bus & 0xE0) | self.controllers[(address - 0x4016) as usize].peek()
+        }
+        else if address < 0x4000 {
+            self.ppu.peek(address)
+        }
+        else if address >= 0x4020 {
+            self.mapper.peek(address).unwrap_or(self.open_bus)
         }
         else {
-            0
+            self.open_bus
+        }
+    }
+    fn tick(&mut self) {
+        self.ppu.tick(&mut *self.mapper);
+        self.mapper.tick();
+        self.apu.tick();
+        if let Some(address) = self.apu.dmc_fetch() {
+            // the 2A03 halts the CPU for the read, usually four cycles
+            let value = self.read(address);
+            self.apu.dmc_fill(value);
+            self.dmc_stall += 4;
         }
+        let sample = self.apu.output() + self.mapper.audio();
+        self.audio.push(sample);
+    }
+    fn irq(&self) -> bool {
+        self.mapper.irq() || self.apu.irq()
+    }
+    fn nmi(&self) -> bool {
+        self.ppu.nmi()
     }
+    fn take_oam_dma(&mut self) -> Option<u8> {
+        self.oam_dma.take()
+    }
+    fn take_stall(&mut self) -> u32 {
+        let cycles = self.dmc_stall;
+        self.dmc_stall = 0;
+        cycles
+    }
+}
 
-    pub fn write(&mut self, address: u16, value: u8) {
-        if address < 0x2000 {
-            self.ram.write(address, value);
+impl Savable for MemMap {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.ram.ram);
+        self.ppu.save(w);
+        self.apu.save(w);
+        for c in &self.controllers {
+            c.save(w);
         }
+        self.mapper.save(w);
+        w.write_u8(self.open_bus);
+        self.ram_init.save(w);
+        w.write_bool(self.oam_dma.is_some());
+        w.write_u8(self.oam_dma.unwrap_or(0));
+        w.write_u16(self.dmc_stall as u16);
     }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.ram.ram)?;
+        self.ppu.load(r)?;
+        self.apu.load(r)?;
+        for c in &mut self.controllers {
+            c.load(r)?;
+        }
+        self.mapper.load(r)?;
+        self.open_bus = r.read_u8()?;
+        self.ram_init.load(r)?;
+        let dma = r.read_bool()?;
+        let page = r.read_u8()?;
+        self.oam_dma = if dma { Some(page) } else { None };
+        self.dmc_stall = r.read_u16()? as u32;
+        Ok(())
+    }
+}
 
-    pub fn readw(&self, address: u16) -> u16 {
-         ((self.read(address + 1) as u16) << 8) | (self.read(address) as u16)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> MemMap {
+        let mut image = b"NES\x1A\x01\x01".to_vec();
+        image.resize(16 + 0x4000 + 0x2000, 0);
+        let mut memory = MemMap::new(mapper::new(rom::Cartridge::load(image).unwrap(), None).unwrap());
+        memory.power_on();
+        memory
     }
-    pub fn readw_zp(&self, address: u16) -> u16 {
-        if address > 0xFF {
-            panic!("readw_zp address should be less than or equal to 0x00FF");
+
+    #[test]
+    fn peek_has_no_side_effects() {
+        let mut memory = memory();
+        while memory.peek(0x2002) & 0x80 == 0 {
+            memory.tick();
         }
-        if address == 0xFF {
-            ((self.read(0x0000) as u16) << 8) | (self.read(0x00FF) as u16)
+        assert_eq!(memory.peek(0x2002) & 0x80, 0x80);
+        assert_eq!(memory.read(0x2002) & 0x80, 0x80);
+        assert_eq!(memory.peek(0x2002) & 0x80, 0);
+
+        // the frame IRQ
+        memory.write(0x4017, 0x00);
+        while memory.peek(0x4015) & 0x40 == 0 {
+            memory.tick();
         }
-        else {
-            ((self.read(address + 1) as u16) << 8) | (self.read(address) as u16)
+        assert_eq!(memory.peek(0x4015) & 0x40, 0x40);
+        assert_eq!(memory.read(0x4015) & 0x40, 0x40);
+        assert_eq!(memory.peek(0x4015) & 0x40, 0);
+
+        memory.set_input(0, controller::BUTTON_B);
+        memory.write(0x4016, 1);
+        memory.write(0x4016, 0);
+        assert_eq!(memory.peek(0x4016) & 0x01, 0);
+        assert_eq!(memory.peek(0x4016) & 0x01, 0);
+        assert_eq!(memory.read(0x4016) & 0x01, 0);
+        assert_eq!(memory.peek(0x4016) & 0x01, 1);
+        assert_eq!(memory.read(0x4016) & 0x01, 1);
+    }
+
+    #[test]
+    fn open_bus() {
+        let mut memory = memory();
+        memory.write(0x0000, 0x5A);
+        memory.write(0x0001, 0xE3);
+        assert_eq!(memory.read(0x0000), 0x5A);
+        // nothing mapped, and write-only registers
+        assert_eq!(memory.read(0x5000), 0x5A);
+        assert_eq!(memory.peek(0x5000), 0x5A);
+        assert_eq!(memory.read(0x4000), 0x5A);
+        assert_eq!(memory.read(0x0001), 0xE3);
+        assert_eq!(memory.read(0x4016), 0xE0);
+        // $4015 is inside the CPU and doesn't change the bus
+        assert_eq!(memory.read(0x4015), 0x20);
+        assert_eq!(memory.read(0x4017), 0xE0);
+        // writes drive the bus too
+        memory.write(0x4000, 0x11);
+        assert_eq!(memory.read(0x5000), 0x11);
+    }
+
+    #[test]
+    fn ram_init_names() {
+        assert_eq!(RamInit::from_name("zeros"), Some(RamInit::Zeros));
+        assert_eq!(RamInit::from_name("00"), Some(RamInit::Zeros));
+        assert_eq!(RamInit::from_name("ff"), Some(RamInit::Ones));
+        assert_eq!(RamInit::from_name("pattern"), Some(RamInit::Pattern));
+        assert_eq!(RamInit::from_name("random:42"), Some(RamInit::Random(42)));
+        assert!(matches!(RamInit::from_name("random"), Some(RamInit::Random(_))));
+        assert_eq!(RamInit::from_name("random:x"), None);
+        assert_eq!(RamInit::from_name("55"), None);
+        for &init in &[RamInit::Zeros, RamInit::Ones, RamInit::Pattern, RamInit::Random(7)] {
+            assert_eq!(RamInit::from_name(&init.to_string()), Some(init));
+        }
+    }
+
+    #[test]
+    fn ram_init_fills() {
+        let mut ram = [0x55; 12];
+        RamInit::Ones.fill(&mut ram, 0);
+        assert_eq!(ram, [0xFF; 12]);
+        RamInit::Zeros.fill(&mut ram, 0);
+        assert_eq!(ram, [0; 12]);
+        RamInit::Pattern.fill(&mut ram, 0);
+        assert_eq!(ram, [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0]);
+
+        let fill = |seed: u64, chip: u64| {
+            let mut ram = [0; 20];
+            RamInit::Random(seed).fill(&mut ram, chip);
+            ram
+        };
+        assert_eq!(fill(1, 0), fill(1, 0));
+        assert_ne!(fill(1, 0), fill(2, 0));
+        assert_ne!(fill(1, 0), fill(1, 1));
+    }
+
+    #[test]
+    fn power_on_fills_ram() {
+        let mut memory = memory();
+        memory.set_ram_init(RamInit::Ones);
+        assert_eq!(memory.peek(0x0000), 0x00);
+        memory.power_on();
+        assert_eq!(memory.peek(0x0000), 0xFF);
+        assert_eq!(memory.peek(0x07FF), 0xFF);
+        assert_eq!(memory.peek(0x6000), 0xFF);
+
+        memory.set_ram_init(RamInit::Random(3));
+        memory.power_on();
+        let first: Vec<u8> = (0..0x800).map(|a| memory.peek(a)).collect();
+        memory.power_on();
+        assert!((0..0x800).all(|a| memory.peek(a) == first[a as usize]));
+    }
+
+    #[test]
+    fn frame_lengths() {
+        // rendering is off, so NTSC frames don't skip a dot
+        for &(region, frames, cycles) in &[(Region::Ntsc, 3, 89342), (Region::Pal, 2, 66495), (Region::Dendy, 1, 35464)] {
+            let mut memory = memory();
+            memory.set_region(region);
+            while !memory.take_frame() {
+                memory.tick();
+            }
+            let mut count = 0;
+            for _ in 0..frames {
+                loop {
+                    memory.tick();
+                    count += 1;
+                    if memory.take_frame() {
+                        break;
+                    }
+                }
+            }
+            assert_eq!(count, cycles, "{}", region);
         }
     }
 }
@@ -0,0 +1,680 @@
+// Nintendo MMC5, mapper 5
+//
+//   $5000-$5015  sound, see mmc5_audio.rs
+//   $5100        PRG mode: one 32K bank, two 16K, 16K and two 8K, four 8K
+//   $5101        CHR mode: 8K, 4K, 2K or 1K banks
+//   $5102-$5103  PRG-RAM writes are allowed while these hold 2 and 1
+//   $5104        ExRAM mode: extra nametable, extended attributes, RAM, ROM
+//   $5105        nametable mapping, 2 bits per nametable: the console's
+//                first or second page, ExRAM, or the fill tile
+//   $5106-$5107  fill mode tile and attribute
+//   $5113        PRG-RAM bank at $6000
+//   $5114-$5117  PRG banks; bit 7 picks ROM over RAM, except at $E000
+//   $5120-$5127  CHR banks for sprites ("A")
+//   $5128-$512B  CHR banks for the background with 8x16 sprites ("B")
+//   $5130        upper CHR bank bits
+//   $5200-$5202  vertical split: control, scroll and CHR bank
+//   $5203        IRQ scanline
+//   $5204        IRQ enable; reads back the IRQ (7, acknowledging it) and
+//                whether the PPU is rendering (6)
+//   $5205-$5206  8x8 unsigned multiplier; reads back the 16-bit product
+//   $5C00-$5FFF  ExRAM
+//
+// Much of the board works on the PPU side: it tells sprite fetches from
+// background ones, finds the start of each scanline by watching for the
+// three reads of the same nametable address the PPU makes around dot
+// 337, and swaps in ExRAM, fill mode and split screen data as tiles are
+// fetched. All of that goes through ppu_read() and ppu_write(), so it
+// only happens while the PPU is rendering; outside of it, ExRAM in modes
+// 0 and 1 only takes zeros, as on the real board.
+
+use memory::RamInit;
+use mapper::{self, PpuAccess, PpuFetch};
+use mmc5_audio::Mmc5Audio;
+use rom;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+// CPU cycles without a PPU read before the board decides rendering
+// stopped.
+const IDLE_CYCLES: u8 = 3;
+
+pub struct Mmc5 {
+    rom: rom::Cartridge,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    exram: [u8; 0x400],
+
+    prg_mode: u8,
+    chr_mode: u8,
+    ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametables: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    // $5113-$5117
+    prg_banks: [u8; 5],
+    chr_a: [u16; 8],
+    chr_b: [u16; 4],
+    chr_upper: u8,
+    // whether $5128-$512B were written after $5120-$5127
+    last_set_b: bool,
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_scanline: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    scanline: u8,
+
+    multiplicand: u8,
+    multiplier: u8,
+
+    // snooped from $2000 and $2001
+    sprites_8x16: bool,
+    rendering: bool,
+
+    // fetch tracking
+    last_ppu_address: u16,
+    repeats: u8,
+    idle_cycles: u8,
+    // Nametable fetches since the scanline started; the first is tile 2.
+    tile: u8,
+    split_y: u8,
+    in_split: bool,
+    // the ExRAM byte of the tile being fetched, in extended attribute mode
+    exram_tile: u8,
+
+    audio: Mmc5Audio,
+}
+
+impl Mmc5 {
+    pub fn new(rom: rom::Cartridge) -> Mmc5 {
+        let prg_ram = vec![0; rom.prg_ram_size().max(0x2000) as usize];
+        let chr_ram = vec![0; rom.chr_ram_size() as usize];
+        Mmc5 {
+            rom,
+            prg_ram,
+            chr_ram,
+            exram: [0; 0x400],
+            prg_mode: 3,
+            chr_mode: 0,
+            ram_protect: [0; 2],
+            exram_mode: 0,
+            nametables: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_a: [0; 8],
+            chr_b: [0; 4],
+            chr_upper: 0,
+            last_set_b: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_scanline: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline: 0,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            sprites_8x16: false,
+            rendering: false,
+            last_ppu_address: 0,
+            repeats: 0,
+            idle_cycles: IDLE_CYCLES,
+            tile: 0,
+            split_y: 0,
+            in_split: false,
+            exram_tile: 0,
+            audio: Mmc5Audio::new(),
+        }
+    }
+    // The register ($5113 + n) picking the bank at `address` ($8000-$FFFF),
+    // and the 8K bank within what it selects.
+    fn prg_bank(&self, address: u16) -> (usize, usize) {
+        let slot = ((address - 0x8000) >> 13) as usize;
+        let (register, size) = match (self.prg_mode, slot) {
+            (0, _) => (4, 4),
+            (1, 0..=1) | (2, 0..=1) => (2, 2),
+            (1, _) => (4, 2),
+            (2, 2) => (3, 1),
+            (2, _) => (4, 1),
+            (_, slot) => (1 + slot, 1),
+        };
+        let bank = (self.prg_banks[register] & 0x7F) as usize & !(size - 1);
+        (register, bank + slot % size)
+    }
+    // Byte `offset` of 8K PRG bank `bank`, wrapping around the ROM.
+    fn prg(&self, bank: usize, offset: u16) -> u8 {
+        let pages = &self.rom.prg_rom;
+        let bank = bank % (pages.len() * 2);
+        pages[bank / 2][(bank % 2) * 0x2000 + offset as usize]
+    }
+    fn prg_ram_index(&self, bank: usize, offset: u16) -> usize {
+        ((bank & 0x07) * 0x2000 + offset as usize) % self.prg_ram.len()
+    }
+    fn prg_ram_writable(&self) -> bool {
+        self.ram_protect == [2, 1]
+    }
+    // $5114-$5116 select RAM with bit 7 clear; $5117 always selects ROM,
+    // wherever the mode puts its bank.
+    fn is_rom(&self, register: usize) -> bool {
+        register == 4 || self.prg_banks[register] & 0x80 != 0
+    }
+    fn chr_byte(&self, index: usize) -> u8 {
+        let chr = if self.rom.chr_rom().is_empty() { &self.chr_ram[..] } else { self.rom.chr_rom() };
+        mapper::chr_byte(chr, index)
+    }
+    fn chr_index(&self, address: u16, set_b: bool) -> usize {
+        let size = 0x2000 >> self.chr_mode;
+        let slot = address as usize / size;
+        let register = (slot + 1) * (8 >> self.chr_mode) - 1;
+        let bank = if set_b { self.chr_b[register & 3] } else { self.chr_a[register] };
+        bank as usize * size + address as usize % size
+    }
+    // Which set of CHR banks a fetch uses: with 8x16 sprites, B for the
+    // background and A for sprites, and the last one written outside
+    // rendering; with 8x8 sprites, always A.
+    fn uses_set_b(&self, fetch: PpuFetch) -> bool {
+        self.sprites_8x16 && match fetch {
+            PpuFetch::Sprite => false,
+            PpuFetch::Cpu => self.last_set_b,
+            _ => true,
+        }
+    }
+    fn nametable_read(&self, address: u16) -> PpuAccess {
+        let offset = address & 0x03FF;
+        match (self.nametables >> ((address >> 9) & 0x06)) & 0x03 {
+            0 => PpuAccess::Ciram(offset),
+            1 => PpuAccess::Ciram(0x400 | offset),
+            2 => PpuAccess::Cartridge(if self.exram_mode < 2 { self.exram[offset as usize] } else { 0 }),
+            _ if offset >= 0x3C0 => PpuAccess::Cartridge((self.fill_attribute & 0x03) * 0x55),
+            _ => PpuAccess::Cartridge(self.fill_tile),
+        }
+    }
+    // Three reads of the same address in a row mark the start of a line.
+    fn detect_scanline(&mut self, address: u16) {
+        if address == self.last_ppu_address {
+            self.repeats += 1;
+        }
+        else {
+            self.repeats = 0;
+        }
+        self.last_ppu_address = address;
+        self.idle_cycles = 0;
+        if self.repeats != 2 || !(0x2000..0x3000).contains(&address) {
+            return;
+        }
+        if self.in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            self.split_y = if self.split_y >= 239 { 0 } else { self.split_y + 1 };
+            if self.scanline == self.irq_scanline {
+                self.irq_pending = true;
+            }
+        }
+        else {
+            self.in_frame = true;
+            self.scanline = 0;
+            self.split_y = self.split_scroll % 240;
+            self.irq_pending = false;
+        }
+        self.tile = 0;
+    }
+    fn end_frame(&mut self) {
+        self.in_frame = false;
+        self.repeats = 0;
+    }
+    // The split's row for the tile being fetched; the last two fetches of
+    // a line are the first two tiles of the next.
+    fn split_row(&self) -> u8 {
+        let y = if self.tile > 32 { self.split_y + 1 } else { self.split_y };
+        if y >= 240 { y - 240 } else { y }
+    }
+    // Background fetches while rendering: the split, extended attributes
+    // and the nametable mapping, in that order.
+    fn fetch(&mut self, address: u16, fetch: PpuFetch) -> PpuAccess {
+        match fetch {
+            PpuFetch::Nametable => {
+                let column = (self.tile + 2) % 34;
+                self.tile = self.tile.saturating_add(1);
+                let threshold = self.split_control & 0x1F;
+                let right = self.split_control & 0x40 != 0;
+                self.in_split = self.split_control & 0x80 != 0
+                    && self.exram_mode < 2
+                    && column < 32
+                    && (if right { column >= threshold } else { column < threshold });
+                if self.in_split {
+                    let y = self.split_row() as usize;
+                    return PpuAccess::Cartridge(self.exram[y / 8 * 32 + column as usize]);
+                }
+                if self.exram_mode == 1 {
+                    self.exram_tile = self.exram[(address & 0x03FF) as usize];
+                }
+                self.nametable_read(address)
+            }
+            PpuFetch::Attribute if self.in_split => {
+                let column = ((self.tile + 1) % 34) as usize;
+                let y = self.split_row() as usize;
+                let attribute = self.exram[0x3C0 + y / 32 * 8 + column / 4];
+                let shift = (y & 0x10) >> 2 | (column & 0x02);
+                PpuAccess::Cartridge(((attribute >> shift) & 0x03) * 0x55)
+            }
+            PpuFetch::Attribute if self.exram_mode == 1 => PpuAccess::Cartridge((self.exram_tile >> 6) * 0x55),
+            PpuFetch::Background if self.in_split => {
+                let y = self.split_row() as usize;
+                let index = self.split_bank as usize * 0x1000 + (address as usize & 0x0FF8) + (y & 0x07);
+                PpuAccess::Cartridge(self.chr_byte(index))
+            }
+            PpuFetch::Background if self.exram_mode == 1 => {
+                let bank = (self.chr_upper as usize) << 6 | (self.exram_tile & 0x3F) as usize;
+                PpuAccess::Cartridge(self.chr_byte(bank * 0x1000 + (address & 0x0FFF) as usize))
+            }
+            _ if address >= 0x2000 => self.nametable_read(address),
+            _ => PpuAccess::Cartridge(self.chr_byte(self.chr_index(address, self.uses_set_b(fetch)))),
+        }
+    }
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5015 => self.audio.write(address, value),
+            0x5100 => self.prg_mode = value & 0x03,
+            0x5101 => self.chr_mode = value & 0x03,
+            0x5102 => self.ram_protect[0] = value & 0x03,
+            0x5103 => self.ram_protect[1] = value & 0x03,
+            0x5104 => self.exram_mode = value & 0x03,
+            0x5105 => self.nametables = value,
+            0x5106 => self.fill_tile = value,
+            0x5107 => self.fill_attribute = value & 0x03,
+            0x5113..=0x5117 => self.prg_banks[(address - 0x5113) as usize] = value,
+            0x5120..=0x5127 => {
+                self.chr_a[(address - 0x5120) as usize] = (self.chr_upper as u16) << 8 | value as u16;
+                self.last_set_b = false;
+            }
+            0x5128..=0x512B => {
+                self.chr_b[(address - 0x5128) as usize] = (self.chr_upper as u16) << 8 | value as u16;
+                self.last_set_b = true;
+            }
+            0x5130 => self.chr_upper = value & 0x03,
+            0x5200 => self.split_control = value,
+            0x5201 => self.split_scroll = value,
+            0x5202 => self.split_bank = value,
+            0x5203 => self.irq_scanline = value,
+            0x5204 => self.irq_enabled = value & 0x80 != 0,
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            0x5C00..=0x5FFF => {
+                let index = (address - 0x5C00) as usize;
+                match self.exram_mode {
+                    0 | 1 => self.exram[index] = if self.in_frame { value } else { 0 },
+                    2 => self.exram[index] = value,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl ::mapper::Mapper for Mmc5 {
+    fn read(&mut self, address: u16) -> Option<u8> {
+        let value = self.peek(address);
+        match address {
+            0x5010 => return self.audio.read(address),
+            0x5204 => self.irq_pending = false,
+            0x8000..=0xBFFF => self.audio.pcm_read(value.unwrap_or(0)),
+            // the CPU fetching the NMI vector means the frame is over
+            0xFFFA | 0xFFFB => self.end_frame(),
+            _ => {}
+        }
+        value
+    }
+    fn peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x5010 | 0x5015 => self.audio.peek(address),
+            0x5204 => Some((self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6),
+            0x5205 => Some((self.multiplicand as u16 * self.multiplier as u16) as u8),
+            0x5206 => Some(((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8),
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => Some(self.exram[(address - 0x5C00) as usize]),
+            0x6000..=0x7FFF => Some(self.prg_ram[self.prg_ram_index(self.prg_banks[0] as usize, address & 0x1FFF)]),
+            0x8000..=0xFFFF => {
+                let (register, bank) = self.prg_bank(address);
+                let offset = address & 0x1FFF;
+                if self.is_rom(register) {
+                    Some(self.prg(bank, offset))
+                }
+                else {
+                    Some(self.prg_ram[self.prg_ram_index(bank, offset)])
+                }
+            }
+            _ => None,
+        }
+    }
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5FFF => self.write_register(address, value),
+            0x6000..=0x7FFF if self.prg_ram_writable() => {
+                let index = self.prg_ram_index(self.prg_banks[0] as usize, address & 0x1FFF);
+                self.prg_ram[index] = value;
+            }
+            0x8000..=0xDFFF if self.prg_ram_writable() => {
+                let (register, bank) = self.prg_bank(address);
+                if !self.is_rom(register) {
+                    let index = self.prg_ram_index(bank, address & 0x1FFF);
+                    self.prg_ram[index] = value;
+                }
+            }
+            _ => {}
+        }
+    }
+    fn power_on(&mut self, init: RamInit) {
+        init.fill(&mut self.prg_ram, 1);
+        init.fill(&mut self.chr_ram, 2);
+        init.fill(&mut self.exram, 3);
+    }
+    fn rom(&self) -> &rom::Cartridge {
+        &self.rom
+    }
+    fn tick(&mut self) {
+        if self.idle_cycles < IDLE_CYCLES {
+            self.idle_cycles += 1;
+            if self.idle_cycles == IDLE_CYCLES {
+                self.end_frame();
+            }
+        }
+        self.audio.clock();
+    }
+    fn irq(&self) -> bool {
+        (self.irq_pending && self.irq_enabled) || self.audio.irq()
+    }
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+    fn ppu_register_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x2000 => self.sprites_8x16 = value & 0x20 != 0,
+            0x2001 => {
+                self.rendering = value & 0x18 != 0;
+                if !self.rendering {
+                    self.end_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+    fn ppu_read(&mut self, address: u16, fetch: PpuFetch) -> PpuAccess {
+        // $3000-$3EFF mirrors the nametables
+        let address = if address >= 0x3000 { address - 0x1000 } else { address };
+        if fetch == PpuFetch::Cpu {
+            return if address >= 0x2000 {
+                self.nametable_read(address)
+            }
+            else {
+                PpuAccess::Cartridge(self.chr_byte(self.chr_index(address, self.uses_set_b(fetch))))
+            };
+        }
+        self.detect_scanline(address);
+        self.fetch(address, fetch)
+    }
+    fn ppu_write(&mut self, address: u16, value: u8) -> PpuAccess {
+        let address = if address >= 0x3000 { address - 0x1000 } else { address };
+        if address < 0x2000 {
+            if self.rom.chr_rom().is_empty() && !self.chr_ram.is_empty() {
+                let index = self.chr_index(address, self.uses_set_b(PpuFetch::Cpu)) % self.chr_ram.len();
+                self.chr_ram[index] = value;
+            }
+            return PpuAccess::Cartridge(value);
+        }
+        let offset = address & 0x03FF;
+        match (self.nametables >> ((address >> 9) & 0x06)) & 0x03 {
+            0 => PpuAccess::Ciram(offset),
+            1 => PpuAccess::Ciram(0x400 | offset),
+            2 => {
+                if self.exram_mode < 2 {
+                    self.exram[offset as usize] = value;
+                }
+                PpuAccess::Cartridge(value)
+            }
+            _ => PpuAccess::Cartridge(value),
+        }
+    }
+}
+
+impl Savable for Mmc5 {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        w.write_bytes(&self.chr_ram);
+        w.write_bytes(&self.exram);
+        w.write_u8(self.prg_mode);
+        w.write_u8(self.chr_mode);
+        w.write_bytes(&self.ram_protect);
+        w.write_u8(self.exram_mode);
+        w.write_u8(self.nametables);
+        w.write_u8(self.fill_tile);
+        w.write_u8(self.fill_attribute);
+        w.write_bytes(&self.prg_banks);
+        for &bank in self.chr_a.iter().chain(&self.chr_b) {
+            w.write_u16(bank);
+        }
+        w.write_u8(self.chr_upper);
+        w.write_bool(self.last_set_b);
+        w.write_u8(self.split_control);
+        w.write_u8(self.split_scroll);
+        w.write_u8(self.split_bank);
+        w.write_u8(self.irq_scanline);
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq_pending);
+        w.write_bool(self.in_frame);
+        w.write_u8(self.scanline);
+        w.write_u8(self.multiplicand);
+        w.write_u8(self.multiplier);
+        w.write_bool(self.sprites_8x16);
+        w.write_bool(self.rendering);
+        w.write_u16(self.last_ppu_address);
+        w.write_u8(self.repeats);
+        w.write_u8(self.idle_cycles);
+        w.write_u8(self.tile);
+        w.write_u8(self.split_y);
+        w.write_bool(self.in_split);
+        w.write_u8(self.exram_tile);
+        self.audio.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.prg_ram)?;
+        r.read_bytes(&mut self.chr_ram)?;
+        r.read_bytes(&mut self.exram)?;
+        self.prg_mode = r.read_u8()? & 0x03;
+        self.chr_mode = r.read_u8()? & 0x03;
+        r.read_bytes(&mut self.ram_protect)?;
+        self.exram_mode = r.read_u8()? & 0x03;
+        self.nametables = r.read_u8()?;
+        self.fill_tile = r.read_u8()?;
+        self.fill_attribute = r.read_u8()? & 0x03;
+        r.read_bytes(&mut self.prg_banks)?;
+        for bank in self.chr_a.iter_mut().chain(&mut self.chr_b) {
+            *bank = r.read_u16()? & 0x03FF;
+        }
+        self.chr_upper = r.read_u8()? & 0x03;
+        self.last_set_b = r.read_bool()?;
+        self.split_control = r.read_u8()?;
+        self.split_scroll = r.read_u8()?;
+        self.split_bank = r.read_u8()?;
+        self.irq_scanline = r.read_u8()?;
+        self.irq_enabled = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        self.in_frame = r.read_bool()?;
+        self.scanline = r.read_u8()?;
+        self.multiplicand = r.read_u8()?;
+        self.multiplier = r.read_u8()?;
+        self.sprites_8x16 = r.read_bool()?;
+        self.rendering = r.read_bool()?;
+        self.last_ppu_address = r.read_u16()?;
+        self.repeats = r.read_u8()?;
+        self.idle_cycles = r.read_u8()?.min(IDLE_CYCLES);
+        self.tile = r.read_u8()?;
+        self.split_y = r.read_u8()? % 240;
+        self.in_split = r.read_bool()?;
+        self.exram_tile = r.read_u8()?;
+        self.audio.load(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::{test_rom, Mapper};
+
+    fn chr(mmc5: &mut Mmc5, address: u16, fetch: PpuFetch) -> u8 {
+        match mmc5.ppu_read(address, fetch) {
+            PpuAccess::Cartridge(value) => value,
+            PpuAccess::Ciram(_) => panic!("${:04X} went to nametable RAM", address),
+        }
+    }
+
+    // The PPU's two dummy nametable reads at the end of a line and the
+    // first one of the next.
+    fn start_line(mmc5: &mut Mmc5) {
+        for _ in 0..3 {
+            mmc5.ppu_read(0x2000, PpuFetch::Nametable);
+        }
+    }
+
+    #[test]
+    fn prg_modes() {
+        let mut mmc5 = Mmc5::new(test_rom(5, 32, 8));
+        for (i, bank) in [0x81, 0x86, 0x8B, 0x10].iter().enumerate() {
+            mmc5.write(0x5114 + i as u16, *bank);
+        }
+        let banks = |mmc5: &Mmc5| -> Vec<u8> { (0..4).map(|i| mmc5.peek(0x8000 + i * 0x2000).unwrap()).collect() };
+        assert_eq!(banks(&mmc5), [1, 6, 11, 16]);
+        mmc5.write(0x5100, 2);
+        assert_eq!(banks(&mmc5), [6, 7, 11, 16]);
+        mmc5.write(0x5100, 1);
+        assert_eq!(banks(&mmc5), [6, 7, 16, 17]);
+        mmc5.write(0x5100, 0);
+        assert_eq!(banks(&mmc5), [16, 17, 18, 19]);
+    }
+
+    #[test]
+    fn prg_ram() {
+        let mut mmc5 = Mmc5::new(test_rom(5, 8, 8));
+        mmc5.write(0x6000, 1);
+        assert_eq!(mmc5.peek(0x6000), Some(0));
+        mmc5.write(0x5102, 2);
+        mmc5.write(0x5103, 1);
+        mmc5.write(0x6000, 1);
+        assert_eq!(mmc5.peek(0x6000), Some(1));
+        // RAM at $8000 with bit 7 clear, but never at $E000
+        mmc5.write(0x5114, 0x00);
+        assert_eq!(mmc5.peek(0x8000), Some(1));
+        mmc5.write(0x8001, 2);
+        assert_eq!(mmc5.peek(0x6001), Some(2));
+        mmc5.write(0x5117, 0x00);
+        mmc5.write(0xE000, 3);
+        assert_eq!(mmc5.peek(0xE000), Some(0));
+    }
+
+    #[test]
+    fn chr_modes_and_sets() {
+        let mut mmc5 = Mmc5::new(test_rom(5, 4, 160));
+        for i in 0..8 {
+            mmc5.write(0x5120 + i, 16 + i as u8);
+        }
+        for i in 0..4 {
+            mmc5.write(0x5128 + i, 32 + i as u8);
+        }
+        mmc5.write(0x5101, 3);
+        assert_eq!(chr(&mut mmc5, 0x1C00, PpuFetch::Background), 23);
+        mmc5.write(0x5101, 1);
+        // 4K banks from registers 3 and 7
+        assert_eq!(chr(&mut mmc5, 0x0000, PpuFetch::Background), 19 * 4);
+        assert_eq!(chr(&mut mmc5, 0x1000, PpuFetch::Background), 23 * 4);
+        assert_eq!(chr(&mut mmc5, 0x1400, PpuFetch::Background), 23 * 4 + 1);
+
+        // with 8x16 sprites, the background uses set B
+        mmc5.ppu_register_write(0x2000, 0x20);
+        assert_eq!(chr(&mut mmc5, 0x1000, PpuFetch::Background), 35 * 4);
+        assert_eq!(chr(&mut mmc5, 0x1000, PpuFetch::Sprite), 23 * 4);
+        assert_eq!(chr(&mut mmc5, 0x1000, PpuFetch::Cpu), 35 * 4);
+        mmc5.write(0x5127, 0);
+        assert_eq!(chr(&mut mmc5, 0x1000, PpuFetch::Cpu), 0);
+    }
+
+    #[test]
+    fn nametables_and_fill_mode() {
+        let mut mmc5 = Mmc5::new(test_rom(5, 4, 8));
+        mmc5.write(0x5104, 2);
+        mmc5.write(0x5C05, 0x42);
+        mmc5.write(0x5105, 0b11_10_01_00);
+        mmc5.write(0x5106, 0x99);
+        mmc5.write(0x5107, 0x02);
+        assert!(matches!(mmc5.ppu_read(0x2005, PpuFetch::Cpu), PpuAccess::Ciram(0x0005)));
+        assert!(matches!(mmc5.ppu_read(0x2405, PpuFetch::Cpu), PpuAccess::Ciram(0x0405)));
+        // ExRAM only serves as a nametable in modes 0 and 1
+        assert!(matches!(mmc5.ppu_read(0x2805, PpuFetch::Cpu), PpuAccess::Cartridge(0)));
+        mmc5.write(0x5104, 0);
+        assert!(matches!(mmc5.ppu_read(0x2805, PpuFetch::Cpu), PpuAccess::Cartridge(0x42)));
+        assert!(matches!(mmc5.ppu_read(0x2C05, PpuFetch::Cpu), PpuAccess::Cartridge(0x99)));
+        assert!(matches!(mmc5.ppu_read(0x2FC5, PpuFetch::Cpu), PpuAccess::Cartridge(0xAA)));
+        // $3000-$3EFF mirrors the nametables
+        assert!(matches!(mmc5.ppu_read(0x3405, PpuFetch::Cpu), PpuAccess::Ciram(0x0405)));
+    }
+
+    #[test]
+    fn exram_writes() {
+        let mut mmc5 = Mmc5::new(test_rom(5, 4, 8));
+        mmc5.write(0x5104, 2);
+        mmc5.write(0x5C00, 7);
+        assert_eq!(mmc5.peek(0x5C00), Some(7));
+        // read-only in mode 3
+        mmc5.write(0x5104, 3);
+        mmc5.write(0x5C00, 8);
+        assert_eq!(mmc5.peek(0x5C00), Some(7));
+        // modes 0 and 1 take zeros outside of rendering
+        mmc5.write(0x5104, 0);
+        assert_eq!(mmc5.peek(0x5C00), None);
+        mmc5.write(0x5C00, 9);
+        mmc5.write(0x5104, 2);
+        assert_eq!(mmc5.peek(0x5C00), Some(0));
+    }
+
+    #[test]
+    fn multiplier() {
+        let mut mmc5 = Mmc5::new(test_rom(5, 4, 8));
+        assert_eq!((mmc5.peek(0x5205), mmc5.peek(0x5206)), (Some(0x01), Some(0xFE)));
+        mmc5.write(0x5205, 200);
+        mmc5.write(0x5206, 100);
+        assert_eq!((mmc5.peek(0x5205), mmc5.peek(0x5206)), (Some(0x20), Some(0x4E)));
+    }
+
+    #[test]
+    fn scanline_irq() {
+        let mut mmc5 = Mmc5::new(test_rom(5, 4, 8));
+        mmc5.write(0x5203, 2);
+        mmc5.write(0x5204, 0x80);
+        assert_eq!(mmc5.peek(0x5204), Some(0x00));
+        start_line(&mut mmc5);
+        assert_eq!(mmc5.peek(0x5204), Some(0x40));
+        for _ in 0..2 {
+            assert!(!mmc5.irq());
+            mmc5.ppu_read(0x0000, PpuFetch::Background);
+            start_line(&mut mmc5);
+        }
+        assert!(mmc5.irq());
+        assert_eq!(mmc5.read(0x5204), Some(0xC0));
+        assert!(!mmc5.irq());
+
+        // the frame ends when the PPU stops reading
+        for _ in 0..IDLE_CYCLES {
+            mmc5.tick();
+        }
+        assert_eq!(mmc5.peek(0x5204), Some(0x00));
+        // and the count starts over
+        start_line(&mut mmc5);
+        mmc5.ppu_read(0x0000, PpuFetch::Background);
+        start_line(&mut mmc5);
+        assert!(!mmc5.irq());
+    }
+}
@@ -0,0 +1,261 @@
+// MMC5 sound
+//
+// Two pulse channels like the 2A03's, without the sweep units, and an
+// 8-bit PCM channel:
+//   $5000-$5003  pulse 1: duty, envelope and volume; unused; period low;
+//                length counter load and period high
+//   $5004-$5007  pulse 2, the same
+//   $5010        PCM: IRQ enable (7), read mode (0)
+//   $5011        PCM: sample, in write mode
+//   $5015        pulse enables (0-1); reads back which are still playing
+// The pulse timers run every other cycle like the APU's, while the
+// envelopes and length counters are clocked at a steady 240Hz instead of
+// by the APU's frame counter.
+//
+// In read mode, the PCM channel plays whatever the CPU reads from
+// $8000-$BFFF. Reading a 0 there raises the PCM IRQ instead, and $5010
+// reads back (and acknowledges) it in bit 7. Writing 0 to $5011 is ignored.
+
+use apu;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+const LENGTHS: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+const DUTIES: [u8; 4] = [0b0100_0000, 0b0110_0000, 0b0111_1000, 0b1001_1111];
+// CPU cycles per envelope and length counter clock, 240Hz on NTSC.
+const FRAME_CYCLES: u16 = 7457;
+
+#[derive(Default)]
+struct Pulse {
+    enabled: bool,
+    duty: u8,
+    halt: bool,
+    constant: bool,
+    volume: u8,
+    period: u16,
+    timer: u16,
+    step: u8,
+    length: u8,
+    envelope_start: bool,
+    envelope_divider: u8,
+    decay: u8,
+}
+
+impl Pulse {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = value >> 6;
+                self.halt = value & 0x20 != 0;
+                self.constant = value & 0x10 != 0;
+                self.volume = value & 0x0F;
+            }
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            3 => {
+                self.period = (self.period & 0x00FF) | ((value & 0x07) as u16) << 8;
+                if self.enabled {
+                    self.length = LENGTHS[(value >> 3) as usize];
+                }
+                self.step = 0;
+                self.envelope_start = true;
+            }
+            _ => {}
+        }
+    }
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+    // Every other CPU cycle.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 0x07;
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+    fn clock_frame(&mut self) {
+        if self.envelope_start {
+            self.envelope_start = false;
+            self.decay = 15;
+            self.envelope_divider = self.volume;
+        }
+        else if self.envelope_divider == 0 {
+            self.envelope_divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            }
+            else if self.halt {
+                self.decay = 15;
+            }
+        }
+        else {
+            self.envelope_divider -= 1;
+        }
+        if !self.halt && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.length == 0 || DUTIES[self.duty as usize] & (0x80 >> self.step) == 0 {
+            0
+        }
+        else if self.constant {
+            self.volume
+        }
+        else {
+            self.decay
+        }
+    }
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u8(self.duty);
+        w.write_bool(self.halt);
+        w.write_bool(self.constant);
+        w.write_u8(self.volume);
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        w.write_u8(self.step);
+        w.write_u8(self.length);
+        w.write_bool(self.envelope_start);
+        w.write_u8(self.envelope_divider);
+        w.write_u8(self.decay);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.read_bool()?;
+        self.duty = r.read_u8()? & 0x03;
+        self.halt = r.read_bool()?;
+        self.constant = r.read_bool()?;
+        self.volume = r.read_u8()? & 0x0F;
+        self.period = r.read_u16()? & 0x07FF;
+        self.timer = r.read_u16()?;
+        self.step = r.read_u8()? & 0x07;
+        self.length = r.read_u8()?;
+        self.envelope_start = r.read_bool()?;
+        self.envelope_divider = r.read_u8()?;
+        self.decay = r.read_u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+pub struct Mmc5Audio {
+    pulses: [Pulse; 2],
+    odd_cycle: bool,
+    frame_divider: u16,
+    pcm: u8,
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    pcm_irq: bool,
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Mmc5Audio {
+        Mmc5Audio {
+            pulses: Default::default(),
+            odd_cycle: false,
+            frame_divider: FRAME_CYCLES,
+            pcm: 0,
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq: false,
+        }
+    }
+    pub fn read(&mut self, address: u16) -> Option<u8> {
+        let value = self.peek(address);
+        if address == 0x5010 {
+            self.pcm_irq = false;
+        }
+        value
+    }
+    pub fn peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x5010 => Some(if self.pcm_irq && self.pcm_irq_enabled { 0x80 } else { 0 } | self.pcm_read_mode as u8),
+            0x5015 => Some(self.pulses.iter().enumerate().fold(0, |status, (i, p)| status | ((p.length > 0) as u8) << i)),
+            _ => None,
+        }
+    }
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5007 => self.pulses[((address >> 2) & 1) as usize].write(address & 0x03, value),
+            0x5010 => {
+                self.pcm_read_mode = value & 0x01 != 0;
+                self.pcm_irq_enabled = value & 0x80 != 0;
+            }
+            0x5011 if !self.pcm_read_mode && value != 0 => self.pcm = value,
+            0x5015 => {
+                self.pulses[0].set_enabled(value & 0x01 != 0);
+                self.pulses[1].set_enabled(value & 0x02 != 0);
+            }
+            _ => {}
+        }
+    }
+    // A CPU read of $8000-$BFFF, which the PCM channel plays in read mode.
+    pub fn pcm_read(&mut self, value: u8) {
+        if !self.pcm_read_mode {
+            return;
+        }
+        if value == 0 {
+            self.pcm_irq = true;
+        }
+        else {
+            self.pcm = value;
+        }
+    }
+    pub fn irq(&self) -> bool {
+        self.pcm_irq && self.pcm_irq_enabled
+    }
+    // One CPU cycle.
+    pub fn clock(&mut self) {
+        self.odd_cycle = !self.odd_cycle;
+        if self.odd_cycle {
+            for pulse in &mut self.pulses {
+                pulse.clock_timer();
+            }
+        }
+        self.frame_divider -= 1;
+        if self.frame_divider == 0 {
+            self.frame_divider = FRAME_CYCLES;
+            for pulse in &mut self.pulses {
+                pulse.clock_frame();
+            }
+        }
+    }
+    // On the APU's scale: the pulses are as loud as the APU's, and PCM at
+    // full scale twice one of them.
+    pub fn output(&self) -> f32 {
+        let pulses = (self.pulses[0].output() + self.pulses[1].output()) as f32 / 15.0;
+        (pulses + self.pcm as f32 / 255.0 * 2.0) * apu::PULSE_FULL_VOLUME
+    }
+}
+
+impl Savable for Mmc5Audio {
+    fn save(&self, w: &mut StateWriter) {
+        for pulse in &self.pulses {
+            pulse.save(w);
+        }
+        w.write_bool(self.odd_cycle);
+        w.write_u16(self.frame_divider);
+        w.write_u8(self.pcm);
+        w.write_bool(self.pcm_read_mode);
+        w.write_bool(self.pcm_irq_enabled);
+        w.write_bool(self.pcm_irq);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for pulse in &mut self.pulses {
+            pulse.load(r)?;
+        }
+        self.odd_cycle = r.read_bool()?;
+        self.frame_divider = r.read_u16()?.clamp(1, FRAME_CYCLES);
+        self.pcm = r.read_u8()?;
+        self.pcm_read_mode = r.read_bool()?;
+        self.pcm_irq_enabled = r.read_bool()?;
+        self.pcm_irq = r.read_bool()?;
+        Ok(())
+    }
+}
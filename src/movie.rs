@@ -0,0 +1,437 @@
+// Input movies
+//
+// A movie is a start point (power-on or a save state) followed by the
+// controller input for every frame after it. Playing one back applies each
+// frame's input before running that frame, so from the same start point
+// the machine always ends up in the same state.
+//
+// Movies are imported from and exported to FCEUX's FM2 text format:
+// a list of "key value" header lines followed by one input line per frame,
+//   |commands|port0|port1|port2|
+// where each gamepad field is 8 characters in the order RLDUTSBA and any
+// character other than '.' or ' ' means the button is held.
+//
+// The power-on RAM contents are stored in an extra "ramInit" header line
+// (see memory::RamInit), which FCEUX ignores. Movies without one start
+// from zeroed RAM.
+//
+// Only FM2 files with gamepads (or nothing) in ports 0 and 1 are supported.
+// Save-state anchored movies must carry a futilenes save state, since FCEUX
+// save states can not be loaded here.
+
+use std::fmt;
+
+use controller;
+use cpu::CPU;
+use md5;
+use memory::RamInit;
+use region::Region;
+use savestate::StateError;
+
+pub const COMMAND_SOFT_RESET: u8 = 0x01;
+pub const COMMAND_HARD_RESET: u8 = 0x02;
+
+// FM2 gamepad field order, leftmost character first.
+const FM2_BUTTONS: [(char, u8); 8] = [
+    ('R', controller::BUTTON_RIGHT),
+    ('L', controller::BUTTON_LEFT),
+    ('D', controller::BUTTON_DOWN),
+    ('U', controller::BUTTON_UP),
+    ('T', controller::BUTTON_START),
+    ('S', controller::BUTTON_SELECT),
+    ('B', controller::BUTTON_B),
+    ('A', controller::BUTTON_A),
+];
+
+#[derive(Debug)]
+pub enum MovieError {
+    Format(String),
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::Format(ref msg) => write!(f, "bad movie: {}", msg),
+            MovieError::State(ref e) => write!(f, "movie save state: {}", e),
+        }
+    }
+}
+
+impl From<StateError> for MovieError {
+    fn from(e: StateError) -> MovieError {
+        MovieError::State(e)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum MovieStart {
+    PowerOn,
+    SaveState(Vec<u8>),
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct FrameInput {
+    pub commands: u8,
+    pub ports: [u8; 2],
+}
+
+pub struct Movie {
+    pub start: MovieStart,
+    pub rom_filename: String,
+    pub rom_md5: [u8; 16],
+    pub rerecord_count: u32,
+    pub pal: bool,
+    pub ram_init: RamInit,
+    pub comments: Vec<String>,
+    frames: Vec<FrameInput>,
+}
+
+impl Movie {
+    // Starts a new, empty movie for the ROM loaded in `cpu`. A save state
+    // start is taken from the machine as it is right now.
+    pub fn new(cpu: &CPU, rom_filename: &str, from_power_on: bool) -> Movie {
+        Movie {
+            start: if from_power_on { MovieStart::PowerOn } else { MovieStart::SaveState(cpu.save_state()) },
+            rom_filename: rom_filename.to_string(),
+            rom_md5: cpu.rom().md5(),
+            rerecord_count: 0,
+            pal: cpu.region() == Region::Pal,
+            ram_init: cpu.ram_init(),
+            comments: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn frame(&self, n: usize) -> Option<FrameInput> {
+        self.frames.get(n).cloned()
+    }
+
+    pub fn record(&mut self, input: FrameInput) {
+        self.frames.push(input);
+    }
+
+    // Drops everything from frame `n` on, e.g. after loading a state while
+    // recording. Counts as a rerecord.
+    #[cfg(feature = "frontend")]
+    pub fn truncate(&mut self, n: usize) {
+        self.frames.truncate(n);
+        self.rerecord_count += 1;
+    }
+
+    pub fn matches_rom(&self, cpu: &CPU) -> bool {
+        self.rom_md5 == cpu.rom().md5()
+    }
+
+    // Puts the machine at the movie's start point.
+    pub fn rewind(&self, cpu: &mut CPU) -> Result<(), MovieError> {
+        match self.start {
+            MovieStart::PowerOn => {
+                cpu.set_ram_init(self.ram_init);
+                cpu.power_on();
+            }
+            MovieStart::SaveState(ref state) => cpu.load_state(state)?,
+        }
+        Ok(())
+    }
+
+    // Feeds frame `n` into the machine. Call before running that frame.
+    // Returns false once the movie has run out of input.
+    pub fn apply(&self, cpu: &mut CPU, n: usize) -> bool {
+        let input = match self.frame(n) {
+            Some(input) => input,
+            None => return false,
+        };
+        if input.commands & COMMAND_HARD_RESET != 0 {
+            cpu.power_on();
+        }
+        else if input.commands & COMMAND_SOFT_RESET != 0 {
+            cpu.reset();
+        }
+        cpu.set_input(0, input.ports[0]);
+        cpu.set_input(1, input.ports[1]);
+        true
+    }
+
+    pub fn from_fm2(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie {
+            start: MovieStart::PowerOn,
+            rom_filename: String::new(),
+            rom_md5: [0; 16],
+            rerecord_count: 0,
+            pal: false,
+            ram_init: RamInit::default(),
+            comments: Vec::new(),
+            frames: Vec::new(),
+        };
+        let mut ports = [1u8, 1, 0];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                let input = parse_fm2_input(line, &ports)
+                    .ok_or_else(|| MovieError::Format(format!("bad input on line {}", i + 1)))?;
+                movie.frames.push(input);
+                continue;
+            }
+            let mut parts = line.splitn(2, ' ');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+            match key {
+                "" => {}
+                "version" if value != "3" => {
+                    return Err(MovieError::Format(format!("unsupported FM2 version {}", value)));
+                }
+                "binary" if value != "0" => {
+                    return Err(MovieError::Format("binary FM2 input is not supported".to_string()));
+                }
+                "rerecordCount" => movie.rerecord_count = value.parse().unwrap_or(0),
+                "palFlag" => movie.pal = value == "1",
+                "ramInit" => {
+                    movie.ram_init = RamInit::from_name(value)
+                        .ok_or_else(|| MovieError::Format(format!("bad ramInit {}", value)))?;
+                }
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => {
+                    let digest = decode_fm2_base64(value)
+                        .ok_or_else(|| MovieError::Format("bad romChecksum".to_string()))?;
+                    if digest.len() != 16 {
+                        return Err(MovieError::Format("bad romChecksum".to_string()));
+                    }
+                    movie.rom_md5.copy_from_slice(&digest);
+                }
+                "savestate" => {
+                    let state = decode_fm2_base64(value)
+                        .ok_or_else(|| MovieError::Format("bad savestate".to_string()))?;
+                    movie.start = MovieStart::SaveState(state);
+                }
+                "port0" | "port1" | "port2" => {
+                    let port = (key.as_bytes()[4] - b'0') as usize;
+                    ports[port] = value.parse().unwrap_or(0);
+                    if port < 2 && ports[port] > 1 {
+                        return Err(MovieError::Format(format!("{} device {} is not supported", key, value)));
+                    }
+                }
+                "fourscore" if value == "1" => {
+                    return Err(MovieError::Format("four score movies are not supported".to_string()));
+                }
+                "comment" => movie.comments.push(value.to_string()),
+                _ => {}
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut out = String::new();
+        out.push_str("version 3\n");
+        // FCEUX only uses this to decide on compatibility quirks
+        out.push_str("emuVersion 22020\n");
+        out.push_str(&format!("rerecordCount {}\n", self.rerecord_count));
+        out.push_str(&format!("palFlag {}\n", self.pal as u8));
+        out.push_str(&format!("ramInit {}\n", self.ram_init));
+        out.push_str(&format!("romFilename {}\n", self.rom_filename));
+        out.push_str(&format!("romChecksum base64:{}\n", encode_base64(&self.rom_md5)));
+        out.push_str(&format!("guid {}\n", self.guid()));
+        out.push_str("fourscore 0\nmicrophone 0\nport0 1\nport1 1\nport2 0\nFDS 0\nNewPPU 0\n");
+        if let MovieStart::SaveState(ref state) = self.start {
+            out.push_str(&format!("savestate base64:{}\n", encode_base64(state)));
+        }
+        for c in &self.comments {
+            out.push_str(&format!("comment {}\n", c));
+        }
+        for input in &self.frames {
+            out.push_str(&format!("|{}|{}|{}||\n", input.commands, fm2_buttons(input.ports[0]), fm2_buttons(input.ports[1])));
+        }
+        out
+    }
+
+    // FM2 wants a GUID per movie. Deriving it from the contents keeps
+    // exports reproducible.
+    fn guid(&self) -> String {
+        let mut md5 = md5::Md5::new();
+        md5.update(&self.rom_md5);
+        for input in &self.frames {
+            md5.update(&[input.commands, input.ports[0], input.ports[1]]);
+        }
+        let d = md5.finish();
+        let hex: Vec<String> = d.iter().map(|b| format!("{:02X}", b)).collect();
+        format!("{}-{}-{}-{}-{}", hex[0..4].concat(), hex[4..6].concat(), hex[6..8].concat(),
+                hex[8..10].concat(), hex[10..16].concat())
+    }
+}
+
+fn fm2_buttons(buttons: u8) -> String {
+    FM2_BUTTONS.iter()
+        .map(|&(c, bit)| if buttons & bit != 0 { c } else { '.' })
+        .collect()
+}
+
+fn parse_fm2_buttons(field: &str) -> Option<u8> {
+    if field.is_empty() {
+        return Some(0);
+    }
+    if field.chars().count() != 8 {
+        return None;
+    }
+    let mut buttons = 0;
+    for (c, &(_, bit)) in field.chars().zip(FM2_BUTTONS.iter()) {
+        if c != '.' && c != ' ' {
+            buttons |= bit;
+        }
+    }
+    Some(buttons)
+}
+
+fn parse_fm2_input(line: &str, ports: &[u8; 3]) -> Option<FrameInput> {
+    let fields: Vec<&str> = line.split('|').collect();
+    // a leading and a trailing '|' give empty first and last fields
+    if fields.len() < 5 {
+        return None;
+    }
+    let mut input = FrameInput {
+        commands: fields[1].trim().parse().ok()?,
+        ports: [0, 0],
+    };
+    for port in 0..2 {
+        if ports[port] == 1 {
+            input.ports[port] = parse_fm2_buttons(fields[2 + port])?;
+        }
+    }
+    Some(input)
+}
+
+const BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_CHARS[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// FM2 binary blobs are either "base64:..." or plain hex.
+fn decode_fm2_base64(value: &str) -> Option<Vec<u8>> {
+    if !value.starts_with("base64:") {
+        let hex = value.trim_start_matches("0x");
+        if !hex.len().is_multiple_of(2) {
+            return None;
+        }
+        return (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect();
+    }
+    let mut out = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in value["base64:".len()..].bytes() {
+        if c == b'=' {
+            break;
+        }
+        let v = BASE64_CHARS.iter().position(|&x| x == c)? as u32;
+        acc = (acc << 6) | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FM2: &str = "version 3\n\
+        emuVersion 22020\n\
+        rerecordCount 12\n\
+        palFlag 0\n\
+        romFilename game\n\
+        romChecksum base64:kAFQmDzS9LDWlj99KOF/cg==\n\
+        guid 00000000-0000-0000-0000-000000000000\n\
+        port0 1\n\
+        port1 1\n\
+        port2 0\n\
+        comment author someone\n\
+        |0|........|........||\n\
+        |0|R..U...A|........||\n\
+        |1|........|.L..T.B.||\n";
+
+    #[test]
+    fn parse_fm2() {
+        let movie = Movie::from_fm2(FM2).unwrap();
+        assert_eq!(movie.len(), 3);
+        assert_eq!(movie.rerecord_count, 12);
+        assert!(!movie.pal);
+        assert_eq!(movie.rom_filename, "game");
+        // MD5 of "abc"
+        assert_eq!(movie.rom_md5[..4], [0x90, 0x01, 0x50, 0x98]);
+        assert_eq!(movie.comments, ["author someone"]);
+        assert!(movie.start == MovieStart::PowerOn);
+        assert_eq!(movie.frame(0), Some(FrameInput::default()));
+        let right_up_a = controller::BUTTON_RIGHT | controller::BUTTON_UP | controller::BUTTON_A;
+        assert_eq!(movie.frame(1), Some(FrameInput { commands: 0, ports: [right_up_a, 0] }));
+        let left_start_b = controller::BUTTON_LEFT | controller::BUTTON_START | controller::BUTTON_B;
+        assert_eq!(movie.frame(2), Some(FrameInput { commands: COMMAND_SOFT_RESET, ports: [0, left_start_b] }));
+        assert_eq!(movie.frame(3), None);
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let mut movie = Movie::from_fm2(FM2).unwrap();
+        movie.pal = true;
+        movie.ram_init = RamInit::Random(42);
+        movie.start = MovieStart::SaveState(vec![0, 1, 2, 0xFE, 0xFF]);
+        movie.record(FrameInput { commands: COMMAND_HARD_RESET, ports: [0xFF, 0x5A] });
+
+        let copy = Movie::from_fm2(&movie.to_fm2()).unwrap();
+        assert_eq!(copy.len(), movie.len());
+        for n in 0..movie.len() {
+            assert_eq!(copy.frame(n), movie.frame(n));
+        }
+        assert_eq!(copy.rerecord_count, movie.rerecord_count);
+        assert!(copy.pal);
+        assert_eq!(copy.ram_init, movie.ram_init);
+        assert_eq!(copy.rom_filename, movie.rom_filename);
+        assert_eq!(copy.rom_md5, movie.rom_md5);
+        assert_eq!(copy.comments, movie.comments);
+        assert!(copy.start == movie.start);
+        assert_eq!(copy.to_fm2(), movie.to_fm2());
+    }
+
+    #[test]
+    fn base64() {
+        for len in 0..8 {
+            let data: Vec<u8> = (0..len).map(|i| i * 37 + 1).collect();
+            let encoded = format!("base64:{}", encode_base64(&data));
+            assert_eq!(decode_fm2_base64(&encoded), Some(data));
+        }
+        assert_eq!(encode_base64(b"abc"), "YWJj");
+        assert_eq!(encode_base64(b"ab"), "YWI=");
+        assert_eq!(decode_fm2_base64("0x00ff10"), Some(vec![0x00, 0xFF, 0x10]));
+        assert_eq!(decode_fm2_base64("abc"), None);
+    }
+
+    #[test]
+    fn unsupported_movies() {
+        assert!(Movie::from_fm2("version 2\n").is_err());
+        assert!(Movie::from_fm2("binary 1\n").is_err());
+        assert!(Movie::from_fm2("fourscore 1\n").is_err());
+        assert!(Movie::from_fm2("port0 2\n").is_err());
+        assert!(Movie::from_fm2("|0|RLDU|\n").is_err());
+        assert!(Movie::from_fm2("|x|........|........||\n").is_err());
+    }
+}
@@ -0,0 +1,359 @@
+// Namco 163, mapper 19
+//
+//   $4800-$4FFF  sound RAM data port, see n163_audio.rs
+//   $5000-$57FF  IRQ counter, low 8 bits
+//   $5800-$5FFF  IRQ counter, high 7 bits, and enable (7)
+//   $8000-$BFFF  eight 1K CHR banks; $E0-$FF select the console's
+//                nametables unless $E800 says otherwise
+//   $C000-$DFFF  four nametable banks
+//   $E000-$E7FF  8K PRG bank at $8000 (0-5), sound disable (6)
+//   $E800-$EFFF  8K PRG bank at $A000 (0-5), CHR-RAM disables (6-7)
+//   $F000-$F7FF  8K PRG bank at $C000 (0-5)
+//   $F800-$FFFF  sound RAM address port, and PRG-RAM write protection
+// with the last 8K of PRG fixed at $E000. The IRQ counter counts up every
+// cycle while enabled, stopping at $7FFF, which holds the IRQ line.
+//
+// PRG-RAM takes writes when $F800 holds $4x and the bit for the 2K
+// window (bits 0-3, $6000 first) is clear.
+//
+// On battery-backed boards, PRG-RAM and the 128 bytes of sound RAM are
+// kept in the save file, one after the other; several games keep their
+// saves only in the latter. A save file that can't be read stops the game
+// from loading rather than being overwritten.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use mapper::{self, PpuAccess, PpuFetch};
+use memory::RamInit;
+use n163_audio::N163Audio;
+use rom;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+pub struct N163 {
+    rom: rom::Cartridge,
+    prg_ram: [u8; 0x2000],
+    chr_ram: Vec<u8>,
+    save_path: Option<PathBuf>,
+    prg_banks: [u8; 3],
+    // $8000-$DFFF
+    chr_banks: [u8; 12],
+    // $E800 bits 6-7
+    chr_ram_disable: u8,
+    sound_disabled: bool,
+    // $F800
+    write_protect: u8,
+    irq_counter: u16,
+    irq_enabled: bool,
+    audio: N163Audio,
+}
+
+impl N163 {
+    // Battery RAM is read from and written to `save_path`.
+    pub fn new(rom: rom::Cartridge, save_path: Option<PathBuf>) -> io::Result<N163> {
+        let chr_ram = vec![0; rom.chr_ram_size() as usize];
+        let mut n163 = N163 {
+            save_path: if rom.battery { save_path } else { None },
+            rom,
+            prg_ram: [0; 0x2000],
+            chr_ram,
+            prg_banks: [0; 3],
+            chr_banks: [0; 12],
+            chr_ram_disable: 0,
+            sound_disabled: false,
+            write_protect: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            audio: N163Audio::new(),
+        };
+        n163.load_battery()?;
+        Ok(n163)
+    }
+    fn load_battery(&mut self) -> io::Result<()> {
+        let path = match self.save_path {
+            Some(ref p) if p.exists() => p,
+            _ => return Ok(()),
+        };
+        let save = fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        if save.len() != self.prg_ram.len() + self.audio.ram().len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is the wrong size", path.display())));
+        }
+        let (prg_ram, sound_ram) = save.split_at(self.prg_ram.len());
+        self.prg_ram.copy_from_slice(prg_ram);
+        self.audio.ram_mut().copy_from_slice(sound_ram);
+        Ok(())
+    }
+    // Byte `offset` of 8K PRG bank `bank`, wrapping around the ROM.
+    fn prg(&self, bank: usize, offset: u16) -> u8 {
+        let pages = &self.rom.prg_rom;
+        let bank = bank % (pages.len() * 2);
+        pages[bank / 2][(bank % 2) * 0x2000 + offset as usize]
+    }
+    fn prg_ram_writable(&self, address: u16) -> bool {
+        let window = (address - 0x6000) >> 11;
+        self.write_protect & 0xF0 == 0x40 && self.write_protect & (1 << window) == 0
+    }
+    // Banks $E0-$FF select a page of the console's nametable RAM instead
+    // of CHR, where $E800 allows it.
+    fn ciram_page(&self, slot: usize) -> Option<u16> {
+        let bank = self.chr_banks[slot];
+        let allowed = match slot {
+            0..=3 => self.chr_ram_disable & 0x40 == 0,
+            4..=7 => self.chr_ram_disable & 0x80 == 0,
+            _ => true,
+        };
+        if bank >= 0xE0 && allowed { Some(((bank & 0x01) as u16) << 10) } else { None }
+    }
+    fn chr_index(&self, address: u16) -> usize {
+        self.chr_banks[(address >> 10) as usize] as usize * 0x400 + (address & 0x03FF) as usize
+    }
+}
+
+impl ::mapper::Mapper for N163 {
+    fn read(&mut self, address: u16) -> Option<u8> {
+        match address {
+            0x4800..=0x4FFF => Some(self.audio.read_data()),
+            _ => self.peek(address),
+        }
+    }
+    fn peek(&self, address: u16) -> Option<u8> {
+        let offset = address & 0x1FFF;
+        match address {
+            0x4800..=0x4FFF => Some(self.audio.peek_data()),
+            0x5000..=0x57FF => Some(self.irq_counter as u8),
+            0x5800..=0x5FFF => Some((self.irq_counter >> 8) as u8 | if self.irq_enabled { 0x80 } else { 0 }),
+            0x6000..=0x7FFF => Some(self.prg_ram[offset as usize]),
+            0x8000..=0xDFFF => {
+                let slot = ((address - 0x8000) >> 13) as usize;
+                Some(self.prg(self.prg_banks[slot] as usize, offset))
+            }
+            0xE000..=0xFFFF => Some(self.prg(self.rom.prg_rom.len() * 2 - 1, offset)),
+            _ => None,
+        }
+    }
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4800..=0x4FFF => self.audio.write_data(value),
+            0x5000..=0x57FF => self.irq_counter = (self.irq_counter & 0x7F00) | value as u16,
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | ((value & 0x7F) as u16) << 8;
+                self.irq_enabled = value & 0x80 != 0;
+            }
+            0x6000..=0x7FFF if self.prg_ram_writable(address) => self.prg_ram[(address & 0x1FFF) as usize] = value,
+            0x8000..=0xDFFF => self.chr_banks[((address - 0x8000) >> 11) as usize] = value,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = value & 0x3F;
+                self.sound_disabled = value & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => {
+                self.prg_banks[1] = value & 0x3F;
+                self.chr_ram_disable = value & 0xC0;
+            }
+            0xF000..=0xF7FF => self.prg_banks[2] = value & 0x3F,
+            0xF800..=0xFFFF => {
+                self.write_protect = value;
+                self.audio.write_address(value);
+            }
+            _ => {}
+        }
+    }
+    // Battery-backed RAM keeps what it had.
+    fn power_on(&mut self, init: RamInit) {
+        if self.save_path.is_none() {
+            init.fill(&mut self.prg_ram, 1);
+        }
+        init.fill(&mut self.chr_ram, 2);
+    }
+    fn rom(&self) -> &rom::Cartridge {
+        &self.rom
+    }
+    fn ppu_read(&mut self, address: u16, _fetch: PpuFetch) -> PpuAccess {
+        // $3000-$3EFF mirrors the nametables
+        let address = if address >= 0x3000 { address - 0x1000 } else { address };
+        if let Some(page) = self.ciram_page((address >> 10) as usize) {
+            PpuAccess::Ciram(page | address & 0x03FF)
+        }
+        else if self.chr_ram.is_empty() {
+            PpuAccess::Cartridge(mapper::chr_byte(self.rom.chr_rom(), self.chr_index(address)))
+        }
+        else {
+            PpuAccess::Cartridge(mapper::chr_byte(&self.chr_ram, self.chr_index(address)))
+        }
+    }
+    fn ppu_write(&mut self, address: u16, value: u8) -> PpuAccess {
+        let address = if address >= 0x3000 { address - 0x1000 } else { address };
+        if let Some(page) = self.ciram_page((address >> 10) as usize) {
+            return PpuAccess::Ciram(page | address & 0x03FF);
+        }
+        if !self.chr_ram.is_empty() {
+            let index = self.chr_index(address) % self.chr_ram.len();
+            self.chr_ram[index] = value;
+        }
+        PpuAccess::Cartridge(value)
+    }
+    fn tick(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+        }
+        if !self.sound_disabled {
+            self.audio.clock();
+        }
+    }
+    fn irq(&self) -> bool {
+        self.irq_enabled && self.irq_counter == 0x7FFF
+    }
+    fn audio(&self) -> f32 {
+        if self.sound_disabled { 0.0 } else { self.audio.output() }
+    }
+    fn flush_save(&mut self) -> io::Result<()> {
+        let path = match self.save_path {
+            Some(ref p) => p,
+            None => return Ok(()),
+        };
+        let mut save = self.prg_ram.to_vec();
+        save.extend_from_slice(self.audio.ram());
+        fs::write(path, save).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+}
+
+impl Savable for N163 {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        w.write_bytes(&self.chr_ram);
+        w.write_bytes(&self.prg_banks);
+        w.write_bytes(&self.chr_banks);
+        w.write_u8(self.chr_ram_disable);
+        w.write_bool(self.sound_disabled);
+        w.write_u8(self.write_protect);
+        w.write_u16(self.irq_counter);
+        w.write_bool(self.irq_enabled);
+        self.audio.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.prg_ram)?;
+        r.read_bytes(&mut self.chr_ram)?;
+        r.read_bytes(&mut self.prg_banks)?;
+        r.read_bytes(&mut self.chr_banks)?;
+        self.chr_ram_disable = r.read_u8()?;
+        self.sound_disabled = r.read_bool()?;
+        self.write_protect = r.read_u8()?;
+        self.irq_counter = r.read_u16()? & 0x7FFF;
+        self.irq_enabled = r.read_bool()?;
+        self.audio.load(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::{test_rom, Mapper};
+    use std::env;
+
+    #[test]
+    fn prg_banks() {
+        let mut n163 = N163::new(test_rom(19, 16, 8), None).unwrap();
+        n163.write(0xE000, 0x45);
+        n163.write(0xE800, 0x06);
+        n163.write(0xF000, 0x47);
+        assert_eq!(n163.peek(0x8000), Some(5));
+        assert_eq!(n163.peek(0xA000), Some(6));
+        assert_eq!(n163.peek(0xC000), Some(7));
+        assert_eq!(n163.peek(0xE000), Some(15));
+        // bit 6 of $E000 turns the sound off, not a PRG bit
+        assert!(n163.sound_disabled);
+    }
+
+    #[test]
+    fn chr_and_nametable_banks() {
+        let mut n163 = N163::new(test_rom(19, 4, 32), None).unwrap();
+        n163.write(0x8000, 3);
+        n163.write(0xB800, 0xE1);
+        n163.write(0xC000, 0xE1);
+        n163.write(0xC800, 0x10);
+        assert!(matches!(n163.ppu_read(0x0000, PpuFetch::Background), PpuAccess::Cartridge(3)));
+        assert!(matches!(n163.ppu_read(0x1C05, PpuFetch::Sprite), PpuAccess::Ciram(0x0405)));
+        assert!(matches!(n163.ppu_read(0x2005, PpuFetch::Nametable), PpuAccess::Ciram(0x0405)));
+        // nametables can come from CHR-ROM too
+        assert!(matches!(n163.ppu_read(0x2400, PpuFetch::Nametable), PpuAccess::Cartridge(16)));
+        // and $3000-$3EFF mirrors $2000-$2EFF
+        assert!(matches!(n163.ppu_read(0x3005, PpuFetch::Nametable), PpuAccess::Ciram(0x0405)));
+        // $E800 bit 7 keeps $1000-$1FFF on CHR-ROM
+        n163.write(0xE800, 0x80);
+        assert!(matches!(n163.ppu_read(0x1C05, PpuFetch::Sprite), PpuAccess::Cartridge(1)));
+    }
+
+    #[test]
+    fn prg_ram_write_protection() {
+        let mut n163 = N163::new(test_rom(19, 4, 8), None).unwrap();
+        n163.write(0x6000, 1);
+        assert_eq!(n163.peek(0x6000), Some(0));
+        // all but the second 2K window
+        n163.write(0xF800, 0x42);
+        n163.write(0x6000, 1);
+        n163.write(0x6800, 2);
+        n163.write(0x7800, 3);
+        assert_eq!(n163.peek(0x6000), Some(1));
+        assert_eq!(n163.peek(0x6800), Some(0));
+        assert_eq!(n163.peek(0x7800), Some(3));
+    }
+
+    #[test]
+    fn irq() {
+        let mut n163 = N163::new(test_rom(19, 4, 8), None).unwrap();
+        n163.write(0x5000, 0xFD);
+        n163.write(0x5800, 0xFF);
+        assert_eq!(n163.peek(0x5000), Some(0xFD));
+        assert_eq!(n163.peek(0x5800), Some(0xFF));
+        n163.tick();
+        assert!(!n163.irq());
+        n163.tick();
+        assert!(n163.irq());
+        // it stops there
+        n163.tick();
+        assert_eq!(n163.peek(0x5000), Some(0xFF));
+        assert!(n163.irq());
+        n163.write(0x5800, 0x7F);
+        assert!(!n163.irq());
+    }
+
+    #[test]
+    fn sound_ram_port() {
+        let mut n163 = N163::new(test_rom(19, 4, 8), None).unwrap();
+        n163.write(0xF800, 0x80 | 0x10);
+        n163.write(0x4800, 0xAB);
+        n163.write(0x4800, 0xCD);
+        n163.write(0xF800, 0x10);
+        assert_eq!(n163.read(0x4800), Some(0xAB));
+        assert_eq!(n163.read(0x4800), Some(0xAB));
+        n163.write(0xF800, 0x91);
+        assert_eq!(n163.peek(0x4800), Some(0xCD));
+    }
+
+    #[test]
+    fn battery() {
+        let path = env::temp_dir().join(format!("futilenes-n163-{}.sav", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut rom = test_rom(19, 4, 8);
+        rom.battery = true;
+        let mut n163 = N163::new(rom, Some(path.clone())).unwrap();
+        n163.write(0xF800, 0x40);
+        n163.write(0x6123, 0x12);
+        n163.write(0xF800, 0xFF);
+        n163.write(0x4800, 0x34);
+        n163.flush_save().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0x2000 + 0x80);
+
+        let mut rom = test_rom(19, 4, 8);
+        rom.battery = true;
+        let n163 = N163::new(rom, Some(path.clone())).unwrap();
+        assert_eq!(n163.peek(0x6123), Some(0x12));
+        assert_eq!(n163.audio.ram()[0x7F], 0x34);
+
+        fs::write(&path, [0; 10]).unwrap();
+        let mut rom = test_rom(19, 4, 8);
+        rom.battery = true;
+        assert!(N163::new(rom, Some(path.clone())).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+// Namco 163 sound
+//
+// Up to eight channels playing 4-bit wavetables out of the chip's 128
+// bytes of internal RAM, which also holds their registers. Channel n
+// (0-7) keeps its eight registers at $40 + 8n:
+//   +0, +2, +4   18-bit frequency, low to high (+4 bits 0-1)
+//   +1, +3, +5   24-bit phase, low to high
+//   +4           wave length in samples, 256 - 4 * (bits 2-7)
+//   +6           wave address in samples
+//   +7           volume (0-3); on channel 7, bits 4-6 are the number of
+//                channels enabled, minus one
+// Channels are enabled from 7 downwards. Samples are two to a byte, the
+// low nibble first, so a wave can start anywhere in RAM, including over
+// the registers.
+//
+// The chip updates one channel every 15 cycles and outputs them in turn,
+// which at 8 channels is audible as a whine. Like most emulators, this
+// averages them instead.
+//
+// The RAM is reached through an address port ($F800 on the board) and a
+// data port ($4800), with optional auto-increment.
+
+use apu;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+// CPU cycles between channel updates.
+const UPDATE_CYCLES: u8 = 15;
+
+pub struct N163Audio {
+    ram: [u8; 0x80],
+    address: u8,
+    auto_increment: bool,
+    // The channel updated next, and the cycles until then.
+    channel: u8,
+    divider: u8,
+    outputs: [u8; 8],
+}
+
+impl N163Audio {
+    pub fn new() -> N163Audio {
+        N163Audio {
+            ram: [0; 0x80],
+            address: 0,
+            auto_increment: false,
+            channel: 7,
+            divider: UPDATE_CYCLES,
+            outputs: [0; 8],
+        }
+    }
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+    pub fn write_address(&mut self, value: u8) {
+        self.address = value & 0x7F;
+        self.auto_increment = value & 0x80 != 0;
+    }
+    pub fn peek_data(&self) -> u8 {
+        self.ram[self.address as usize]
+    }
+    pub fn read_data(&mut self) -> u8 {
+        let value = self.peek_data();
+        self.increment();
+        value
+    }
+    pub fn write_data(&mut self, value: u8) {
+        self.ram[self.address as usize] = value;
+        self.increment();
+    }
+    fn increment(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+    }
+    fn enabled_channels(&self) -> u8 {
+        ((self.ram[0x7F] >> 4) & 0x07) + 1
+    }
+    fn update_channel(&mut self, channel: u8) {
+        let base = 0x40 + channel as usize * 8;
+        let regs = &mut self.ram[base..base + 8];
+        let frequency = regs[0] as u32 | (regs[2] as u32) << 8 | ((regs[4] & 0x03) as u32) << 16;
+        let length = (256 - (regs[4] & 0xFC) as u32) << 16;
+        let mut phase = regs[1] as u32 | (regs[3] as u32) << 8 | (regs[5] as u32) << 16;
+        phase = (phase + frequency) % length;
+        regs[1] = phase as u8;
+        regs[3] = (phase >> 8) as u8;
+        regs[5] = (phase >> 16) as u8;
+        let sample = ((phase >> 16) + regs[6] as u32) & 0xFF;
+        let volume = regs[7] & 0x0F;
+        let byte = self.ram[(sample >> 1) as usize];
+        let nibble = if sample & 1 == 0 { byte & 0x0F } else { byte >> 4 };
+        self.outputs[channel as usize] = nibble * volume;
+    }
+    // One CPU cycle.
+    pub fn clock(&mut self) {
+        self.divider -= 1;
+        if self.divider > 0 {
+            return;
+        }
+        self.divider = UPDATE_CYCLES;
+        let channel = self.channel;
+        self.update_channel(channel);
+        self.channel = if channel <= 8 - self.enabled_channels() { 7 } else { channel - 1 };
+    }
+    // On the APU's scale: the average channel at full volume is half as
+    // loud again as an APU pulse at 15.
+    pub fn output(&self) -> f32 {
+        let enabled = self.enabled_channels();
+        let sum: u32 = self.outputs[(8 - enabled) as usize..].iter().map(|&o| o as u32).sum();
+        sum as f32 / (225.0 * enabled as f32) * 1.5 * apu::PULSE_FULL_VOLUME
+    }
+}
+
+impl Savable for N163Audio {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.ram);
+        w.write_u8(self.address);
+        w.write_bool(self.auto_increment);
+        w.write_u8(self.channel);
+        w.write_u8(self.divider);
+        w.write_bytes(&self.outputs);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.ram)?;
+        self.address = r.read_u8()? & 0x7F;
+        self.auto_increment = r.read_bool()?;
+        self.channel = r.read_u8()? & 0x07;
+        self.divider = r.read_u8()?.clamp(1, UPDATE_CYCLES);
+        r.read_bytes(&mut self.outputs)?;
+        Ok(())
+    }
+}
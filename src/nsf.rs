@@ -0,0 +1,788 @@
+// NSF and NSFe music files
+//
+// An NSF is the sound code and data ripped out of a game, plus a header
+// saying where to load it and which routines to call: INIT once with the
+// song number in A, then PLAY at a fixed rate (normally once a frame).
+// Header layout (128 bytes, little endian):
+//   $00  "NESM\x1A", version
+//   $06  song count, first song (from 1)
+//   $08  load, init and play addresses
+//   $0E  name, artist and copyright, 32 bytes each
+//   $6E  NTSC play period in microseconds
+//   $70  initial 4K banks for $8000-$FFFF; all zeros means no banking
+//   $78  PAL play period
+//   $7A  bit 0 PAL, bit 1 both
+//   $7B  expansion chips: VRC6, VRC7, FDS, MMC5, N163, 5B
+//   $7D  (NSF2) data length, 0 meaning the rest of the file
+// NSFe holds the same things in chunks of a length, an ID and the data,
+// with names in "auth" and per-song names in "tlbl".
+//
+// Banked files switch 4K pages into $8000-$FFFF by writing to $5FF8-$5FFF,
+// with the data padded so that its load address lands in the right place
+// in the first page. FDS tunes get RAM at $6000-$FFFF instead, and bank
+// into it through $5FF6-$5FFF.
+//
+// Player drives the CPU directly: it sets up the registers and pushes a
+// return address as if the routine had been reached with a JSR, and the
+// return lands in a JMP-to-itself at $4100 where the CPU idles until the
+// next call.
+
+use std::fmt;
+
+use bus::Bus;
+use cpu::CPU;
+use fds_audio::FdsAudio;
+use mapper::Mapper;
+use memory::RamInit;
+use region::Region;
+use rom;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+use vrc6_audio::Vrc6Audio;
+use n163_audio::N163Audio;
+use sunsoft5b_audio::Sunsoft5bAudio;
+use mmc5_audio::Mmc5Audio;
+
+pub const MAGIC: &[u8] = b"NESM\x1A";
+pub const NSFE_MAGIC: &[u8] = b"NSFE";
+const HEADER_SIZE: usize = 128;
+
+// Expansion chip bits in the header, in the order of CHIP_NAMES.
+const CHIP_VRC6: u8 = 0x01;
+const CHIP_FDS: u8 = 0x04;
+const CHIP_MMC5: u8 = 0x08;
+const CHIP_N163: u8 = 0x10;
+const CHIP_5B: u8 = 0x20;
+const CHIP_NAMES: [&str; 6] = ["VRC6", "VRC7", "FDS", "MMC5", "N163", "5B"];
+// The chips whose sound is emulated.
+const SUPPORTED_CHIPS: u8 = CHIP_VRC6 | CHIP_FDS | CHIP_MMC5 | CHIP_N163 | CHIP_5B;
+
+// The idle loop routines return to.
+const DRIVER: u16 = 0x4100;
+const DRIVER_CODE: [u8; 3] = [0x4C, DRIVER as u8, (DRIVER >> 8) as u8];
+
+// NSFe files without a RATE chunk play at the frame rate.
+const DEFAULT_NTSC_PERIOD: u16 = 16639;
+const DEFAULT_PAL_PERIOD: u16 = 19997;
+
+#[derive(Debug)]
+pub enum NsfError {
+    UnknownFormat,
+    Truncated,
+    Invalid(&'static str),
+    UnknownChunk(String),
+}
+
+impl fmt::Display for NsfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NsfError::UnknownFormat => write!(f, "not an NSF or NSFe file"),
+            NsfError::Truncated => write!(f, "NSF file is truncated"),
+            NsfError::Invalid(what) => write!(f, "{}", what),
+            NsfError::UnknownChunk(ref id) => write!(f, "NSFe file needs unsupported chunk {}", id),
+        }
+    }
+}
+
+pub struct NsfFile {
+    pub songs: u8,
+    // From 1, like the header.
+    pub first_song: u8,
+    pub load: u16,
+    pub init: u16,
+    pub play: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub track_names: Vec<String>,
+    pub ntsc_period: u16,
+    pub pal_period: u16,
+    pub banks: Option<[u8; 8]>,
+    pub region: Region,
+    pub chips: u8,
+    pub data: Vec<u8>,
+}
+
+impl NsfFile {
+    pub fn load(bin: &[u8]) -> Result<NsfFile, NsfError> {
+        if bin.starts_with(MAGIC) {
+            NsfFile::from_nsf(bin)
+        }
+        else if bin.starts_with(NSFE_MAGIC) {
+            NsfFile::from_nsfe(bin)
+        }
+        else {
+            Err(NsfError::UnknownFormat)
+        }
+    }
+    fn from_nsf(bin: &[u8]) -> Result<NsfFile, NsfError> {
+        let header = bin.get(..HEADER_SIZE).ok_or(NsfError::Truncated)?;
+        let word = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+        let mut banks = [0; 8];
+        banks.copy_from_slice(&header[0x70..0x78]);
+        let data_len = header[0x7D] as usize | (header[0x7E] as usize) << 8 | (header[0x7F] as usize) << 16;
+        let data = if header[5] >= 2 && data_len != 0 {
+            bin.get(HEADER_SIZE..HEADER_SIZE + data_len).ok_or(NsfError::Truncated)?
+        }
+        else {
+            &bin[HEADER_SIZE..]
+        };
+        NsfFile {
+            songs: header[6],
+            first_song: header[7],
+            load: word(0x08),
+            init: word(0x0A),
+            play: word(0x0C),
+            title: string(&header[0x0E..0x2E]),
+            artist: string(&header[0x2E..0x4E]),
+            copyright: string(&header[0x4E..0x6E]),
+            track_names: Vec::new(),
+            ntsc_period: word(0x6E),
+            pal_period: word(0x78),
+            banks: if banks.iter().any(|&b| b != 0) { Some(banks) } else { None },
+            region: region(header[0x7A]),
+            chips: header[0x7B],
+            data: data.to_vec(),
+        }.check()
+    }
+    fn from_nsfe(bin: &[u8]) -> Result<NsfFile, NsfError> {
+        let mut nsf = NsfFile {
+            songs: 1,
+            first_song: 1,
+            load: 0,
+            init: 0,
+            play: 0,
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            track_names: Vec::new(),
+            ntsc_period: DEFAULT_NTSC_PERIOD,
+            pal_period: DEFAULT_PAL_PERIOD,
+            banks: None,
+            region: Region::Ntsc,
+            chips: 0,
+            data: Vec::new(),
+        };
+        let mut has_info = false;
+        let mut pos = NSFE_MAGIC.len();
+        loop {
+            let header = bin.get(pos..pos + 8).ok_or(NsfError::Truncated)?;
+            let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let id = &header[4..8];
+            pos += 8;
+            let data = bin.get(pos..pos + len).ok_or(NsfError::Truncated)?;
+            pos += len;
+            let word = |i: usize| data.get(i..i + 2).map(|w| u16::from_le_bytes([w[0], w[1]]));
+            match id {
+                b"INFO" => {
+                    if data.len() < 8 {
+                        return Err(NsfError::Truncated);
+                    }
+                    has_info = true;
+                    nsf.load = word(0).unwrap();
+                    nsf.init = word(2).unwrap();
+                    nsf.play = word(4).unwrap();
+                    nsf.region = region(data[6]);
+                    nsf.chips = data[7];
+                    nsf.songs = data.get(8).cloned().unwrap_or(1);
+                    // NSFe counts from 0
+                    nsf.first_song = data.get(9).cloned().unwrap_or(0).saturating_add(1);
+                }
+                b"DATA" => nsf.data = data.to_vec(),
+                b"BANK" => {
+                    let mut banks = [0; 8];
+                    for (b, &d) in banks.iter_mut().zip(data) {
+                        *b = d;
+                    }
+                    nsf.banks = Some(banks);
+                }
+                b"RATE" => {
+                    nsf.ntsc_period = word(0).unwrap_or(nsf.ntsc_period);
+                    nsf.pal_period = word(2).unwrap_or(nsf.pal_period);
+                }
+                b"auth" => {
+                    let mut fields = data.split(|&b| b == 0).map(string);
+                    nsf.title = fields.next().unwrap_or_default();
+                    nsf.artist = fields.next().unwrap_or_default();
+                    nsf.copyright = fields.next().unwrap_or_default();
+                }
+                b"tlbl" => nsf.track_names = data.split(|&b| b == 0).map(string).collect(),
+                b"NEND" => break,
+                // chunks starting with a capital letter can't be skipped
+                _ if id[0].is_ascii_uppercase() => {
+                    return Err(NsfError::UnknownChunk(String::from_utf8_lossy(id).into_owned()));
+                }
+                _ => {}
+            }
+        }
+        if !has_info {
+            return Err(NsfError::Invalid("NSFe file has no INFO chunk"));
+        }
+        nsf.check()
+    }
+    fn check(mut self) -> Result<NsfFile, NsfError> {
+        if self.songs == 0 {
+            return Err(NsfError::Invalid("NSF file has no songs"));
+        }
+        self.first_song = self.first_song.clamp(1, self.songs);
+        // rippers leave a zero for "the usual rate"
+        if self.ntsc_period == 0 {
+            self.ntsc_period = DEFAULT_NTSC_PERIOD;
+        }
+        if self.pal_period == 0 {
+            self.pal_period = DEFAULT_PAL_PERIOD;
+        }
+        if self.data.is_empty() {
+            return Err(NsfError::Invalid("NSF file has no data"));
+        }
+        let lowest = if self.chips & CHIP_FDS != 0 { 0x6000 } else { 0x8000 };
+        if self.load < lowest {
+            return Err(NsfError::Invalid("NSF load address is too low"));
+        }
+        Ok(self)
+    }
+    // The name of song `n` (from 1), if the file has one.
+    pub fn track_name(&self, n: u8) -> Option<&str> {
+        self.track_names.get(n as usize - 1).map(|s| s.as_str()).filter(|s| !s.is_empty())
+    }
+    // In microseconds.
+    pub fn play_period(&self) -> u16 {
+        match self.region {
+            Region::Pal => self.pal_period,
+            _ => self.ntsc_period,
+        }
+    }
+    // The chips the tune uses, by name.
+    pub fn chip_names(&self) -> Vec<&'static str> {
+        CHIP_NAMES.iter().enumerate().filter(|&(i, _)| self.chips & 1 << i != 0).map(|(_, &n)| n).collect()
+    }
+    pub fn unsupported_chips(&self) -> u8 {
+        self.chips & !SUPPORTED_CHIPS
+    }
+}
+
+fn string(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+// Tunes for both regions are played as NTSC.
+fn region(flags: u8) -> Region {
+    if flags & 0x03 == 0x01 { Region::Pal } else { Region::Ntsc }
+}
+
+// The cartridge side of an NSF player: the tune's banks, RAM, the idle loop
+// and any expansion sound.
+pub struct NsfMapper {
+    rom: rom::Cartridge,
+    // The data in 4K pages, starting at the page holding the load address.
+    pages: Vec<[u8; 0x1000]>,
+    banks: [u8; 8],
+    banked: bool,
+    // $6000-$7FFF, or $6000-$FFFF for FDS tunes.
+    ram: Vec<u8>,
+    fds: Option<FdsAudio>,
+    vrc6: Option<Vrc6Audio>,
+    n163: Option<N163Audio>,
+    s5b: Option<Sunsoft5bAudio>,
+    mmc5: Option<Mmc5Audio>,
+    // MMC5 tunes also get its ExRAM (up to the bank registers) and
+    // multiplier.
+    exram: Vec<u8>,
+    multiplier: [u8; 2],
+}
+
+impl NsfMapper {
+    pub fn new(nsf: &NsfFile) -> NsfMapper {
+        let fds = nsf.chips & CHIP_FDS != 0;
+        // unbanked tunes are laid out from $8000 (or $6000 on FDS), and
+        // then treated like banked ones
+        let base = if nsf.banks.is_some() { nsf.load & 0xF000 } else if fds { 0x6000 } else { 0x8000 };
+        let mut image = vec![0; (nsf.load - base) as usize];
+        image.extend_from_slice(&nsf.data);
+        let pages = image.chunks(0x1000).map(|chunk| {
+            let mut page = [0; 0x1000];
+            page[..chunk.len()].copy_from_slice(chunk);
+            page
+        }).collect();
+        NsfMapper {
+            rom: rom::Cartridge::from_nsf(nsf),
+            pages,
+            banks: [0, 1, 2, 3, 4, 5, 6, 7],
+            banked: nsf.banks.is_some(),
+            ram: vec![0; if fds { 0xA000 } else { 0x2000 }],
+            fds: if fds { Some(FdsAudio::new()) } else { None },
+            vrc6: if nsf.chips & CHIP_VRC6 != 0 { Some(Vrc6Audio::new()) } else { None },
+            n163: if nsf.chips & CHIP_N163 != 0 { Some(N163Audio::new()) } else { None },
+            s5b: if nsf.chips & CHIP_5B != 0 { Some(Sunsoft5bAudio::new()) } else { None },
+            mmc5: if nsf.chips & CHIP_MMC5 != 0 { Some(Mmc5Audio::new()) } else { None },
+            exram: vec![0; if nsf.chips & CHIP_MMC5 != 0 { 0x3F6 } else { 0 }],
+            multiplier: [0xFF; 2],
+        }
+    }
+    fn page(&self, bank: u8) -> &[u8; 0x1000] {
+        static EMPTY: [u8; 0x1000] = [0; 0x1000];
+        self.pages.get(bank as usize).unwrap_or(&EMPTY)
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn read(&mut self, address: u16) -> Option<u8> {
+        match (address, self.n163.as_mut(), self.mmc5.as_mut()) {
+            (0x4800..=0x4FFF, Some(n163), _) => Some(n163.read_data()),
+            (0x5010, _, Some(mmc5)) => mmc5.read(address),
+            _ => self.peek(address),
+        }
+    }
+    fn peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x4040..=0x4097 => self.fds.as_ref().and_then(|f| f.read(address)),
+            0x4800..=0x4FFF => self.n163.as_ref().map(|n| n.peek_data()),
+            0x5010 | 0x5015 => self.mmc5.as_ref().and_then(|m| m.peek(address)),
+            0x5205 | 0x5206 if self.mmc5.is_some() => {
+                let product = self.multiplier[0] as u16 * self.multiplier[1] as u16;
+                Some(if address == 0x5205 { product as u8 } else { (product >> 8) as u8 })
+            }
+            0x5C00..=0x5FF5 if self.mmc5.is_some() => Some(self.exram[(address - 0x5C00) as usize]),
+            DRIVER..=0x4102 => Some(DRIVER_CODE[(address - DRIVER) as usize]),
+            0x6000..=0xFFFF if self.fds.is_some() => Some(self.ram[(address - 0x6000) as usize]),
+            0x6000..=0x7FFF => Some(self.ram[(address - 0x6000) as usize]),
+            0x8000..=0xFFFF => {
+                let bank = self.banks[((address - 0x8000) >> 12) as usize];
+                Some(self.page(bank)[(address & 0x0FFF) as usize])
+            }
+            _ => None,
+        }
+    }
+    fn write(&mut self, address: u16, value: u8) {
+        // most sound registers sit over the tune's data, which on FDS
+        // tunes is RAM that still takes the write
+        if let Some(ref mut vrc6) = self.vrc6 {
+            if let 0x9000..=0xB002 = address {
+                vrc6.write(address, value);
+            }
+        }
+        if let Some(ref mut n163) = self.n163 {
+            match address {
+                0x4800..=0x4FFF => n163.write_data(value),
+                0xF800..=0xFFFF => n163.write_address(value),
+                _ => {}
+            }
+        }
+        if let Some(ref mut s5b) = self.s5b {
+            match address {
+                0xC000..=0xDFFF => s5b.select(value),
+                0xE000..=0xFFFF => s5b.write(value),
+                _ => {}
+            }
+        }
+        match address {
+            0x4040..=0x408A => {
+                if let Some(ref mut fds) = self.fds {
+                    fds.write(address, value);
+                }
+            }
+            0x5000..=0x5015 => {
+                if let Some(ref mut mmc5) = self.mmc5 {
+                    mmc5.write(address, value);
+                }
+            }
+            0x5205 | 0x5206 => self.multiplier[(address - 0x5205) as usize] = value,
+            0x5C00..=0x5FF5 if self.mmc5.is_some() => self.exram[(address - 0x5C00) as usize] = value,
+            0x5FF6..=0x5FFF if self.fds.is_some() => {
+                // FDS tunes copy the page into RAM
+                let start = (address - 0x5FF6) as usize * 0x1000;
+                let page = *self.page(value);
+                self.ram[start..start + 0x1000].copy_from_slice(&page);
+            }
+            0x5FF8..=0x5FFF => self.banks[(address - 0x5FF8) as usize] = value,
+            0x6000..=0xFFFF if self.fds.is_some() => self.ram[(address - 0x6000) as usize] = value,
+            0x6000..=0x7FFF => self.ram[(address - 0x6000) as usize] = value,
+            _ => {}
+        }
+    }
+    // Players start every song from cleared RAM, whatever the console
+    // does; unbanked FDS tunes are loaded straight into it.
+    fn power_on(&mut self, _init: RamInit) {
+        self.banks = [0, 1, 2, 3, 4, 5, 6, 7];
+        for b in self.ram.iter_mut().chain(&mut self.exram) {
+            *b = 0;
+        }
+        if self.mmc5.is_some() {
+            self.mmc5 = Some(Mmc5Audio::new());
+            self.multiplier = [0xFF; 2];
+        }
+        if self.vrc6.is_some() {
+            self.vrc6 = Some(Vrc6Audio::new());
+        }
+        if self.n163.is_some() {
+            self.n163 = Some(N163Audio::new());
+        }
+        if self.s5b.is_some() {
+            self.s5b = Some(Sunsoft5bAudio::new());
+        }
+        if self.fds.is_some() {
+            self.fds = Some(FdsAudio::new());
+            if !self.banked {
+                for (chunk, page) in self.ram.chunks_mut(0x1000).zip(&self.pages) {
+                    chunk.copy_from_slice(page);
+                }
+            }
+        }
+    }
+    fn rom(&self) -> &rom::Cartridge {
+        &self.rom
+    }
+    fn tick(&mut self) {
+        if let Some(ref mut fds) = self.fds {
+            fds.clock();
+        }
+        if let Some(ref mut vrc6) = self.vrc6 {
+            vrc6.clock();
+        }
+        if let Some(ref mut n163) = self.n163 {
+            n163.clock();
+        }
+        if let Some(ref mut s5b) = self.s5b {
+            s5b.clock();
+        }
+        if let Some(ref mut mmc5) = self.mmc5 {
+            mmc5.clock();
+        }
+    }
+    fn audio(&self) -> f32 {
+        let outputs = [
+            self.fds.as_ref().map(|f| f.output()),
+            self.vrc6.as_ref().map(|v| v.output()),
+            self.n163.as_ref().map(|n| n.output()),
+            self.s5b.as_ref().map(|s| s.output()),
+            self.mmc5.as_ref().map(|m| m.output()),
+        ];
+        outputs.iter().flatten().sum()
+    }
+}
+
+impl Savable for NsfMapper {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.banks);
+        w.write_bytes(&self.ram);
+        if let Some(ref fds) = self.fds {
+            fds.save(w);
+        }
+        if let Some(ref vrc6) = self.vrc6 {
+            vrc6.save(w);
+        }
+        if let Some(ref n163) = self.n163 {
+            n163.save(w);
+        }
+        if let Some(ref s5b) = self.s5b {
+            s5b.save(w);
+        }
+        if let Some(ref mmc5) = self.mmc5 {
+            mmc5.save(w);
+        }
+        w.write_bytes(&self.exram);
+        w.write_bytes(&self.multiplier);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.banks)?;
+        r.read_bytes(&mut self.ram)?;
+        if let Some(ref mut fds) = self.fds {
+            fds.load(r)?;
+        }
+        if let Some(ref mut vrc6) = self.vrc6 {
+            vrc6.load(r)?;
+        }
+        if let Some(ref mut n163) = self.n163 {
+            n163.load(r)?;
+        }
+        if let Some(ref mut s5b) = self.s5b {
+            s5b.load(r)?;
+        }
+        if let Some(ref mut mmc5) = self.mmc5 {
+            mmc5.load(r)?;
+        }
+        r.read_bytes(&mut self.exram)?;
+        r.read_bytes(&mut self.multiplier)?;
+        Ok(())
+    }
+}
+
+pub struct Player {
+    cpu: CPU,
+    init: u16,
+    play: u16,
+    banks: Option<[u8; 8]>,
+    fds: bool,
+    pal: bool,
+    // PLAY is due when cycles * 1000000 reaches this, so that periods that
+    // aren't a whole number of cycles don't drift.
+    next_play: u64,
+    play_period: u64,
+    // Set between calling a routine and it returning to the idle loop.
+    busy: bool,
+}
+
+impl Player {
+    pub fn new(nsf: &NsfFile) -> Player {
+        let mut cpu = CPU::new(Box::new(NsfMapper::new(nsf)));
+        cpu.set_trace(false);
+        cpu.set_region(nsf.region);
+        Player {
+            cpu,
+            init: nsf.init,
+            play: nsf.play,
+            banks: nsf.banks,
+            fds: nsf.chips & CHIP_FDS != 0,
+            pal: nsf.region == Region::Pal,
+            next_play: 0,
+            play_period: nsf.play_period() as u64 * nsf.region.clock_rate(),
+            busy: false,
+        }
+    }
+    // Sets up the machine the way the NSF spec asks and calls INIT for
+    // song `n` (from 1).
+    pub fn start(&mut self, n: u8) {
+        // this also clears $6000-$7FFF, see NsfMapper::power_on()
+        self.cpu.power_on();
+        let bus = self.cpu.bus_mut();
+        for address in 0x0000..0x0800 {
+            bus.write(address, 0);
+        }
+        for address in 0x4000..0x4014 {
+            bus.write(address, 0);
+        }
+        bus.write(0x4015, 0x0F);
+        // 4-step frame counter, no frame IRQ
+        bus.write(0x4017, 0x40);
+        if self.fds {
+            bus.write(0x4089, 0x80);
+            bus.write(0x408A, 0xE8);
+        }
+        if let Some(banks) = self.banks {
+            // FDS tunes also bank $6000 and $7000, from the $E000 and
+            // $F000 values
+            if self.fds {
+                bus.write(0x5FF6, banks[6]);
+                bus.write(0x5FF7, banks[7]);
+            }
+            for (i, &bank) in banks.iter().enumerate() {
+                bus.write(0x5FF8 + i as u16, bank);
+            }
+        }
+        let (init, pal) = (self.init, self.pal as u8);
+        self.call(init, n - 1, pal);
+        self.next_play = self.cpu.cycles() * 1_000_000 + self.play_period;
+    }
+    // Runs the CPU for `cycles` cycles, calling PLAY whenever it's due and
+    // the last call has returned.
+    pub fn run(&mut self, cycles: u64) {
+        let end = self.cpu.cycles() + cycles;
+        while self.cpu.cycles() < end {
+            if self.busy && self.cpu.pc() == DRIVER {
+                self.busy = false;
+            }
+            if self.cpu.cycles() * 1_000_000 >= self.next_play {
+                self.next_play += self.play_period;
+                if !self.busy {
+                    let play = self.play;
+                    self.call(play, 0, 0);
+                }
+            }
+            self.cpu.step();
+        }
+    }
+    pub fn take_audio(&mut self, out: &mut Vec<f32>) {
+        self.cpu.take_audio(out);
+    }
+    pub fn clock_rate(&self) -> u64 {
+        self.cpu.region().clock_rate()
+    }
+    // A JSR to `address` from the idle loop.
+    fn call(&mut self, address: u16, a: u8, x: u8) {
+        let mut r = self.cpu.registers();
+        let ret = DRIVER.wrapping_sub(1);
+        let bus = self.cpu.bus_mut();
+        bus.write(0x0100 | r.sp as u16, (ret >> 8) as u8);
+        bus.write(0x0100 | r.sp.wrapping_sub(1) as u16, ret as u8);
+        r.sp = r.sp.wrapping_sub(2);
+        r.pc = address;
+        r.a = a;
+        r.x = x;
+        r.y = 0;
+        self.cpu.set_registers(&r);
+        self.busy = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // INIT keeps A and X at $00 and $01 and counts its calls at $02, PLAY
+    // counts its calls at $03.
+    const TUNE: &[u8] = &[
+        0x85, 0x00,             // 8000  STA $00
+        0x86, 0x01,             // 8002  STX $01
+        0xE6, 0x02,             // 8004  INC $02
+        0x60,                   // 8006  RTS
+        0xE6, 0x03,             // 8007  INC $03
+        0x60,                   // 8009  RTS
+    ];
+
+    fn nsf(songs: u8, first: u8, load: u16) -> Vec<u8> {
+        let mut bin = MAGIC.to_vec();
+        bin.resize(HEADER_SIZE, 0);
+        bin[5] = 1;
+        bin[6] = songs;
+        bin[7] = first;
+        bin[0x08..0x0A].copy_from_slice(&load.to_le_bytes());
+        bin[0x0A..0x0C].copy_from_slice(&0x8000u16.to_le_bytes());
+        bin[0x0C..0x0E].copy_from_slice(&0x8007u16.to_le_bytes());
+        bin[0x0E..0x13].copy_from_slice(b"Title");
+        bin[0x2E..0x34].copy_from_slice(b"Artist");
+        bin[0x4E..0x52].copy_from_slice(b"1986");
+        bin[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        bin.extend_from_slice(TUNE);
+        bin
+    }
+
+    fn chunk(bin: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+        bin.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bin.extend_from_slice(id);
+        bin.extend_from_slice(data);
+    }
+
+    #[test]
+    fn nsf_header() {
+        let mut bin = nsf(5, 2, 0x8000);
+        bin[0x70..0x78].copy_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        bin[0x7A] = 0x01;
+        bin[0x7B] = CHIP_VRC6 | CHIP_N163 | 0x02;
+        let nsf = NsfFile::load(&bin).unwrap();
+        assert_eq!((nsf.songs, nsf.first_song), (5, 2));
+        assert_eq!((nsf.load, nsf.init, nsf.play), (0x8000, 0x8000, 0x8007));
+        assert_eq!((nsf.title.as_str(), nsf.artist.as_str(), nsf.copyright.as_str()), ("Title", "Artist", "1986"));
+        assert_eq!(nsf.banks, Some([0, 1, 2, 3, 4, 5, 6, 7]));
+        assert_eq!(nsf.region, Region::Pal);
+        // zero means the usual rate
+        assert_eq!(nsf.play_period(), DEFAULT_PAL_PERIOD);
+        assert_eq!(nsf.chip_names(), ["VRC6", "VRC7", "N163"]);
+        assert_eq!(nsf.unsupported_chips(), 0x02);
+        assert_eq!(nsf.data, TUNE);
+        assert_eq!(nsf.track_name(1), None);
+    }
+
+    #[test]
+    fn nsf2_data_length() {
+        let mut bin = nsf(1, 9, 0x8000);
+        bin[5] = 2;
+        bin[0x7D] = 4;
+        bin.extend_from_slice(b"metadata");
+        let nsf = NsfFile::load(&bin).unwrap();
+        assert_eq!(nsf.data, &TUNE[..4]);
+        assert_eq!(nsf.first_song, 1);
+        assert_eq!(nsf.banks, None);
+        assert_eq!(nsf.region, Region::Ntsc);
+
+        bin[0x7E] = 1;
+        assert!(matches!(NsfFile::load(&bin), Err(NsfError::Truncated)));
+    }
+
+    #[test]
+    fn bad_nsfs() {
+        assert!(matches!(NsfFile::load(b"NESM"), Err(NsfError::UnknownFormat)));
+        assert!(matches!(NsfFile::load(&nsf(1, 1, 0x8000)[..100]), Err(NsfError::Truncated)));
+        assert!(matches!(NsfFile::load(&nsf(0, 1, 0x8000)), Err(NsfError::Invalid(_))));
+        assert!(matches!(NsfFile::load(&nsf(1, 1, 0x6000)), Err(NsfError::Invalid(_))));
+        assert!(matches!(NsfFile::load(&nsf(1, 1, 0x8000)[..HEADER_SIZE]), Err(NsfError::Invalid(_))));
+        // FDS tunes can load into RAM
+        let mut bin = nsf(1, 1, 0x6000);
+        bin[0x7B] = CHIP_FDS;
+        assert!(NsfFile::load(&bin).is_ok());
+    }
+
+    #[test]
+    fn nsfe_chunks() {
+        let mut bin = NSFE_MAGIC.to_vec();
+        chunk(&mut bin, b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x07, 0x80, 0x00, CHIP_5B, 3, 1]);
+        chunk(&mut bin, b"DATA", TUNE);
+        chunk(&mut bin, b"BANK", &[1, 2]);
+        chunk(&mut bin, b"RATE", &[0x0A, 0x1A]);
+        chunk(&mut bin, b"auth", b"Title\0Artist\0\0Ripper");
+        chunk(&mut bin, b"tlbl", b"One\0\0Three");
+        chunk(&mut bin, b"text", b"skipped");
+        chunk(&mut bin, b"NEND", &[]);
+        let nsf = NsfFile::load(&bin).unwrap();
+        assert_eq!((nsf.songs, nsf.first_song), (3, 2));
+        assert_eq!((nsf.load, nsf.init, nsf.play), (0x8000, 0x8000, 0x8007));
+        assert_eq!(nsf.chips, CHIP_5B);
+        assert_eq!(nsf.data, TUNE);
+        assert_eq!(nsf.banks, Some([1, 2, 0, 0, 0, 0, 0, 0]));
+        assert_eq!((nsf.ntsc_period, nsf.pal_period), (0x1A0A, DEFAULT_PAL_PERIOD));
+        assert_eq!((nsf.title.as_str(), nsf.artist.as_str(), nsf.copyright.as_str()), ("Title", "Artist", ""));
+        assert_eq!((nsf.track_name(1), nsf.track_name(2), nsf.track_name(3)), (Some("One"), None, Some("Three")));
+    }
+
+    #[test]
+    fn bad_nsfes() {
+        let mut bin = NSFE_MAGIC.to_vec();
+        chunk(&mut bin, b"DATA", TUNE);
+        chunk(&mut bin, b"NEND", &[]);
+        assert!(matches!(NsfFile::load(&bin), Err(NsfError::Invalid(_))));
+
+        let mut bin = NSFE_MAGIC.to_vec();
+        chunk(&mut bin, b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x07, 0x80, 0x00, 0x00]);
+        chunk(&mut bin, b"DATA", TUNE);
+        chunk(&mut bin, b"VRC7", &[]);
+        chunk(&mut bin, b"NEND", &[]);
+        assert!(matches!(NsfFile::load(&bin), Err(NsfError::UnknownChunk(ref id)) if id == "VRC7"));
+
+        // no NEND
+        let mut bin = NSFE_MAGIC.to_vec();
+        chunk(&mut bin, b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x07, 0x80, 0x00, 0x00]);
+        assert!(matches!(NsfFile::load(&bin), Err(NsfError::Truncated)));
+    }
+
+    #[test]
+    fn banking() {
+        // loaded halfway into the first page
+        let mut bin = nsf(1, 1, 0x8800);
+        bin[0x70..0x78].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        bin[0x71] = 1;
+        bin.resize(HEADER_SIZE + 0x1000, 0xAA);
+        let nsf = NsfFile::load(&bin).unwrap();
+        let mut mapper = NsfMapper::new(&nsf);
+        mapper.write(0x5FF8, 0);
+        mapper.write(0x5FF9, 1);
+        assert_eq!(mapper.peek(0x8800), Some(0x85));
+        assert_eq!(mapper.peek(0x9000), Some(0xAA));
+        assert_eq!(mapper.peek(0x9800), Some(0x00));
+        mapper.write(0x5FFF, 0);
+        assert_eq!(mapper.peek(0xF800), Some(0x85));
+        // past the end of the data
+        mapper.write(0x5FF8, 9);
+        assert_eq!(mapper.peek(0x8000), Some(0x00));
+        assert_eq!(mapper.peek(DRIVER), Some(0x4C));
+    }
+
+    #[test]
+    fn player_calls_init_and_play() {
+        let nsf = NsfFile::load(&nsf(3, 1, 0x8000)).unwrap();
+        let mut player = Player::new(&nsf);
+        player.start(3);
+        let second = player.clock_rate();
+        player.run(second);
+        assert_eq!(player.cpu.peek(0x00), 2);
+        assert_eq!(player.cpu.peek(0x01), 0);
+        assert_eq!(player.cpu.peek(0x02), 1);
+        let plays = player.cpu.peek(0x03);
+        assert!(plays == 60 || plays == 61, "{} plays", plays);
+        assert_eq!(player.cpu.pc(), DRIVER);
+
+        // starting again clears RAM
+        player.start(1);
+        player.run(10);
+        assert_eq!(player.cpu.peek(0x02), 1);
+        assert_eq!(player.cpu.peek(0x03), 0);
+    }
+}
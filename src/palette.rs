@@ -0,0 +1,144 @@
+// Turns PPU output into RGB.
+//
+// A pixel from the PPU is a 6-bit palette index in bits 0-5 and the three
+// PPUMASK colour emphasis bits (red, green, blue on the 2C02) in bits 6-8.
+// A Palette holds all 512 combinations as 0x00RRGGBB.
+
+use std::fmt;
+
+// A typical 2C02 palette, 0x00RRGGBB.
+static NTSC: [u32; 64] = [
+    0x666666, 0x002A88, 0x1412A7, 0x3B00A4, 0x5C007E, 0x6E0040, 0x6C0600, 0x561D00,
+    0x333500, 0x0B4800, 0x005200, 0x004F08, 0x00404D, 0x000000, 0x000000, 0x000000,
+    0xADADAD, 0x155FD9, 0x4240FF, 0x7527FE, 0xA01ACC, 0xB71E7B, 0xB53120, 0x994E00,
+    0x6B6D00, 0x388700, 0x0C9300, 0x008F32, 0x007C8D, 0x000000, 0x000000, 0x000000,
+    0xFFFEFF, 0x64B0FF, 0x9290FF, 0xC676FF, 0xF36AFF, 0xFE6ECC, 0xFE8170, 0xEA9E22,
+    0xBCBE00, 0x88D800, 0x5CE430, 0x45E082, 0x48CDDE, 0x4F4F4F, 0x000000, 0x000000,
+    0xFFFEFF, 0xC0DFFF, 0xD3D2FF, 0xE8C8FF, 0xFBC2FF, 0xFEC4EA, 0xFECCC5, 0xF7D8A5,
+    0xE4E594, 0xCFEF96, 0xBDF4AB, 0xB3F3CC, 0xB5EBF2, 0xB8B8B8, 0x000000, 0x000000,
+];
+
+// How much an emphasis bit darkens the colour channels it doesn't emphasise.
+const EMPHASIS_ATTENUATION: f32 = 0.816328;
+
+#[derive(Debug)]
+pub struct PaletteError(usize);
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a .pal file should be 192 or 1536 bytes, not {}", self.0)
+    }
+}
+
+pub struct Palette {
+    colors: Vec<u32>,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::with_generated_emphasis(&NTSC)
+    }
+}
+
+impl Palette {
+    // Loads a .pal file: either 64 RGB triplets, in which case the emphasis
+    // colours are derived from them, or 512 triplets covering every
+    // emphasis combination.
+    pub fn from_pal(data: &[u8]) -> Result<Palette, PaletteError> {
+        if data.len() != 192 && data.len() != 1536 {
+            return Err(PaletteError(data.len()));
+        }
+        let colors: Vec<u32> = data.chunks(3)
+            .map(|c| (c[0] as u32) << 16 | (c[1] as u32) << 8 | c[2] as u32)
+            .collect();
+        if colors.len() == 64 {
+            Ok(Palette::with_generated_emphasis(&colors))
+        } else {
+            Ok(Palette { colors })
+        }
+    }
+
+    fn with_generated_emphasis(base: &[u32]) -> Palette {
+        let mut colors = Vec::with_capacity(512);
+        for emphasis in 0..8 {
+            for &c in base {
+                colors.push(emphasize(c, emphasis));
+            }
+        }
+        Palette { colors }
+    }
+
+    pub fn rgb(&self, pixel: u16) -> u32 {
+        self.colors[(pixel & 0x1FF) as usize]
+    }
+
+    pub fn convert(&self, framebuffer: &[u16], out: &mut [u32]) {
+        for (o, &p) in out.iter_mut().zip(framebuffer) {
+            *o = self.rgb(p);
+        }
+    }
+}
+
+// Builds a framebuffer pixel from a palette index and the PPUMASK value in
+// effect when it was output: greyscale (bit 0) keeps only the brightness
+// column of the index, and the emphasis bits (5-7) move up to bits 6-8.
+pub fn pixel(index: u8, mask: u8) -> u16 {
+    let index = if mask & 0x01 != 0 { index & 0x30 } else { index & 0x3F };
+    index as u16 | ((mask & 0xE0) as u16) << 1
+}
+
+fn emphasize(color: u32, emphasis: u8) -> u32 {
+    if emphasis == 0 {
+        return color;
+    }
+    let mut out = 0;
+    // emphasis bit 0 is red, which lives in the top byte of the colour
+    for channel in 0..3 {
+        let shift = 16 - channel * 8;
+        let mut v = ((color >> shift) & 0xFF) as f32;
+        if emphasis & (1 << channel) == 0 {
+            v *= EMPHASIS_ATTENUATION;
+        }
+        out |= (v as u32) << shift;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pal_with_64_colours() {
+        let mut data = vec![0; 192];
+        data[3..6].copy_from_slice(&[0x12, 0x34, 0x56]);
+        data[189..].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+        let palette = Palette::from_pal(&data).unwrap();
+        assert_eq!(palette.rgb(0x01), 0x123456);
+        assert_eq!(palette.rgb(0x3F), 0xFFFFFF);
+        // red emphasis darkens green and blue
+        assert_eq!(palette.rgb(0x3F | 1 << 6), 0xFFD0D0);
+        assert_eq!(palette.rgb(0x3F | 7 << 6), 0xFFFFFF);
+    }
+
+    #[test]
+    fn pal_with_512_colours() {
+        let data: Vec<u8> = (0..1536).map(|i| (i / 3) as u8).collect();
+        let palette = Palette::from_pal(&data).unwrap();
+        assert_eq!(palette.rgb(0x41), 0x414141);
+        assert_eq!(palette.rgb(0x1FF), 0xFFFFFF);
+    }
+
+    #[test]
+    fn bad_pal_size() {
+        let err = Palette::from_pal(&[0; 100]).err().unwrap();
+        assert_eq!(err.to_string(), "a .pal file should be 192 or 1536 bytes, not 100");
+    }
+
+    #[test]
+    fn greyscale_and_emphasis() {
+        assert_eq!(pixel(0x2A, 0x00), 0x2A);
+        assert_eq!(pixel(0x2A, 0x01), 0x20);
+        assert_eq!(pixel(0x2A, 0xA0), 0x2A | 0x140);
+    }
+}
@@ -0,0 +1,422 @@
+// IPS, UPS and BPS patches, applied to the ROM image in memory
+//
+// IPS is a list of (offset, bytes) records with no checksums at all. UPS
+// and BPS (both by byuu) end with the CRC32s of the source, the target and
+// the patch itself, which are all checked. All three patch the whole file,
+// header included.
+//
+// Numbers in UPS and BPS are a variable length encoding where each byte
+// holds 7 bits and the top bit marks the last byte; every continuation also
+// adds one, so each value has a single encoding.
+
+use std::fmt;
+
+use crc32::Crc32;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: usize = 0x454F46;
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+// the three CRC32s at the end of UPS and BPS patches
+const FOOTER_SIZE: usize = 12;
+// Far beyond any real cartridge, but a bad patch's target size would
+// otherwise be allocated as given.
+const MAX_TARGET_SIZE: usize = 16 << 20;
+
+#[derive(Debug)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated,
+    PatchChecksum,
+    SourceMismatch { expected: u32, found: u32 },
+    TargetMismatch { expected: u32, found: u32 },
+    TargetTooLarge(usize),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Truncated => write!(f, "patch is truncated"),
+            PatchError::PatchChecksum => write!(f, "patch is corrupt"),
+            PatchError::SourceMismatch { expected, found } => {
+                write!(f, "patch is for a ROM with CRC32 {:08X}, this one is {:08X}", expected, found)
+            }
+            PatchError::TargetMismatch { expected, found } => {
+                write!(f, "patched ROM has CRC32 {:08X}, expected {:08X}", found, expected)
+            }
+            PatchError::TargetTooLarge(size) => write!(f, "patched ROM would be {} bytes, which is too large", size),
+        }
+    }
+}
+
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        let b = *self.data.get(self.pos).ok_or(PatchError::Truncated)?;
+        self.pos += 1;
+        Ok(b)
+    }
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], PatchError> {
+        let b = self.data.get(self.pos..self.pos + n).ok_or(PatchError::Truncated)?;
+        self.pos += n;
+        Ok(b)
+    }
+    fn big_endian(&mut self, n: usize) -> Result<usize, PatchError> {
+        Ok(self.bytes(n)?.iter().fold(0, |v, &b| v << 8 | b as usize))
+    }
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let b = self.byte()?;
+            value = value.wrapping_add((b & 0x7F) as usize * shift);
+            if b & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift <<= 7;
+            value = value.wrapping_add(shift);
+        }
+    }
+}
+
+// Records of a 3 byte offset and a 2 byte length, both big endian, then the
+// data. A zero length is a run: a 2 byte count and the byte to repeat. The
+// list ends with "EOF", optionally followed by a 3 byte size to truncate to.
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = rom.to_vec();
+    let mut r = Reader { data: patch, pos: IPS_MAGIC.len() };
+    loop {
+        let offset = r.big_endian(3)?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let len = r.big_endian(2)?;
+        let (len, run) = if len == 0 {
+            (r.big_endian(2)?, Some(r.byte()?))
+        } else {
+            (len, None)
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        match run {
+            Some(value) => out[offset..offset + len].iter_mut().for_each(|b| *b = value),
+            None => out[offset..offset + len].copy_from_slice(r.bytes(len)?),
+        }
+    }
+    if let Ok(size) = r.big_endian(3) {
+        out.truncate(size);
+    }
+    Ok(out)
+}
+
+// The reverse of apply_ips(): a record for every run of bytes that differ,
+// and a truncation size if `modified` is shorter.
+pub fn make_ips(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    let mut out = IPS_MAGIC.to_vec();
+    let mut pos = 0;
+    while pos < modified.len() {
+        if original.get(pos) == Some(&modified[pos]) {
+            pos += 1;
+            continue;
+        }
+        // an offset that reads as "EOF" would end the patch, so such runs
+        // start one byte early
+        let start = if pos == IPS_EOF { pos - 1 } else { pos };
+        while pos < modified.len() && pos - start < 0xFFFF && original.get(pos) != Some(&modified[pos]) {
+            pos += 1;
+        }
+        let len = pos - start;
+        out.extend_from_slice(&[(start >> 16) as u8, (start >> 8) as u8, start as u8]);
+        out.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+        out.extend_from_slice(&modified[start..pos]);
+    }
+    out.extend_from_slice(b"EOF");
+    if modified.len() < original.len() {
+        let size = modified.len();
+        out.extend_from_slice(&[(size >> 16) as u8, (size >> 8) as u8, size as u8]);
+    }
+    out
+}
+
+// Checks the footer and returns the source and target CRC32s.
+fn check_footer(rom: &[u8], patch: &[u8]) -> Result<u32, PatchError> {
+    if patch.len() < 4 + FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+    }
+    let footer = &patch[patch.len() - FOOTER_SIZE..];
+    let word = |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+    if crc32(&patch[..patch.len() - 4]) != word(8) {
+        return Err(PatchError::PatchChecksum);
+    }
+    let found = crc32(rom);
+    if found != word(0) {
+        return Err(PatchError::SourceMismatch { expected: word(0), found });
+    }
+    Ok(word(4))
+}
+
+fn check_target(out: &[u8], expected: u32) -> Result<(), PatchError> {
+    let found = crc32(out);
+    if found != expected {
+        return Err(PatchError::TargetMismatch { expected, found });
+    }
+    Ok(())
+}
+
+fn target_size(r: &mut Reader) -> Result<usize, PatchError> {
+    let size = r.number()?;
+    if size > MAX_TARGET_SIZE {
+        return Err(PatchError::TargetTooLarge(size));
+    }
+    Ok(size)
+}
+
+// The source and target sizes, then hunks of a number of bytes to skip and
+// bytes to XOR in, up to and including a zero.
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = check_footer(rom, patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+    let mut r = Reader { data: &patch[..end], pos: UPS_MAGIC.len() };
+    r.number()?;
+    let target_size = target_size(&mut r)?;
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+    let mut pos = 0;
+    while r.pos < end {
+        pos += r.number()?;
+        loop {
+            let x = r.byte()?;
+            if pos < out.len() {
+                out[pos] ^= x;
+            }
+            pos += 1;
+            if x == 0 {
+                break;
+            }
+        }
+    }
+    check_target(&out, target_crc)?;
+    Ok(out)
+}
+
+// The source and target sizes and some metadata, then commands that build
+// the target from the start: copy from the same place in the source, take
+// bytes from the patch, or copy from elsewhere in the source or the target
+// so far. Each command is a number holding the kind in the low 2 bits and
+// the length minus one above that.
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = check_footer(rom, patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+    let mut r = Reader { data: &patch[..end], pos: BPS_MAGIC.len() };
+    r.number()?;
+    let target_size = target_size(&mut r)?;
+    let metadata = r.number()?;
+    r.bytes(metadata)?;
+
+    let mut out: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_pos = 0usize;
+    let mut target_pos = 0usize;
+    while r.pos < end {
+        let command = r.number()?;
+        let len = (command >> 2) + 1;
+        if len > target_size - out.len() {
+            return Err(PatchError::TargetTooLarge(out.len().saturating_add(len)));
+        }
+        match command & 3 {
+            0 => {
+                let start = out.len();
+                out.extend_from_slice(rom.get(start..start + len).ok_or(PatchError::Truncated)?);
+            }
+            1 => out.extend_from_slice(r.bytes(len)?),
+            kind => {
+                // offsets are relative to where the last copy of the same
+                // kind left off, with the sign in the low bit
+                let delta = r.number()?;
+                let from = if kind == 2 { &mut source_pos } else { &mut target_pos };
+                if delta & 1 != 0 {
+                    *from = from.wrapping_sub(delta >> 1);
+                } else {
+                    *from = from.wrapping_add(delta >> 1);
+                }
+                for _ in 0..len {
+                    // target copies can overlap what they are writing
+                    let b = if kind == 2 { rom.get(*from) } else { out.get(*from) };
+                    let b = *b.ok_or(PatchError::Truncated)?;
+                    out.push(b);
+                    *from += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size {
+        return Err(PatchError::Truncated);
+    }
+    check_target(&out, target_crc)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The UPS and BPS number encoding.
+    fn number(mut value: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let low = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(0x80 | low);
+                return out;
+            }
+            out.push(low);
+            value -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let own = crc32(&patch);
+        patch.extend_from_slice(&own.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn numbers() {
+        for &value in &[0, 1, 0x7F, 0x80, 0x407F, 0x4080, 0x12_3456] {
+            let encoded = number(value);
+            let mut r = Reader { data: &encoded, pos: 0 };
+            assert_eq!(r.number().unwrap(), value);
+            assert_eq!(r.pos, encoded.len());
+        }
+    }
+
+    #[test]
+    fn ips_records_and_runs() {
+        let rom = [0u8; 8];
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x04, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&rom, &patch).unwrap(), [0, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC, 0xCC, 0xCC]);
+
+        patch.extend_from_slice(&[0x00, 0x00, 0x03]);
+        assert_eq!(apply(&rom, &patch).unwrap(), [0, 0xAA, 0xBB]);
+
+        assert!(matches!(apply(&rom, b"PATCH\x00\x00"), Err(PatchError::Truncated)));
+    }
+
+    #[test]
+    fn make_ips_round_trip() {
+        let original: Vec<u8> = (0..=255).collect();
+        let mut modified = original.clone();
+        modified[10] = 0;
+        modified[11] = 0;
+        modified[200] ^= 0xFF;
+        modified.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(apply(&original, &make_ips(&original, &modified)).unwrap(), modified);
+
+        let shorter = &original[..100];
+        assert_eq!(apply(&original, &make_ips(&original, shorter)).unwrap(), shorter);
+        assert_eq!(make_ips(&original, &original), b"PATCHEOF");
+    }
+
+    #[test]
+    fn ups() {
+        let source = b"Hello, world".to_vec();
+        let target = b"Jello, world!".to_vec();
+        let mut patch = UPS_MAGIC.to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(0));
+        patch.extend_from_slice(&[b'H' ^ b'J', 0]);
+        patch.extend(number(10));
+        patch.extend_from_slice(&[b'!', 0]);
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+
+        assert!(matches!(apply(b"Goodbye", &patch), Err(PatchError::SourceMismatch { .. })));
+        let mut corrupt = patch.clone();
+        corrupt[6] ^= 1;
+        assert!(matches!(apply(&source, &corrupt), Err(PatchError::PatchChecksum)));
+    }
+
+    #[test]
+    fn bps() {
+        let source = b"abcdefgh".to_vec();
+        let target = b"abcdXYXYXYefgh".to_vec();
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(0));
+        // source read "abcd"
+        patch.extend(number(3 << 2));
+        // target read "XY"
+        patch.extend(number(1 << 2 | 1));
+        patch.extend_from_slice(b"XY");
+        // target copy of 4 bytes from 4, overlapping itself
+        patch.extend(number(3 << 2 | 3));
+        patch.extend(number(4 << 1));
+        // source copy of "efgh" from 4
+        patch.extend(number(3 << 2 | 2));
+        patch.extend(number(4 << 1));
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+
+        let wrong = with_footer(patch[..patch.len() - FOOTER_SIZE].to_vec(), &source, b"something else");
+        assert!(matches!(apply(&source, &wrong), Err(PatchError::TargetMismatch { .. })));
+    }
+
+    #[test]
+    fn target_size_limit() {
+        let source = b"abcdefgh".to_vec();
+        for magic in &[UPS_MAGIC, BPS_MAGIC] {
+            let mut patch = magic.to_vec();
+            patch.extend(number(source.len()));
+            patch.extend(number(MAX_TARGET_SIZE + 1));
+            patch.extend(number(0));
+            let patch = with_footer(patch, &source, &[]);
+            assert!(matches!(apply(&source, &patch), Err(PatchError::TargetTooLarge(n)) if n == MAX_TARGET_SIZE + 1));
+        }
+
+        // a BPS command running past the size it gave
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(4));
+        patch.extend(number(0));
+        patch.extend(number(7 << 2));
+        let patch = with_footer(patch, &source, &source);
+        assert!(matches!(apply(&source, &patch), Err(PatchError::TargetTooLarge(8))));
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(matches!(apply(b"rom", b"not a patch"), Err(PatchError::UnknownFormat)));
+    }
+}
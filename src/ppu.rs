@@ -0,0 +1,781 @@
+// Picture processing unit
+//
+// The PPU runs dot by dot: 341 dots per scanline, with the region deciding
+// how many lines there are and where vblank starts (see region.rs). Every
+// CPU cycle it catches up by the master clock cycles that passed, so PAL's
+// 3.2 dots per CPU cycle work out over time.
+//
+//   $2000  PPUCTRL: nametable (0-1), increment (2), sprite table (3),
+//          background table (4), 8x16 sprites (5), NMI at vblank (7)
+//   $2001  PPUMASK: greyscale, clipping, rendering enables, emphasis
+//   $2002  PPUSTATUS: sprite overflow (5), sprite 0 hit (6), vblank (7);
+//          reading it clears vblank and the $2005/$2006 write toggle
+//   $2003  OAMADDR
+//   $2004  OAMDATA
+//   $2005  PPUSCROLL, X then Y
+//   $2006  PPUADDR, high byte then low
+//   $2007  PPUDATA, through a read buffer except for the palette
+//
+// While rendering is on, the PPU makes the fetches real hardware makes, at
+// the same dots, and runs them through the mapper's ppu_read(): nametable,
+// attribute and two pattern bytes per tile, eight sprites' patterns in
+// dots 257-320 and the two extra nametable reads at the end of the line.
+// The scroll registers move the way Loopy's notes describe, and sprites
+// are evaluated (with the overflow flag) for the next line.
+//
+// The background tiles go through 16-bit shift registers, so fine X and
+// mid-line register writes land on the right pixel, and each visible dot
+// puts one pixel in the 256x240 framebuffer (pixels as described in
+// palette.rs): background and sprite pixels after left-edge clipping,
+// sprite priority, and sprite 0 hit. With rendering off the picture is
+// the backdrop colour, or the palette entry v points at.
+
+use mapper::{Mapper, PpuAccess, PpuFetch};
+use palette;
+use region::Region;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+const DOTS: u16 = 341;
+
+pub struct Ppu {
+    region: Region,
+    scanlines: u16,
+    vblank_line: u16,
+
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_addr: u8,
+    oam: [u8; 0x100],
+    // Loopy's registers: the current VRAM address, the one $2000, $2005
+    // and $2006 build up for it, fine X scroll and the write toggle.
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    write_toggle: bool,
+    read_buffer: u8,
+    // The PPU's side of the data bus, which the unused bits of $2002 and
+    // the write-only registers read back.
+    latch: u8,
+    // Nametable RAM: the console's 2K, then four-screen boards' 2K.
+    vram: [u8; 0x1000],
+    palette: [u8; 0x20],
+
+    scanline: u16,
+    dot: u16,
+    odd_frame: bool,
+    // Master clock cycles not yet spent on dots.
+    master_cycles: u8,
+    frame_done: bool,
+    // The tile being fetched: nametable byte, attribute bits and the two
+    // pattern bytes, until they're loaded into the shift registers.
+    tile: u8,
+    attribute: u8,
+    pattern_low: u8,
+    pattern_high: u8,
+    // Background shifters. The high byte is the tile being drawn, the low
+    // byte the next one; the attribute bits are spread over all 8 bits.
+    background_low: u16,
+    background_high: u16,
+    attribute_low: u16,
+    attribute_high: u16,
+    // Up to eight sprites' OAM entries for the next line, their pattern
+    // bytes (already flipped horizontally) and whether slot 0 is sprite 0.
+    sprites: [u8; 32],
+    sprite_count: u8,
+    sprite_low: [u8; 8],
+    sprite_high: [u8; 8],
+    sprite_zero: bool,
+
+    framebuffer: Vec<u16>,
+}
+
+impl Ppu {
+    pub fn new(region: Region) -> Ppu {
+        let mut ppu = Ppu {
+            region,
+            scanlines: 0,
+            vblank_line: 0,
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            oam: [0; 0x100],
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            write_toggle: false,
+            read_buffer: 0,
+            latch: 0,
+            vram: [0; 0x1000],
+            palette: [0; 0x20],
+            scanline: 0,
+            dot: 0,
+            odd_frame: false,
+            master_cycles: 0,
+            frame_done: false,
+            tile: 0,
+            attribute: 0,
+            pattern_low: 0,
+            pattern_high: 0,
+            background_low: 0,
+            background_high: 0,
+            attribute_low: 0,
+            attribute_high: 0,
+            sprites: [0xFF; 32],
+            sprite_count: 0,
+            sprite_low: [0; 8],
+            sprite_high: [0; 8],
+            sprite_zero: false,
+            framebuffer: vec![0; WIDTH * HEIGHT],
+        };
+        ppu.set_region(region);
+        ppu
+    }
+    // Nametable RAM and the palette keep whatever they had.
+    pub fn power_on(&mut self) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.status = 0;
+        self.oam_addr = 0;
+        self.v = 0;
+        self.t = 0;
+        self.fine_x = 0;
+        self.write_toggle = false;
+        self.read_buffer = 0;
+        self.latch = 0;
+        self.scanline = 0;
+        self.dot = 0;
+        self.odd_frame = false;
+        self.master_cycles = 0;
+        self.frame_done = false;
+        self.sprite_count = 0;
+    }
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.scanlines = region.scanlines() as u16;
+        self.vblank_line = region.vblank_line() as u16;
+        self.scanline = self.scanline.min(self.scanlines - 1);
+    }
+    // $2000-$3FFF, mirrored every 8 bytes
+    pub fn read(&mut self, address: u16, mapper: &mut dyn Mapper) -> u8 {
+        let value = match address & 0x07 {
+            2 => {
+                let value = self.peek(address);
+                self.status &= !0x80;
+                self.write_toggle = false;
+                value
+            }
+            4 => self.peek(address),
+            7 => {
+                let address = self.v & 0x3FFF;
+                let value = if address >= 0x3F00 {
+                    // The buffer gets the nametable byte underneath instead.
+                    self.read_buffer = self.fetch(mapper, address & 0x2FFF, PpuFetch::Cpu);
+                    self.peek(0x2007)
+                }
+                else {
+                    let value = self.read_buffer;
+                    self.read_buffer = self.fetch(mapper, address, PpuFetch::Cpu);
+                    value
+                };
+                self.increment_v();
+                value
+            }
+            _ => self.latch,
+        };
+        self.latch = value;
+        value
+    }
+    // What read() would return, without clearing anything.
+    pub fn peek(&self, address: u16) -> u8 {
+        match address & 0x07 {
+            2 => self.status & 0xE0 | self.latch & 0x1F,
+            // bits 2-4 of the attribute byte don't exist
+            4 if self.oam_addr & 0x03 == 2 => self.oam[self.oam_addr as usize] & 0xE3,
+            4 => self.oam[self.oam_addr as usize],
+            7 if self.v & 0x3FFF >= 0x3F00 => self.palette[palette_index(self.v)] | self.latch & 0xC0,
+            7 => self.read_buffer,
+            _ => self.latch,
+        }
+    }
+    pub fn write(&mut self, address: u16, value: u8, mapper: &mut dyn Mapper) {
+        self.latch = value;
+        match address & 0x07 {
+            0 => {
+                self.ctrl = value;
+                self.t = (self.t & !0x0C00) | ((value & 0x03) as u16) << 10;
+            }
+            1 => self.mask = value,
+            3 => self.oam_addr = value,
+            4 => {
+                self.oam[self.oam_addr as usize] = value;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            5 => {
+                if self.write_toggle {
+                    self.t = (self.t & !0x73E0) | ((value & 0x07) as u16) << 12 | ((value & 0xF8) as u16) << 2;
+                }
+                else {
+                    self.t = (self.t & !0x001F) | (value >> 3) as u16;
+                    self.fine_x = value & 0x07;
+                }
+                self.write_toggle = !self.write_toggle;
+            }
+            6 => {
+                if self.write_toggle {
+                    self.t = (self.t & 0xFF00) | value as u16;
+                    self.v = self.t;
+                }
+                else {
+                    self.t = (self.t & 0x00FF) | ((value & 0x3F) as u16) << 8;
+                }
+                self.write_toggle = !self.write_toggle;
+            }
+            7 => {
+                let address = self.v & 0x3FFF;
+                if address >= 0x3F00 {
+                    self.palette[palette_index(address)] = value & 0x3F;
+                }
+                else if let PpuAccess::Ciram(offset) = mapper.ppu_write(address, value) {
+                    self.vram[(offset & 0x0FFF) as usize] = value;
+                }
+                self.increment_v();
+            }
+            _ => {}
+        }
+    }
+    // The level of the NMI output, true while asserted.
+    pub fn nmi(&self) -> bool {
+        self.ctrl & 0x80 != 0 && self.status & 0x80 != 0
+    }
+    // Whether vblank has started since the last call.
+    pub fn take_frame(&mut self) -> bool {
+        let done = self.frame_done;
+        self.frame_done = false;
+        done
+    }
+    // One CPU cycle.
+    pub fn tick(&mut self, mapper: &mut dyn Mapper) {
+        let ppu_divider = self.region.ppu_divider() as u8;
+        self.master_cycles += self.region.cpu_divider() as u8;
+        while self.master_cycles >= ppu_divider {
+            self.master_cycles -= ppu_divider;
+            self.step(mapper);
+        }
+    }
+    fn step(&mut self, mapper: &mut dyn Mapper) {
+        let rendering = self.mask & 0x18 != 0;
+        let visible = self.scanline < HEIGHT as u16;
+        let pre_render = self.scanline == self.scanlines - 1;
+        if rendering && (visible || pre_render) {
+            self.render_dot(mapper, pre_render);
+        }
+        if visible && (1..=256).contains(&self.dot) {
+            self.output_pixel();
+        }
+        if self.dot == 1 {
+            if self.scanline == self.vblank_line {
+                self.status |= 0x80;
+                self.frame_done = true;
+            }
+            else if pre_render {
+                self.status &= !0xE0;
+            }
+        }
+        // NTSC skips the last dot of the pre-render line every other
+        // frame, while rendering.
+        if pre_render && self.dot == 339 && self.odd_frame && rendering && self.region == Region::Ntsc {
+            self.dot = 340;
+        }
+        self.dot += 1;
+        if self.dot == DOTS {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == self.scanlines {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+    // The fetches and scroll updates of one dot on a rendering line.
+    fn render_dot(&mut self, mapper: &mut dyn Mapper, pre_render: bool) {
+        if let 2..=257 | 322..=337 = self.dot {
+            self.shift();
+        }
+        // The tile fetched over the last eight dots goes in behind the one
+        // being drawn.
+        if let 9..=257 | 329 | 337 = self.dot {
+            if self.dot & 0x07 == 1 {
+                self.reload_shifters();
+            }
+        }
+        if self.dot == 257 {
+            self.v = (self.v & !0x041F) | (self.t & 0x041F);
+            if pre_render {
+                self.sprite_count = 0;
+            }
+            else {
+                self.evaluate_sprites();
+            }
+        }
+        match self.dot {
+            1..=256 | 321..=336 => self.fetch_background(mapper),
+            257..=320 => self.fetch_sprite(mapper),
+            337 | 339 => {
+                self.fetch(mapper, 0x2000 | (self.v & 0x0FFF), PpuFetch::Nametable);
+            }
+            _ => {}
+        }
+        if self.dot == 256 {
+            self.increment_y();
+        }
+        if pre_render && (280..=304).contains(&self.dot) {
+            self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+        }
+    }
+    fn fetch_background(&mut self, mapper: &mut dyn Mapper) {
+        let v = self.v;
+        let pattern = ((self.ctrl & 0x10) as u16) << 8 | (self.tile as u16) << 4 | v >> 12;
+        match self.dot & 0x07 {
+            1 => self.tile = self.fetch(mapper, 0x2000 | (v & 0x0FFF), PpuFetch::Nametable),
+            3 => {
+                let address = 0x23C0 | (v & 0x0C00) | (v >> 4) & 0x38 | (v >> 2) & 0x07;
+                let attribute = self.fetch(mapper, address, PpuFetch::Attribute);
+                // each byte covers 4x4 tiles, two bits per 2x2 quadrant
+                let shift = (v >> 4) & 0x04 | v & 0x02;
+                self.attribute = (attribute >> shift) & 0x03;
+            }
+            5 => self.pattern_low = self.fetch(mapper, pattern, PpuFetch::Background),
+            7 => self.pattern_high = self.fetch(mapper, pattern | 0x08, PpuFetch::Background),
+            0 => self.increment_x(),
+            _ => {}
+        }
+    }
+    // Each of the eight slots reads the nametable twice, for nothing, and
+    // then the sprite's two pattern bytes. Empty slots fetch tile $FF.
+    fn fetch_sprite(&mut self, mapper: &mut dyn Mapper) {
+        let slot = ((self.dot - 257) / 8) as usize;
+        match (self.dot - 257) % 8 {
+            0 | 2 => {
+                self.fetch(mapper, 0x2000 | (self.v & 0x0FFF), PpuFetch::Sprite);
+            }
+            4 => {
+                let address = self.sprite_pattern(slot);
+                let value = self.fetch(mapper, address, PpuFetch::Sprite);
+                self.sprite_low[slot] = self.sprite_bits(slot, value);
+            }
+            6 => {
+                let address = self.sprite_pattern(slot) | 0x08;
+                let value = self.fetch(mapper, address, PpuFetch::Sprite);
+                self.sprite_high[slot] = self.sprite_bits(slot, value);
+            }
+            _ => {}
+        }
+    }
+    // A fetched pattern byte as the slot draws it: left pixel in bit 7,
+    // and nothing at all for an empty slot.
+    fn sprite_bits(&self, slot: usize, value: u8) -> u8 {
+        if slot >= self.sprite_count as usize {
+            0
+        }
+        else if self.sprites[slot * 4 + 2] & 0x40 != 0 {
+            value.reverse_bits()
+        }
+        else {
+            value
+        }
+    }
+    fn shift(&mut self) {
+        self.background_low <<= 1;
+        self.background_high <<= 1;
+        self.attribute_low <<= 1;
+        self.attribute_high <<= 1;
+    }
+    fn reload_shifters(&mut self) {
+        self.background_low = (self.background_low & 0xFF00) | self.pattern_low as u16;
+        self.background_high = (self.background_high & 0xFF00) | self.pattern_high as u16;
+        let attribute = self.attribute;
+        let spread = |bit: u8| if attribute & bit != 0 { 0xFF } else { 0x00 };
+        self.attribute_low = (self.attribute_low & 0xFF00) | spread(0x01);
+        self.attribute_high = (self.attribute_high & 0xFF00) | spread(0x02);
+    }
+    // The pixel for the current dot, from the shifters and this line's
+    // sprites.
+    fn output_pixel(&mut self) {
+        let x = self.dot - 1;
+        let background = if self.mask & 0x08 != 0 && (x >= 8 || self.mask & 0x02 != 0) {
+            let bit = 15 - self.fine_x as u16;
+            let pixel = ((self.background_high >> bit) & 1) << 1 | (self.background_low >> bit) & 1;
+            let palette = ((self.attribute_high >> bit) & 1) << 1 | (self.attribute_low >> bit) & 1;
+            if pixel == 0 { 0 } else { (palette << 2 | pixel) as u8 }
+        }
+        else {
+            0
+        };
+        let sprite = if self.mask & 0x10 != 0 && (x >= 8 || self.mask & 0x04 != 0) {
+            self.sprite_pixel(x)
+        }
+        else {
+            None
+        };
+        let index = match sprite {
+            Some((slot, color, behind)) => {
+                if slot == 0 && self.sprite_zero && background != 0 && x != 255 {
+                    self.status |= 0x40;
+                }
+                if behind && background != 0 { background } else { 0x10 | color }
+            }
+            None => background,
+        };
+        // With rendering off, a v pointing into the palette shows that entry.
+        let address = if self.mask & 0x18 == 0 && self.v & 0x3F00 == 0x3F00 {
+            self.v
+        }
+        else {
+            index as u16
+        };
+        let color = self.palette[palette_index(address)];
+        self.framebuffer[self.scanline as usize * WIDTH + x as usize] = palette::pixel(color, self.mask);
+    }
+    // The first opaque sprite pixel at `x`: its slot, palette and pixel
+    // value as a palette RAM index, and whether it's behind the background.
+    fn sprite_pixel(&self, x: u16) -> Option<(usize, u8, bool)> {
+        for slot in 0..self.sprite_count as usize {
+            let offset = x.wrapping_sub(self.sprites[slot * 4 + 3] as u16);
+            if offset >= 8 {
+                continue;
+            }
+            let bit = 7 - offset;
+            let pixel = ((self.sprite_high[slot] >> bit) & 1) << 1 | (self.sprite_low[slot] >> bit) & 1;
+            if pixel != 0 {
+                let attributes = self.sprites[slot * 4 + 2];
+                return Some((slot, (attributes & 0x03) << 2 | pixel, attributes & 0x20 != 0));
+            }
+        }
+        None
+    }
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & 0x20 != 0 { 16 } else { 8 }
+    }
+    // Finds the first eight sprites on the current line, which the next
+    // line shows, and flags any more.
+    fn evaluate_sprites(&mut self) {
+        let height = self.sprite_height();
+        self.sprite_count = 0;
+        self.sprite_zero = false;
+        for (i, sprite) in self.oam.chunks(4).enumerate() {
+            if self.scanline.wrapping_sub(sprite[0] as u16) >= height {
+                continue;
+            }
+            if self.sprite_count == 8 {
+                self.status |= 0x20;
+                break;
+            }
+            let start = self.sprite_count as usize * 4;
+            self.sprites[start..start + 4].copy_from_slice(sprite);
+            self.sprite_zero |= i == 0;
+            self.sprite_count += 1;
+        }
+    }
+    // The low pattern byte's address for the sprite in `slot`.
+    fn sprite_pattern(&self, slot: usize) -> u16 {
+        let (y, tile, attributes) = if slot < self.sprite_count as usize {
+            let sprite = &self.sprites[slot * 4..slot * 4 + 4];
+            (sprite[0], sprite[1], sprite[2])
+        }
+        else {
+            (0xFF, 0xFF, 0xFF)
+        };
+        let height = self.sprite_height();
+        let mut row = self.scanline.wrapping_sub(y as u16) & (height - 1);
+        if attributes & 0x80 != 0 {
+            row = height - 1 - row;
+        }
+        if height == 8 {
+            ((self.ctrl & 0x08) as u16) << 9 | (tile as u16) << 4 | row
+        }
+        else {
+            let table = ((tile & 0x01) as u16) << 12;
+            let tile = (tile & 0xFE) as u16 + row / 8;
+            table | tile << 4 | row & 0x07
+        }
+    }
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 0x001F {
+            self.v = (self.v & !0x001F) ^ 0x0400;
+        }
+        else {
+            self.v += 1;
+        }
+    }
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let y = match (self.v & 0x03E0) >> 5 {
+            29 => {
+                self.v ^= 0x0800;
+                0
+            }
+            31 => 0,
+            y => y + 1,
+        };
+        self.v = (self.v & !0x03E0) | y << 5;
+    }
+    // After a $2007 access: across a row or down a column of nametable.
+    fn increment_v(&mut self) {
+        let step = if self.ctrl & 0x04 != 0 { 32 } else { 1 };
+        self.v = self.v.wrapping_add(step) & 0x7FFF;
+    }
+    fn fetch(&mut self, mapper: &mut dyn Mapper, address: u16, fetch: PpuFetch) -> u8 {
+        match mapper.ppu_read(address, fetch) {
+            PpuAccess::Cartridge(value) => value,
+            PpuAccess::Ciram(offset) => self.vram[(offset & 0x0FFF) as usize],
+        }
+    }
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+}
+
+// $3F10, $3F14, $3F18 and $3F1C are the same bytes as $3F00, $3F04, $3F08
+// and $3F0C.
+fn palette_index(address: u16) -> usize {
+    let index = address as usize & 0x1F;
+    if index & 0x13 == 0x10 { index & 0x0F } else { index }
+}
+
+impl Savable for Ppu {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u8(self.ctrl);
+        w.write_u8(self.mask);
+        w.write_u8(self.status);
+        w.write_u8(self.oam_addr);
+        w.write_bytes(&self.oam);
+        w.write_u16(self.v);
+        w.write_u16(self.t);
+        w.write_u8(self.fine_x);
+        w.write_bool(self.write_toggle);
+        w.write_u8(self.read_buffer);
+        w.write_u8(self.latch);
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.palette);
+        w.write_u16(self.scanline);
+        w.write_u16(self.dot);
+        w.write_bool(self.odd_frame);
+        w.write_u8(self.master_cycles);
+        w.write_bool(self.frame_done);
+        w.write_u8(self.tile);
+        w.write_u8(self.attribute);
+        w.write_u8(self.pattern_low);
+        w.write_u8(self.pattern_high);
+        w.write_u16(self.background_low);
+        w.write_u16(self.background_high);
+        w.write_u16(self.attribute_low);
+        w.write_u16(self.attribute_high);
+        w.write_bytes(&self.sprites);
+        w.write_u8(self.sprite_count);
+        w.write_bytes(&self.sprite_low);
+        w.write_bytes(&self.sprite_high);
+        w.write_bool(self.sprite_zero);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ctrl = r.read_u8()?;
+        self.mask = r.read_u8()?;
+        self.status = r.read_u8()? & 0xE0;
+        self.oam_addr = r.read_u8()?;
+        r.read_bytes(&mut self.oam)?;
+        self.v = r.read_u16()? & 0x7FFF;
+        self.t = r.read_u16()? & 0x7FFF;
+        self.fine_x = r.read_u8()? & 0x07;
+        self.write_toggle = r.read_bool()?;
+        self.read_buffer = r.read_u8()?;
+        self.latch = r.read_u8()?;
+        r.read_bytes(&mut self.vram)?;
+        r.read_bytes(&mut self.palette)?;
+        self.scanline = r.read_u16()?;
+        if self.scanline >= self.scanlines {
+            return Err(StateError::Invalid("PPU scanline"));
+        }
+        self.dot = r.read_u16()?;
+        if self.dot >= DOTS {
+            return Err(StateError::Invalid("PPU dot"));
+        }
+        self.odd_frame = r.read_bool()?;
+        self.master_cycles = r.read_u8()? % self.region.ppu_divider() as u8;
+        self.frame_done = r.read_bool()?;
+        self.tile = r.read_u8()?;
+        self.attribute = r.read_u8()? & 0x03;
+        self.pattern_low = r.read_u8()?;
+        self.pattern_high = r.read_u8()?;
+        self.background_low = r.read_u16()?;
+        self.background_high = r.read_u16()?;
+        self.attribute_low = r.read_u16()?;
+        self.attribute_high = r.read_u16()?;
+        r.read_bytes(&mut self.sprites)?;
+        self.sprite_count = r.read_u8()?.min(8);
+        r.read_bytes(&mut self.sprite_low)?;
+        r.read_bytes(&mut self.sprite_high)?;
+        self.sprite_zero = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::Nrom;
+    use rom::Cartridge;
+
+    // NROM with 8K of CHR-RAM and vertical mirroring.
+    fn nrom() -> Nrom {
+        let mut image = b"NES\x1A\x01\x00\x01\x00".to_vec();
+        image.resize(16 + 0x4000, 0);
+        let mut nrom = Nrom::new(Cartridge::load(image).unwrap());
+        nrom.load();
+        nrom
+    }
+
+    fn write_vram(ppu: &mut Ppu, mapper: &mut Nrom, address: u16, data: &[u8]) {
+        ppu.write(0x2006, (address >> 8) as u8, mapper);
+        ppu.write(0x2006, address as u8, mapper);
+        for &value in data {
+            ppu.write(0x2007, value, mapper);
+        }
+    }
+
+    // Tile 1 is solid colour 1, the top-left tile of the first nametable
+    // is tile 1, and background colour 1 is $16 on a $0F backdrop.
+    fn setup() -> (Ppu, Nrom) {
+        let mut ppu = Ppu::new(Region::Ntsc);
+        let mut mapper = nrom();
+        write_vram(&mut ppu, &mut mapper, 0x0010, &[0xFF; 8]);
+        write_vram(&mut ppu, &mut mapper, 0x2000, &[1]);
+        write_vram(&mut ppu, &mut mapper, 0x3F00, &[0x0F, 0x16]);
+        write_vram(&mut ppu, &mut mapper, 0x3F11, &[0x2A]);
+        ppu.write(0x2006, 0, &mut mapper);
+        ppu.write(0x2006, 0, &mut mapper);
+        (ppu, mapper)
+    }
+
+    // Runs to the start of vblank, twice, so that the second picture had a
+    // pre-render line with rendering on.
+    fn frames(ppu: &mut Ppu, mapper: &mut Nrom) {
+        for _ in 0..2 {
+            while !ppu.take_frame() {
+                ppu.tick(mapper);
+            }
+        }
+    }
+
+    fn row(ppu: &Ppu, y: usize) -> &[u16] {
+        &ppu.framebuffer[y * WIDTH..(y + 1) * WIDTH]
+    }
+
+    #[test]
+    fn background() {
+        let (mut ppu, mut mapper) = setup();
+        ppu.write(0x2001, 0x0A, &mut mapper);
+        frames(&mut ppu, &mut mapper);
+        for y in 0..8 {
+            assert_eq!(&row(&ppu, y)[0..9], &[0x16, 0x16, 0x16, 0x16, 0x16, 0x16, 0x16, 0x16, 0x0F]);
+        }
+        assert_eq!(row(&ppu, 8)[0], 0x0F);
+    }
+
+    #[test]
+    fn fine_x_and_clipping() {
+        let (mut ppu, mut mapper) = setup();
+        ppu.write(0x2005, 3, &mut mapper);
+        ppu.write(0x2005, 0, &mut mapper);
+        ppu.write(0x2001, 0x0A, &mut mapper);
+        frames(&mut ppu, &mut mapper);
+        assert_eq!(&row(&ppu, 0)[0..6], &[0x16, 0x16, 0x16, 0x16, 0x16, 0x0F]);
+        // the left 8 pixels clipped, and greyscale
+        ppu.write(0x2001, 0x09, &mut mapper);
+        frames(&mut ppu, &mut mapper);
+        assert!(row(&ppu, 0)[0..256].iter().all(|&p| p == 0x00));
+    }
+
+    #[test]
+    fn emphasis() {
+        let (mut ppu, mut mapper) = setup();
+        ppu.write(0x2001, 0xA0, &mut mapper);
+        frames(&mut ppu, &mut mapper);
+        assert!(ppu.framebuffer.iter().all(|&p| p == 0x14F));
+    }
+
+    #[test]
+    fn sprites_and_sprite_zero_hit() {
+        let (mut ppu, mut mapper) = setup();
+        // sprite 0 at (4, 1) over the background tile, flipped; sprite 1
+        // behind the background at (100, 20)
+        ppu.write(0x2003, 0, &mut mapper);
+        for &value in &[0, 1, 0x40, 4, 19, 1, 0x20, 100] {
+            ppu.write(0x2004, value, &mut mapper);
+        }
+        for _ in 8..256 {
+            ppu.write(0x2004, 0xFF, &mut mapper);
+        }
+        ppu.write(0x2001, 0x1E, &mut mapper);
+        frames(&mut ppu, &mut mapper);
+        assert_eq!(ppu.peek(0x2002) & 0x40, 0x40);
+        assert_eq!(&row(&ppu, 1)[3..13], &[0x16, 0x2A, 0x2A, 0x2A, 0x2A, 0x2A, 0x2A, 0x2A, 0x2A, 0x0F]);
+        assert_eq!(&row(&ppu, 20)[99..109], &[0x0F, 0x2A, 0x2A, 0x2A, 0x2A, 0x2A, 0x2A, 0x2A, 0x2A, 0x0F]);
+        // cleared on the pre-render line, and missing over an empty tile
+        write_vram(&mut ppu, &mut mapper, 0x2000, &[0]);
+        ppu.write(0x2006, 0, &mut mapper);
+        ppu.write(0x2006, 0, &mut mapper);
+        frames(&mut ppu, &mut mapper);
+        assert_eq!(ppu.peek(0x2002) & 0x40, 0);
+    }
+
+    #[test]
+    fn rendering_off_shows_backdrop() {
+        let (mut ppu, mut mapper) = setup();
+        frames(&mut ppu, &mut mapper);
+        assert!(ppu.framebuffer.iter().all(|&p| p == 0x0F));
+        // unless v points into the palette
+        ppu.write(0x2006, 0x3F, &mut mapper);
+        ppu.write(0x2006, 0x01, &mut mapper);
+        frames(&mut ppu, &mut mapper);
+        assert!(ppu.framebuffer.iter().all(|&p| p == 0x16));
+    }
+
+    #[test]
+    fn vblank_flag_and_nmi() {
+        let (mut ppu, mut mapper) = setup();
+        frames(&mut ppu, &mut mapper);
+        assert!(!ppu.nmi());
+        ppu.write(0x2000, 0x80, &mut mapper);
+        assert!(ppu.nmi());
+        // reading $2002 acknowledges it
+        assert_eq!(ppu.read(0x2002, &mut mapper) & 0x80, 0x80);
+        assert!(!ppu.nmi());
+        assert_eq!(ppu.read(0x2002, &mut mapper) & 0x80, 0);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let (mut ppu, mut mapper) = setup();
+        ppu.write(0x2001, 0x1E, &mut mapper);
+        frames(&mut ppu, &mut mapper);
+        for _ in 0..12345 {
+            ppu.tick(&mut mapper);
+        }
+        let mut w = StateWriter::new(0, Region::Ntsc);
+        ppu.save(&mut w);
+        let data = w.finish();
+        let mut copy = Ppu::new(Region::Ntsc);
+        copy.load(&mut StateReader::new(&data, 0, Region::Ntsc).unwrap()).unwrap();
+        frames(&mut ppu, &mut mapper);
+        frames(&mut copy, &mut mapper);
+        assert!(ppu.framebuffer == copy.framebuffer);
+    }
+}
@@ -0,0 +1,159 @@
+// Console regions
+//
+// NTSC, PAL and Dendy (the Russian Famiclone) consoles run their CPU and
+// PPU off different master clocks and dividers, and draw a different number
+// of scanlines per frame:
+//
+//            master clock    CPU    PPU    scanlines  vblank lines
+//   NTSC     21.477272 MHz   /12    /4     262        20
+//   PAL      26.601712 MHz   /16    /5     312        70
+//   Dendy    26.601712 MHz   /15    /5     312        20
+//
+// so the PPU does 3 dots per CPU cycle on NTSC and Dendy, and 3.2 on PAL.
+// Dendy keeps NTSC's short vblank and idles for 50 extra lines after the
+// picture instead, which is what lets NTSC games run on it unchanged.
+//
+// The region sets the CPU clock rate, the PPU's dots per CPU cycle, its
+// line count and where vblank starts, and so the frame rate. The PAL 2A07
+// also has its own APU frame counter step lengths and noise and DMC period
+// tables, so that they sound about the same at its slower clock. Dendy
+// clones kept NTSC's.
+
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+    // In Hz
+    pub fn master_clock(self) -> u64 {
+        match self {
+            Region::Ntsc => 21_477_272,
+            Region::Pal | Region::Dendy => 26_601_712,
+        }
+    }
+    pub fn cpu_divider(self) -> u64 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+    pub fn ppu_divider(self) -> u64 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+    // CPU cycles per second, rounded to the nearest cycle.
+    pub fn clock_rate(self) -> u64 {
+        (self.master_clock() + self.cpu_divider() / 2) / self.cpu_divider()
+    }
+    pub fn vblank_scanlines(self) -> u64 {
+        match self {
+            Region::Pal => 70,
+            Region::Ntsc | Region::Dendy => 20,
+        }
+    }
+    // Idle lines between the picture and vblank, not counting the one every
+    // region has.
+    fn extra_post_render_scanlines(self) -> u64 {
+        match self {
+            Region::Dendy => 50,
+            Region::Ntsc | Region::Pal => 0,
+        }
+    }
+    // The line vblank starts on, after the picture and the idle lines.
+    pub fn vblank_line(self) -> u64 {
+        240 + 1 + self.extra_post_render_scanlines()
+    }
+    // Visible, post-render, vblank and pre-render lines.
+    pub fn scanlines(self) -> u64 {
+        self.vblank_line() + self.vblank_scanlines() + 1
+    }
+    // The CPU cycles at which the APU frame counter's steps fall: the three
+    // quarter frames, the last step of the 4-step sequence and the last of
+    // the 5-step one.
+    pub fn frame_counter_steps(self) -> [u32; 5] {
+        match self {
+            Region::Pal => [8313, 16627, 24939, 33253, 41565],
+            Region::Ntsc | Region::Dendy => [7457, 14913, 22371, 29829, 37281],
+        }
+    }
+    // The noise channel's periods, in CPU cycles.
+    pub fn noise_periods(self) -> &'static [u16; 16] {
+        match self {
+            Region::Pal => &[4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778],
+            Region::Ntsc | Region::Dendy => &[4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068],
+        }
+    }
+    // The DMC's periods, in CPU cycles per output bit.
+    pub fn dmc_periods(self) -> &'static [u16; 16] {
+        match self {
+            Region::Pal => &[398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50],
+            Region::Ntsc | Region::Dendy => &[428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54],
+        }
+    }
+    // Frames per second, as a fraction: 341 dots per scanline. NTSC frames
+    // are a dot shorter every other frame while rendering, which this
+    // leaves out.
+    pub fn frame_rate(self) -> (u64, u64) {
+        (self.master_clock(), 341 * self.scanlines() * self.ppu_divider())
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Region::Ntsc => write!(f, "NTSC"),
+            Region::Pal => write!(f, "PAL"),
+            Region::Dendy => write!(f, "Dendy"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        for &region in &[Region::Ntsc, Region::Pal, Region::Dendy] {
+            assert_eq!(Region::from_name(&region.to_string().to_lowercase()), Some(region));
+        }
+        assert_eq!(Region::from_name("secam"), None);
+    }
+
+    #[test]
+    fn clocks() {
+        assert_eq!(Region::Ntsc.clock_rate(), 1_789_773);
+        assert_eq!(Region::Pal.clock_rate(), 1_662_607);
+        assert_eq!(Region::Dendy.clock_rate(), 1_773_447);
+        assert_eq!((Region::Ntsc.scanlines(), Region::Pal.scanlines(), Region::Dendy.scanlines()), (262, 312, 312));
+        assert_eq!(Region::Dendy.vblank_line(), 291);
+    }
+
+    #[test]
+    fn frame_rates() {
+        let fps = |region: Region| {
+            let (n, d) = region.frame_rate();
+            n as f64 / d as f64
+        };
+        assert!((fps(Region::Ntsc) - 60.0985).abs() < 0.0001);
+        assert!((fps(Region::Pal) - 50.0070).abs() < 0.0001);
+        assert!((fps(Region::Dendy) - 50.0070).abs() < 0.0001);
+    }
+}
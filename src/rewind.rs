@@ -0,0 +1,259 @@
+// Rewind buffer
+//
+// Keeps a bounded history of save states so gameplay can be scrubbed
+// backwards. Only the newest snapshot is stored in full. Every older one is
+// stored as the XOR of itself against the next newer snapshot, run-length
+// encoded so that the (mostly unchanged) bytes between two snapshots take up
+// almost no space. Walking backwards is then just undoing one delta at a
+// time, and dropping the oldest entry when the buffer is full never
+// invalidates the others.
+
+use std::collections::VecDeque;
+
+use cpu::CPU;
+use savestate::StateError;
+
+struct Delta {
+    frame: u64,
+    data: Vec<u8>,
+}
+
+pub struct RewindBuffer {
+    interval: u64,
+    capacity: usize,
+    newest: Vec<u8>,
+    newest_frame: u64,
+    deltas: VecDeque<Delta>,
+}
+
+impl RewindBuffer {
+    // Snapshot every `interval` frames, remembering at most `capacity` of them.
+    pub fn new(interval: u64, capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            newest: Vec::new(),
+            newest_frame: 0,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    // Call once per frame; takes a snapshot when the interval is up.
+    pub fn record(&mut self, cpu: &CPU) {
+        if cpu.frame().is_multiple_of(self.interval) {
+            self.push(cpu.frame(), cpu.save_state());
+        }
+    }
+
+    fn push(&mut self, frame: u64, state: Vec<u8>) {
+        if !self.newest.is_empty() {
+            if self.newest.len() == state.len() && frame > self.newest_frame {
+                let data = encode_delta(&self.newest, &state);
+                self.deltas.push_back(Delta { frame: self.newest_frame, data });
+                if self.deltas.len() >= self.capacity {
+                    self.deltas.pop_front();
+                }
+            } else {
+                // A different sized state (new ROM, new format) or going back
+                // in time without rewinding: the history no longer applies.
+                self.deltas.clear();
+            }
+        }
+        self.newest = state;
+        self.newest_frame = frame;
+    }
+
+    // The frame of the oldest snapshot still kept.
+    pub fn oldest_frame(&self) -> Option<u64> {
+        match self.deltas.front() {
+            Some(d) => Some(d.frame),
+            None if !self.newest.is_empty() => Some(self.newest_frame),
+            None => None,
+        }
+    }
+
+    // Restores the newest snapshot taken at or before `frame` and discards
+    // everything after it, so recording can carry on from there. Returns the
+    // frame that was restored, or None if `frame` is older than the buffer.
+    pub fn rewind_to(&mut self, cpu: &mut CPU, frame: u64) -> Result<Option<u64>, StateError> {
+        match self.oldest_frame() {
+            Some(oldest) if oldest <= frame => {}
+            _ => return Ok(None),
+        }
+        while self.newest_frame > frame {
+            let delta = self.deltas.pop_back().unwrap();
+            apply_delta(&mut self.newest, &delta.data);
+            self.newest_frame = delta.frame;
+        }
+        cpu.load_state(&self.newest)?;
+        Ok(Some(self.newest_frame))
+    }
+
+    // Steps back by one snapshot from the current position.
+    pub fn step_back(&mut self, cpu: &mut CPU) -> Result<Option<u64>, StateError> {
+        let target = if cpu.frame() > self.newest_frame {
+            self.newest_frame
+        } else {
+            match self.deltas.back() {
+                Some(d) => d.frame,
+                None => return Ok(None),
+            }
+        };
+        self.rewind_to(cpu, target)
+    }
+}
+
+// Delta encoding: XOR the two snapshots, then store alternating runs of
+// (zero count, literal count, literal bytes), with counts as LEB128 varints.
+fn encode_delta(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < a.len() {
+        let start = i;
+        while i < a.len() && a[i] == b[i] {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+        let start = i;
+        while i < a.len() && a[i] != b[i] {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+        out.extend(a[start..i].iter().zip(&b[start..i]).map(|(x, y)| x ^ y));
+    }
+    out
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut pos = 0;
+    let mut i = 0;
+    while i < delta.len() {
+        pos += read_varint(delta, &mut i);
+        let literals = read_varint(delta, &mut i);
+        for b in &mut state[pos..pos + literals] {
+            *b ^= delta[i];
+            i += 1;
+        }
+        pos += literals;
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let b = data[*pos];
+        *pos += 1;
+        value |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper;
+    use rom;
+
+    // Counts at $00 forever.
+    fn machine() -> CPU {
+        let mut image = b"NES\x1A\x01\x01".to_vec();
+        image.resize(16, 0);
+        let mut prg = vec![0; 0x4000];
+        prg[..5].copy_from_slice(&[0xE6, 0x00, 0x4C, 0x00, 0xC0]); // INC $00; JMP $C000
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC0, 0x00, 0xC0]);
+        image.extend_from_slice(&prg);
+        image.extend_from_slice(&[0; 0x2000]);
+        let mut cpu = CPU::new(mapper::new(rom::Cartridge::load(image).unwrap(), None).unwrap());
+        cpu.set_trace(false);
+        cpu
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let old: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let mut new = old.clone();
+        new[0] ^= 1;
+        for b in &mut new[500..700] {
+            *b = 0xAA;
+        }
+        new[999] = 0;
+        let delta = encode_delta(&old, &new);
+        assert!(delta.len() < 220, "{} bytes", delta.len());
+        let mut state = new.clone();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, old);
+        // a run of 1000 unchanged bytes and no literals
+        assert_eq!(encode_delta(&old, &old), [0xE8, 0x07, 0x00]);
+    }
+
+    #[test]
+    fn varints() {
+        let values = [0, 1, 127, 128, 300, 1 << 20, usize::MAX];
+        let mut data = Vec::new();
+        for &v in &values {
+            write_varint(&mut data, v);
+        }
+        assert_eq!(&data[..5], &[0, 1, 127, 0x80, 0x01]);
+        let mut pos = 0;
+        for &v in &values {
+            assert_eq!(read_varint(&data, &mut pos), v);
+        }
+        assert_eq!(pos, data.len());
+    }
+
+    #[test]
+    fn rewinds_to_snapshots() {
+        let mut cpu = machine();
+        // snapshots at frames 2, 4, ... of which the last three are kept
+        let mut buffer = RewindBuffer::new(2, 3);
+        let mut states = vec![cpu.save_state()];
+        for _ in 0..10 {
+            cpu.run_frame();
+            buffer.record(&cpu);
+            states.push(cpu.save_state());
+        }
+        assert_eq!(buffer.step_back(&mut cpu), Ok(Some(8)));
+        assert_eq!(cpu.save_state(), states[8]);
+        cpu.run_frame();
+        assert_eq!(buffer.step_back(&mut cpu), Ok(Some(8)));
+        assert_eq!(buffer.rewind_to(&mut cpu, 5), Ok(None));
+        assert_eq!(buffer.rewind_to(&mut cpu, 7), Ok(Some(6)));
+        assert_eq!(cpu.save_state(), states[6]);
+        assert_eq!(buffer.step_back(&mut cpu), Ok(None));
+    }
+
+    #[test]
+    fn every_delta_restores_its_state() {
+        let mut cpu = machine();
+        let mut buffer = RewindBuffer::new(1, 100);
+        let mut states = vec![cpu.save_state()];
+        for _ in 0..20 {
+            cpu.run_frame();
+            buffer.record(&cpu);
+            states.push(cpu.save_state());
+        }
+        assert_eq!(buffer.oldest_frame(), Some(1));
+        for frame in (1..20).rev() {
+            assert_eq!(buffer.rewind_to(&mut cpu, frame), Ok(Some(frame)));
+            assert_eq!(cpu.save_state(), states[frame as usize]);
+        }
+        // and recording carries on from there
+        cpu.run_frame();
+        buffer.record(&cpu);
+        assert_eq!(cpu.save_state(), states[2]);
+        assert_eq!(buffer.rewind_to(&mut cpu, 1), Ok(Some(1)));
+        assert_eq!(cpu.save_state(), states[1]);
+    }
+}
@@ -1,15 +1,64 @@
-use std::io::Write;
+use std::fmt;
+use std::io::{self, Write};
 use std::fs::File;
 
+use crc32;
+use fds;
+use md5;
+use nsf;
+use region::Region;
+use romdb;
+use unif;
+
+const INES_MAGIC: &[u8] = b"NES\x1A";
+
+#[derive(Debug)]
+pub enum RomError {
+    UnknownFormat,
+    Truncated,
+    Invalid(&'static str),
+    UnsupportedBoard(String),
+    UnsupportedMapper(u16),
+    Save(io::Error),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::UnknownFormat => write!(f, "not an iNES, UNIF or FDS file"),
+            RomError::Truncated => write!(f, "ROM file is truncated"),
+            RomError::Invalid(what) => write!(f, "{}", what),
+            RomError::UnsupportedBoard(ref name) => write!(f, "unsupported board {}", name),
+            RomError::UnsupportedMapper(n) => write!(f, "mapper {} is not supported", n),
+            RomError::Save(ref e) => write!(f, "problem reading the save file: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+    // all four nametables on the first or the second page of CIRAM
+    SingleScreenLower,
+    SingleScreenUpper,
+}
+
 #[allow(dead_code)]
-pub struct INesFile {
+pub struct Cartridge {
     magic: [u8; 4],
     has_trainer: bool,
-    pub mapper: u8,
+    pub mapper: u16,
+    pub submapper: u8,
     pub prg_rom_cnt:    u8,
     prg_rom_size:   u32,
     chr_rom_size:   u32,
     prg_ram_size:   u32,
+    chr_ram_size:   u32,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    region: Option<Region>,
     flags6:         u8,
     flags7:         u8,
     flags9:         u8,
@@ -21,15 +70,33 @@ pub struct INesFile {
     pc_inst_rom: Vec<u8>,
     pc_prom: Vec<u8>,
     title: Vec<u8>,
+    // Disk System images have no PRG or CHR, only the disk.
+    disk_sides: Vec<Vec<u8>>,
 }
 
-impl INesFile {
-    pub fn load(bin: Vec<u8>) -> INesFile {
+impl Cartridge {
+    // Loads an iNES, UNIF or FDS file, telling them apart by their magic
+    // bytes.
+    pub fn load(bin: Vec<u8>) -> Result<Cartridge, RomError> {
+        if bin.starts_with(INES_MAGIC) {
+            Cartridge::from_ines(bin)
+        }
+        else if bin.starts_with(unif::MAGIC) {
+            Cartridge::from_unif(&bin)
+        }
+        else if fds::is_image(&bin) {
+            Cartridge::from_fds(&bin)
+        }
+        else {
+            Err(RomError::UnknownFormat)
+        }
+    }
+    fn from_ines(bin: Vec<u8>) -> Result<Cartridge, RomError> {
+        if bin.len() < 16 {
+            return Err(RomError::Truncated);
+        }
         let header = &bin[0..16];
         let m = [ header[0], header[1], header[2], header[3] ];
-        if m[0] != 'N' as u8 || m[1] != 'E' as u8 || m[2] != 'S' as u8 || m[3] != 0x1A {
-            panic!("Invalid ROM!");
-        }
         let prg_rom_cnt = header[4];
         let prg_rom_size = prg_rom_cnt as u32 * 16384;
 
@@ -38,14 +105,62 @@ impl INesFile {
 
         let flags6 = header[6];
         let flags7 = header[7];
-        let prg_ram_size = header[8] as u32 * 8192;
         let flags9 = header[9];
         let flags10 = header[10];
         let zeros = [ header[11], header[12], header[13], header[14], header[15]];
 
-        let mapper = (flags7 & 0xF0) | ((flags6 & 0xF0) >> 4);
+        let mirroring = if flags6 & 0x08 != 0 {
+            Mirroring::FourScreen
+        }
+        else if flags6 & 0x01 != 0 {
+            Mirroring::Vertical
+        }
+        else {
+            Mirroring::Horizontal
+        };
+        let battery = flags6 & 0x02 != 0;
+
+        let nes2 = flags7 & 0x0C == 0x08;
+        let mut mapper = (flags6 >> 4) as u16;
+        let mut submapper = 0;
+        let prg_ram_size;
+        let chr_ram_size;
+        let region;
+        if nes2 {
+            mapper |= (flags7 & 0xF0) as u16 | ((header[8] & 0x0F) as u16) << 8;
+            submapper = header[8] >> 4;
+            // shift counts: 64 << n bytes, 0 for none
+            let size = |n: u8| if n == 0 { 0 } else { 64 << n };
+            prg_ram_size = size(header[10] & 0x0F) + size(header[10] >> 4);
+            chr_ram_size = size(header[11] & 0x0F) + size(header[11] >> 4);
+            region = match header[12] & 0x03 {
+                0 => Some(Region::Ntsc),
+                1 => Some(Region::Pal),
+                3 => Some(Region::Dendy),
+                // multi-region
+                _ => None,
+            };
+        }
+        else {
+            // Old dumps tools like DiskDude! wrote their name over bytes
+            // 7-15, which turns into nonsense in the upper mapper bits.
+            // Real iNES 1.0 headers have zeros in 12-15.
+            let dirty = header[12..16].iter().any(|&b| b != 0);
+            if !dirty {
+                mapper |= (flags7 & 0xF0) as u16;
+            }
+            // 0 means 8K, for compatibility with even older headers
+            prg_ram_size = if dirty || header[8] == 0 { 8192 } else { header[8] as u32 * 8192 };
+            chr_ram_size = if chr_rom_size == 0 { 8192 } else { 0 };
+            // only trusted when the padding is clean
+            region = if !dirty && zeros[0] == 0 && flags9 & 0x01 != 0 { Some(Region::Pal) } else { None };
+        }
 
         let mut pos: usize = 16;
+        let trainer_size = if flags6 & 0x04 != 0 { 0x200 } else { 0 };
+        if bin.len() < pos + trainer_size + prg_rom_size as usize + chr_rom_size as usize {
+            return Err(RomError::Truncated);
+        }
 
         let has_trainer: bool;
         let mut trainer = [0u8; 0x200];
@@ -70,15 +185,21 @@ impl INesFile {
             prg_rom.push(page);
         }
 
+        let chr_rom = bin[pos..pos + chr_rom_size as usize].to_vec();
 
 
-        INesFile {
+        Ok(Cartridge {
             magic: m,
             has_trainer: has_trainer,
             mapper: mapper,
+            submapper,
             prg_rom_size: prg_rom_size,
             prg_rom_cnt: prg_rom_cnt,
             chr_rom_size: chr_rom_size,
+            chr_ram_size,
+            mirroring,
+            battery,
+            region,
             flags6: flags6,
             flags7: flags7,
             prg_ram_size: prg_ram_size,
@@ -87,17 +208,183 @@ impl INesFile {
             zeros: zeros,
             trainer: trainer,
             prg_rom: prg_rom,
+            chr_rom,
             //TODO finish initializing these properly
-            chr_rom: vec![0],
             pc_inst_rom: vec![0],
             pc_prom: vec![0],
             title: vec![0],
+            disk_sides: Vec::new(),
+        })
+    }
+    fn from_unif(bin: &[u8]) -> Result<Cartridge, RomError> {
+        let file = unif::parse(bin)?;
+        let mut prg_rom = Vec::new();
+        for chunk in file.prg.chunks(0x4000) {
+            // a 16K page at minimum, with smaller PRG mirrored to fill it
+            let mut page = [0u8; 0x4000];
+            for (i, b) in page.iter_mut().enumerate() {
+                *b = chunk[i % chunk.len()];
+            }
+            prg_rom.push(page);
+        }
+        let chr_rom_size = file.chr.len() as u32;
+        Ok(Cartridge {
+            magic: [b'U', b'N', b'I', b'F'],
+            has_trainer: false,
+            mapper: file.mapper,
+            submapper: 0,
+            prg_rom_cnt: prg_rom.len() as u8,
+            prg_rom_size: prg_rom.len() as u32 * 0x4000,
+            chr_rom_size,
+            prg_ram_size: 0x2000,
+            chr_ram_size: if chr_rom_size == 0 { 0x2000 } else { 0 },
+            mirroring: file.mirroring,
+            battery: file.battery,
+            region: file.region,
+            flags6: 0,
+            flags7: 0,
+            flags9: 0,
+            flags10: 0,
+            zeros: [0; 5],
+            trainer: [0; 0x200],
+            prg_rom,
+            chr_rom: file.chr,
+            pc_inst_rom: vec![0],
+            pc_prom: vec![0],
+            title: file.name.into_bytes(),
+            disk_sides: Vec::new(),
+        })
+    }
+    fn from_fds(bin: &[u8]) -> Result<Cartridge, RomError> {
+        let sides = fds::parse_image(bin)?;
+        Ok(Cartridge {
+            magic: [b'F', b'D', b'S', 0x1A],
+            has_trainer: false,
+            // the number iNES reserves for the Disk System
+            mapper: 20,
+            submapper: 0,
+            prg_rom_cnt: 0,
+            prg_rom_size: 0,
+            chr_rom_size: 0,
+            prg_ram_size: 0x8000,
+            chr_ram_size: 0x2000,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            region: Some(Region::Ntsc),
+            flags6: 0,
+            flags7: 0,
+            flags9: 0,
+            flags10: 0,
+            zeros: [0; 5],
+            trainer: [0; 0x200],
+            prg_rom: Vec::new(),
+            chr_rom: Vec::new(),
+            pc_inst_rom: vec![0],
+            pc_prom: vec![0],
+            title: fds::title(&sides[0]),
+            disk_sides: sides,
+        })
+    }
+    // Not a cartridge, but NSF players need one to hold the tune for
+    // hashing and the region.
+    pub fn from_nsf(nsf: &nsf::NsfFile) -> Cartridge {
+        let prg_rom: Vec<[u8; 0x4000]> = nsf.data.chunks(0x4000).map(|chunk| {
+            let mut page = [0u8; 0x4000];
+            page[..chunk.len()].copy_from_slice(chunk);
+            page
+        }).collect();
+        Cartridge {
+            magic: [b'N', b'E', b'S', b'M'],
+            has_trainer: false,
+            mapper: 0,
+            submapper: 0,
+            prg_rom_cnt: prg_rom.len() as u8,
+            prg_rom_size: nsf.data.len() as u32,
+            chr_rom_size: 0,
+            prg_ram_size: 0x2000,
+            chr_ram_size: 0,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            region: Some(nsf.region),
+            flags6: 0,
+            flags7: 0,
+            flags9: 0,
+            flags10: 0,
+            zeros: [0; 5],
+            trainer: [0; 0x200],
+            prg_rom,
+            chr_rom: Vec::new(),
+            pc_inst_rom: vec![0],
+            pc_prom: vec![0],
+            title: nsf.title.clone().into_bytes(),
+            disk_sides: Vec::new(),
+        }
+    }
+    // Replaces what the header says with what the database knows about
+    // the game, if it's in there, and returns the entry that was used.
+    pub fn apply_database(&mut self) -> Option<romdb::Entry> {
+        let entry = romdb::lookup(self.hash())?;
+        self.mapper = entry.mapper;
+        self.submapper = entry.submapper;
+        self.mirroring = entry.mirroring;
+        self.battery = entry.battery;
+        self.prg_ram_size = entry.prg_ram_size;
+        self.chr_ram_size = entry.chr_ram_size;
+        self.region = Some(entry.region);
+        Some(entry)
+    }
+    // Empty for boards with CHR-RAM instead.
+    pub fn chr_rom(&self) -> &[u8] {
+        &self.chr_rom
+    }
+    // CRC32 of the PRG and CHR data (or the disk sides), ignoring the
+    // header and trainer. This is what ROM databases key on, and what save
+    // states use to make sure they are loaded against the right game.
+    pub fn hash(&self) -> u32 {
+        let mut crc = crc32::Crc32::new();
+        for page in &self.prg_rom {
+            crc.update(page);
+        }
+        crc.update(&self.chr_rom);
+        for side in &self.disk_sides {
+            crc.update(side);
+        }
+        crc.finish()
+    }
+    // The console the game was made for, when the header or the database
+    // says.
+    pub fn region(&self) -> Option<Region> {
+        self.region
+    }
+    pub fn is_disk(&self) -> bool {
+        !self.disk_sides.is_empty()
+    }
+    // Each side of a Disk System image, as stored in the file.
+    pub fn disk_sides(&self) -> &[Vec<u8>] {
+        &self.disk_sides
+    }
+    pub fn prg_ram_size(&self) -> u32 {
+        self.prg_ram_size
+    }
+    pub fn chr_ram_size(&self) -> u32 {
+        self.chr_ram_size
+    }
+    // FCEUX identifies games by the MD5 of the same data.
+    pub fn md5(&self) -> [u8; 16] {
+        let mut md5 = md5::Md5::new();
+        for page in &self.prg_rom {
+            md5.update(page);
+        }
+        md5.update(&self.chr_rom);
+        for side in &self.disk_sides {
+            md5.update(side);
         }
+        md5.finish()
     }
     #[allow(dead_code)]
     pub fn info(&self) {
         println!("has_trainer: {}", self.has_trainer);
-        println!("mapper: {}", self.mapper);
+        println!("mapper: {}.{}", self.mapper, self.submapper);
         println!("prg_rom_size: {}", self.prg_rom_size);
         println!("chr_rom_size: {}", self.chr_rom_size);
         println!("prg_ram_size: {}", self.prg_ram_size);
@@ -115,3 +402,63 @@ impl INesFile {
         f.write_all(buf).expect("failed to write to file");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ines(header: &[u8]) -> Vec<u8> {
+        let mut image = header.to_vec();
+        image.resize(16, 0);
+        image.resize(16 + 0x4000 + 0x2000, 0);
+        image
+    }
+
+    #[test]
+    fn ines_header() {
+        let rom = Cartridge::load(ines(b"NES\x1A\x01\x01\x13\x40\x02\x01")).unwrap();
+        assert_eq!(rom.mapper, 0x41);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+        assert_eq!(rom.prg_ram_size, 0x4000);
+        assert_eq!(rom.chr_ram_size(), 0);
+        assert_eq!(rom.region(), Some(Region::Pal));
+    }
+
+    #[test]
+    fn diskdude_header() {
+        let rom = Cartridge::load(ines(b"NES\x1A\x01\x01\x40\x44iskDude!")).unwrap();
+        assert_eq!(rom.mapper, 4);
+        assert_eq!(rom.prg_ram_size, 0x2000);
+        assert_eq!(rom.region(), None);
+    }
+
+    #[test]
+    fn nes2_header() {
+        let rom = Cartridge::load(ines(b"NES\x1A\x01\x01\x08\x58\x31\x00\x70\x07\x03")).unwrap();
+        assert_eq!((rom.mapper, rom.submapper), (0x150, 3));
+        assert_eq!(rom.mirroring, Mirroring::FourScreen);
+        assert_eq!(rom.prg_ram_size, 0x2000);
+        assert_eq!(rom.chr_ram_size(), 0x2000);
+        assert_eq!(rom.region(), Some(Region::Dendy));
+        let rom = Cartridge::load(ines(b"NES\x1A\x01\x01\x00\x08\x00\x00\x00\x00\x02")).unwrap();
+        assert_eq!(rom.region(), None);
+    }
+
+    #[test]
+    fn unknown_games_keep_their_header() {
+        let mut rom = Cartridge::load(ines(b"NES\x1A\x01\x01\x13\x40\x02\x01")).unwrap();
+        assert!(rom.apply_database().is_none());
+        assert_eq!(rom.mapper, 0x41);
+        assert_eq!(rom.region(), Some(Region::Pal));
+    }
+
+    #[test]
+    fn bad_images() {
+        let mut short = ines(b"NES\x1A\x02\x01");
+        short.truncate(100);
+        assert!(matches!(Cartridge::load(short), Err(RomError::Truncated)));
+        assert!(matches!(Cartridge::load(b"NES".to_vec()), Err(RomError::UnknownFormat)));
+        assert!(matches!(Cartridge::load(vec![0; 64]), Err(RomError::UnknownFormat)));
+    }
+}
@@ -0,0 +1,111 @@
+// Built-in ROM database
+//
+// Many iNES 1.0 dumps have a wrong or incomplete header, so games whose
+// header is known to be unreliable are listed in romdb.txt by the CRC32 of
+// their PRG and CHR data, one per line:
+//   <crc32> <mapper>.<submapper> <mirroring> <battery> <prg-ram> <chr-ram> <region> <name>
+// Mirroring is H, V or 4 (four-screen), battery is B or -, RAM sizes are in
+// bytes with an optional K suffix, and region is ntsc, pal or dendy.
+
+use region::Region;
+use rom::Mirroring;
+
+const DATABASE: &str = include_str!("romdb.txt");
+
+pub struct Entry {
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub prg_ram_size: u32,
+    pub chr_ram_size: u32,
+    pub region: Region,
+    pub name: String,
+}
+
+pub fn lookup(crc: u32) -> Option<Entry> {
+    for (i, line) in DATABASE.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let key = line.split_whitespace().next().unwrap_or("");
+        if u32::from_str_radix(key, 16).ok() != Some(crc) {
+            continue;
+        }
+        // the database is built in, so a bad line is a bug
+        match parse_entry(line) {
+            Some(entry) => return Some(entry),
+            None => panic!("romdb.txt line {} is malformed", i + 1),
+        }
+    }
+    None
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut fields = line.split_whitespace();
+    fields.next()?;
+    let mut board = fields.next()?.splitn(2, '.');
+    let mapper = board.next()?.parse().ok()?;
+    let submapper = board.next().unwrap_or("0").parse().ok()?;
+    let mirroring = match fields.next()? {
+        "H" => Mirroring::Horizontal,
+        "V" => Mirroring::Vertical,
+        "4" => Mirroring::FourScreen,
+        _ => return None,
+    };
+    let battery = match fields.next()? {
+        "B" => true,
+        "-" => false,
+        _ => return None,
+    };
+    let prg_ram_size = parse_size(fields.next()?)?;
+    let chr_ram_size = parse_size(fields.next()?)?;
+    let region = Region::from_name(fields.next()?)?;
+    let name = fields.collect::<Vec<&str>>().join(" ");
+    Some(Entry { mapper, submapper, mirroring, battery, prg_ram_size, chr_ram_size, region, name })
+}
+
+fn parse_size(field: &str) -> Option<u32> {
+    if let Some(k) = field.strip_suffix('K') {
+        k.parse::<u32>().ok().map(|n| n * 1024)
+    } else {
+        field.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries() {
+        let e = parse_entry("0123ABCD   4.1     4          B        8K       512      pal     Some Game (Europe)").unwrap();
+        assert_eq!((e.mapper, e.submapper), (4, 1));
+        assert_eq!(e.mirroring, Mirroring::FourScreen);
+        assert!(e.battery);
+        assert_eq!((e.prg_ram_size, e.chr_ram_size), (8192, 512));
+        assert_eq!(e.region, Region::Pal);
+        assert_eq!(e.name, "Some Game (Europe)");
+
+        let e = parse_entry("0123ABCD 66 H - 0 0 dendy X").unwrap();
+        assert_eq!((e.mapper, e.submapper), (66, 0));
+        assert_eq!(e.mirroring, Mirroring::Horizontal);
+        assert!(!e.battery);
+
+        assert!(parse_entry("0123ABCD 0.0 X - 0 0 ntsc X").is_none());
+        assert!(parse_entry("0123ABCD 0.0 V Y 0 0 ntsc X").is_none());
+        assert!(parse_entry("0123ABCD 0.0 V - 8M 0 ntsc X").is_none());
+        assert!(parse_entry("0123ABCD 0.0 V - 0 0 secam X").is_none());
+        assert!(parse_entry("0123ABCD 0.0 V -").is_none());
+    }
+
+    #[test]
+    fn lookup_by_crc() {
+        let e = lookup(0x3337EC46).unwrap();
+        assert_eq!(e.mapper, 0);
+        assert_eq!(e.mirroring, Mirroring::Vertical);
+        assert_eq!(e.name, "Super Mario Bros. (World)");
+        assert!(lookup(0).is_none());
+    }
+}
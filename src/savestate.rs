@@ -0,0 +1,296 @@
+// Save state format
+//
+// A state is a 16 byte header followed by the body:
+//   0..4   magic "FNST"
+//   4..6   format version (little endian)
+//   6      console region: 0 NTSC, 1 PAL, 2 Dendy
+//   7      reserved, zero
+//   8..12  CRC32 of the loaded ROM's PRG+CHR data
+//   12..16 body length in bytes
+//
+// The body is written by each subsystem in a fixed order, starting from the
+// CPU and working outwards through the memory map. All multi-byte values are
+// little endian. Bump VERSION whenever the layout of any section changes.
+//
+// A state only loads into a machine running the same ROM in the same
+// region, since the timing of everything in it depends on the region.
+
+use std::fmt;
+
+use region::Region;
+
+const MAGIC: [u8; 4] = [b'F', b'N', b'S', b'T'];
+const HEADER_SIZE: usize = 16;
+pub const VERSION: u16 = 11;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch { expected: u32, found: u32 },
+    RegionMismatch { expected: Region, found: Region },
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "not a futilenes save state"),
+            StateError::UnsupportedVersion(v) => write!(f, "unsupported save state version {}", v),
+            StateError::RomMismatch { expected, found } => {
+                write!(f, "save state is for ROM {:08X}, but {:08X} is loaded", found, expected)
+            }
+            StateError::RegionMismatch { expected, found } => {
+                write!(f, "save state is from a {} console, but this one is {}", found, expected)
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(what) => write!(f, "save state has a bad {}", what),
+        }
+    }
+}
+
+// Implemented by every piece of the machine that holds state.
+// load() must read exactly what save() wrote, in the same order.
+pub trait Savable {
+    fn save(&self, w: &mut StateWriter);
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_hash: u32, region: Region) -> StateWriter {
+        let mut buf = Vec::with_capacity(0x1000);
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&[region_code(region), 0]);
+        buf.extend_from_slice(&rom_hash.to_le_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]); // body length, filled in by finish()
+        StateWriter { buf }
+    }
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+    pub fn finish(mut self) -> Vec<u8> {
+        let body_len = (self.buf.len() - HEADER_SIZE) as u32;
+        self.buf[12..16].copy_from_slice(&body_len.to_le_bytes());
+        self.buf
+    }
+}
+
+fn region_code(region: Region) -> u8 {
+    match region {
+        Region::Ntsc => 0,
+        Region::Pal => 1,
+        Region::Dendy => 2,
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // Validates the header so that a truncated or mismatched state is
+    // rejected before any subsystem has been modified.
+    pub fn new(data: &'a [u8], rom_hash: u32, region: Region) -> Result<StateReader<'a>, StateError> {
+        if data.len() < HEADER_SIZE {
+            return Err(StateError::Truncated);
+        }
+        if data[0..4] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let found = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+        if found != rom_hash {
+            return Err(StateError::RomMismatch { expected: rom_hash, found });
+        }
+        let found = match data[6] {
+            0 => Region::Ntsc,
+            1 => Region::Pal,
+            2 => Region::Dendy,
+            _ => return Err(StateError::Invalid("region")),
+        };
+        if found != region {
+            return Err(StateError::RegionMismatch { expected: region, found });
+        }
+        let body_len = u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as usize;
+        if data.len() != HEADER_SIZE + body_len {
+            return Err(StateError::Truncated);
+        }
+        Ok(StateReader { data, pos: HEADER_SIZE })
+    }
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.pos + len > self.data.len() {
+            return Err(StateError::Truncated);
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.take(1)?[0] != 0)
+    }
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let b = self.take(8)?;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(b);
+        Ok(u64::from_le_bytes(bytes))
+    }
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        let b = self.take(out.len())?;
+        out.copy_from_slice(b);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::CPU;
+    use mapper;
+    use rom;
+
+    // Turns on the vblank NMI, which counts frames at $01, and then keeps
+    // writing a counter at $00 through $2007.
+    const PROGRAM: &[u8] = &[
+        0x78,                   // C000  SEI
+        0xA9, 0x80,             // C001  LDA #$80
+        0x8D, 0x00, 0x20,       // C003  STA $2000
+        0xA9, 0x20,             // C006  LDA #$20
+        0x8D, 0x06, 0x20,       // C008  STA $2006
+        0xA9, 0x00,             // C00B  LDA #$00
+        0x8D, 0x06, 0x20,       // C00D  STA $2006
+        0xE6, 0x00,             // C010  INC $00
+        0xA5, 0x00,             // C012  LDA $00
+        0x8D, 0x07, 0x20,       // C014  STA $2007
+        0x4C, 0x10, 0xC0,       // C017  JMP $C010
+        0xE6, 0x01,             // C01A  INC $01
+        0x40,                   // C01C  RTI
+    ];
+
+    fn machine() -> CPU {
+        let mut image = b"NES\x1A\x01\x01".to_vec();
+        image.resize(16, 0);
+        let mut prg = vec![0; 0x4000];
+        prg[..PROGRAM.len()].copy_from_slice(PROGRAM);
+        prg[0x3FFA..].copy_from_slice(&[0x1A, 0xC0, 0x00, 0xC0, 0x00, 0xC0]);
+        image.extend_from_slice(&prg);
+        image.extend_from_slice(&[0; 0x2000]);
+        let mut cpu = CPU::new(mapper::new(rom::Cartridge::load(image).unwrap(), None).unwrap());
+        cpu.set_trace(false);
+        cpu
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let mut w = StateWriter::new(0x1234_5678, Region::Ntsc);
+        w.write_u8(0xAB);
+        w.write_u16(0xBEEF);
+        w.write_u64(0x0123_4567_89AB_CDEF);
+        w.write_bytes(&[1, 2, 3]);
+        let data = w.finish();
+        assert_eq!(data.len(), HEADER_SIZE + 14);
+
+        let mut r = StateReader::new(&data, 0x1234_5678, Region::Ntsc).unwrap();
+        assert_eq!(r.read_u8(), Ok(0xAB));
+        assert_eq!(r.read_u16(), Ok(0xBEEF));
+        assert_eq!(r.read_u64(), Ok(0x0123_4567_89AB_CDEF));
+        let mut bytes = [0; 3];
+        r.read_bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(r.read_u8(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn bad_headers() {
+        let mut w = StateWriter::new(7, Region::Pal);
+        w.write_u8(0);
+        let data = w.finish();
+
+        assert_eq!(StateReader::new(&data[..8], 7, Region::Pal).err(), Some(StateError::Truncated));
+        assert_eq!(StateReader::new(&data[..data.len() - 1], 7, Region::Pal).err(), Some(StateError::Truncated));
+        assert_eq!(StateReader::new(&data, 8, Region::Pal).err(), Some(StateError::RomMismatch { expected: 8, found: 7 }));
+
+        let mut bad = data.clone();
+        bad[0] = b'X';
+        assert_eq!(StateReader::new(&bad, 7, Region::Pal).err(), Some(StateError::BadMagic));
+
+        let mut old = data.clone();
+        old[4..6].copy_from_slice(&(VERSION - 1).to_le_bytes());
+        assert_eq!(StateReader::new(&old, 7, Region::Pal).err(), Some(StateError::UnsupportedVersion(VERSION - 1)));
+
+        assert_eq!(StateReader::new(&data, 7, Region::Dendy).err(),
+                   Some(StateError::RegionMismatch { expected: Region::Dendy, found: Region::Pal }));
+        let mut bad = data.clone();
+        bad[6] = 3;
+        assert_eq!(StateReader::new(&bad, 7, Region::Pal).err(), Some(StateError::Invalid("region")));
+    }
+
+    #[test]
+    fn machine_round_trip() {
+        let mut cpu = machine();
+        for _ in 0..3 {
+            cpu.run_frame();
+        }
+        let state = cpu.save_state();
+        for _ in 0..2 {
+            cpu.run_frame();
+        }
+        let later = cpu.save_state();
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.save_state(), state);
+        for _ in 0..2 {
+            cpu.run_frame();
+        }
+        assert_eq!(cpu.save_state(), later);
+    }
+
+    #[test]
+    fn state_for_another_rom() {
+        let mut cpu = machine();
+        let mut state = cpu.save_state();
+        state[8] ^= 0xFF;
+        assert!(matches!(cpu.load_state(&state), Err(StateError::RomMismatch { .. })));
+    }
+
+    #[test]
+    fn state_for_another_region() {
+        let mut cpu = machine();
+        cpu.run_frame();
+        let state = cpu.save_state();
+        cpu.set_region(Region::Pal);
+        let before = cpu.save_state();
+        assert!(matches!(cpu.load_state(&state), Err(StateError::RegionMismatch { .. })));
+        assert_eq!(cpu.save_state(), before);
+        cpu.set_region(Region::Ntsc);
+        cpu.load_state(&state).unwrap();
+    }
+}
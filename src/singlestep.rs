@@ -0,0 +1,264 @@
+// Runner for the per-opcode CPU test vectors from the SingleStepTests
+// project (github.com/SingleStepTests/65x02, formerly Tom Harte's
+// ProcessorTests). Each of 00.json to ff.json holds an array of tests:
+//   { "name": "...",
+//     "initial": { "pc", "s", "a", "x", "y", "p", "ram": [[address, value], ...] },
+//     "final":   { same as initial },
+//     "cycles":  [[address, value, "read" or "write"], ...] }
+// Every test is a single instruction run against a flat 64K bus, checking
+// the registers, the memory and each bus access along the way. Use the
+// nes6502 set with the 2A03 variant and the 6502 set with the NMOS one.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use bus::Bus;
+use cpu::{CPU, Registers, Variant};
+use json::{self, Value};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Access {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:04X} {:02X}", if self.write { "write" } else { "read" }, self.address, self.value)
+    }
+}
+
+// 64K of RAM that logs every access.
+pub struct TestBus {
+    ram: Vec<u8>,
+    log: Vec<Access>,
+    // everything that needs clearing before the next test
+    touched: Vec<u16>,
+}
+
+impl TestBus {
+    pub fn new() -> TestBus {
+        TestBus { ram: vec![0; 0x10000], log: Vec::new(), touched: Vec::new() }
+    }
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.ram[address as usize] = value;
+        self.touched.push(address);
+    }
+    pub fn log(&self) -> &[Access] {
+        &self.log
+    }
+    pub fn clear(&mut self) {
+        for &address in &self.touched {
+            self.ram[address as usize] = 0;
+        }
+        self.touched.clear();
+        self.log.clear();
+    }
+}
+
+impl Bus for TestBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.ram[address as usize];
+        self.log.push(Access { address, value, write: false });
+        value
+    }
+    fn write(&mut self, address: u16, value: u8) {
+        self.poke(address, value);
+        self.log.push(Access { address, value, write: true });
+    }
+    fn peek(&self, address: u16) -> u8 {
+        self.ram[address as usize]
+    }
+}
+
+pub struct FileReport {
+    pub total: usize,
+    // test name and what went wrong
+    pub failures: Vec<(String, Vec<String>)>,
+}
+
+pub fn run_file<P: AsRef<Path>>(path: P, variant: Variant) -> Result<FileReport, String> {
+    let mut text = String::new();
+    File::open(&path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| e.to_string())?;
+    let doc = json::parse(&text).map_err(|e| e.to_string())?;
+    let tests = doc.as_array().ok_or("expected an array of tests")?;
+
+    let mut report = FileReport { total: tests.len(), failures: Vec::new() };
+    let mut bus = TestBus::new();
+    for (i, test) in tests.iter().enumerate() {
+        let name = match test.get("name").and_then(Value::as_str) {
+            Some(n) => n.to_string(),
+            None => format!("#{}", i),
+        };
+        let (problems, b) = run_test(test, variant, bus).map_err(|e| format!("{}: {}", name, e))?;
+        bus = b;
+        if !problems.is_empty() {
+            report.failures.push((name, problems));
+        }
+    }
+    Ok(report)
+}
+
+fn run_test(test: &Value, variant: Variant, mut bus: TestBus) -> Result<(Vec<String>, TestBus), String> {
+    let initial = test.get("initial").ok_or("no initial state")?;
+    let expected = test.get("final").ok_or("no final state")?;
+    let cycles = parse_cycles(test.get("cycles").ok_or("no cycles")?)?;
+
+    bus.clear();
+    for (address, value) in parse_ram(initial)? {
+        bus.poke(address, value);
+    }
+    let mut cpu = CPU::with_bus(bus);
+    cpu.set_variant(variant);
+    cpu.set_trace(false);
+    cpu.set_registers(&parse_registers(initial)?);
+    cpu.bus_mut().log.clear();
+    cpu.step();
+
+    let mut problems = Vec::new();
+    let want = parse_registers(expected)?;
+    let got = cpu.registers();
+    // B and bit 5 don't exist inside the CPU, only when P is pushed
+    let regs = [("PC", want.pc, got.pc),
+                ("S", want.sp as u16, got.sp as u16),
+                ("A", want.a as u16, got.a as u16),
+                ("X", want.x as u16, got.x as u16),
+                ("Y", want.y as u16, got.y as u16),
+                ("P", (want.p | 0x30) as u16, (got.p | 0x30) as u16)];
+    for &(reg, want, got) in &regs {
+        if want != got {
+            problems.push(format!("{} is {:02X}, expected {:02X}", reg, got, want));
+        }
+    }
+    let bus = cpu.bus();
+    for (address, value) in parse_ram(expected)? {
+        if bus.peek(address) != value {
+            problems.push(format!("{:04X} is {:02X}, expected {:02X}", address, bus.peek(address), value));
+        }
+    }
+    let n = cycles.len().max(bus.log().len());
+    for i in 0..n {
+        match (cycles.get(i), bus.log().get(i)) {
+            (Some(w), Some(g)) if w == g => {}
+            (Some(w), Some(g)) => problems.push(format!("cycle {}: {}, expected {}", i + 1, g, w)),
+            (Some(w), None) => problems.push(format!("cycle {}: nothing, expected {}", i + 1, w)),
+            (None, Some(g)) => problems.push(format!("cycle {}: {}, expected nothing", i + 1, g)),
+            (None, None) => {}
+        }
+    }
+    if cpu.cycles() != cycles.len() as u64 {
+        problems.push(format!("took {} cycles, expected {}", cpu.cycles(), cycles.len()));
+    }
+    Ok((problems, cpu.into_bus()))
+}
+
+fn number(state: &Value, key: &str) -> Result<u64, String> {
+    state.get(key).and_then(Value::as_u64).ok_or_else(|| format!("missing or bad '{}'", key))
+}
+
+fn parse_registers(state: &Value) -> Result<Registers, String> {
+    Ok(Registers {
+        pc: number(state, "pc")? as u16,
+        sp: number(state, "s")? as u8,
+        a: number(state, "a")? as u8,
+        x: number(state, "x")? as u8,
+        y: number(state, "y")? as u8,
+        p: number(state, "p")? as u8,
+    })
+}
+
+fn pair(entry: &Value) -> Option<(u16, u8, &[Value])> {
+    let items = entry.as_array()?;
+    let address = items.first()?.as_u64()?;
+    let value = items.get(1)?.as_u64()?;
+    Some((address as u16, value as u8, &items[2..]))
+}
+
+fn parse_ram(state: &Value) -> Result<Vec<(u16, u8)>, String> {
+    let ram = state.get("ram").and_then(Value::as_array).ok_or("missing or bad 'ram'")?;
+    ram.iter()
+        .map(|e| pair(e).map(|(a, v, _)| (a, v)).ok_or_else(|| "bad ram entry".to_string()))
+        .collect()
+}
+
+fn parse_cycles(cycles: &Value) -> Result<Vec<Access>, String> {
+    let cycles = cycles.as_array().ok_or("bad cycles")?;
+    cycles.iter()
+        .map(|e| {
+            let (address, value, rest) = pair(e).ok_or("bad cycle entry")?;
+            let write = match rest.first().and_then(Value::as_str) {
+                Some("read") => false,
+                Some("write") => true,
+                _ => return Err("bad cycle entry".to_string()),
+            };
+            Ok(Access { address, value, write })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    const TESTS: &str = r#"[
+        { "name": "a9 pass",
+          "initial": { "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[512, 169], [513, 128]] },
+          "final": { "pc": 514, "s": 253, "a": 128, "x": 0, "y": 0, "p": 164, "ram": [[512, 169], [513, 128]] },
+          "cycles": [[512, 169, "read"], [513, 128, "read"]] },
+        { "name": "a9 fail",
+          "initial": { "pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[512, 169], [513, 0]] },
+          "final": { "pc": 514, "s": 253, "a": 1, "x": 0, "y": 0, "p": 38, "ram": [[513, 1]] },
+          "cycles": [[512, 169, "read"], [513, 0, "read"], [514, 0, "read"]] }
+    ]"#;
+
+    fn run(name: &str, text: &str) -> Result<FileReport, String> {
+        let path = env::temp_dir().join(format!("futilenes-singlestep-{}-{}.json", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        let report = run_file(&path, Variant::Ricoh2A03);
+        fs::remove_file(&path).unwrap();
+        report
+    }
+
+    #[test]
+    fn reports_failures() {
+        let report = run("a9", TESTS).unwrap();
+        assert_eq!(report.total, 2);
+        assert_eq!(report.failures.len(), 1);
+        let (ref name, ref problems) = report.failures[0];
+        assert_eq!(name, "a9 fail");
+        assert_eq!(problems, &[
+            "A is 00, expected 01",
+            "0201 is 00, expected 01",
+            "cycle 3: nothing, expected read 0202 00",
+            "took 2 cycles, expected 3",
+        ]);
+    }
+
+    #[test]
+    fn bad_files() {
+        assert!(run("object", "{}").is_err());
+        assert!(run("syntax", "[").is_err());
+        let err = run("missing", r#"[{ "name": "x", "initial": {}, "final": {}, "cycles": [] }]"#).err().unwrap();
+        assert!(err.starts_with("x: "), "{}", err);
+    }
+
+    #[test]
+    fn test_bus_logs_and_clears() {
+        let mut bus = TestBus::new();
+        bus.write(0x1234, 5);
+        assert_eq!(bus.read(0x1234), 5);
+        assert_eq!(bus.peek(0x1234), 5);
+        assert_eq!(bus.log(), &[
+            Access { address: 0x1234, value: 5, write: true },
+            Access { address: 0x1234, value: 5, write: false },
+        ]);
+        bus.clear();
+        assert!(bus.log().is_empty());
+        assert_eq!(bus.peek(0x1234), 0);
+    }
+}
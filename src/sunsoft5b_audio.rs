@@ -0,0 +1,255 @@
+// Sunsoft 5B sound
+//
+// A Yamaha YM2149 (an AY-3-8910 with a finer envelope) inside the FME-7:
+// three square channels, a noise generator and an envelope, reached by
+// writing a register number to $C000 and then its value to $E000:
+//   0-5  12-bit tone periods of channels A, B and C, low byte first
+//   6    5-bit noise period
+//   7    mixer, tone (0-2) and noise (3-5) disables per channel, active low
+//   8-A  channel volumes (0-3), or the envelope when bit 4 is set
+//   B-C  16-bit envelope period
+//   D    envelope shape: hold (0), alternate (1), attack (2), continue (3)
+//
+// The chip divides the CPU clock by 2 and then by 8 for the tone and
+// envelope dividers, so a square toggles every 16 * period cycles and the
+// envelope takes a step every 16 * period cycles, 32 steps to a ramp.
+// The noise divider runs at half the tone rate. Volumes are logarithmic,
+// 1.5dB to an envelope step and 3dB to a volume step.
+
+use apu;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+// CPU cycles per tone divider step.
+const PRESCALE: u8 = 16;
+
+#[derive(Default)]
+struct Tone {
+    period: u16,
+    divider: u16,
+    high: bool,
+}
+
+impl Tone {
+    fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider >= self.period.max(1) {
+            self.divider = 0;
+            self.high = !self.high;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    period: u16,
+    divider: u16,
+    // Counts down from 31; XORed with `attack` for the level.
+    count: i8,
+    attack: u8,
+    alternate: bool,
+    hold: bool,
+    holding: bool,
+}
+
+impl Envelope {
+    fn write_shape(&mut self, value: u8) {
+        self.attack = if value & 0x04 != 0 { 0x1F } else { 0 };
+        if value & 0x08 == 0 {
+            // one ramp, then silence
+            self.hold = true;
+            self.alternate = self.attack != 0;
+        }
+        else {
+            self.hold = value & 0x01 != 0;
+            self.alternate = value & 0x02 != 0;
+        }
+        self.count = 0x1F;
+        self.divider = 0;
+        self.holding = false;
+    }
+    fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider < self.period.max(1) {
+            return;
+        }
+        self.divider = 0;
+        if self.holding {
+            return;
+        }
+        self.count -= 1;
+        if self.count >= 0 {
+            return;
+        }
+        if self.hold {
+            if self.alternate {
+                self.attack ^= 0x1F;
+            }
+            self.holding = true;
+            self.count = 0;
+        }
+        else {
+            if self.alternate {
+                self.attack ^= 0x1F;
+            }
+            self.count = 0x1F;
+        }
+    }
+    fn level(&self) -> u8 {
+        self.count as u8 ^ self.attack
+    }
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u16(self.period);
+        w.write_u16(self.divider);
+        w.write_u8(self.count as u8);
+        w.write_u8(self.attack);
+        w.write_bool(self.alternate);
+        w.write_bool(self.hold);
+        w.write_bool(self.holding);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.period = r.read_u16()?;
+        self.divider = r.read_u16()?;
+        self.count = (r.read_u8()? & 0x1F) as i8;
+        self.attack = r.read_u8()? & 0x1F;
+        self.alternate = r.read_bool()?;
+        self.hold = r.read_bool()?;
+        self.holding = r.read_bool()?;
+        Ok(())
+    }
+}
+
+pub struct Sunsoft5bAudio {
+    registers: [u8; 16],
+    selected: u8,
+    prescaler: u8,
+    tones: [Tone; 3],
+    noise_divider: u8,
+    // 17-bit LFSR
+    noise: u32,
+    noise_phase: bool,
+    envelope: Envelope,
+    // 32 levels, in 1/1000ths of full scale
+    levels: [u16; 32],
+}
+
+impl Sunsoft5bAudio {
+    pub fn new() -> Sunsoft5bAudio {
+        let mut levels = [0; 32];
+        for (i, level) in levels.iter_mut().enumerate().skip(1) {
+            *level = (1000.0 * 10f32.powf(-1.5 * (31 - i) as f32 / 20.0)) as u16;
+        }
+        Sunsoft5bAudio {
+            registers: [0; 16],
+            selected: 0,
+            prescaler: PRESCALE,
+            tones: Default::default(),
+            noise_divider: 0,
+            noise: 1,
+            noise_phase: false,
+            envelope: Envelope::default(),
+            levels,
+        }
+    }
+    pub fn select(&mut self, value: u8) {
+        self.selected = value;
+    }
+    pub fn write(&mut self, value: u8) {
+        // the upper bits of the register number have to be clear
+        if self.selected > 0x0F {
+            return;
+        }
+        let register = self.selected as usize;
+        self.registers[register] = value;
+        match register {
+            0..=5 => {
+                let channel = register / 2;
+                self.tones[channel].period = self.registers[channel * 2] as u16
+                    | ((self.registers[channel * 2 + 1] & 0x0F) as u16) << 8;
+            }
+            0x0B | 0x0C => self.envelope.period = self.registers[0x0B] as u16 | (self.registers[0x0C] as u16) << 8,
+            0x0D => self.envelope.write_shape(value),
+            _ => {}
+        }
+    }
+    fn clock_noise(&mut self) {
+        // the noise divider is clocked at half the tone rate
+        self.noise_phase = !self.noise_phase;
+        if self.noise_phase {
+            return;
+        }
+        self.noise_divider += 1;
+        if self.noise_divider >= (self.registers[6] & 0x1F).max(1) {
+            self.noise_divider = 0;
+            let feedback = (self.noise ^ (self.noise >> 3)) & 1;
+            self.noise = (self.noise >> 1) | feedback << 16;
+        }
+    }
+    // One CPU cycle.
+    pub fn clock(&mut self) {
+        self.prescaler -= 1;
+        if self.prescaler > 0 {
+            return;
+        }
+        self.prescaler = PRESCALE;
+        for tone in &mut self.tones {
+            tone.clock();
+        }
+        self.clock_noise();
+        self.envelope.clock();
+    }
+    fn channel_level(&self, channel: usize) -> u16 {
+        let mixer = self.registers[7];
+        let tone = self.tones[channel].high || mixer & (1 << channel) != 0;
+        let noise = self.noise & 1 != 0 || mixer & (8 << channel) != 0;
+        if !(tone && noise) {
+            return 0;
+        }
+        let volume = self.registers[8 + channel];
+        if volume & 0x10 != 0 {
+            self.levels[self.envelope.level() as usize]
+        }
+        else if volume & 0x0F == 0 {
+            0
+        }
+        else {
+            self.levels[((volume & 0x0F) * 2 + 1) as usize]
+        }
+    }
+    // On the APU's scale: a channel at full volume is as loud as an APU
+    // pulse at 15.
+    pub fn output(&self) -> f32 {
+        let sum: u16 = (0..3).map(|channel| self.channel_level(channel)).sum();
+        sum as f32 / 1000.0 * apu::PULSE_FULL_VOLUME
+    }
+}
+
+impl Savable for Sunsoft5bAudio {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.registers);
+        w.write_u8(self.selected);
+        w.write_u8(self.prescaler);
+        for tone in &self.tones {
+            w.write_u16(tone.divider);
+            w.write_bool(tone.high);
+        }
+        w.write_u8(self.noise_divider);
+        w.write_u64(self.noise as u64);
+        w.write_bool(self.noise_phase);
+        self.envelope.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.registers)?;
+        self.selected = r.read_u8()?;
+        self.prescaler = r.read_u8()?.clamp(1, PRESCALE);
+        for (channel, tone) in self.tones.iter_mut().enumerate() {
+            tone.period = self.registers[channel * 2] as u16 | ((self.registers[channel * 2 + 1] & 0x0F) as u16) << 8;
+            tone.divider = r.read_u16()?;
+            tone.high = r.read_bool()?;
+        }
+        self.noise_divider = r.read_u8()?;
+        // an LFSR stuck at zero would never make noise again
+        self.noise = (r.read_u64()? as u32 & 0x1FFFF).max(1);
+        self.noise_phase = r.read_bool()?;
+        self.envelope.load(r)
+    }
+}
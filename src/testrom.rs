@@ -0,0 +1,259 @@
+// Test ROM runner
+//
+// Most of the community test ROMs (blargg's instr_test, ppu_vbl_nmi,
+// apu_test, mmc3_test, ...) report through PRG-RAM:
+//   $6000       status: $80 while running, $81 when the reset button needs
+//               to be pressed, anything else is the final result (0 = pass)
+//   $6001-$6003 $DE $B0 $61 once the status is valid
+//   $6004-      zero terminated result text
+// ROMs that only show their result on screen are checked instead by
+// hashing the framebuffer after a fixed number of frames.
+//
+// A suite file lists one test per line:
+//   <rom>                      pass/fail from $6000
+//   <rom> <frames> <crc32>     framebuffer hash after <frames> frames
+// Blank lines and lines starting with '#' are ignored.
+
+use std::fmt;
+
+use crc32::Crc32;
+use cpu::CPU;
+
+// Give up on a ROM that hasn't finished after this much emulated time.
+pub const DEFAULT_TIMEOUT_FRAMES: u64 = 60 * 60;
+
+// The reset has to come at least 100ms after $81 shows up.
+const RESET_DELAY_FRAMES: u64 = 7;
+
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+pub enum TestResult {
+    Passed(String),
+    Failed(u8, String),
+    TimedOut,
+    Jammed(u8, u16),
+    HashMismatch { expected: u32, found: u32 },
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        matches!(*self, TestResult::Passed(_))
+    }
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TestResult::Passed(ref text) => write!(f, "passed {}", text.trim()),
+            TestResult::Failed(code, ref text) => write!(f, "failed with code {}: {}", code, text.trim()),
+            TestResult::TimedOut => write!(f, "timed out"),
+            TestResult::Jammed(opcode, pc) => write!(f, "CPU jammed by opcode {:02X} at {:04X}", opcode, pc),
+            TestResult::HashMismatch { expected, found } => {
+                write!(f, "frame hash {:08X}, expected {:08X}", found, expected)
+            }
+        }
+    }
+}
+
+// Runs a ROM that reports through $6000 until it finishes or times out.
+pub fn run_status(cpu: &mut CPU, timeout_frames: u64) -> TestResult {
+    let mut reset_at = None;
+    for frame in 0..timeout_frames {
+        cpu.run_frame();
+        if cpu.jammed() {
+            return TestResult::Jammed(cpu.peek(cpu.pc()), cpu.pc());
+        }
+        if !has_signature(cpu) {
+            continue;
+        }
+        match cpu.peek(0x6000) {
+            0x80 => {}
+            0x81 => match reset_at {
+                None => reset_at = Some(frame + RESET_DELAY_FRAMES),
+                Some(f) if f == frame => {
+                    cpu.reset();
+                    reset_at = None;
+                }
+                Some(_) => {}
+            },
+            0 => return TestResult::Passed(read_text(cpu)),
+            code => return TestResult::Failed(code, read_text(cpu)),
+        }
+    }
+    TestResult::TimedOut
+}
+
+// Runs a ROM for a fixed number of frames and compares the framebuffer.
+pub fn run_frame_hash(cpu: &mut CPU, frames: u64, expected: u32) -> TestResult {
+    for _ in 0..frames {
+        cpu.run_frame();
+    }
+    let found = frame_hash(cpu);
+    if found == expected {
+        TestResult::Passed(String::new())
+    } else {
+        TestResult::HashMismatch { expected, found }
+    }
+}
+
+// CRC32 of the framebuffer's palette indices, independent of the palette.
+pub fn frame_hash(cpu: &CPU) -> u32 {
+    let mut crc = Crc32::new();
+    for &p in cpu.framebuffer() {
+        crc.update(&p.to_le_bytes());
+    }
+    crc.finish()
+}
+
+pub struct SuiteEntry {
+    pub rom: String,
+    pub frame_hash: Option<(u64, u32)>,
+}
+
+pub fn parse_suite(text: &str) -> Result<Vec<SuiteEntry>, String> {
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let frame_hash = match fields.len() {
+            1 => None,
+            3 => {
+                let frames = fields[1].parse().map_err(|_| format!("line {}: bad frame count", i + 1))?;
+                let hash = u32::from_str_radix(fields[2].trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("line {}: bad hash", i + 1))?;
+                Some((frames, hash))
+            }
+            _ => return Err(format!("line {}: expected <rom> [<frames> <crc32>]", i + 1)),
+        };
+        entries.push(SuiteEntry { rom: fields[0].to_string(), frame_hash });
+    }
+    Ok(entries)
+}
+
+fn has_signature(cpu: &CPU) -> bool {
+    (0..3).all(|i| cpu.peek(0x6001 + i) == SIGNATURE[i as usize])
+}
+
+fn read_text(cpu: &CPU) -> String {
+    let mut text = Vec::new();
+    for address in 0x6004..0x8000 {
+        let c = cpu.peek(address);
+        if c == 0 {
+            break;
+        }
+        text.push(c);
+    }
+    String::from_utf8_lossy(&text).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper;
+    use rom;
+
+    fn machine(program: &[u8]) -> CPU {
+        let mut image = b"NES\x1A\x01\x01".to_vec();
+        image.resize(16, 0);
+        let mut prg = vec![0; 0x4000];
+        prg[0x2000..0x2000 + program.len()].copy_from_slice(program);
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0xE0, 0x00, 0xE0, 0x00, 0xE0]);
+        image.extend_from_slice(&prg);
+        image.extend_from_slice(&[0; 0x2000]);
+        let mut cpu = CPU::new(mapper::new(rom::Cartridge::load(image).unwrap(), None).unwrap());
+        cpu.set_trace(false);
+        cpu
+    }
+
+    // Writes the signature, "ok" and then `status`, and waits.
+    fn reporting(status: u8) -> Vec<u8> {
+        let mut program = Vec::new();
+        for (i, &b) in [0xDE, 0xB0, 0x61, b'o', b'k', 0, status].iter().enumerate() {
+            let address = if i == 6 { 0x6000 } else { 0x6001 + i as u16 };
+            program.extend_from_slice(&[0xA9, b, 0x8D, address as u8, (address >> 8) as u8]);
+        }
+        let here = 0xE000 + program.len() as u16;
+        program.extend_from_slice(&[0x4C, here as u8, (here >> 8) as u8]);
+        program
+    }
+
+    // Sets the backdrop colour with rendering off, and waits.
+    fn backdrop(color: u8) -> Vec<u8> {
+        vec![
+            0xA9, 0x3F, 0x8D, 0x06, 0x20,   // LDA #$3F, STA $2006
+            0xA9, 0x00, 0x8D, 0x06, 0x20,   // LDA #$00, STA $2006
+            0xA9, color, 0x8D, 0x07, 0x20,  // LDA #color, STA $2007
+            0xA9, 0x20, 0x8D, 0x06, 0x20,   // LDA #$20, STA $2006
+            0xA9, 0x00, 0x8D, 0x06, 0x20,   // LDA #$00, STA $2006
+            0x4C, 0x19, 0xE0,               // JMP $E019
+        ]
+    }
+
+    #[test]
+    fn status_protocol() {
+        let result = run_status(&mut machine(&reporting(0)), 10);
+        assert!(result.passed());
+        assert_eq!(result.to_string(), "passed ok");
+        let result = run_status(&mut machine(&reporting(3)), 10);
+        assert!(matches!(result, TestResult::Failed(3, ref text) if text == "ok"));
+        // still running
+        assert!(matches!(run_status(&mut machine(&reporting(0x80)), 10), TestResult::TimedOut));
+        assert!(matches!(run_status(&mut machine(&[0xEA, 0x02]), 10), TestResult::Jammed(0x02, 0xE001)));
+    }
+
+    #[test]
+    fn reset_request() {
+        // $81 the first time, then a pass after the reset, counted at $0300
+        // which survives it: everything but the last store of `reporting`,
+        // then the status
+        let mut program = reporting(0)[..30].to_vec();
+        program.extend_from_slice(&[
+            0xEE, 0x00, 0x03,   // INC $0300
+            0xAD, 0x00, 0x03,   // LDA $0300
+            0xC9, 0x01,         // CMP #$01
+            0xD0, 0x04,         // BNE +4
+            0xA9, 0x81,         // LDA #$81: the first time
+            0xD0, 0x02,         // BNE +2
+            0xA9, 0x00,         // LDA #$00: after the reset
+            0x8D, 0x00, 0x60,   // STA $6000
+        ]);
+        let here = 0xE000 + program.len() as u16;
+        program.extend_from_slice(&[0x4C, here as u8, (here >> 8) as u8]);
+        let mut cpu = machine(&program);
+        assert!(run_status(&mut cpu, 20).passed());
+        assert_eq!(cpu.peek(0x0300), 2);
+    }
+
+    #[test]
+    fn frame_hashes() {
+        let mut cpu = machine(&backdrop(0x21));
+        let hash = match run_frame_hash(&mut cpu, 2, 0) {
+            TestResult::HashMismatch { expected: 0, found } => found,
+            _ => panic!("expected a mismatch"),
+        };
+        assert!(run_frame_hash(&mut machine(&backdrop(0x21)), 2, hash).passed());
+        assert!(!run_frame_hash(&mut machine(&backdrop(0x0F)), 2, hash).passed());
+        // the hash is of the picture, not the palette
+        assert!(cpu.framebuffer().iter().all(|&p| p == 0x21));
+    }
+
+    #[test]
+    fn suite_lines() {
+        let entries = parse_suite("# comment\n\ninstr_test.nes\n  sprite_hit.nes 120 0xDEADBEEF \n").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].rom, "instr_test.nes");
+        assert!(entries[0].frame_hash.is_none());
+        assert_eq!(entries[1].rom, "sprite_hit.nes");
+        assert_eq!(entries[1].frame_hash, Some((120, 0xDEAD_BEEF)));
+    }
+
+    #[test]
+    fn bad_suite_lines() {
+        assert_eq!(parse_suite("a.nes 10").err().unwrap(), "line 1: expected <rom> [<frames> <crc32>]");
+        assert_eq!(parse_suite("\na.nes x 0").err().unwrap(), "line 2: bad frame count");
+        assert_eq!(parse_suite("a.nes 10 xyz").err().unwrap(), "line 1: bad hash");
+    }
+}
@@ -0,0 +1,180 @@
+// Disassembler and per-instruction trace in the format of nestest.log:
+//   C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD CYC:15
+// Operand values are looked up with peek() before the instruction runs, so
+// tracing never changes what the program sees. Unofficial opcodes get a '*'
+// in front like in nestest.log. CYC is the CPU cycle count rather than the
+// PPU dot, as in the newer versions of the log.
+//
+// The opcode table is the NMOS one; 65C02 code is shown with NMOS names.
+
+use bus::Bus;
+use cpu::CPU;
+
+use self::Mode::*;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+impl Mode {
+    fn operand_len(self) -> u16 {
+        match self {
+            Implied | Accumulator => 0,
+            Absolute | AbsoluteX | AbsoluteY | Indirect => 2,
+            _ => 1,
+        }
+    }
+}
+
+const OPCODES: [(&str, Mode); 256] = [
+    ("BRK", Implied), ("ORA", IndirectX), ("*KIL", Implied), ("*SLO", IndirectX), ("*NOP", ZeroPage), ("ORA", ZeroPage), ("ASL", ZeroPage), ("*SLO", ZeroPage),
+    ("PHP", Implied), ("ORA", Immediate), ("ASL", Accumulator), ("*ANC", Immediate), ("*NOP", Absolute), ("ORA", Absolute), ("ASL", Absolute), ("*SLO", Absolute),
+    ("BPL", Relative), ("ORA", IndirectY), ("*KIL", Implied), ("*SLO", IndirectY), ("*NOP", ZeroPageX), ("ORA", ZeroPageX), ("ASL", ZeroPageX), ("*SLO", ZeroPageX),
+    ("CLC", Implied), ("ORA", AbsoluteY), ("*NOP", Implied), ("*SLO", AbsoluteY), ("*NOP", AbsoluteX), ("ORA", AbsoluteX), ("ASL", AbsoluteX), ("*SLO", AbsoluteX),
+    ("JSR", Absolute), ("AND", IndirectX), ("*KIL", Implied), ("*RLA", IndirectX), ("BIT", ZeroPage), ("AND", ZeroPage), ("ROL", ZeroPage), ("*RLA", ZeroPage),
+    ("PLP", Implied), ("AND", Immediate), ("ROL", Accumulator), ("*ANC", Immediate), ("BIT", Absolute), ("AND", Absolute), ("ROL", Absolute), ("*RLA", Absolute),
+    ("BMI", Relative), ("AND", IndirectY), ("*KIL", Implied), ("*RLA", IndirectY), ("*NOP", ZeroPageX), ("AND", ZeroPageX), ("ROL", ZeroPageX), ("*RLA", ZeroPageX),
+    ("SEC", Implied), ("AND", AbsoluteY), ("*NOP", Implied), ("*RLA", AbsoluteY), ("*NOP", AbsoluteX), ("AND", AbsoluteX), ("ROL", AbsoluteX), ("*RLA", AbsoluteX),
+    ("RTI", Implied), ("EOR", IndirectX), ("*KIL", Implied), ("*SRE", IndirectX), ("*NOP", ZeroPage), ("EOR", ZeroPage), ("LSR", ZeroPage), ("*SRE", ZeroPage),
+    ("PHA", Implied), ("EOR", Immediate), ("LSR", Accumulator), ("*ALR", Immediate), ("JMP", Absolute), ("EOR", Absolute), ("LSR", Absolute), ("*SRE", Absolute),
+    ("BVC", Relative), ("EOR", IndirectY), ("*KIL", Implied), ("*SRE", IndirectY), ("*NOP", ZeroPageX), ("EOR", ZeroPageX), ("LSR", ZeroPageX), ("*SRE", ZeroPageX),
+    ("CLI", Implied), ("EOR", AbsoluteY), ("*NOP", Implied), ("*SRE", AbsoluteY), ("*NOP", AbsoluteX), ("EOR", AbsoluteX), ("LSR", AbsoluteX), ("*SRE", AbsoluteX),
+    ("RTS", Implied), ("ADC", IndirectX), ("*KIL", Implied), ("*RRA", IndirectX), ("*NOP", ZeroPage), ("ADC", ZeroPage), ("ROR", ZeroPage), ("*RRA", ZeroPage),
+    ("PLA", Implied), ("ADC", Immediate), ("ROR", Accumulator), ("*ARR", Immediate), ("JMP", Indirect), ("ADC", Absolute), ("ROR", Absolute), ("*RRA", Absolute),
+    ("BVS", Relative), ("ADC", IndirectY), ("*KIL", Implied), ("*RRA", IndirectY), ("*NOP", ZeroPageX), ("ADC", ZeroPageX), ("ROR", ZeroPageX), ("*RRA", ZeroPageX),
+    ("SEI", Implied), ("ADC", AbsoluteY), ("*NOP", Implied), ("*RRA", AbsoluteY), ("*NOP", AbsoluteX), ("ADC", AbsoluteX), ("ROR", AbsoluteX), ("*RRA", AbsoluteX),
+    ("*NOP", Immediate), ("STA", IndirectX), ("*NOP", Immediate), ("*SAX", IndirectX), ("STY", ZeroPage), ("STA", ZeroPage), ("STX", ZeroPage), ("*SAX", ZeroPage),
+    ("DEY", Implied), ("*NOP", Immediate), ("TXA", Implied), ("*XAA", Immediate), ("STY", Absolute), ("STA", Absolute), ("STX", Absolute), ("*SAX", Absolute),
+    ("BCC", Relative), ("STA", IndirectY), ("*KIL", Implied), ("*SHA", IndirectY), ("STY", ZeroPageX), ("STA", ZeroPageX), ("STX", ZeroPageY), ("*SAX", ZeroPageY),
+    ("TYA", Implied), ("STA", AbsoluteY), ("TXS", Implied), ("*TAS", AbsoluteY), ("*SHY", AbsoluteX), ("STA", AbsoluteX), ("*SHX", AbsoluteY), ("*SHA", AbsoluteY),
+    ("LDY", Immediate), ("LDA", IndirectX), ("LDX", Immediate), ("*LAX", IndirectX), ("LDY", ZeroPage), ("LDA", ZeroPage), ("LDX", ZeroPage), ("*LAX", ZeroPage),
+    ("TAY", Implied), ("LDA", Immediate), ("TAX", Implied), ("*LXA", Immediate), ("LDY", Absolute), ("LDA", Absolute), ("LDX", Absolute), ("*LAX", Absolute),
+    ("BCS", Relative), ("LDA", IndirectY), ("*KIL", Implied), ("*LAX", IndirectY), ("LDY", ZeroPageX), ("LDA", ZeroPageX), ("LDX", ZeroPageY), ("*LAX", ZeroPageY),
+    ("CLV", Implied), ("LDA", AbsoluteY), ("TSX", Implied), ("*LAS", AbsoluteY), ("LDY", AbsoluteX), ("LDA", AbsoluteX), ("LDX", AbsoluteY), ("*LAX", AbsoluteY),
+    ("CPY", Immediate), ("CMP", IndirectX), ("*NOP", Immediate), ("*DCP", IndirectX), ("CPY", ZeroPage), ("CMP", ZeroPage), ("DEC", ZeroPage), ("*DCP", ZeroPage),
+    ("INY", Implied), ("CMP", Immediate), ("DEX", Implied), ("*AXS", Immediate), ("CPY", Absolute), ("CMP", Absolute), ("DEC", Absolute), ("*DCP", Absolute),
+    ("BNE", Relative), ("CMP", IndirectY), ("*KIL", Implied), ("*DCP", IndirectY), ("*NOP", ZeroPageX), ("CMP", ZeroPageX), ("DEC", ZeroPageX), ("*DCP", ZeroPageX),
+    ("CLD", Implied), ("CMP", AbsoluteY), ("*NOP", Implied), ("*DCP", AbsoluteY), ("*NOP", AbsoluteX), ("CMP", AbsoluteX), ("DEC", AbsoluteX), ("*DCP", AbsoluteX),
+    ("CPX", Immediate), ("SBC", IndirectX), ("*NOP", Immediate), ("*ISB", IndirectX), ("CPX", ZeroPage), ("SBC", ZeroPage), ("INC", ZeroPage), ("*ISB", ZeroPage),
+    ("INX", Implied), ("SBC", Immediate), ("NOP", Implied), ("*SBC", Immediate), ("CPX", Absolute), ("SBC", Absolute), ("INC", Absolute), ("*ISB", Absolute),
+    ("BEQ", Relative), ("SBC", IndirectY), ("*KIL", Implied), ("*ISB", IndirectY), ("*NOP", ZeroPageX), ("SBC", ZeroPageX), ("INC", ZeroPageX), ("*ISB", ZeroPageX),
+    ("SED", Implied), ("SBC", AbsoluteY), ("*NOP", Implied), ("*ISB", AbsoluteY), ("*NOP", AbsoluteX), ("SBC", AbsoluteX), ("INC", AbsoluteX), ("*ISB", AbsoluteX),
+];
+
+// The instruction at `pc` as text, and its length in bytes.
+pub fn disassemble<B: Bus>(bus: &B, pc: u16, x: u8, y: u8) -> (String, u16) {
+    let opcode = bus.peek(pc);
+    let (name, mode) = OPCODES[opcode as usize];
+    let b1 = bus.peek(pc.wrapping_add(1));
+    let b2 = bus.peek(pc.wrapping_add(2));
+    let word = (b2 as u16) << 8 | b1 as u16;
+    let peekw_zp = |a: u8| (bus.peek(a.wrapping_add(1) as u16) as u16) << 8 | bus.peek(a as u16) as u16;
+
+    let operand = match mode {
+        Implied => String::new(),
+        Accumulator => "A".to_string(),
+        Immediate => format!("#${:02X}", b1),
+        ZeroPage => format!("${:02X} = {:02X}", b1, bus.peek(b1 as u16)),
+        ZeroPageX | ZeroPageY => {
+            let (reg, index) = if mode == ZeroPageX { ('X', x) } else { ('Y', y) };
+            let address = b1.wrapping_add(index);
+            format!("${:02X},{} @ {:02X} = {:02X}", b1, reg, address, bus.peek(address as u16))
+        }
+        Absolute if opcode == 0x20 || opcode == 0x4C => format!("${:04X}", word),
+        Absolute => format!("${:04X} = {:02X}", word, bus.peek(word)),
+        AbsoluteX | AbsoluteY => {
+            let (reg, index) = if mode == AbsoluteX { ('X', x) } else { ('Y', y) };
+            let address = word.wrapping_add(index as u16);
+            format!("${:04X},{} @ {:04X} = {:02X}", word, reg, address, bus.peek(address))
+        }
+        Indirect => {
+            // the pointer's high byte comes from the same page
+            let high = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+            let target = (bus.peek(high) as u16) << 8 | bus.peek(word) as u16;
+            format!("(${:04X}) = {:04X}", word, target)
+        }
+        IndirectX => {
+            let pointer = b1.wrapping_add(x);
+            let address = peekw_zp(pointer);
+            format!("(${:02X},X) @ {:02X} = {:04X} = {:02X}", b1, pointer, address, bus.peek(address))
+        }
+        IndirectY => {
+            let base = peekw_zp(b1);
+            let address = base.wrapping_add(y as u16);
+            format!("(${:02X}),Y = {:04X} @ {:04X} = {:02X}", b1, base, address, bus.peek(address))
+        }
+        Relative => format!("${:04X}", pc.wrapping_add(2).wrapping_add(b1 as i8 as u16)),
+    };
+    let name = if name.starts_with('*') { name.to_string() } else { format!(" {}", name) };
+    let text = if operand.is_empty() { name } else { format!("{} {}", name, operand) };
+    (text, 1 + mode.operand_len())
+}
+
+// One line of trace for the instruction the CPU is about to run.
+pub fn trace_line<B: Bus>(cpu: &CPU<B>) -> String {
+    let r = cpu.registers();
+    let (text, len) = disassemble(cpu.bus(), r.pc, r.x, r.y);
+    let bytes: Vec<String> = (0..len).map(|i| format!("{:02X}", cpu.peek(r.pc.wrapping_add(i)))).collect();
+    format!("{:04X}  {:<8} {:<32} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            r.pc, bytes.join(" "), text, r.a, r.x, r.y, r.p, r.sp, cpu.cycles())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Registers;
+    use singlestep::TestBus;
+
+    fn bus(code: &[(u16, u8)]) -> TestBus {
+        let mut bus = TestBus::new();
+        for &(address, value) in code {
+            bus.poke(address, value);
+        }
+        bus
+    }
+
+    #[test]
+    fn addressing_modes() {
+        let bus = bus(&[
+            (0x0010, 0x34), (0x0011, 0x12), (0x0015, 0x77), (0x1234, 0xAB), (0x1236, 0xCD),
+            (0x8000, 0xB5), (0x8001, 0x10),
+            (0x8010, 0xB1), (0x8011, 0x10),
+            (0x8020, 0x6C), (0x8021, 0xFF), (0x8022, 0x10),
+            (0x8030, 0xD0), (0x8031, 0xFE),
+            (0x8040, 0x4C), (0x8041, 0x34), (0x8042, 0x12),
+            (0x8050, 0xA3), (0x8051, 0x0B),
+            (0x8060, 0x0A),
+        ]);
+        assert_eq!(disassemble(&bus, 0x8000, 5, 0), (" LDA $10,X @ 15 = 77".to_string(), 2));
+        assert_eq!(disassemble(&bus, 0x8010, 0, 2), (" LDA ($10),Y = 1234 @ 1236 = CD".to_string(), 2));
+        // the JMP ($xxFF) page wrap
+        assert_eq!(disassemble(&bus, 0x8020, 0, 0), (" JMP ($10FF) = 0000".to_string(), 3));
+        assert_eq!(disassemble(&bus, 0x8030, 0, 0), (" BNE $8030".to_string(), 2));
+        assert_eq!(disassemble(&bus, 0x8040, 0, 0), (" JMP $1234".to_string(), 3));
+        assert_eq!(disassemble(&bus, 0x8050, 5, 0), ("*LAX ($0B,X) @ 10 = 1234 = AB".to_string(), 2));
+        assert_eq!(disassemble(&bus, 0x8060, 0, 0), (" ASL A".to_string(), 1));
+    }
+
+    #[test]
+    fn nestest_line() {
+        let mut cpu = CPU::with_bus(bus(&[(0xC5F7, 0x86), (0xC5F8, 0x00)]));
+        cpu.set_registers(&Registers { pc: 0xC5F7, sp: 0xFD, a: 0, x: 0, y: 0, p: 0x26 });
+        assert_eq!(trace_line(&cpu),
+                   "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD CYC:0");
+        // tracing doesn't touch the bus
+        assert!(cpu.bus().log().is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+// UNIF cartridge files
+//
+// A 32 byte header ("UNIF", a little endian revision number and padding)
+// followed by chunks of a 4 byte ID, a little endian length and the data:
+//   MAPR        board name, e.g. "NES-NROM-256", zero terminated
+//   PRG0..PRGF  PRG-ROM, concatenated in order
+//   CHR0..CHRF  CHR-ROM, concatenated in order
+//   MIRR        one byte, see mirroring() below
+//   BATR        present if the board has battery-backed RAM
+//   TVCI        0 NTSC, 1 PAL, 2 either
+//   NAME        game name, zero terminated
+// Everything else (dumper info, chunk checksums, ...) is skipped.
+//
+// Boards are named instead of numbered, so they are mapped back to iNES
+// mapper numbers to pick an implementation. Only Nintendo's own boards are
+// known: the UNL- and BMC- boards of unlicensed carts and multicarts have
+// no mapper implementations here, and load as UnsupportedBoard.
+
+use rom::{Mirroring, RomError};
+use region::Region;
+
+pub const MAGIC: &[u8] = b"UNIF";
+const HEADER_SIZE: usize = 32;
+
+pub struct UnifFile {
+    pub board: String,
+    pub mapper: u16,
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub region: Option<Region>,
+    pub name: String,
+}
+
+pub fn parse(bin: &[u8]) -> Result<UnifFile, RomError> {
+    if bin.len() < HEADER_SIZE || !bin.starts_with(MAGIC) {
+        return Err(RomError::Truncated);
+    }
+    let mut board = None;
+    let mut prg_chunks: Vec<(u8, &[u8])> = Vec::new();
+    let mut chr_chunks: Vec<(u8, &[u8])> = Vec::new();
+    let mut file = UnifFile {
+        board: String::new(),
+        mapper: 0,
+        prg: Vec::new(),
+        chr: Vec::new(),
+        mirroring: Mirroring::Horizontal,
+        battery: false,
+        region: None,
+        name: String::new(),
+    };
+
+    let mut pos = HEADER_SIZE;
+    while pos < bin.len() {
+        let header = bin.get(pos..pos + 8).ok_or(RomError::Truncated)?;
+        let id = &header[0..4];
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        pos += 8;
+        let data = bin.get(pos..pos + len).ok_or(RomError::Truncated)?;
+        pos += len;
+        match id {
+            b"MAPR" => board = Some(string(data)),
+            b"NAME" => file.name = string(data),
+            b"BATR" => file.battery = true,
+            b"MIRR" => file.mirroring = mirroring(*data.first().ok_or(RomError::Truncated)?)?,
+            b"TVCI" => {
+                file.region = match data.first() {
+                    Some(0) => Some(Region::Ntsc),
+                    Some(1) => Some(Region::Pal),
+                    _ => None,
+                };
+            }
+            _ if id.starts_with(b"PRG") => prg_chunks.push((chunk_number(id[3])?, data)),
+            _ if id.starts_with(b"CHR") => chr_chunks.push((chunk_number(id[3])?, data)),
+            _ => {}
+        }
+    }
+
+    file.board = board.ok_or(RomError::Invalid("UNIF file has no MAPR chunk"))?;
+    file.mapper = board_mapper(&file.board).ok_or_else(|| RomError::UnsupportedBoard(file.board.clone()))?;
+    prg_chunks.sort_by_key(|c| c.0);
+    chr_chunks.sort_by_key(|c| c.0);
+    for (_, data) in prg_chunks {
+        file.prg.extend_from_slice(data);
+    }
+    for (_, data) in chr_chunks {
+        file.chr.extend_from_slice(data);
+    }
+    if file.prg.is_empty() {
+        return Err(RomError::Invalid("UNIF file has no PRG data"));
+    }
+    Ok(file)
+}
+
+// PRG0..PRGF and CHR0..CHRF
+fn chunk_number(c: u8) -> Result<u8, RomError> {
+    (c as char).to_digit(16).map(|n| n as u8).ok_or(RomError::Invalid("bad UNIF chunk number"))
+}
+
+fn string(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+// 0 horizontal, 1 vertical, 2 and 3 single-screen on the first or second
+// page, 4 four-screen. 5 means the board switches it with a register, so
+// what the file says is never used.
+fn mirroring(value: u8) -> Result<Mirroring, RomError> {
+    match value {
+        0 | 5 => Ok(Mirroring::Horizontal),
+        1 => Ok(Mirroring::Vertical),
+        2 => Ok(Mirroring::SingleScreenLower),
+        3 => Ok(Mirroring::SingleScreenUpper),
+        4 => Ok(Mirroring::FourScreen),
+        _ => Err(RomError::Invalid("bad UNIF mirroring")),
+    }
+}
+
+// Nintendo's own boards, by the name without the NES-/HVC- prefix.
+const BOARDS: &[(&str, u16)] = &[
+    ("NROM", 0), ("NROM-128", 0), ("NROM-256", 0), ("RROM", 0), ("RROM-128", 0),
+    ("SAROM", 1), ("SBROM", 1), ("SCROM", 1), ("SEROM", 1), ("SFROM", 1), ("SGROM", 1),
+    ("SHROM", 1), ("SJROM", 1), ("SKROM", 1), ("SLROM", 1), ("SL1ROM", 1), ("SNROM", 1),
+    ("SOROM", 1), ("SUROM", 1), ("SXROM", 1),
+    ("UNROM", 2), ("UOROM", 2),
+    ("CNROM", 3),
+    ("TBROM", 4), ("TEROM", 4), ("TFROM", 4), ("TGROM", 4), ("TKROM", 4), ("TLROM", 4),
+    ("TL1ROM", 4), ("TNROM", 4), ("TR1ROM", 4), ("TSROM", 4), ("TVROM", 4),
+    ("EKROM", 5), ("ELROM", 5), ("ETROM", 5), ("EWROM", 5),
+    ("AMROM", 7), ("ANROM", 7), ("AN1ROM", 7), ("AOROM", 7),
+    ("PNROM", 9), ("PEEOROM", 9),
+    ("FJROM", 10), ("FKROM", 10),
+    ("CPROM", 13),
+    ("BNROM", 34),
+    ("GNROM", 66), ("MHROM", 66),
+    ("TKSROM", 118), ("TLSROM", 118),
+    ("TQROM", 119),
+];
+
+fn board_mapper(board: &str) -> Option<u16> {
+    let name = board.trim_start_matches("NES-").trim_start_matches("HVC-");
+    BOARDS.iter().find(|b| b.0 == name).map(|b| b.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper;
+
+    fn chunk(image: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+        image.extend_from_slice(id);
+        image.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        image.extend_from_slice(data);
+    }
+
+    fn header() -> Vec<u8> {
+        let mut image = b"UNIF\x07\x00\x00\x00".to_vec();
+        image.resize(HEADER_SIZE, 0);
+        image
+    }
+
+    #[test]
+    fn chunks() {
+        let mut image = header();
+        chunk(&mut image, b"MAPR", b"NES-SNROM\0");
+        chunk(&mut image, b"NAME", b"Game\0junk");
+        chunk(&mut image, b"PRG1", &[2; 4]);
+        chunk(&mut image, b"PRG0", &[1; 4]);
+        chunk(&mut image, b"CHR0", &[3; 2]);
+        chunk(&mut image, b"DINF", &[0; 204]);
+        chunk(&mut image, b"MIRR", &[1]);
+        chunk(&mut image, b"BATR", &[]);
+        chunk(&mut image, b"TVCI", &[1]);
+        let file = parse(&image).unwrap();
+        assert_eq!(file.board, "NES-SNROM");
+        assert_eq!(file.mapper, 1);
+        assert_eq!(file.name, "Game");
+        assert_eq!(file.prg, [1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(file.chr, [3, 3]);
+        assert_eq!(file.mirroring, Mirroring::Vertical);
+        assert!(file.battery);
+        assert_eq!(file.region, Some(Region::Pal));
+    }
+
+    #[test]
+    fn defaults() {
+        let mut image = header();
+        chunk(&mut image, b"MAPR", b"HVC-UNROM");
+        chunk(&mut image, b"PRG0", &[0; 16]);
+        let file = parse(&image).unwrap();
+        assert_eq!(file.mapper, 2);
+        assert_eq!(file.mirroring, Mirroring::Horizontal);
+        assert!(!file.battery);
+        assert_eq!(file.region, None);
+        assert!(file.chr.is_empty());
+    }
+
+    #[test]
+    fn bad_files() {
+        let mut image = header();
+        chunk(&mut image, b"MAPR", b"UNL-Sachen-8259A");
+        chunk(&mut image, b"PRG0", &[0; 16]);
+        assert!(matches!(parse(&image), Err(RomError::UnsupportedBoard(ref b)) if b == "UNL-Sachen-8259A"));
+
+        let mut image = header();
+        chunk(&mut image, b"PRG0", &[0; 16]);
+        assert!(matches!(parse(&image), Err(RomError::Invalid(_))));
+
+        let mut image = header();
+        chunk(&mut image, b"MAPR", b"NES-NROM");
+        assert!(matches!(parse(&image), Err(RomError::Invalid(_))));
+
+        let mut image = header();
+        chunk(&mut image, b"MAPR", b"NES-NROM");
+        chunk(&mut image, b"PRGX", &[0; 16]);
+        assert!(matches!(parse(&image), Err(RomError::Invalid(_))));
+
+        let mut image = header();
+        chunk(&mut image, b"PRG0", &[0; 16]);
+        image.truncate(image.len() - 1);
+        assert!(matches!(parse(&image), Err(RomError::Truncated)));
+        assert!(matches!(parse(&image[..20]), Err(RomError::Truncated)));
+    }
+
+    #[test]
+    fn single_screen_mirroring() {
+        let with_mirroring = |value| {
+            let mut image = header();
+            chunk(&mut image, b"MAPR", b"NES-NROM");
+            chunk(&mut image, b"PRG0", &[0; 16]);
+            chunk(&mut image, b"MIRR", &[value]);
+            parse(&image).map(|file| file.mirroring)
+        };
+        assert_eq!(with_mirroring(2).unwrap(), Mirroring::SingleScreenLower);
+        assert_eq!(with_mirroring(3).unwrap(), Mirroring::SingleScreenUpper);
+        assert_eq!(with_mirroring(5).unwrap(), Mirroring::Horizontal);
+        assert!(matches!(with_mirroring(6), Err(RomError::Invalid(_))));
+
+        // every nametable lands on the one page
+        for &address in &[0x2000, 0x2400, 0x2800, 0x2C00] {
+            assert_eq!(mapper::ciram_offset(address + 0x12, Mirroring::SingleScreenLower), 0x0012);
+            assert_eq!(mapper::ciram_offset(address + 0x12, Mirroring::SingleScreenUpper), 0x0412);
+        }
+    }
+}
@@ -0,0 +1,370 @@
+// Konami VRC6, mappers 24 and 26
+//
+// Mapper 26 boards (Madara, Esper Dream 2) swap the A0 and A1 lines, so
+// registers are normalized to mapper 24's layout first:
+//   $8000-$8003  16K PRG bank at $8000
+//   $9000-$B002  sound, see vrc6_audio.rs
+//   $B003        PPU banking mode (0-1), mirroring (2-3), PRG-RAM enable (7)
+//   $C000-$C003  8K PRG bank at $C000
+//   $D000-$E003  eight CHR bank registers
+//   $F000-$F002  IRQ latch, control and acknowledge
+// with the last 8K of PRG fixed at $E000.
+//
+// Only the PPU banking mode every VRC6 game uses is emulated: eight 1K CHR
+// banks, with the console's nametable RAM mirrored vertically,
+// horizontally or onto either page. The other modes, and nametables
+// from CHR-ROM, are treated the same way.
+
+use mapper::{self, PpuAccess, PpuFetch};
+use memory::RamInit;
+use rom;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+use vrc6_audio::Vrc6Audio;
+
+// The IRQ counter Konami put in the VRC4, 6 and 7. It counts up from the
+// latch and fires when it wraps, either every CPU cycle or every scanline,
+// which it approximates with a prescaler that runs 3 steps per cycle
+// against the 341 dots of a line.
+#[derive(Default)]
+struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    fn write_control(&mut self, value: u8) {
+        self.enable_after_ack = value & 0x01 != 0;
+        self.enabled = value & 0x02 != 0;
+        self.cycle_mode = value & 0x04 != 0;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+        self.pending = false;
+    }
+    fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+    fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if !self.cycle_mode {
+            self.prescaler -= 3;
+            if self.prescaler > 0 {
+                return;
+            }
+            self.prescaler += 341;
+        }
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        }
+        else {
+            self.counter += 1;
+        }
+    }
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u8(self.latch);
+        w.write_u8(self.counter);
+        w.write_u16(self.prescaler as u16);
+        w.write_bool(self.enabled);
+        w.write_bool(self.enable_after_ack);
+        w.write_bool(self.cycle_mode);
+        w.write_bool(self.pending);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.latch = r.read_u8()?;
+        self.counter = r.read_u8()?;
+        self.prescaler = r.read_u16()? as i16;
+        self.enabled = r.read_bool()?;
+        self.enable_after_ack = r.read_bool()?;
+        self.cycle_mode = r.read_bool()?;
+        self.pending = r.read_bool()?;
+        Ok(())
+    }
+}
+
+pub struct Vrc6 {
+    rom: rom::Cartridge,
+    prg_ram: [u8; 0x2000],
+    chr_ram: Vec<u8>,
+    // mapper 26
+    swapped_lines: bool,
+    prg_16k: u8,
+    prg_8k: u8,
+    chr_banks: [u8; 8],
+    // $B003
+    ppu_control: u8,
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+}
+
+impl Vrc6 {
+    pub fn new(rom: rom::Cartridge) -> Vrc6 {
+        let chr_ram = vec![0; rom.chr_ram_size() as usize];
+        Vrc6 {
+            swapped_lines: rom.mapper == 26,
+            rom,
+            prg_ram: [0; 0x2000],
+            chr_ram,
+            prg_16k: 0,
+            prg_8k: 0,
+            chr_banks: [0; 8],
+            ppu_control: 0,
+            irq: VrcIrq::default(),
+            audio: Vrc6Audio::new(),
+        }
+    }
+    // Byte `offset` of 8K PRG bank `bank`, wrapping around the ROM.
+    fn prg(&self, bank: usize, offset: u16) -> u8 {
+        let pages = &self.rom.prg_rom;
+        let bank = bank % (pages.len() * 2);
+        pages[bank / 2][(bank % 2) * 0x2000 + offset as usize]
+    }
+    fn prg_ram_enabled(&self) -> bool {
+        self.ppu_control & 0x80 != 0
+    }
+    fn chr_index(&self, address: u16) -> usize {
+        self.chr_banks[(address >> 10) as usize] as usize * 0x400 + (address & 0x03FF) as usize
+    }
+}
+
+impl ::mapper::Mapper for Vrc6 {
+    fn peek(&self, address: u16) -> Option<u8> {
+        let offset = address & 0x1FFF;
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => Some(self.prg_ram[offset as usize]),
+            0x8000..=0xBFFF => Some(self.prg(self.prg_16k as usize * 2 + (address as usize >> 13 & 1), offset)),
+            0xC000..=0xDFFF => Some(self.prg(self.prg_8k as usize, offset)),
+            0xE000..=0xFFFF => Some(self.prg(self.rom.prg_rom.len() * 2 - 1, offset)),
+            _ => None,
+        }
+    }
+    fn write(&mut self, address: u16, value: u8) {
+        if address < 0x8000 {
+            if address >= 0x6000 && self.prg_ram_enabled() {
+                self.prg_ram[(address & 0x1FFF) as usize] = value;
+            }
+            return;
+        }
+        let mut register = address & 0xF003;
+        if self.swapped_lines {
+            register = (register & 0xF000) | (register & 1) << 1 | (register & 2) >> 1;
+        }
+        match register {
+            0x8000..=0x8003 => self.prg_16k = value & 0x0F,
+            0x9000..=0xB002 => self.audio.write(register, value),
+            0xB003 => self.ppu_control = value,
+            0xC000..=0xC003 => self.prg_8k = value & 0x1F,
+            0xD000..=0xE003 => {
+                let i = ((register - 0xD000) >> 10 & 4) | (register & 3);
+                self.chr_banks[i as usize] = value;
+            }
+            0xF000 => self.irq.latch = value,
+            0xF001 => self.irq.write_control(value),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+    fn power_on(&mut self, init: RamInit) {
+        init.fill(&mut self.prg_ram, 1);
+        init.fill(&mut self.chr_ram, 2);
+    }
+    fn rom(&self) -> &rom::Cartridge {
+        &self.rom
+    }
+    fn ppu_read(&mut self, address: u16, _fetch: PpuFetch) -> PpuAccess {
+        if address >= 0x2000 {
+            PpuAccess::Ciram(mapper::switched_ciram_offset(address, self.ppu_control >> 2))
+        }
+        else if self.chr_ram.is_empty() {
+            PpuAccess::Cartridge(mapper::chr_byte(self.rom.chr_rom(), self.chr_index(address)))
+        }
+        else {
+            PpuAccess::Cartridge(mapper::chr_byte(&self.chr_ram, self.chr_index(address)))
+        }
+    }
+    fn ppu_write(&mut self, address: u16, value: u8) -> PpuAccess {
+        if address >= 0x2000 {
+            return PpuAccess::Ciram(mapper::switched_ciram_offset(address, self.ppu_control >> 2));
+        }
+        if !self.chr_ram.is_empty() {
+            let index = self.chr_index(address) % self.chr_ram.len();
+            self.chr_ram[index] = value;
+        }
+        PpuAccess::Cartridge(value)
+    }
+    fn tick(&mut self) {
+        self.irq.clock();
+        self.audio.clock();
+    }
+    fn irq(&self) -> bool {
+        self.irq.pending
+    }
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+}
+
+impl Savable for Vrc6 {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        w.write_bytes(&self.chr_ram);
+        w.write_u8(self.prg_16k);
+        w.write_u8(self.prg_8k);
+        w.write_bytes(&self.chr_banks);
+        w.write_u8(self.ppu_control);
+        self.irq.save(w);
+        self.audio.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes(&mut self.prg_ram)?;
+        r.read_bytes(&mut self.chr_ram)?;
+        self.prg_16k = r.read_u8()?;
+        self.prg_8k = r.read_u8()?;
+        r.read_bytes(&mut self.chr_banks)?;
+        self.ppu_control = r.read_u8()?;
+        self.irq.load(r)?;
+        self.audio.load(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::{test_rom, Mapper};
+
+    fn chr(vrc6: &mut Vrc6, address: u16) -> u8 {
+        match vrc6.ppu_read(address, PpuFetch::Background) {
+            PpuAccess::Cartridge(value) => value,
+            PpuAccess::Ciram(_) => panic!("${:04X} went to nametable RAM", address),
+        }
+    }
+
+    fn ciram(vrc6: &mut Vrc6, address: u16) -> u16 {
+        match vrc6.ppu_read(address, PpuFetch::Nametable) {
+            PpuAccess::Ciram(offset) => offset,
+            PpuAccess::Cartridge(_) => panic!("${:04X} went to the cartridge", address),
+        }
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut vrc6 = Vrc6::new(test_rom(24, 16, 8));
+        vrc6.write(0x8000, 3);
+        vrc6.write(0xC000, 9);
+        assert_eq!(vrc6.peek(0x8000), Some(6));
+        assert_eq!(vrc6.peek(0xA000), Some(7));
+        assert_eq!(vrc6.peek(0xC000), Some(9));
+        assert_eq!(vrc6.peek(0xE000), Some(15));
+
+        assert_eq!(vrc6.peek(0x6000), None);
+        vrc6.write(0xB003, 0x80);
+        vrc6.write(0x6000, 0x42);
+        assert_eq!(vrc6.peek(0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn chr_banks_and_mirroring() {
+        let mut vrc6 = Vrc6::new(test_rom(24, 4, 16));
+        for i in 0..4 {
+            vrc6.write(0xD000 + i, 15 - i as u8);
+            vrc6.write(0xE000 + i, 4 + i as u8);
+        }
+        let banks: Vec<u8> = (0..8).map(|i| chr(&mut vrc6, i * 0x400)).collect();
+        assert_eq!(banks, [15, 14, 13, 12, 4, 5, 6, 7]);
+
+        vrc6.write(0xB003, 0x00);
+        assert_eq!(ciram(&mut vrc6, 0x2400), 0x0400);
+        vrc6.write(0xB003, 0x04);
+        assert_eq!(ciram(&mut vrc6, 0x2400), 0x0000);
+        assert_eq!(ciram(&mut vrc6, 0x2800), 0x0400);
+        vrc6.write(0xB003, 0x0C);
+        assert_eq!(ciram(&mut vrc6, 0x2000), 0x0400);
+    }
+
+    #[test]
+    fn mapper_26_swaps_a0_and_a1() {
+        let mut vrc6 = Vrc6::new(test_rom(26, 4, 16));
+        vrc6.write(0xD001, 5);
+        vrc6.write(0xD002, 9);
+        assert_eq!(chr(&mut vrc6, 0x0400), 9);
+        assert_eq!(chr(&mut vrc6, 0x0800), 5);
+        // $B003 is still $B003
+        vrc6.write(0xB003, 0x80);
+        vrc6.write(0x6000, 1);
+        assert_eq!(vrc6.peek(0x6000), Some(1));
+    }
+
+    #[test]
+    fn cycle_irq() {
+        let mut vrc6 = Vrc6::new(test_rom(24, 4, 8));
+        vrc6.write(0xF000, 0xFD);
+        // enabled, cycle mode, re-enabled after the acknowledge
+        vrc6.write(0xF001, 0x07);
+        for _ in 0..2 {
+            vrc6.tick();
+            assert!(!vrc6.irq());
+        }
+        vrc6.tick();
+        assert!(vrc6.irq());
+        vrc6.write(0xF002, 0);
+        assert!(!vrc6.irq());
+        // reloaded from the latch
+        for _ in 0..3 {
+            vrc6.tick();
+        }
+        assert!(vrc6.irq());
+
+        vrc6.write(0xF001, 0x00);
+        assert!(!vrc6.irq());
+        for _ in 0..10 {
+            vrc6.tick();
+        }
+        assert!(!vrc6.irq());
+    }
+
+    #[test]
+    fn scanline_irq() {
+        let mut vrc6 = Vrc6::new(test_rom(24, 4, 8));
+        vrc6.write(0xF000, 0xFE);
+        vrc6.write(0xF001, 0x02);
+        // 341 dots at 3 per cycle
+        let mut cycles = 0;
+        while !vrc6.irq() {
+            vrc6.tick();
+            cycles += 1;
+        }
+        assert_eq!(cycles, 114 + 114);
+        // acknowledging without bit 0 set leaves it off
+        vrc6.write(0xF002, 0);
+        for _ in 0..1000 {
+            vrc6.tick();
+        }
+        assert!(!vrc6.irq());
+    }
+
+    #[test]
+    fn audio() {
+        let mut vrc6 = Vrc6::new(test_rom(24, 4, 8));
+        assert_eq!(vrc6.audio(), 0.0);
+        // constant volume 15 on the first pulse
+        vrc6.write(0x9000, 0x8F);
+        vrc6.write(0x9002, 0x80);
+        vrc6.tick();
+        assert_eq!(vrc6.audio(), ::apu::PULSE_FULL_VOLUME);
+        // mapper 26 has the same registers on swapped lines
+        let mut vrc6 = Vrc6::new(test_rom(26, 4, 8));
+        vrc6.write(0x9000, 0x8F);
+        vrc6.write(0x9001, 0x80);
+        vrc6.tick();
+        assert_eq!(vrc6.audio(), ::apu::PULSE_FULL_VOLUME);
+    }
+}
@@ -0,0 +1,212 @@
+// Konami VRC6 sound
+//
+// Two pulse channels with 8 duty cycles and a sawtooth, on top of the
+// 2A03's own. Registers, as seen with mapper 24's address lines:
+//   $9000/$A000  pulse: bit 7 constant output, duty (4-6), volume (0-3)
+//   $9001/$A001  pulse period, low 8 bits
+//   $9002/$A002  pulse enable (7), period high 4 bits
+//   $9003        bit 0 halts everything, bits 1 and 2 speed the periods
+//                up by 16 and 256 (for testing, games leave them clear)
+//   $B000        sawtooth accumulator rate (0-5)
+//   $B001-$B002  sawtooth period and enable, like the pulses
+//
+// Each channel's divider counts down from its period, so a step takes
+// period + 1 cycles. A pulse runs through 16 steps and is high on the ones
+// at or below its duty. The sawtooth adds its rate to an accumulator every
+// other step and starts over after 14, outputting the top 5 bits.
+
+use apu;
+use savestate::{Savable, StateWriter, StateReader, StateError};
+
+#[derive(Default)]
+struct Pulse {
+    volume: u8,
+    duty: u8,
+    constant: bool,
+    enabled: bool,
+    period: u16,
+    divider: u16,
+    step: u8,
+}
+
+impl Pulse {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.volume = value & 0x0F;
+                self.duty = (value >> 4) & 0x07;
+                self.constant = value & 0x80 != 0;
+            }
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value & 0x0F) as u16) << 8;
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.divider == 0 {
+            self.divider = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        }
+        else {
+            self.divider -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) { self.volume } else { 0 }
+    }
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u8(self.volume);
+        w.write_u8(self.duty);
+        w.write_bool(self.constant);
+        w.write_bool(self.enabled);
+        w.write_u16(self.period);
+        w.write_u16(self.divider);
+        w.write_u8(self.step);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.volume = r.read_u8()? & 0x0F;
+        self.duty = r.read_u8()? & 0x07;
+        self.constant = r.read_bool()?;
+        self.enabled = r.read_bool()?;
+        self.period = r.read_u16()? & 0x0FFF;
+        self.divider = r.read_u16()?;
+        self.step = r.read_u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Sawtooth {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    divider: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Sawtooth {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => self.rate = value & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value & 0x0F) as u16) << 8;
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.period >> shift;
+        self.step = (self.step + 1) % 14;
+        if self.step == 0 {
+            self.accumulator = 0;
+        }
+        else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u8(self.rate);
+        w.write_bool(self.enabled);
+        w.write_u16(self.period);
+        w.write_u16(self.divider);
+        w.write_u8(self.step);
+        w.write_u8(self.accumulator);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.rate = r.read_u8()? & 0x3F;
+        self.enabled = r.read_bool()?;
+        self.period = r.read_u16()? & 0x0FFF;
+        self.divider = r.read_u16()?;
+        self.step = r.read_u8()? % 14;
+        self.accumulator = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Vrc6Audio {
+    pulses: [Pulse; 2],
+    saw: Sawtooth,
+    halt: bool,
+    shift: u8,
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Vrc6Audio {
+        Vrc6Audio::default()
+    }
+    // `address` with mapper 24's layout.
+    pub fn write(&mut self, address: u16, value: u8) {
+        let register = address & 0x0003;
+        match address & 0xF000 {
+            0x9000 if register == 3 => {
+                self.halt = value & 0x01 != 0;
+                self.shift = if value & 0x04 != 0 { 8 } else if value & 0x02 != 0 { 4 } else { 0 };
+            }
+            0x9000 => self.pulses[0].write(register, value),
+            0xA000 if register != 3 => self.pulses[1].write(register, value),
+            0xB000 if register != 3 => self.saw.write(register, value),
+            _ => {}
+        }
+    }
+    // One CPU cycle.
+    pub fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        for pulse in &mut self.pulses {
+            pulse.clock(self.shift);
+        }
+        self.saw.clock(self.shift);
+    }
+    // On the APU's scale: a pulse at volume 15 is as loud as an APU pulse
+    // at 15.
+    pub fn output(&self) -> f32 {
+        let level = self.pulses[0].output() + self.pulses[1].output() + self.saw.output();
+        level as f32 / 15.0 * apu::PULSE_FULL_VOLUME
+    }
+}
+
+impl Savable for Vrc6Audio {
+    fn save(&self, w: &mut StateWriter) {
+        for pulse in &self.pulses {
+            pulse.save(w);
+        }
+        self.saw.save(w);
+        w.write_bool(self.halt);
+        w.write_u8(self.shift);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for pulse in &mut self.pulses {
+            pulse.load(r)?;
+        }
+        self.saw.load(r)?;
+        self.halt = r.read_bool()?;
+        self.shift = r.read_u8()?.min(8);
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+// Runs small test ROMs through `futilenes --test`, the same way the
+// community test ROMs are run. The ROMs are built here and report through
+// $6000 like blargg's do; see src/testrom.rs.
+//
+// To run a suite of real test ROMs as well, point FUTILENES_TEST_SUITE at
+// a suite file and run `cargo test -- --ignored`.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// Where each ROM's code goes, in the last 8K of PRG.
+const BASE: u16 = 0xE000;
+
+struct Rom {
+    name: &'static str,
+    mapper: u8,
+    code: Vec<u8>,
+    nmi: u16,
+    irq: u16,
+}
+
+impl Rom {
+    fn new(name: &'static str, mapper: u8) -> Rom {
+        Rom { name, mapper, code: Vec::new(), nmi: BASE, irq: BASE }
+    }
+    fn here(&self) -> u16 {
+        BASE + self.code.len() as u16
+    }
+    fn emit(&mut self, bytes: &[u8]) -> &mut Rom {
+        self.code.extend_from_slice(bytes);
+        self
+    }
+    // LDA #value; STA address
+    fn store(&mut self, address: u16, value: u8) -> &mut Rom {
+        self.emit(&[0xA9, value, 0x8D, address as u8, (address >> 8) as u8])
+    }
+    // Marks the result as valid but not ready, as test ROMs do first.
+    fn start(&mut self) -> &mut Rom {
+        self.store(0x6000, 0x80).store(0x6001, 0xDE).store(0x6002, 0xB0).store(0x6003, 0x61)
+    }
+    // Writes the result and stops.
+    fn finish(&mut self, code: u8, text: &str) -> &mut Rom {
+        for (i, &c) in text.as_bytes().iter().chain(&[0]).enumerate() {
+            self.store(0x6004 + i as u16, c);
+        }
+        self.store(0x6000, code);
+        let here = self.here();
+        self.emit(&[0x4C, here as u8, (here >> 8) as u8])
+    }
+    // Fails with `code` unless the last comparison was equal.
+    fn check_equal(&mut self, code: u8, text: &str) -> &mut Rom {
+        self.emit(&[0xF0, 0x00]); // BEQ over the failure
+        let start = self.code.len();
+        self.finish(code, text);
+        self.code[start - 1] = (self.code.len() - start) as u8;
+        self
+    }
+    // 32K of PRG with the code in the last 8K, and 8K of blank CHR-ROM.
+    fn image(&self) -> Vec<u8> {
+        let mut image = b"NES\x1A\x02\x01".to_vec();
+        image.extend_from_slice(&[(self.mapper & 0x0F) << 4, self.mapper & 0xF0]);
+        image.resize(16, 0);
+        let mut prg = vec![0xEA; 0x8000];
+        prg[0x6000..0x6000 + self.code.len()].copy_from_slice(&self.code);
+        let vectors = [self.nmi, BASE, self.irq];
+        for (i, v) in vectors.iter().enumerate() {
+            prg[0x7FFA + i * 2] = *v as u8;
+            prg[0x7FFB + i * 2] = (*v >> 8) as u8;
+        }
+        image.extend_from_slice(&prg);
+        image.extend_from_slice(&[0; 0x2000]);
+        image
+    }
+    fn write(&self) -> PathBuf {
+        let dir = env::temp_dir().join(format!("futilenes-test-roms-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.nes", self.name));
+        fs::write(&path, self.image()).unwrap();
+        path
+    }
+}
+
+// Exit status and stdout of a run.
+fn futilenes(args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_futilenes")).args(args).output().unwrap();
+    (output.status.success(), String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn run_test(rom: &Rom) -> (bool, String) {
+    let path = rom.write();
+    let result = futilenes(&[path.to_str().unwrap(), "--test"]);
+    fs::remove_file(path).unwrap();
+    result
+}
+
+#[test]
+fn pass() {
+    let mut rom = Rom::new("pass", 0);
+    rom.emit(&[0x78]).start().finish(0, "All tests passed");
+    let (ok, out) = run_test(&rom);
+    assert!(ok, "{}", out);
+    assert!(out.contains("PASS") && out.contains("passed All tests passed"), "{}", out);
+}
+
+#[test]
+fn fail() {
+    let mut rom = Rom::new("fail", 0);
+    rom.emit(&[0x78]).start().finish(3, "Failed #3");
+    let (ok, out) = run_test(&rom);
+    assert!(!ok);
+    assert!(out.contains("failed with code 3: Failed #3"), "{}", out);
+}
+
+#[test]
+fn jammed() {
+    let mut rom = Rom::new("jammed", 0);
+    rom.emit(&[0x78]).start().emit(&[0x02]);
+    let (ok, out) = run_test(&rom);
+    assert!(!ok);
+    assert!(out.contains("CPU jammed by opcode 02 at E015"), "{}", out);
+}
+
+// The way blargg's ROMs sync to the PPU: poll $2002 for vblank twice, then
+// count frames with the NMI.
+#[test]
+fn vblank_and_nmi() {
+    let mut rom = Rom::new("nmi", 0);
+    rom.emit(&[0x78]).start();
+    rom.emit(&[0x2C, 0x02, 0x20, 0x10, 0xFB]) // BIT $2002; BPL *-3
+        .emit(&[0x2C, 0x02, 0x20, 0x10, 0xFB])
+        .emit(&[0xA9, 0x00, 0x85, 0x10]) // LDA #0; STA $10
+        .store(0x2000, 0x80)
+        .emit(&[0xA5, 0x10, 0xC9, 0x03, 0x90, 0xFA]) // LDA $10; CMP #3; BCC *-4
+        .store(0x2000, 0x00)
+        .finish(0, "NMI");
+    rom.nmi = rom.here();
+    rom.emit(&[0xE6, 0x10, 0x40]); // INC $10; RTI
+    let (ok, out) = run_test(&rom);
+    assert!(ok, "{}", out);
+}
+
+// The MMC5's scanline IRQ only fires when the board sees the PPU fetch
+// through it.
+#[test]
+fn mmc5_scanline_irq() {
+    let mut rom = Rom::new("mmc5", 5);
+    rom.emit(&[0x78])
+        .store(0x5102, 2)
+        .store(0x5103, 1)
+        .store(0x5113, 0)
+        .start()
+        .emit(&[0xA9, 0x00, 0x85, 0x10])
+        .store(0x5203, 100)
+        .store(0x5204, 0x80)
+        .store(0x2001, 0x08)
+        .store(0x4017, 0x40) // no APU frame IRQs
+        .emit(&[0x58]) // CLI
+        .emit(&[0xA5, 0x10, 0xF0, 0xFC]) // LDA $10; BEQ *-2
+        .emit(&[0x78])
+        .finish(0, "IRQ");
+    rom.irq = rom.here();
+    rom.emit(&[0xAD, 0x04, 0x52, 0xE6, 0x10, 0x40]); // LDA $5204; INC $10; RTI
+    let (ok, out) = run_test(&rom);
+    assert!(ok, "{}", out);
+}
+
+// OAM DMA copies the page, and leaves OAMADDR where it was.
+#[test]
+fn oam_dma() {
+    let mut rom = Rom::new("oam-dma", 0);
+    rom.emit(&[0x78]).start();
+    for i in 0..4 {
+        rom.store(0x0200 + i, 0x10 + i as u8);
+    }
+    rom.store(0x2003, 0x00)
+        .store(0x4014, 0x02)
+        .store(0x2003, 0x01)
+        .emit(&[0xAD, 0x04, 0x20, 0xC9, 0x11]) // LDA $2004; CMP #$11
+        .check_equal(1, "OAMDATA")
+        .finish(0, "DMA");
+    let (ok, out) = run_test(&rom);
+    assert!(ok, "{}", out);
+}
+
+// The APU's frame counter raises an IRQ every four steps unless $4017
+// inhibits it, and $4015 reads it back (and clears it).
+#[test]
+fn apu_frame_irq() {
+    let mut rom = Rom::new("frame-irq", 0);
+    rom.emit(&[0x78]).start()
+        .emit(&[0xA9, 0x00, 0x85, 0x10])
+        .emit(&[0x58]) // CLI
+        .emit(&[0xA5, 0x10, 0xF0, 0xFC]) // LDA $10; BEQ *-2
+        .emit(&[0x78, 0xC9, 0x40]) // SEI; CMP #$40
+        .check_equal(1, "$4015")
+        .finish(0, "frame IRQ");
+    rom.irq = rom.here();
+    // LDA $4015; AND #$40; STA $10; LDA $4015; AND #$40; ORA #$01; RTI
+    // (the second read, after the first cleared the flag, makes $10 $41 if
+    // it didn't)
+    rom.emit(&[0xAD, 0x15, 0x40, 0x29, 0x40, 0x85, 0x10])
+        .emit(&[0xAD, 0x15, 0x40, 0x29, 0x40, 0x05, 0x10, 0x85, 0x10, 0x40]);
+    let (ok, out) = run_test(&rom);
+    assert!(ok, "{}", out);
+}
+
+#[test]
+fn suite() {
+    let mut pass = Rom::new("suite-pass", 0);
+    pass.emit(&[0x78]).start().finish(0, "");
+    let mut fail = Rom::new("suite-fail", 0);
+    fail.emit(&[0x78]).start().finish(1, "");
+    let pass = pass.write();
+    let fail = fail.write();
+    let suite = pass.with_file_name("suite.txt");
+    fs::write(&suite, "# two tests\nsuite-pass.nes\n\nsuite-fail.nes\n").unwrap();
+    let (ok, out) = futilenes(&["--test-suite", suite.to_str().unwrap()]);
+    for path in &[pass, fail, suite] {
+        fs::remove_file(path).unwrap();
+    }
+    assert!(!ok);
+    assert!(out.contains("PASS suite-pass.nes") && out.contains("FAIL suite-fail.nes"), "{}", out);
+    assert!(out.contains("1 of 2 tests passed."), "{}", out);
+}
+
+// A missing ROM is skipped and a bad one fails, without ending the run.
+#[test]
+fn suite_problems() {
+    let mut pass = Rom::new("suite-ok", 0);
+    pass.emit(&[0x78]).start().finish(0, "");
+    let pass = pass.write();
+    let bad = pass.with_file_name("suite-bad.nes");
+    fs::write(&bad, b"not a ROM").unwrap();
+    let suite = pass.with_file_name("suite-problems.txt");
+    fs::write(&suite, "suite-missing.nes\nsuite-bad.nes\nsuite-ok.nes\n").unwrap();
+    let (ok, out) = futilenes(&["--test-suite", suite.to_str().unwrap()]);
+    for path in &[pass, bad, suite] {
+        fs::remove_file(path).unwrap();
+    }
+    assert!(!ok);
+    assert!(out.contains("SKIP suite-missing.nes") && out.contains("FAIL suite-bad.nes"), "{}", out);
+    assert!(out.contains("PASS suite-ok.nes"), "{}", out);
+    assert!(out.contains("1 of 2 tests passed, 1 skipped."), "{}", out);
+}
+
+// A file that can't be read counts as a failed test.
+#[test]
+fn single_step_bad_file() {
+    let dir = env::temp_dir().join(format!("futilenes-single-step-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a9.json"), "[]").unwrap();
+    fs::write(dir.join("ea.json"), "not JSON").unwrap();
+    let (ok, out) = futilenes(&["--single-step", dir.to_str().unwrap()]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(!ok);
+    assert!(out.contains("FAIL ea.json"), "{}", out);
+    assert!(out.contains("0 of 1 tests passed."), "{}", out);
+}
+
+// A track that isn't a number is a usage error, not track 0.
+#[test]
+fn nsf_bad_track() {
+    let (ok, out) = futilenes(&["nsf", "music.nsf", "--track", "two"]);
+    assert!(!ok);
+    assert!(out.starts_with("Usage:"), "{}", out);
+}
+
+#[test]
+#[ignore = "needs FUTILENES_TEST_SUITE set to a suite file"]
+fn external_suite() {
+    let suite = env::var("FUTILENES_TEST_SUITE").expect("FUTILENES_TEST_SUITE is not set");
+    let (ok, out) = futilenes(&["--test-suite", &suite]);
+    assert!(ok, "{}", out);
+}